@@ -0,0 +1,80 @@
+//! Sends every Ethernet frame received by a VirtIO network device back to its sender, driving
+//! [`VirtIONetEmbassy`] through the [`Driver`] trait as the embassy network stack does.
+//!
+//! This is the driver-side part of the example: call [`init`] with the platform's [`Hal`] and the
+//! [`Transport`] of a network device, e.g. the MMIO transport of a `virtio-net-device` found in
+//! the device tree of the QEMU aarch64 `virt` machine, put the driver somewhere the interrupt
+//! handler for the device can reach it, and have the handler call
+//! [`VirtIONetEmbassy::handle_interrupt`]. `M` must be a mutex which can be locked from the
+//! interrupt handler, e.g. one which masks interrupts. Then call [`reflect`], or pass the driver to
+//! `embassy_net::new` instead. The device can be attached with
+//!
+//! ```sh
+//! qemu-system-aarch64 -machine virt ... \
+//!     -netdev user,id=net0 \
+//!     -device virtio-net-device,netdev=net0
+//! ```
+//!
+//! [`reflect`] polls the driver in a loop with a waker which does nothing, where an executor would
+//! sleep until the driver wakes it from the interrupt handler.
+
+#![no_std]
+
+use core::hint::spin_loop;
+use core::task::{Context, Waker};
+use embassy_net_driver::{Driver, HardwareAddress, LinkState, RxToken, TxToken};
+use lock_api::RawMutex;
+use log::info;
+use virtio_drivers_sel4::device::net::{embassy::VirtIONetEmbassy, VirtIONet};
+use virtio_drivers_sel4::transport::Transport;
+use virtio_drivers_sel4::{Error, Hal};
+
+const QUEUE_SIZE: usize = 16;
+const BUF_LEN: usize = 2048;
+
+/// The driver, shared between the network stack and the interrupt handler.
+pub type Net<M, H, T> = VirtIONetEmbassy<M, H, T, QUEUE_SIZE>;
+
+/// Sets up the driver for the given network device.
+pub fn init<M: RawMutex, H: Hal, T: Transport>(
+    transport: T,
+    hal: H,
+) -> Result<Net<M, H, T>, Error> {
+    Ok(VirtIONetEmbassy::new(VirtIONet::new(
+        transport, hal, BUF_LEN,
+    )?))
+}
+
+/// Sends each of the next `count` frames which the device receives back to its sender.
+pub fn reflect<M: RawMutex, H: Hal, T: Transport>(net: &Net<M, H, T>, count: usize) {
+    let mut driver = net;
+    let HardwareAddress::Ethernet(mac) = driver.hardware_address() else {
+        unreachable!("VirtIO network devices are Ethernet devices");
+    };
+    let caps = driver.capabilities();
+    info!(
+        "MAC address {:02x?}, MTU {}",
+        mac, caps.max_transmission_unit
+    );
+
+    let mut cx = Context::from_waker(Waker::noop());
+    while driver.link_state(&mut cx) == LinkState::Down {
+        spin_loop();
+    }
+    for _ in 0..count {
+        let (rx_token, tx_token) = loop {
+            match driver.receive(&mut cx) {
+                Some(tokens) => break tokens,
+                None => spin_loop(),
+            }
+        };
+        rx_token.consume(|frame| {
+            info!("reflecting a frame of {} bytes", frame.len());
+            tx_token.consume(frame.len(), |reply| {
+                reply.copy_from_slice(frame);
+                reply[..6].copy_from_slice(&frame[6..12]);
+                reply[6..12].copy_from_slice(&mac);
+            })
+        });
+    }
+}
@@ -0,0 +1,62 @@
+//! Mounts the first FAT volume of a VirtIO block device through [`SdmmcBlockDevice`], lists its
+//! root directory and prints the start of `README.TXT`.
+//!
+//! This is the driver-side part of the example: call [`run`] with the platform's [`Hal`] and the
+//! [`Transport`] of a block device, e.g. the MMIO transport of a `virtio-blk-device` found in the
+//! device tree of the QEMU aarch64 `virt` machine. A suitable image can be made with
+//!
+//! ```sh
+//! truncate -s 64M fat.img
+//! echo ',,c' | sfdisk fat.img
+//! mkfs.vfat --offset 2048 fat.img
+//! echo hello | mcopy -i fat.img@@1M - ::README.TXT
+//! ```
+//!
+//! and attached with
+//!
+//! ```sh
+//! qemu-system-aarch64 -machine virt ... \
+//!     -drive file=fat.img,if=none,format=raw,id=x0 \
+//!     -device virtio-blk-device,drive=x0
+//! ```
+
+#![no_std]
+
+use core::ops::ControlFlow;
+use embedded_sdmmc::{Mode, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use log::info;
+use virtio_drivers_sel4::device::blk::{SdmmcBlockDevice, VirtIOBlk};
+use virtio_drivers_sel4::transport::Transport;
+use virtio_drivers_sel4::{Error, Hal};
+
+/// There is no real-time clock, so files are timestamped with the FAT epoch, 1980-01-01.
+struct FatEpoch;
+
+impl TimeSource for FatEpoch {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp::from_fat(0x21, 0)
+    }
+}
+
+/// Mounts the FAT volume on the given block device and dumps some of its contents to the log.
+pub fn run<H: Hal, T: Transport>(transport: T, hal: H) -> Result<(), embedded_sdmmc::Error<Error>> {
+    let blk = VirtIOBlk::<H, T>::new(transport, hal).map_err(embedded_sdmmc::Error::DeviceError)?;
+    info!("block device has {} sectors", blk.capacity());
+    let volume_mgr: VolumeManager<_, _> = VolumeManager::new(SdmmcBlockDevice::new(blk), FatEpoch);
+
+    let volume = volume_mgr.open_volume(VolumeIdx(0))?;
+    let root_dir = volume.open_root_dir()?;
+    root_dir.iterate_dir(|entry| {
+        info!("{} ({} bytes)", entry.name, entry.size);
+        ControlFlow::Continue(())
+    })?;
+
+    let file = root_dir.open_file_in_dir("README.TXT", Mode::ReadOnly)?;
+    let mut buffer = [0; 64];
+    let len = file.read(&mut buffer)?;
+    info!(
+        "README.TXT starts with {:?}",
+        core::str::from_utf8(&buffer[..len])
+    );
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Echoes UDP datagrams sent to port 7 of a VirtIO network device, through the smoltcp network
+//! stack.
+//!
+//! This is the driver-side part of the example: call [`run`] with the platform's [`Hal`], the
+//! [`Transport`] of a network device, e.g. the MMIO transport of a `virtio-net-device` found in
+//! the device tree of the QEMU aarch64 `virt` machine, and a clock. The interface takes its MTU
+//! from the one which the device reports, and the socket buffers are sized to match. With QEMU's
+//! user networking the guest is 10.0.2.15, so forwarding a host port to it with
+//!
+//! ```sh
+//! qemu-system-aarch64 -machine virt ... \
+//!     -netdev user,id=net0,hostfwd=udp::5555-:7 \
+//!     -device virtio-net-device,netdev=net0,host_mtu=9000
+//! ```
+//!
+//! lets `nc -u localhost 5555` talk to it. smoltcp's `proto-ipv4` and `socket-udp` features must
+//! be enabled.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec;
+use log::{info, warn};
+use smoltcp::iface::{Config, Interface, SocketSet, SocketStorage};
+use smoltcp::phy::Device;
+use smoltcp::socket::udp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr};
+use virtio_drivers_sel4::device::net::{smoltcp::VirtIONetSmoltcp, VirtIONet};
+use virtio_drivers_sel4::transport::Transport;
+use virtio_drivers_sel4::{Error, Hal};
+
+const QUEUE_SIZE: usize = 16;
+const BUF_LEN: usize = 2048;
+const ECHO_PORT: u16 = 7;
+/// The number of datagrams which each socket buffer can hold.
+const SOCKET_PACKETS: usize = 4;
+/// The length of an Ethernet header, and of IPv4 and UDP headers without options.
+const HEADERS_LEN: usize = 14 + 20 + 8;
+
+/// Echoes the next `count` datagrams which are sent to the echo port, calling `now` for the
+/// current time.
+pub fn run<H: Hal, T: Transport>(
+    transport: T,
+    hal: H,
+    mut now: impl FnMut() -> Instant,
+    count: usize,
+) -> Result<(), Error> {
+    let net = VirtIONet::<H, T, QUEUE_SIZE>::new(transport, hal, BUF_LEN)?;
+    let mac = net.mac_address();
+    info!("MAC address {:02x?}, MTU {:?}", mac, net.mtu());
+    let mut device = VirtIONetSmoltcp::new(net);
+    // The capabilities of the device include the frame length for its MTU, which smoltcp uses as
+    // the interface MTU.
+    let max_datagram = device.capabilities().max_transmission_unit - HEADERS_LEN;
+
+    let config = Config::new(HardwareAddress::Ethernet(EthernetAddress(mac)));
+    let mut iface = Interface::new(config, &mut device, now());
+    iface.update_ip_addrs(|addrs| {
+        addrs
+            .push(IpCidr::new(IpAddress::v4(10, 0, 2, 15), 24))
+            .unwrap();
+    });
+
+    let mut rx_metadata = [udp::PacketMetadata::EMPTY; SOCKET_PACKETS];
+    let mut rx_payload = vec![0; SOCKET_PACKETS * max_datagram];
+    let mut tx_metadata = [udp::PacketMetadata::EMPTY; SOCKET_PACKETS];
+    let mut tx_payload = vec![0; SOCKET_PACKETS * max_datagram];
+    let socket = udp::Socket::new(
+        udp::PacketBuffer::new(&mut rx_metadata[..], &mut rx_payload[..]),
+        udp::PacketBuffer::new(&mut tx_metadata[..], &mut tx_payload[..]),
+    );
+    let mut storage = [SocketStorage::EMPTY; 1];
+    let mut sockets = SocketSet::new(&mut storage[..]);
+    let handle = sockets.add(socket);
+    sockets
+        .get_mut::<udp::Socket>(handle)
+        .bind(ECHO_PORT)
+        .expect("a new socket can be bound");
+
+    let mut datagram = vec![0; max_datagram];
+    let mut echoed = 0;
+    while echoed < count {
+        iface.poll(now(), &mut device, &mut sockets);
+        let socket = sockets.get_mut::<udp::Socket>(handle);
+        while let Ok((len, metadata)) = socket.recv_slice(&mut datagram) {
+            info!("echoing {} bytes to {}", len, metadata.endpoint);
+            if let Err(e) = socket.send_slice(&datagram[..len], metadata) {
+                warn!("failed to echo datagram: {}", e);
+            }
+            echoed += 1;
+        }
+    }
+    // Send the last replies.
+    iface.poll(now(), &mut device, &mut sockets);
+    Ok(())
+}
@@ -1,18 +1,48 @@
 //! Driver for VirtIO block devices.
 
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, Volatile};
-use crate::{Error, Result};
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::{SharedBuffer, VirtQueue};
+use crate::transport::{DeviceStatus, InterruptStatus, Transport};
+use crate::volatile::{volread, volwrite, Volatile};
+use crate::{nonnull_slice_from_raw_parts, pages, Error, Result};
 use bitflags::bitflags;
-use log::info;
+use core::array;
+#[cfg(feature = "embedded-sdmmc")]
+use core::cell::RefCell;
+use core::fmt::{self, Display, Formatter};
+use core::hint::spin_loop;
+use core::iter;
+use core::mem::ManuallyDrop;
+use core::ptr::NonNull;
+use log::{info, warn};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-const QUEUE: u16 = 0;
 const QUEUE_SIZE: u16 = 16;
-const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
+/// The maximum number of data segments the driver puts in a single request, leaving room in the
+/// queue for the request header and status. This is also the limit used when the device doesn't
+/// advertise `seg_max`.
+pub const MAX_SEGMENTS: usize = QUEUE_SIZE as usize - 2;
+/// The interval in microseconds at which blocking requests poll the device when a timeout has been
+/// set with [`VirtIOBlk::set_request_timeout`].
+pub const REQUEST_TIMEOUT_POLL_US: u64 = 100;
+/// Where the status of a blocking request with a timeout goes in its staging memory, after the
+/// header.
+const STAGING_RESP_OFFSET: usize = size_of::<BlkReq>();
+/// Where the data of a blocking request with a timeout goes in its staging memory.
+const STAGING_DATA_OFFSET: usize = SECTOR_SIZE;
+const SUPPORTED_FEATURES: BlkFeature = BlkFeature::SIZE_MAX
+    .union(BlkFeature::SEG_MAX)
+    .union(BlkFeature::RO)
+    .union(BlkFeature::GEOMETRY)
+    .union(BlkFeature::BLK_SIZE)
     .union(BlkFeature::FLUSH)
+    .union(BlkFeature::TOPOLOGY)
+    .union(BlkFeature::CONFIG_WCE)
+    .union(BlkFeature::MQ)
+    .union(BlkFeature::DISCARD)
+    .union(BlkFeature::WRITE_ZEROES)
+    .union(BlkFeature::LIFETIME)
+    .union(BlkFeature::SECURE_ERASE)
     .union(BlkFeature::RING_INDIRECT_DESC)
     .union(BlkFeature::RING_EVENT_IDX);
 
@@ -23,15 +53,19 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// Read and write requests (and other exotic requests) are placed in the queue and serviced
 /// (probably out of order) by the device except where noted.
 ///
+/// If the device supports `VIRTIO_BLK_F_MQ`, up to `MAX_QUEUES` request queues are set up, clamped
+/// to the number the device reports. The methods on `VirtIOBlk` itself all use queue 0; use
+/// [`VirtIOBlk::queue`] to submit requests to the others.
+///
 /// # Example
 ///
 /// ```
-/// # use virtio_drivers::{Error, Hal};
-/// # use virtio_drivers::transport::Transport;
-/// use virtio_drivers::device::blk::{VirtIOBlk, SECTOR_SIZE};
+/// # use virtio_drivers_sel4::{Error, Hal};
+/// # use virtio_drivers_sel4::transport::Transport;
+/// use virtio_drivers_sel4::device::blk::{VirtIOBlk, SECTOR_SIZE};
 ///
-/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
-/// let mut disk = VirtIOBlk::<HalImpl, _>::new(transport)?;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T, hal: HalImpl) -> Result<(), Error> {
+/// let mut disk = VirtIOBlk::<HalImpl, _>::new(transport, hal)?;
 ///
 /// println!("VirtIO block device: {} kB", disk.capacity() * SECTOR_SIZE as u64 / 2);
 ///
@@ -42,151 +76,1053 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// # Ok(())
 /// # }
 /// ```
-pub struct VirtIOBlk<H: Hal, T: Transport> {
+pub struct VirtIOBlk<H: Hal, T: Transport, const MAX_QUEUES: usize = 1> {
     transport: T,
-    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    config: NonNull<BlkConfig>,
+    /// The request queues, of which the first `num_queues` are set up.
+    queues: [Option<VirtQueue<H, { QUEUE_SIZE as usize }>>; MAX_QUEUES],
+    num_queues: u16,
     capacity: u64,
+    /// Whether the capacity has changed since the last call to `poll_event`.
+    capacity_changed: bool,
+    /// The maximum size in bytes of a single data segment, from `size_max`.
+    max_segment_size: usize,
+    /// The maximum number of data segments in a single request, from `seg_max`.
+    max_segments: usize,
+    /// The logical block size in bytes, from `blk_size`, which requests must be aligned to.
+    block_size: usize,
     negotiated_features: BlkFeature,
+    stats: BlkStats,
+    /// Requests on queue 0 which were submitted with a cookie, indexed by token.
+    cookie_requests: [Option<CookieRequest>; QUEUE_SIZE as usize],
+    hal: H,
+    /// How long blocking requests wait for the device before giving up, if at all.
+    request_timeout_us: Option<u64>,
+    /// Blocking requests which timed out, which the device may still access the staging memory of
+    /// until it completes them.
+    quarantine: [Option<QuarantinedRequest<H>>; QUEUE_SIZE as usize],
+    /// The staging memory of the last blocking request with a timeout, kept to use for the next.
+    staging: Option<Dma<H>>,
+    /// Whether the device has been reset by [`VirtIOBlk::prepare_suspend`] and not yet resumed.
+    suspended: bool,
 }
 
-impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
+impl<H: Hal, T: Transport, const MAX_QUEUES: usize> VirtIOBlk<H, T, MAX_QUEUES> {
     /// Create a new VirtIO-Blk driver.
     pub fn new(mut transport: T, hal: H) -> Result<Self> {
+        assert_ne!(MAX_QUEUES, 0, "VirtIOBlk needs at least one queue");
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         // Read configuration space.
         let config = transport.config_space::<BlkConfig>()?;
         info!("config: {:?}", config);
         // Safe because config is a valid pointer to the device configuration space.
-        let capacity = unsafe {
-            volread!(config, capacity_low) as u64 | (volread!(config, capacity_high) as u64) << 32
-        };
+        let capacity = unsafe { read_capacity(config) };
         info!("found a block device of size {}KB", capacity / 2);
 
-        let queue = VirtQueue::new(
-            hal,
-            &mut transport,
-            QUEUE,
-            negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
-            negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
-        )?;
+        let num_queues = if negotiated_features.contains(BlkFeature::MQ) {
+            // Safe because config is a valid pointer to the device configuration space.
+            let device_queues = unsafe { volread!(config, num_queues) };
+            device_queues.clamp(1, MAX_QUEUES.try_into().unwrap_or(u16::MAX))
+        } else {
+            1
+        };
+        info!("using {} request queues", num_queues);
+
+        // Safe because config is a valid pointer to the device configuration space.
+        let max_segment_size = if negotiated_features.contains(BlkFeature::SIZE_MAX) {
+            (unsafe { volread!(config, size_max) } as usize).max(SECTOR_SIZE)
+        } else {
+            usize::MAX
+        };
+        // Safe because config is a valid pointer to the device configuration space.
+        let max_segments = if negotiated_features.contains(BlkFeature::SEG_MAX) {
+            (unsafe { volread!(config, seg_max) } as usize).clamp(1, MAX_SEGMENTS)
+        } else {
+            MAX_SEGMENTS
+        };
+
+        // Only use the block size if it is a whole number of sectors, as requests are still made in
+        // units of sectors.
+        // Safe because config is a valid pointer to the device configuration space.
+        let block_size = if negotiated_features.contains(BlkFeature::BLK_SIZE) {
+            match unsafe { volread!(config, blk_size) } as usize {
+                size if size > SECTOR_SIZE && size % SECTOR_SIZE == 0 => size,
+                _ => SECTOR_SIZE,
+            }
+        } else {
+            SECTOR_SIZE
+        };
+
+        let mut queues = array::from_fn(|_| None);
+        for (index, queue) in queues.iter_mut().take(num_queues.into()).enumerate() {
+            *queue = Some(VirtQueue::new(
+                hal,
+                &mut transport,
+                index as u16,
+                negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
+                negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
+            )?);
+        }
         transport.finish_init();
 
         Ok(VirtIOBlk {
             transport,
-            queue,
+            config,
+            queues,
+            num_queues,
             capacity,
+            capacity_changed: false,
+            max_segment_size,
+            max_segments,
+            block_size,
             negotiated_features,
+            stats: BlkStats::default(),
+            cookie_requests: array::from_fn(|_| None),
+            hal,
+            request_timeout_us: None,
+            quarantine: array::from_fn(|_| None),
+            staging: None,
+            suspended: false,
         })
     }
 
     /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    ///
+    /// This is updated by [`VirtIOBlk::ack_interrupt`] when the device reports a configuration
+    /// change.
     pub fn capacity(&self) -> u64 {
         self.capacity
     }
 
+    /// Gets the capacity of the block device in bytes.
+    pub fn capacity_bytes(&self) -> u64 {
+        self.capacity * SECTOR_SIZE as u64
+    }
+
+    /// Returns the logical block size of the device in bytes.
+    ///
+    /// This is the device's `blk_size` if it supports `VIRTIO_BLK_F_BLK_SIZE` and it is a multiple
+    /// of [`SECTOR_SIZE`], or [`SECTOR_SIZE`] otherwise. Block IDs are always in units of
+    /// [`SECTOR_SIZE`], but reads and writes must start and end on a block boundary.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns [`Error::InvalidParam`] if a request for `len` bytes starting at the given sector
+    /// doesn't start and end on a logical block boundary.
+    fn check_block_alignment(&self, block_id: usize, len: usize) -> Result {
+        if !(block_id * SECTOR_SIZE).is_multiple_of(self.block_size)
+            || !len.is_multiple_of(self.block_size)
+        {
+            Err(Error::InvalidParam)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns information about the device, read from its configuration space.
+    ///
+    /// This reads the configuration space afresh each time it is called, so reflects any changes
+    /// the device has made since it was initialised.
+    pub fn info(&self) -> BlkInfo {
+        let features = self.negotiated_features;
+        let config = self.config;
+        // Safe because config is a valid pointer to the device configuration space.
+        unsafe {
+            BlkInfo {
+                capacity: read_capacity(config),
+                read_only: features.contains(BlkFeature::RO),
+                block_size: features
+                    .contains(BlkFeature::BLK_SIZE)
+                    .then(|| volread!(config, blk_size)),
+                geometry: features
+                    .contains(BlkFeature::GEOMETRY)
+                    .then(|| BlkGeometry {
+                        cylinders: volread!(config, cylinders),
+                        heads: volread!(config, heads),
+                        sectors: volread!(config, sectors),
+                    }),
+                topology: features
+                    .contains(BlkFeature::TOPOLOGY)
+                    .then(|| BlkTopology {
+                        physical_block_exp: volread!(config, physical_block_exp),
+                        alignment_offset: volread!(config, alignment_offset),
+                        min_io_size: volread!(config, min_io_size),
+                        opt_io_size: volread!(config, opt_io_size),
+                    }),
+                max_segment_size: self.max_segment_size,
+                max_segments: self.max_segments,
+            }
+        }
+    }
+
     /// Returns true if the block device is read-only, or false if it allows writes.
     pub fn readonly(&self) -> bool {
         self.negotiated_features.contains(BlkFeature::RO)
     }
 
+    /// Returns [`Error::ReadOnly`] if the device is read-only.
+    fn check_writable(&self) -> Result {
+        if self.readonly() {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the current cache mode of the device.
+    ///
+    /// This is only ever writeback if `VIRTIO_BLK_F_FLUSH` was negotiated, as otherwise there is no
+    /// way to flush the cache, so [`VirtIOBlk::barrier`] and [`VirtIOBlk::write_blocks_fua`] can't
+    /// do any better than waiting for writes to complete. If `VIRTIO_BLK_F_CONFIG_WCE` was also
+    /// negotiated the mode is read from the device configuration, otherwise it is writeback.
+    pub fn cache_mode(&self) -> CacheMode {
+        let features = self.negotiated_features;
+        let writeback = features.contains(BlkFeature::FLUSH)
+            && (!features.contains(BlkFeature::CONFIG_WCE)
+                // Safe because config is a valid pointer to the device configuration space.
+                || unsafe { volread!(self.config, writeback) } != 0);
+        if writeback {
+            CacheMode::Writeback
+        } else {
+            CacheMode::Writethrough
+        }
+    }
+
+    /// Switches the device's cache between writeback (`true`) and writethrough (`false`) mode, by
+    /// writing its configuration space.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_BLK_F_CONFIG_WCE`.
+    pub fn set_write_cache(&mut self, enabled: bool) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            return Err(Error::Unsupported);
+        }
+        // Safe because config is a valid pointer to the device configuration space.
+        unsafe { volwrite!(self.config, writeback, enabled.into()) };
+        Ok(())
+    }
+
+    /// Returns whether the device's cache is in writeback mode, read from its configuration
+    /// space.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_BLK_F_CONFIG_WCE`; see
+    /// [`VirtIOBlk::cache_mode`] for a best guess in that case.
+    pub fn write_cache(&self) -> Result<bool> {
+        if !self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            return Err(Error::Unsupported);
+        }
+        // Safe because config is a valid pointer to the device configuration space.
+        Ok(unsafe { volread!(self.config, writeback) } != 0)
+    }
+
+    /// Returns the I/O statistics which the driver has gathered since it was created or
+    /// [`VirtIOBlk::reset_stats`] was last called.
+    pub fn stats(&self) -> BlkStats {
+        self.stats
+    }
+
+    /// Resets all the I/O statistics to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats = BlkStats::default();
+    }
+
+    /// Returns the number of request queues which have been set up.
+    ///
+    /// This is 1 unless the device supports `VIRTIO_BLK_F_MQ`, and is never more than `MAX_QUEUES`.
+    pub fn num_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    /// Returns a handle to the request queue with the given index, which must be less than
+    /// [`VirtIOBlk::num_queues`].
+    pub fn queue(&mut self, index: u16) -> Result<BlkQueue<'_, H, T, MAX_QUEUES>> {
+        if index >= self.num_queues {
+            return Err(Error::InvalidParam);
+        }
+        Ok(BlkQueue { blk: self, index })
+    }
+
     /// Acknowledges a pending interrupt, if any.
     ///
-    /// Returns true if there was an interrupt to acknowledge.
+    /// Returns true if there was an interrupt to acknowledge. Completed requests on any queue can
+    /// then be found with [`VirtIOBlk::poll_used`].
+    ///
+    /// If the interrupt was caused by a configuration change the capacity is read again, and if it
+    /// has changed a [`BlkEvent::CapacityChanged`] will be returned by the next call to
+    /// [`VirtIOBlk::poll_event`].
     pub fn ack_interrupt(&mut self) -> bool {
-        self.transport.ack_interrupt()
+        let status = self.transport.ack_interrupt_status();
+        if !status.is_empty() {
+            self.stats.interrupts = self.stats.interrupts.wrapping_add(1);
+        }
+        if status.contains(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT) {
+            // Safe because config is a valid pointer to the device configuration space.
+            let capacity = unsafe { read_capacity(self.config) };
+            if capacity != self.capacity {
+                info!(
+                    "block device capacity changed from {}KB to {}KB",
+                    self.capacity / 2,
+                    capacity / 2
+                );
+                self.capacity = capacity;
+                self.capacity_changed = true;
+            }
+        }
+        !status.is_empty()
+    }
+
+    /// Returns the next pending event from the device, if any.
+    ///
+    /// Events are recorded by [`VirtIOBlk::ack_interrupt`].
+    pub fn poll_event(&mut self) -> Option<BlkEvent> {
+        if self.capacity_changed {
+            self.capacity_changed = false;
+            Some(BlkEvent::CapacityChanged(self.capacity))
+        } else {
+            None
+        }
     }
 
-    /// Enables interrupts from the device.
+    /// Enables interrupts from the device, for all request queues.
     pub fn enable_interrupts(&mut self) {
-        self.queue.set_dev_notify(true);
+        for queue in self.queues.iter_mut().flatten() {
+            queue.set_dev_notify(true);
+        }
     }
 
-    /// Disables interrupts from the device.
+    /// Disables interrupts from the device, for all request queues.
     pub fn disable_interrupts(&mut self) {
-        self.queue.set_dev_notify(false);
+        for queue in self.queues.iter_mut().flatten() {
+            queue.set_dev_notify(false);
+        }
+    }
+
+    /// Asks the device not to interrupt until it has completed at least `n` more requests on a
+    /// queue, rather than after every one, for all request queues. 0 is treated as 1, which is the
+    /// default.
+    ///
+    /// This trades latency for fewer interrupts, and only has an effect if
+    /// `VIRTIO_F_RING_EVENT_IDX` was negotiated. If fewer than `n` requests are in flight there may
+    /// be no interrupt until more are submitted, so the caller should poll in that case. The
+    /// threshold is reset to 1 by [`VirtIOBlk::flush`] and [`VirtIOBlk::barrier`].
+    pub fn set_completion_threshold(&mut self, n: u16) {
+        for queue in self.queues.iter_mut().flatten() {
+            queue.set_completion_threshold(n);
+        }
+    }
+
+    /// Returns the request queue with the given index, which must already have been checked.
+    fn virt_queue(&mut self, index: u16) -> &mut VirtQueue<H, { QUEUE_SIZE as usize }> {
+        self.queues[usize::from(index)]
+            .as_mut()
+            .expect("request queue not set up")
+    }
+
+    /// Sets how long blocking requests wait for the device to complete them, in microseconds, or
+    /// `None` (the default) to wait forever.
+    ///
+    /// While waiting, the device is polled every [`REQUEST_TIMEOUT_POLL_US`] microseconds, calling
+    /// [`Hal::delay_us`] in between. If a request times out, [`Error::Timeout`] is returned and the
+    /// driver can carry on being used.
+    ///
+    /// So that the device can't access the caller's buffers after a request has timed out, blocking
+    /// requests with a timeout (including [`VirtIOBlk::read_blocks_dma`] and
+    /// [`VirtIOBlk::write_blocks_dma`]) are staged in DMA memory owned by the driver, and the data
+    /// is copied to or from the caller's buffers. The staging memory of a request which times out
+    /// is quarantined, in the same way as the buffers of an aborted request, until the device
+    /// completes it; it is then reclaimed by the next blocking request with a timeout or call to
+    /// [`VirtIOBlk::pop_completion`]. See [`VirtIOBlk::quarantined_requests`].
+    pub fn set_request_timeout(&mut self, timeout_us: Option<u64>) {
+        self.request_timeout_us = timeout_us;
+    }
+
+    /// Returns the number of blocking requests which timed out and whose staging memory is still
+    /// quarantined, because the device hasn't completed them or they haven't been reclaimed yet.
+    ///
+    /// Each of these takes up a slot in the queue, and blocking requests with a timeout fail with
+    /// [`Error::QueueFull`] once the queue is full of them.
+    pub fn quarantined_requests(&self) -> usize {
+        self.quarantine.iter().flatten().count()
+    }
+
+    /// Reclaims the staging memory of quarantined requests which the device has now completed.
+    fn reap_quarantined(&mut self) {
+        let max_segment_size = self.max_segment_size;
+        for slot in 0..self.quarantine.len() {
+            let Some(mut request) = self.quarantine[slot].take() else {
+                continue;
+            };
+            let virt_queue = self.queues[usize::from(request.queue)].as_mut().unwrap();
+            // Safe because these are the same buffers as were added for the request, and nothing
+            // else uses its staging memory.
+            let result = unsafe {
+                let (inputs, input_count, mut outputs, output_count) = staged_buffers(
+                    &mut request.staging,
+                    request.data_len,
+                    request.write,
+                    max_segment_size,
+                );
+                virt_queue.pop_used(
+                    request.token,
+                    &inputs[..input_count],
+                    &mut outputs[..output_count],
+                )
+            };
+            if let Err(Error::NotReady) = result {
+                self.quarantine[slot] = Some(request);
+            } else {
+                info!("reclaimed block request {} which timed out", request.token);
+            }
+        }
+    }
+
+    /// Returns [`Error::NotReady`] if the device is suspended.
+    fn check_not_suspended(&self) -> Result {
+        if self.suspended {
+            Err(Error::NotReady)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Quiesces the device so that it can be suspended, e.g. for a checkpoint.
+    ///
+    /// This stops any new requests from being submitted, waits for the device to finish the
+    /// requests in flight on all queues, and then resets the device. If a request timeout has been
+    /// set with [`VirtIOBlk::set_request_timeout`] it limits how long to wait, and any requests which
+    /// the device hasn't finished by then are returned, to be submitted again by
+    /// [`VirtIOBlk::resume`]. Without a timeout, this waits for all of them.
+    ///
+    /// Requests which the device did finish can still be completed as usual while the device is
+    /// suspended. Submitting new requests fails with [`Error::NotReady`] until it is resumed.
+    pub fn prepare_suspend(&mut self) -> Result<PendingRequests<MAX_QUEUES>> {
+        self.check_not_suspended()?;
+        self.suspended = true;
+        let (hal, mut timeout) = (self.hal, self.request_timeout_us);
+        for queue in self.queues.iter().flatten() {
+            while queue.pending() != 0 && wait_for_device(hal, &mut timeout) {}
+        }
+        self.transport.set_status(DeviceStatus::empty());
+
+        let mut pending = PendingRequests {
+            tokens: [[false; QUEUE_SIZE as usize]; MAX_QUEUES],
+            features: self.negotiated_features,
+        };
+        for (tokens, queue) in pending.tokens.iter_mut().zip(self.queues.iter_mut()) {
+            if let Some(queue) = queue {
+                *tokens = queue.pending_tokens()?;
+            }
+        }
+        if !pending.is_empty() {
+            info!("suspending with {} block requests pending", pending.len());
+        }
+        Ok(pending)
+    }
+
+    /// Initialises the device again after [`VirtIOBlk::prepare_suspend`], and submits the requests
+    /// which it hadn't finished again.
+    ///
+    /// The same features are negotiated as before, and the queues are set up again with the same
+    /// memory, so tokens returned before the device was suspended stay valid. Returns
+    /// [`Error::InvalidParam`] if the device isn't suspended, or [`Error::Unsupported`] if it no
+    /// longer offers the same features, in which case it stays suspended.
+    pub fn resume(&mut self, pending: PendingRequests<MAX_QUEUES>) -> Result {
+        if !self.suspended {
+            return Err(Error::InvalidParam);
+        }
+        let negotiated_features = self.transport.begin_init(SUPPORTED_FEATURES);
+        if negotiated_features != pending.features {
+            warn!(
+                "block device features changed from {:?} to {:?} while suspended",
+                pending.features, negotiated_features
+            );
+            self.transport.set_status(DeviceStatus::empty());
+            return Err(Error::Unsupported);
+        }
+        for (tokens, queue) in pending.tokens.iter().zip(self.queues.iter_mut()) {
+            if let Some(queue) = queue {
+                if let Err(e) = queue.restart(&mut self.transport, tokens) {
+                    self.transport.set_status(DeviceStatus::empty());
+                    return Err(e);
+                }
+            }
+        }
+        self.transport.finish_init();
+        self.suspended = false;
+
+        // Safe because config is a valid pointer to the device configuration space.
+        let capacity = unsafe { read_capacity(self.config) };
+        if capacity != self.capacity {
+            self.capacity = capacity;
+            self.capacity_changed = true;
+        }
+        for (index, queue) in self.queues.iter().enumerate() {
+            if queue.as_ref().is_some_and(|queue| queue.should_notify()) {
+                self.transport.notify(index as u16);
+            }
+        }
+        Ok(())
     }
 
     /// Sends the given request to the device and waits for a response, with no extra data.
     fn request(&mut self, request: BlkReq) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [resp.as_bytes_mut()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        self.check_not_suspended()?;
+        let result = if self.request_timeout_us.is_some() {
+            self.request_staged(0, &request, 0, false, |_| {}, |_| {})
+        } else {
+            let mut resp = BlkResp::default();
+            self.queues[0]
+                .as_mut()
+                .unwrap()
+                .add_notify_wait_pop(
+                    &[request.as_bytes()],
+                    &mut [resp.as_bytes_mut()],
+                    &mut self.transport,
+                )
+                .and_then(|_| resp.status.into())
+        };
+        self.stats.record(request.type_, 0, &result);
+        result
+    }
+
+    /// Sends the given request to the device with `data_len` bytes of data staged in DMA memory
+    /// owned by the driver, and waits for a response until the request timeout expires.
+    ///
+    /// If `write` is true the data is sent to the device, and `fill` is called first to copy it into
+    /// the staging memory. Otherwise it is read from the device, and passed to `drain` once the
+    /// request has succeeded. If the request times out its staging memory is quarantined until the
+    /// device completes it, so the device never has access to the caller's buffers.
+    fn request_staged(
+        &mut self,
+        queue: u16,
+        request: &BlkReq,
+        data_len: usize,
+        write: bool,
+        fill: impl FnOnce(&mut [u8]),
+        drain: impl FnOnce(&[u8]),
+    ) -> Result {
+        self.reap_quarantined();
+        let Some(slot) = self.quarantine.iter().position(Option::is_none) else {
+            return Err(Error::QueueFull);
+        };
+        let mut staging = match self.staging.take() {
+            Some(staging) if staging.raw_slice().len() >= STAGING_DATA_OFFSET + data_len => staging,
+            _ => Dma::new(
+                self.hal,
+                pages(STAGING_DATA_OFFSET + data_len),
+                BufferDirection::Both,
+            )?,
+        };
+        {
+            // Safe because the staging memory is owned by the driver and not in use by the device.
+            let memory = unsafe { &mut *staging.raw_slice().as_ptr() };
+            memory[..size_of::<BlkReq>()].copy_from_slice(request.as_bytes());
+            memory[STAGING_RESP_OFFSET..][..size_of::<BlkResp>()]
+                .copy_from_slice(BlkResp::default().as_bytes());
+            if write {
+                fill(&mut memory[STAGING_DATA_OFFSET..][..data_len]);
+            }
+        }
+
+        let max_segment_size = self.max_segment_size;
+        let (hal, mut timeout) = (self.hal, self.request_timeout_us);
+        let virt_queue = self.queues[usize::from(queue)].as_mut().unwrap();
+        // Safe because the staging memory is only used for this request until it has been popped,
+        // and if it times out the memory is quarantined along with it.
+        let added = unsafe {
+            let (inputs, input_count, mut outputs, output_count) =
+                staged_buffers(&mut staging, data_len, write, max_segment_size);
+            virt_queue.add(&inputs[..input_count], &mut outputs[..output_count])
+        };
+        let token = match added {
+            Ok(token) => token,
+            Err(e) => {
+                self.staging = Some(staging);
+                return Err(e);
+            }
+        };
+        if virt_queue.should_notify() {
+            self.transport.notify(queue);
+        }
+        let result = loop {
+            // Safe because these are the same buffers as were added above.
+            match unsafe {
+                let (inputs, input_count, mut outputs, output_count) =
+                    staged_buffers(&mut staging, data_len, write, max_segment_size);
+                virt_queue.pop_used(token, &inputs[..input_count], &mut outputs[..output_count])
+            } {
+                Err(Error::NotReady) => {
+                    if !wait_for_device(hal, &mut timeout) {
+                        warn!("block request {} timed out, quarantining it", token);
+                        self.quarantine[slot] = Some(QuarantinedRequest {
+                            queue,
+                            token,
+                            staging,
+                            data_len,
+                            write,
+                        });
+                        return Err(Error::Timeout);
+                    }
+                }
+                result => break result,
+            }
+        };
+        {
+            // Safe because the device has finished with the staging memory.
+            let memory = unsafe { &*staging.raw_slice().as_ptr() };
+            let resp =
+                BlkResp::read_from(&memory[STAGING_RESP_OFFSET..][..size_of::<BlkResp>()]).unwrap();
+            let result = result.and_then(|_| resp.status.into());
+            if result.is_ok() && !write {
+                drain(&memory[STAGING_DATA_OFFSET..][..data_len]);
+            }
+            self.staging = Some(staging);
+            result
+        }
     }
 
     /// Sends the given request to the device and waits for a response, including the given data.
-    fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
+    ///
+    /// The data is split into segments of at most `max_segment_size` bytes, and must not need more
+    /// than `max_segments` of them.
+    fn request_read(&mut self, queue: u16, request: BlkReq, data: &mut [u8]) -> Result {
+        let max_segment_size = self.max_segment_size;
+        self.request_read_segments(queue, request, &mut data.chunks_mut(max_segment_size))
+            .map(|_| ())
+    }
+
+    /// Sends the given request to the device with up to `max_segments` segments taken from
+    /// `segments` to read the data into, and waits for a response.
+    ///
+    /// Returns the total length of the segments which were used.
+    fn request_read_segments<'b>(
+        &mut self,
+        queue: u16,
+        request: BlkReq,
+        segments: &mut impl Iterator<Item = &'b mut [u8]>,
+    ) -> Result<usize> {
+        self.check_not_suspended()?;
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [data, resp.as_bytes_mut()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        let mut outputs: [&mut [u8]; MAX_SEGMENTS + 1] = Default::default();
+        let mut count = 0;
+        let mut len = 0;
+        for segment in segments.take(self.max_segments) {
+            len += segment.len();
+            outputs[count] = segment;
+            count += 1;
+        }
+        let result = if self.request_timeout_us.is_some() {
+            self.request_staged(
+                queue,
+                &request,
+                len,
+                false,
+                |_| {},
+                |data| {
+                    let mut offset = 0;
+                    for segment in &mut outputs[..count] {
+                        segment.copy_from_slice(&data[offset..offset + segment.len()]);
+                        offset += segment.len();
+                    }
+                },
+            )
+        } else {
+            outputs[count] = resp.as_bytes_mut();
+            count += 1;
+            self.queues[usize::from(queue)]
+                .as_mut()
+                .unwrap()
+                .add_notify_wait_pop(
+                    &[request.as_bytes()],
+                    &mut outputs[..count],
+                    &mut self.transport,
+                )
+                .and_then(|_| resp.status.into())
+        };
+        self.stats.record(request.type_, len, &result);
+        result.map(|()| len)
     }
 
     /// Sends the given request and data to the device and waits for a response.
-    fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
+    ///
+    /// The data is split into segments in the same way as for `request_read`.
+    fn request_write(&mut self, queue: u16, request: BlkReq, data: &[u8]) -> Result {
+        let max_segment_size = self.max_segment_size;
+        self.request_write_segments(queue, request, &mut data.chunks(max_segment_size))
+            .map(|_| ())
+    }
+
+    /// Sends the given request to the device with up to `max_segments` segments of data taken from
+    /// `segments`, and waits for a response.
+    ///
+    /// Returns the total length of the segments which were sent.
+    fn request_write_segments<'b>(
+        &mut self,
+        queue: u16,
+        request: BlkReq,
+        segments: &mut impl Iterator<Item = &'b [u8]>,
+    ) -> Result<usize> {
+        self.check_not_suspended()?;
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes(), data],
-            &mut [resp.as_bytes_mut()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        let mut inputs: [&[u8]; MAX_SEGMENTS + 1] = Default::default();
+        inputs[0] = request.as_bytes();
+        let mut count = 1;
+        let mut len = 0;
+        for segment in segments.take(self.max_segments) {
+            len += segment.len();
+            inputs[count] = segment;
+            count += 1;
+        }
+        let result = if self.request_timeout_us.is_some() {
+            let fill = |data: &mut [u8]| {
+                let mut offset = 0;
+                for segment in &inputs[1..count] {
+                    data[offset..offset + segment.len()].copy_from_slice(segment);
+                    offset += segment.len();
+                }
+            };
+            self.request_staged(queue, &request, len, true, fill, |_| {})
+        } else {
+            self.queues[usize::from(queue)]
+                .as_mut()
+                .unwrap()
+                .add_notify_wait_pop(
+                    &inputs[..count],
+                    &mut [resp.as_bytes_mut()],
+                    &mut self.transport,
+                )
+                .and_then(|_| resp.status.into())
+        };
+        self.stats.record(request.type_, len, &result);
+        result.map(|()| len)
+    }
+
+    /// Checks that buffers with the given lengths can be used for a vectored read or write.
+    ///
+    /// Their total length must be a non-zero multiple of the block size. If they need more than
+    /// `max_segments` segments then they are split into several requests, each of which must also
+    /// be a multiple of the block size.
+    fn check_vectored(&self, block_id: usize, lengths: impl Iterator<Item = usize>) -> Result {
+        let mut total = 0;
+        let mut segments = 0;
+        for length in lengths {
+            let mut remaining = length;
+            while remaining > 0 {
+                if segments == self.max_segments {
+                    if total % self.block_size != 0 {
+                        return Err(Error::InvalidParam);
+                    }
+                    segments = 0;
+                }
+                let segment = remaining.min(self.max_segment_size);
+                total += segment;
+                remaining -= segment;
+                segments += 1;
+            }
+        }
+        if total == 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.check_block_alignment(block_id, total)
+    }
+
+    /// Returns [`Error::InvalidParam`] if the given range of bytes extends beyond the end of the
+    /// device.
+    #[cfg(feature = "embedded-storage")]
+    fn check_byte_range(&self, offset: usize, len: usize) -> Result {
+        let end = offset as u64 + len as u64;
+        if end > self.capacity * SECTOR_SIZE as u64 {
+            Err(Error::InvalidParam)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the maximum number of bytes of data which can be sent in a single read or write
+    /// request, as a multiple of the block size.
+    fn max_request_size(&self) -> usize {
+        let max = self.max_segment_size.saturating_mul(self.max_segments);
+        (max - max % self.block_size).max(self.block_size)
     }
 
-    /// Requests the device to flush any pending writes to storage.
+    /// Requests the device to flush any pending writes to storage, and waits for it to finish.
     ///
-    /// This will be ignored if the device doesn't support the `VIRTIO_BLK_F_FLUSH` feature.
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_FLUSH`
+    /// feature. If the cache has been switched to writethrough mode with
+    /// [`VirtIOBlk::set_write_cache`] there is nothing to flush, so this returns immediately. This
+    /// also resets the completion threshold set with [`VirtIOBlk::set_completion_threshold`] to 1.
     pub fn flush(&mut self) -> Result {
-        if self.negotiated_features.contains(BlkFeature::FLUSH) {
-            self.request(BlkReq {
-                type_: ReqType::Flush,
+        self.set_completion_threshold(1);
+        if !self.negotiated_features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        if self.write_cache() == Ok(false) {
+            return Ok(());
+        }
+        self.request(BlkReq {
+            type_: ReqType::Flush,
+            ..Default::default()
+        })
+    }
+
+    /// Waits for the device to finish all requests in flight on any queue, then flushes its cache
+    /// if it is in writeback mode, so that all earlier writes are durable before any later request
+    /// is issued.
+    ///
+    /// Requests submitted with the non-blocking methods still need to be completed by the caller
+    /// afterwards, with the corresponding `complete_*` method. If a request timeout has been set
+    /// with [`VirtIOBlk::set_request_timeout`] it limits how long to wait for them, and
+    /// [`Error::Timeout`] is returned if the device hasn't finished them by then. The requests are
+    /// left in flight in that case, so the barrier can be tried again.
+    pub fn barrier(&mut self) -> Result {
+        self.check_not_suspended()?;
+        self.set_completion_threshold(1);
+        let (hal, mut timeout) = (self.hal, self.request_timeout_us);
+        for queue in self.queues.iter().flatten() {
+            while queue.pending() != 0 {
+                if !wait_for_device(hal, &mut timeout) {
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+        if self.cache_mode() == CacheMode::Writeback {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the maximum number of sectors which the device can discard in a single request, or
+    /// `None` if it doesn't support `VIRTIO_BLK_F_DISCARD`.
+    pub fn max_discard_sectors(&self) -> Option<u32> {
+        self.negotiated_features
+            .contains(BlkFeature::DISCARD)
+            // Safe because config is a valid pointer to the device configuration space.
+            .then(|| unsafe { volread!(self.config, max_discard_sectors) })
+    }
+
+    /// Returns the alignment in sectors which the device expects discard requests to have, or `None`
+    /// if it doesn't support `VIRTIO_BLK_F_DISCARD`.
+    pub fn discard_sector_alignment(&self) -> Option<u32> {
+        self.negotiated_features
+            .contains(BlkFeature::DISCARD)
+            // Safe because config is a valid pointer to the device configuration space.
+            .then(|| unsafe { volread!(self.config, discard_sector_alignment) })
+    }
+
+    /// Returns the maximum number of sectors which the device can write zeroes to in a single
+    /// request, or `None` if it doesn't support `VIRTIO_BLK_F_WRITE_ZEROES`.
+    pub fn max_write_zeroes_sectors(&self) -> Option<u32> {
+        self.negotiated_features
+            .contains(BlkFeature::WRITE_ZEROES)
+            // Safe because config is a valid pointer to the device configuration space.
+            .then(|| unsafe { volread!(self.config, max_write_zeroes_sectors) })
+    }
+
+    /// Tells the device that the given range of sectors is no longer in use, so it may deallocate
+    /// them, and waits for it to finish.
+    ///
+    /// Ranges longer than [`VirtIOBlk::max_discard_sectors`] are split into several requests,
+    /// which are sent one after another. The range is not checked against
+    /// [`VirtIOBlk::discard_sector_alignment`]; the device may ignore parts of the range which are
+    /// not aligned.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_BLK_F_DISCARD`, or
+    /// [`Error::ReadOnly`] if it is read-only.
+    pub fn discard(&mut self, start_sector: u64, num_sectors: u32) -> Result {
+        let max_sectors = self.max_discard_sectors().ok_or(Error::Unsupported)?;
+        self.check_writable()?;
+        self.request_ranges(
+            ReqType::Discard,
+            start_sector,
+            num_sectors,
+            max_sectors,
+            DiscardWriteZeroesFlags::empty(),
+        )
+    }
+
+    /// Writes zeroes to the given range of sectors, and waits for the device to finish.
+    ///
+    /// If `unmap` is true then the device may deallocate the sectors rather than actually writing
+    /// zeroes to them, as long as subsequent reads return zeroes. Ranges longer than
+    /// [`VirtIOBlk::max_write_zeroes_sectors`] are split into several requests, which are sent one
+    /// after another.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_BLK_F_WRITE_ZEROES`, or
+    /// [`Error::ReadOnly`] if it is read-only.
+    pub fn write_zeroes(&mut self, start_sector: u64, num_sectors: u32, unmap: bool) -> Result {
+        let max_sectors = self.max_write_zeroes_sectors().ok_or(Error::Unsupported)?;
+        self.check_writable()?;
+        let flags = if unmap {
+            DiscardWriteZeroesFlags::UNMAP
+        } else {
+            DiscardWriteZeroesFlags::empty()
+        };
+        self.request_ranges(
+            ReqType::WriteZeroes,
+            start_sector,
+            num_sectors,
+            max_sectors,
+            flags,
+        )
+    }
+
+    /// Returns the maximum number of sectors which the device can securely erase in a single
+    /// request, or `None` if it doesn't support `VIRTIO_BLK_F_SECURE_ERASE`.
+    pub fn max_secure_erase_sectors(&self) -> Option<u32> {
+        self.negotiated_features
+            .contains(BlkFeature::SECURE_ERASE)
+            // Safe because config is a valid pointer to the device configuration space.
+            .then(|| unsafe { volread!(self.config, max_secure_erase_sectors) })
+    }
+
+    /// Returns the alignment in sectors which the device expects secure erase requests to have, or
+    /// `None` if it doesn't support `VIRTIO_BLK_F_SECURE_ERASE`.
+    pub fn secure_erase_sector_alignment(&self) -> Option<u32> {
+        self.negotiated_features
+            .contains(BlkFeature::SECURE_ERASE)
+            // Safe because config is a valid pointer to the device configuration space.
+            .then(|| unsafe { volread!(self.config, secure_erase_sector_alignment) })
+    }
+
+    /// Securely erases the given range of sectors, so that their previous contents can't be
+    /// recovered, and waits for the device to finish.
+    ///
+    /// Ranges longer than [`VirtIOBlk::max_secure_erase_sectors`] are split into several requests,
+    /// which are sent one after another. As with [`VirtIOBlk::discard`], the range is not checked
+    /// against [`VirtIOBlk::secure_erase_sector_alignment`].
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_BLK_F_SECURE_ERASE`, or
+    /// [`Error::ReadOnly`] if it is read-only.
+    pub fn secure_erase(&mut self, start_sector: u64, num_sectors: u32) -> Result {
+        let max_sectors = self.max_secure_erase_sectors().ok_or(Error::Unsupported)?;
+        self.check_writable()?;
+        self.request_ranges(
+            ReqType::SecureErase,
+            start_sector,
+            num_sectors,
+            max_sectors,
+            DiscardWriteZeroesFlags::empty(),
+        )
+    }
+
+    /// Queries the device for an estimate of its remaining lifetime, as for eMMC storage.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_BLK_F_LIFETIME`.
+    pub fn lifetime(&mut self) -> Result<BlkLifetime> {
+        if !self.negotiated_features.contains(BlkFeature::LIFETIME) {
+            return Err(Error::Unsupported);
+        }
+        let mut lifetime = BlkLifetime::new_zeroed();
+        self.request_read(
+            0,
+            BlkReq {
+                type_: ReqType::GetLifetime,
                 ..Default::default()
-            })
+            },
+            lifetime.as_bytes_mut(),
+        )?;
+        Ok(lifetime)
+    }
+
+    /// Sends discard, write zeroes or secure erase requests for the given range of sectors, with at
+    /// most `max_sectors` sectors in each request.
+    fn request_ranges(
+        &mut self,
+        type_: ReqType,
+        start_sector: u64,
+        num_sectors: u32,
+        max_sectors: u32,
+        flags: DiscardWriteZeroesFlags,
+    ) -> Result {
+        // A maximum of 0 doesn't make sense, so treat it as no limit.
+        let max_sectors = if max_sectors == 0 {
+            u32::MAX
         } else {
-            Ok(())
+            max_sectors
+        };
+        let mut sector = start_sector;
+        let mut remaining = num_sectors;
+        while remaining > 0 {
+            let count = remaining.min(max_sectors);
+            let range = DiscardWriteZeroes {
+                sector,
+                num_sectors: count,
+                flags,
+            };
+            self.request_write(
+                0,
+                BlkReq {
+                    type_,
+                    ..Default::default()
+                },
+                range.as_bytes(),
+            )?;
+            sector += u64::from(count);
+            remaining -= count;
         }
+        Ok(())
     }
 
-    /// Gets the device ID.
+    /// Gets the device ID, which is typically its serial number.
     ///
-    /// The ID is written as ASCII into the given buffer, which must be 20 bytes long, and the used
-    /// length returned.
-    pub fn device_id(&mut self, id: &mut [u8; 20]) -> Result<usize> {
+    /// The ID is written as ASCII into the given buffer, and the length up to the first NUL byte
+    /// (or the whole buffer if there is none) is returned.
+    ///
+    /// Returns [`Error::Unsupported`] if the device fails the request, as not all devices have an
+    /// ID.
+    pub fn device_id(&mut self, id: &mut [u8; DEVICE_ID_LENGTH]) -> Result<usize> {
+        // Make sure nothing is left over from before if the device writes less than the buffer.
+        id.fill(0);
         self.request_read(
+            0,
             BlkReq {
                 type_: ReqType::GetId,
                 ..Default::default()
             },
             id,
-        )?;
+        )
+        .map_err(|e| match e {
+            Error::BlkDeviceError(BlkError::IoError | BlkError::Unsupported) => Error::Unsupported,
+            e => e,
+        })?;
 
-        let length = id.iter().position(|&x| x == 0).unwrap_or(20);
+        let length = id.iter().position(|&x| x == 0).unwrap_or(DEVICE_ID_LENGTH);
         Ok(length)
     }
 
     /// Reads one or more blocks into the given buffer.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`]. The whole buffer is read
+    /// with a single request if possible, or otherwise with as few requests as the device's
+    /// `size_max` and `seg_max` limits allow.
+    ///
+    /// If the device has a [`block_size`](Self::block_size) larger than [`SECTOR_SIZE`], returns
+    /// [`Error::InvalidParam`] unless the buffer is a multiple of it and `block_id` is aligned to it.
     ///
     /// Blocks until the read completes or there is an error.
     pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        self.read_blocks_on(0, block_id, buf)
+    }
+
+    fn read_blocks_on(&mut self, queue: u16, block_id: usize, buf: &mut [u8]) -> Result {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_read(
-            BlkReq {
-                type_: ReqType::In,
-                reserved: 0,
-                sector: block_id as u64,
-            },
-            buf,
-        )
+        self.check_block_alignment(block_id, buf.len())?;
+        let mut sector = block_id as u64;
+        for chunk in buf.chunks_mut(self.max_request_size()) {
+            self.request_read(
+                queue,
+                BlkReq {
+                    type_: ReqType::In,
+                    reserved: 0,
+                    sector,
+                },
+                chunk,
+            )?;
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
     }
 
     /// Submits a request to read one or more blocks, but returns immediately without waiting for
@@ -198,8 +1134,9 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// * `req` - A buffer which the driver can use for the request to send to the device. The
     ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
     ///   it needs to be valid (and not otherwise used) until the corresponding
-    ///   `complete_read_blocks` call. Its length must be a non-zero multiple of [`SECTOR_SIZE`].
-    /// * `buf` - The buffer in memory into which the block should be read.
+    ///   `complete_read_blocks` call.
+    /// * `buf` - The buffer in memory into which the block should be read. Its length must be a
+    ///   non-zero multiple of [`SECTOR_SIZE`].
     /// * `resp` - A mutable reference to a variable provided by the caller
     ///   to contain the status of the request. The caller can safely
     ///   read the variable only after the request is complete.
@@ -210,15 +1147,20 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// the position of the first Descriptor in the chain. If there are not enough
     /// Descriptors to allocate, then it returns [`Error::QueueFull`].
     ///
+    /// When writing, [`Error::ReadOnly`] is returned without touching the queue if the device is
+    /// read-only.
+    ///
     /// The caller can then call `peek_used` with the returned token to check whether the device has
     /// finished handling the request. Once it has, the caller must call `complete_read_blocks` with
-    /// the same buffers before reading the response.
+    /// the same buffers before reading the response. Several requests may be in flight at once, and
+    /// they can be completed in any order, regardless of the order in which the device finished
+    /// them.
     ///
     /// ```
-    /// # use virtio_drivers::{Error, Hal};
-    /// # use virtio_drivers::device::blk::VirtIOBlk;
-    /// # use virtio_drivers::transport::Transport;
-    /// use virtio_drivers::device::blk::{BlkReq, BlkResp, RespStatus};
+    /// # use virtio_drivers_sel4::{Error, Hal};
+    /// # use virtio_drivers_sel4::device::blk::VirtIOBlk;
+    /// # use virtio_drivers_sel4::transport::Transport;
+    /// use virtio_drivers_sel4::device::blk::{BlkReq, BlkResp, RespStatus};
     ///
     /// # fn example<H: Hal, T: Transport>(blk: &mut VirtIOBlk<H, T>) -> Result<(), Error> {
     /// let mut request = BlkReq::default();
@@ -243,9 +1185,994 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ///
     /// # Safety
     ///
-    /// `req`, `buf` and `resp` are still borrowed by the underlying VirtIO block device even after
-    /// this method returns. Thus, it is the caller's responsibility to guarantee that they are not
-    /// accessed before the request is completed in order to avoid data races.
+    /// `req`, `buf` and `resp` are still borrowed by the underlying VirtIO block device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee that they are not
+    /// accessed before the request is completed in order to avoid data races.
+    pub unsafe fn read_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.read_blocks_nb_on(0, block_id, req, buf, resp)
+    }
+
+    unsafe fn read_blocks_nb_on(
+        &mut self,
+        queue: u16,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_block_alignment(block_id, buf.len())?;
+        self.check_not_suspended()?;
+        *req = BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let len = buf.len();
+        let virt_queue = self.virt_queue(queue);
+        let result = virt_queue.add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()]);
+        self.stats.record(ReqType::In, len, &result);
+        let token = result?;
+        if self.virt_queue(queue).should_notify() {
+            self.transport.notify(queue);
+        }
+        Ok(token)
+    }
+
+    /// Completes a read operation which was started by `read_blocks_nb`.
+    ///
+    /// This is typically called after [`VirtIOBlk::ack_interrupt`]. Returns [`Error::NotReady`] if
+    /// the device hasn't finished the request yet, or [`Error::InvalidParam`] without completing it
+    /// if the buffers are not the same shape as those for which the token was returned. Otherwise
+    /// the status from the response is returned.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `read_blocks_nb` when it returned
+    /// the token.
+    pub unsafe fn complete_read_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.complete_read_blocks_on(0, token, req, buf, resp)
+    }
+
+    unsafe fn complete_read_blocks_on(
+        &mut self,
+        queue: u16,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        if queue == 0 && self.has_cookie(token) {
+            return Err(Error::InvalidParam);
+        }
+        let result = self
+            .virt_queue(queue)
+            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])
+            .and_then(|_| resp.status.into());
+        self.stats.record_completed(&result);
+        result
+    }
+
+    /// Writes the contents of the given buffer to a block or blocks.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`]. As with
+    /// [`VirtIOBlk::read_blocks`], it is split into as few requests as the device allows.
+    ///
+    /// Blocks until the write is complete or there is an error. Returns [`Error::ReadOnly`] without
+    /// sending anything to the device if the device is read-only.
+    pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        self.write_blocks_on(0, block_id, buf)
+    }
+
+    fn write_blocks_on(&mut self, queue: u16, block_id: usize, buf: &[u8]) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_block_alignment(block_id, buf.len())?;
+        self.check_writable()?;
+        let mut sector = block_id as u64;
+        for chunk in buf.chunks(self.max_request_size()) {
+            self.request_write(
+                queue,
+                BlkReq {
+                    type_: ReqType::Out,
+                    sector,
+                    ..Default::default()
+                },
+                chunk,
+            )?;
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Reads blocks into several buffers, starting at `block_id`, with each buffer as its own
+    /// segment of a single request.
+    ///
+    /// The buffers are filled in order, as if they were one contiguous buffer. Buffers larger than
+    /// the device's `size_max` are split into several segments, and if there are more segments
+    /// than the device's `seg_max` then they are sent as several requests.
+    ///
+    /// Returns [`Error::InvalidParam`] without sending anything to the device if the total length
+    /// of the buffers is not a non-zero multiple of the [`block_size`](Self::block_size), if
+    /// `block_id` is not aligned to it, or if they must be split into several requests and a split
+    /// falls partway through a block.
+    pub fn read_blocks_vectored(&mut self, block_id: usize, bufs: &mut [&mut [u8]]) -> Result {
+        self.check_vectored(block_id, bufs.iter().map(|buf| buf.len()))?;
+        let max_segment_size = self.max_segment_size;
+        let mut segments = bufs
+            .iter_mut()
+            .flat_map(|buf| buf.chunks_mut(max_segment_size))
+            .peekable();
+        let mut sector = block_id as u64;
+        while segments.peek().is_some() {
+            let len = self.request_read_segments(
+                0,
+                BlkReq {
+                    type_: ReqType::In,
+                    sector,
+                    ..Default::default()
+                },
+                &mut segments,
+            )?;
+            sector += (len / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of several buffers to blocks starting at `block_id`, with each buffer
+    /// as its own segment of a single request.
+    ///
+    /// See [`VirtIOBlk::read_blocks_vectored`] for how the buffers are split and the errors which
+    /// may be returned. Returns [`Error::ReadOnly`] without sending anything to the device if the
+    /// device is read-only.
+    pub fn write_blocks_vectored(&mut self, block_id: usize, bufs: &[&[u8]]) -> Result {
+        self.check_vectored(block_id, bufs.iter().map(|buf| buf.len()))?;
+        self.check_writable()?;
+        let max_segment_size = self.max_segment_size;
+        let mut segments = bufs
+            .iter()
+            .flat_map(|buf| buf.chunks(max_segment_size))
+            .peekable();
+        let mut sector = block_id as u64;
+        while segments.peek().is_some() {
+            let len = self.request_write_segments(
+                0,
+                BlkReq {
+                    type_: ReqType::Out,
+                    sector,
+                    ..Default::default()
+                },
+                &mut segments,
+            )?;
+            sector += (len / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of the given buffer to a block or blocks like
+    /// [`VirtIOBlk::write_blocks`], then flushes the device's cache if it is in writeback mode, so
+    /// the data is durable once this returns.
+    ///
+    /// VirtIO has no forced unit access flag for individual writes, so this is the closest
+    /// equivalent.
+    pub fn write_blocks_fua(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        self.write_blocks(block_id, buf)?;
+        if self.cache_mode() == CacheMode::Writeback {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Submits a request to write one or more blocks, but returns immediately without waiting for
+    /// the write to complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id` - The identifier of the first block to write.
+    /// * `req` - A buffer which the driver can use for the request to send to the device. The
+    ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
+    ///   it needs to be valid (and not otherwise used) until the corresponding
+    ///   `complete_write_blocks` call.
+    /// * `buf` - The buffer in memory containing the data to write to the blocks. Its length must
+    ///   be a non-zero multiple of [`SECTOR_SIZE`].
+    /// * `resp` - A mutable reference to a variable provided by the caller
+    ///   to contain the status of the request. The caller can safely
+    ///   read the variable only after the request is complete.
+    ///
+    /// # Usage
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    ///
+    /// # Safety
+    ///
+    /// See  [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn write_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.write_blocks_nb_on(0, block_id, req, buf, resp)
+    }
+
+    unsafe fn write_blocks_nb_on(
+        &mut self,
+        queue: u16,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_block_alignment(block_id, buf.len())?;
+        self.check_writable()?;
+        self.check_not_suspended()?;
+        *req = BlkReq {
+            type_: ReqType::Out,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let virt_queue = self.virt_queue(queue);
+        let result = virt_queue.add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()]);
+        self.stats.record(ReqType::Out, buf.len(), &result);
+        let token = result?;
+        if self.virt_queue(queue).should_notify() {
+            self.transport.notify(queue);
+        }
+        Ok(token)
+    }
+
+    /// Completes a write operation which was started by `write_blocks_nb`.
+    ///
+    /// See [`VirtIOBlk::complete_read_blocks`] for the errors which may be returned.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `write_blocks_nb` when it
+    /// returned the token.
+    pub unsafe fn complete_write_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.complete_write_blocks_on(0, token, req, buf, resp)
+    }
+
+    unsafe fn complete_write_blocks_on(
+        &mut self,
+        queue: u16,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        if queue == 0 && self.has_cookie(token) {
+            return Err(Error::InvalidParam);
+        }
+        let result = self
+            .virt_queue(queue)
+            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])
+            .and_then(|_| resp.status.into());
+        self.stats.record_completed(&result);
+        result
+    }
+
+    /// Reads blocks directly into the given DMA region, starting at `block_id`, without copying.
+    ///
+    /// The whole region is read. Its physical address is given to the device as it is rather than
+    /// going through [`Hal::share`], so the region must have been allocated such that the device
+    /// can write to it (with [`BufferDirection::DeviceToDriver`] or [`BufferDirection::Both`]). If
+    /// the device is not cache-coherent with the driver, the caller must invalidate any cached
+    /// copy of the region before reading the data from it.
+    ///
+    /// The region is split into as many requests as needed to respect the device's `size_max`.
+    /// Blocks until the read is complete or there is an error.
+    pub fn read_blocks_dma(&mut self, block_id: usize, buf: &mut Dma<H>) -> Result {
+        self.request_dma(ReqType::In, block_id, buf, BufferDirection::DeviceToDriver)
+    }
+
+    /// Writes the contents of the given DMA region to blocks starting at `block_id`, without
+    /// copying.
+    ///
+    /// As with [`VirtIOBlk::read_blocks_dma`], the region's physical address is given to the device
+    /// directly. If the device is not cache-coherent with the driver, the caller must clean any
+    /// cached writes to the region before calling this.
+    ///
+    /// Blocks until the write is complete or there is an error. Returns [`Error::ReadOnly`] without
+    /// sending anything to the device if the device is read-only.
+    pub fn write_blocks_dma(&mut self, block_id: usize, buf: &Dma<H>) -> Result {
+        self.check_writable()?;
+        self.request_dma(ReqType::Out, block_id, buf, BufferDirection::DriverToDevice)
+    }
+
+    /// Sends read or write requests for the whole of the given DMA region on the first request
+    /// queue, split into segments of at most `max_segment_size`, waiting for each to complete.
+    fn request_dma(
+        &mut self,
+        type_: ReqType,
+        block_id: usize,
+        buf: &Dma<H>,
+        direction: BufferDirection,
+    ) -> Result {
+        self.check_not_suspended()?;
+        let max = self.max_segment_size.min(u32::MAX as usize);
+        let chunk_size = (max - max % self.block_size).max(self.block_size);
+        let len = buf.raw_slice().len();
+        self.check_block_alignment(block_id, len)?;
+        for offset in (0..len).step_by(chunk_size) {
+            let request = BlkReq {
+                type_,
+                sector: (block_id + offset / SECTOR_SIZE) as u64,
+                ..Default::default()
+            };
+            let data_len = chunk_size.min(len - offset);
+            if self.request_timeout_us.is_some() {
+                let region = buf.vaddr(offset);
+                // Safe because the region is valid for `data_len` bytes from `offset`, and the
+                // staging memory is distinct from it.
+                let fill = |data: &mut [u8]| unsafe {
+                    data.as_mut_ptr()
+                        .copy_from_nonoverlapping(region.as_ptr(), data_len)
+                };
+                let drain = |data: &[u8]| unsafe {
+                    region
+                        .as_ptr()
+                        .copy_from_nonoverlapping(data.as_ptr(), data_len)
+                };
+                let write = direction == BufferDirection::DriverToDevice;
+                let result = self.request_staged(0, &request, data_len, write, fill, drain);
+                self.stats.record(type_, data_len, &result);
+                result?;
+                continue;
+            }
+            let data = SharedBuffer::new(
+                nonnull_slice_from_raw_parts(buf.vaddr(offset), data_len),
+                buf.paddr() + offset,
+                direction,
+            );
+            let mut resp = BlkResp::default();
+            let virt_queue = self.virt_queue(0);
+            // Safe because the DMA region is borrowed until we have popped the token below, and the
+            // request and response live until then.
+            let token = match unsafe {
+                virt_queue.add_shared(&[request.as_bytes()], data, &mut [resp.as_bytes_mut()])
+            } {
+                Ok(token) => token,
+                Err(e) => {
+                    let result = Err(e);
+                    self.stats.record(type_, data_len, &result);
+                    return result;
+                }
+            };
+            if virt_queue.should_notify() {
+                self.transport.notify(0);
+            }
+            let result = loop {
+                // Safe because these are the same buffers as we passed to `add_shared` above.
+                match unsafe {
+                    self.virt_queue(0).pop_used_shared(
+                        token,
+                        &[request.as_bytes()],
+                        data,
+                        &mut [resp.as_bytes_mut()],
+                    )
+                } {
+                    Err(Error::NotReady) => spin_loop(),
+                    result => break result,
+                }
+            }
+            .and_then(|_| resp.status.into());
+            self.stats.record(type_, data_len, &result);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Submits a request to read blocks directly into the given DMA buffer, but returns
+    /// immediately without waiting for the read to complete.
+    ///
+    /// This works like [`VirtIOBlk::read_blocks_nb`], but the data is read into the whole DMA
+    /// region without being shared or copied, with the same requirements as for
+    /// [`VirtIOBlk::read_blocks_dma`]. The buffer is marked as in flight until the request is
+    /// completed with [`VirtIOBlk::complete_read_blocks_dma`], so the region can't be taken out of
+    /// it or freed in the meantime.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer is already in flight, or if it is larger than
+    /// the device's `size_max` so can't be sent as a single segment.
+    ///
+    /// # Safety
+    ///
+    /// `req` and `resp` are still borrowed by the device after this method returns, so the caller
+    /// must not access them until the request is completed.
+    pub unsafe fn read_blocks_dma_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut BlkDmaBuffer<H>,
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.request_dma_nb(
+            ReqType::In,
+            block_id,
+            req,
+            buf,
+            BufferDirection::DeviceToDriver,
+            resp,
+        )
+    }
+
+    /// Completes a read operation which was started by `read_blocks_dma_nb`.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer is not in flight for the given token. See
+    /// [`VirtIOBlk::complete_read_blocks`] for the other errors which may be returned.
+    ///
+    /// # Safety
+    ///
+    /// The same `req` and `resp` must be passed in again as were passed to `read_blocks_dma_nb`
+    /// when it returned the token.
+    pub unsafe fn complete_read_blocks_dma(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut BlkDmaBuffer<H>,
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.complete_request_dma(token, req, buf, BufferDirection::DeviceToDriver, resp)
+    }
+
+    /// Submits a request to write the contents of the given DMA buffer, but returns immediately
+    /// without waiting for the write to complete.
+    ///
+    /// See [`VirtIOBlk::read_blocks_dma_nb`]. Returns [`Error::ReadOnly`] without touching the
+    /// queue if the device is read-only.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::read_blocks_dma_nb`].
+    pub unsafe fn write_blocks_dma_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut BlkDmaBuffer<H>,
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.check_writable()?;
+        self.request_dma_nb(
+            ReqType::Out,
+            block_id,
+            req,
+            buf,
+            BufferDirection::DriverToDevice,
+            resp,
+        )
+    }
+
+    /// Completes a write operation which was started by `write_blocks_dma_nb`.
+    ///
+    /// See [`VirtIOBlk::complete_read_blocks_dma`].
+    ///
+    /// # Safety
+    ///
+    /// The same `req` and `resp` must be passed in again as were passed to `write_blocks_dma_nb`
+    /// when it returned the token.
+    pub unsafe fn complete_write_blocks_dma(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut BlkDmaBuffer<H>,
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.complete_request_dma(token, req, buf, BufferDirection::DriverToDevice, resp)
+    }
+
+    unsafe fn request_dma_nb(
+        &mut self,
+        type_: ReqType,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut BlkDmaBuffer<H>,
+        direction: BufferDirection,
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.check_not_suspended()?;
+        let data = buf.shared(direction);
+        let len = buf.dma.raw_slice().len();
+        self.check_block_alignment(block_id, len)?;
+        if buf.token.is_some() || len > self.max_segment_size.min(u32::MAX as usize) {
+            return Err(Error::InvalidParam);
+        }
+        *req = BlkReq {
+            type_,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let virt_queue = self.virt_queue(0);
+        let result = virt_queue.add_shared(&[req.as_bytes()], data, &mut [resp.as_bytes_mut()]);
+        self.stats.record(type_, len, &result);
+        let token = result?;
+        if self.virt_queue(0).should_notify() {
+            self.transport.notify(0);
+        }
+        buf.token = Some(token);
+        Ok(token)
+    }
+
+    unsafe fn complete_request_dma(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut BlkDmaBuffer<H>,
+        direction: BufferDirection,
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        if buf.token != Some(token) {
+            return Err(Error::InvalidParam);
+        }
+        let result = self.virt_queue(0).pop_used_shared(
+            token,
+            &[req.as_bytes()],
+            buf.shared(direction),
+            &mut [resp.as_bytes_mut()],
+        );
+        if result.is_ok() {
+            buf.token = None;
+        }
+        let result = result.and_then(|_| resp.status.into());
+        self.stats.record_completed(&result);
+        result
+    }
+
+    /// Returns the number of requests on all queues which have been submitted but not yet
+    /// completed, including those the device has finished but which haven't been passed to the
+    /// corresponding `complete_*` method yet.
+    pub fn in_flight(&self) -> usize {
+        self.queues.iter().flatten().map(VirtQueue::in_flight).sum()
+    }
+
+    /// Fetches the token of the next completed request from the used ring and returns it, without
+    /// removing it from the used ring. If there are no pending completed requests returns `None`.
+    pub fn peek_used(&mut self) -> Option<u16> {
+        self.virt_queue(0).peek_used()
+    }
+
+    /// Submits a request to read one or more blocks like [`VirtIOBlk::read_blocks_nb`], and
+    /// associates the given cookie with it.
+    ///
+    /// The request must be completed with [`VirtIOBlk::pop_completion`], which returns the cookie
+    /// along with the token. Passing the token to [`VirtIOBlk::complete_read_blocks`] instead
+    /// returns [`Error::InvalidParam`].
+    ///
+    /// # Safety
+    ///
+    /// `req`, `buf` and `resp` are still borrowed by the device after this method returns, and are
+    /// accessed by the driver when the request is completed. The caller must keep them valid and
+    /// not access them until `pop_completion` returns the token.
+    pub unsafe fn read_blocks_nb_with_cookie(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+        cookie: u64,
+    ) -> Result<u16> {
+        let token = self.read_blocks_nb_on(0, block_id, req, buf, resp)?;
+        self.add_cookie_request(token, cookie, req, buf.into(), resp, false);
+        Ok(token)
+    }
+
+    /// Submits a request to write one or more blocks like [`VirtIOBlk::write_blocks_nb`], and
+    /// associates the given cookie with it.
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb_with_cookie`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb_with_cookie`].
+    pub unsafe fn write_blocks_nb_with_cookie(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+        cookie: u64,
+    ) -> Result<u16> {
+        let token = self.write_blocks_nb_on(0, block_id, req, buf, resp)?;
+        self.add_cookie_request(token, cookie, req, buf.into(), resp, true);
+        Ok(token)
+    }
+
+    fn add_cookie_request(
+        &mut self,
+        token: u16,
+        cookie: u64,
+        req: &mut BlkReq,
+        buf: NonNull<[u8]>,
+        resp: &mut BlkResp,
+        write: bool,
+    ) {
+        let entry = &mut self.cookie_requests[usize::from(token)];
+        assert!(entry.is_none(), "token {} already has a cookie", token);
+        *entry = Some(CookieRequest {
+            cookie,
+            req: req.into(),
+            buf,
+            resp: resp.into(),
+            write,
+            aborted: false,
+        });
+    }
+
+    fn has_cookie(&self, token: u16) -> bool {
+        matches!(self.cookie_requests.get(usize::from(token)), Some(Some(_)))
+    }
+
+    /// Completes a request which was submitted with a cookie and which the device has finished,
+    /// if there is one.
+    ///
+    /// Returns the token and cookie with which the request was submitted, and the result of the
+    /// request. Requests can be returned in any order, regardless of the order in which they were
+    /// submitted.
+    pub fn pop_completion(&mut self) -> Option<(u16, u64, Result)> {
+        self.reap_quarantined();
+        for token in 0..QUEUE_SIZE {
+            let Some(request) = self.cookie_requests[usize::from(token)].take() else {
+                continue;
+            };
+            // Safe because the caller of `read_blocks_nb_with_cookie` or
+            // `write_blocks_nb_with_cookie` promised that the buffers remain valid and unused until
+            // we return the token, and they are the same buffers as were submitted for it.
+            let result = unsafe {
+                let req = request.req.as_ref();
+                let resp = &mut *request.resp.as_ptr();
+                if request.write {
+                    self.complete_write_blocks_on(0, token, req, request.buf.as_ref(), resp)
+                } else {
+                    self.complete_read_blocks_on(0, token, req, &mut *request.buf.as_ptr(), resp)
+                }
+            };
+            if let Err(Error::NotReady) = result {
+                self.cookie_requests[usize::from(token)] = Some(request);
+            } else if !request.aborted {
+                return Some((token, request.cookie, result));
+            }
+        }
+        None
+    }
+
+    /// Abandons a request which was submitted with a cookie, so that its result is discarded rather
+    /// than being returned by [`VirtIOBlk::pop_completion`].
+    ///
+    /// The device may still access the request's buffers until it completes the request, so they
+    /// are quarantined rather than released: the caller must keep them valid and unused until
+    /// [`VirtIOBlk::is_quarantined`] returns false for the token. That happens once the device has
+    /// completed the request and it has been reaped by a call to `pop_completion` (or
+    /// [`VirtIOBlk::poll_completions`]). If the device never completes it, the buffers stay
+    /// quarantined for as long as the device is in use.
+    ///
+    /// Returns [`Error::InvalidParam`] if the token is not for a pending request with a cookie.
+    pub fn abort(&mut self, token: u16) -> Result {
+        match self.cookie_requests.get_mut(usize::from(token)) {
+            Some(Some(request)) => {
+                request.aborted = true;
+                Ok(())
+            }
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Returns whether the buffers of the given aborted request may still be accessed by the
+    /// device, so must not be reused yet.
+    pub fn is_quarantined(&self, token: u16) -> bool {
+        matches!(
+            self.cookie_requests.get(usize::from(token)),
+            Some(Some(CookieRequest { aborted: true, .. }))
+        )
+    }
+
+    /// Returns an iterator which completes every request submitted with a cookie that the device
+    /// has finished, as [`VirtIOBlk::pop_completion`] does for one.
+    ///
+    /// This is typically called once after [`VirtIOBlk::ack_interrupt`], so that a single
+    /// interrupt (which with `VIRTIO_F_EVENT_IDX` may cover many requests) reaps everything that
+    /// has finished.
+    pub fn poll_completions(&mut self) -> impl Iterator<Item = Completion> + '_ {
+        iter::from_fn(move || {
+            let (token, cookie, result) = self.pop_completion()?;
+            Some(Completion {
+                token,
+                cookie,
+                result,
+            })
+        })
+    }
+
+    /// Completes finished requests submitted with a cookie like [`VirtIOBlk::poll_completions`],
+    /// writing them to `out` until it is full, and returns how many were written.
+    pub fn drain_completions(&mut self, out: &mut [Completion]) -> usize {
+        out.iter_mut()
+            .zip(self.poll_completions())
+            .map(|(slot, completion)| *slot = completion)
+            .count()
+    }
+
+    /// Returns the queue index and token of a completed request on any of the request queues,
+    /// without removing it from the used ring, or `None` if there are no pending completed
+    /// requests.
+    pub fn poll_used(&mut self) -> Option<(u16, u16)> {
+        self.queues
+            .iter()
+            .flatten()
+            .enumerate()
+            .find_map(|(index, queue)| Some((index as u16, queue.peek_used()?)))
+    }
+
+    /// Returns the size of the device's VirtQueue.
+    ///
+    /// This can be used to tell the caller how many channels to monitor on.
+    pub fn virt_queue_size(&self) -> u16 {
+        QUEUE_SIZE
+    }
+}
+
+// SAFETY: The config space can be accessed from any thread.
+unsafe impl<H: Hal, T: Transport + Send, const MAX_QUEUES: usize> Send
+    for VirtIOBlk<H, T, MAX_QUEUES>
+where
+    VirtQueue<H, { QUEUE_SIZE as usize }>: Send,
+{
+}
+
+// SAFETY: A `&VirtIOBlk` only allows reading the config space.
+unsafe impl<H: Hal, T: Transport + Sync, const MAX_QUEUES: usize> Sync
+    for VirtIOBlk<H, T, MAX_QUEUES>
+where
+    VirtQueue<H, { QUEUE_SIZE as usize }>: Sync,
+{
+}
+
+impl<H: Hal, T: Transport, const MAX_QUEUES: usize> Drop for VirtIOBlk<H, T, MAX_QUEUES> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for index in 0..self.num_queues {
+            self.transport.queue_unset(index);
+        }
+    }
+}
+
+/// Reads from the device as byte-addressed storage, for use with generic storage and filesystem
+/// crates.
+///
+/// Offsets are in bytes, so only the first 4 GiB of the device can be addressed. Reads which
+/// don't start or end on a block boundary read the partial blocks via a bounce buffer. Returns
+/// [`Error::Unsupported`] if a bounce buffer is needed and the block size is larger than
+/// [`PAGE_SIZE`](crate::PAGE_SIZE).
+///
+/// ```
+/// # use virtio_drivers_sel4::{Error, Hal};
+/// # use virtio_drivers_sel4::device::blk::VirtIOBlk;
+/// # use virtio_drivers_sel4::transport::Transport;
+/// use embedded_storage::ReadStorage;
+///
+/// # fn example<H: Hal, T: Transport>(blk: &mut VirtIOBlk<H, T>) -> Result<(), Error> {
+/// let mut boot_sector = [0; 512];
+/// blk.read(0, &mut boot_sector)?;
+/// if boot_sector[510..] == [0x55, 0xaa] {
+///     println!("Found a boot sector, device has {} bytes", blk.capacity());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "embedded-storage")]
+impl<H: Hal, T: Transport, const MAX_QUEUES: usize> embedded_storage::ReadStorage
+    for VirtIOBlk<H, T, MAX_QUEUES>
+{
+    type Error = Error;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result {
+        let offset = offset as usize;
+        self.check_byte_range(offset, bytes.len())?;
+        let block_size = self.block_size;
+        let mut bounce = [0; crate::PAGE_SIZE];
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = offset + done;
+            let sector = (position - position % block_size) / SECTOR_SIZE;
+            let skip = position % block_size;
+            let remaining = &mut bytes[done..];
+            if skip == 0 && remaining.len() >= block_size {
+                let len = remaining.len() - remaining.len() % block_size;
+                self.read_blocks(sector, &mut remaining[..len])?;
+                done += len;
+            } else {
+                let block_buf = bounce.get_mut(..block_size).ok_or(Error::Unsupported)?;
+                let len = remaining.len().min(block_size - skip);
+                self.read_blocks(sector, block_buf)?;
+                remaining[..len].copy_from_slice(&block_buf[skip..skip + len]);
+                done += len;
+            }
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        usize::try_from(self.capacity * SECTOR_SIZE as u64).unwrap_or(usize::MAX)
+    }
+}
+
+/// Writes to the device as byte-addressed storage.
+///
+/// Writes which don't start or end on a block boundary read the partial blocks first, and write
+/// them back with the new data. Returns [`Error::ReadOnly`] if the device is read-only.
+#[cfg(feature = "embedded-storage")]
+impl<H: Hal, T: Transport, const MAX_QUEUES: usize> embedded_storage::Storage
+    for VirtIOBlk<H, T, MAX_QUEUES>
+{
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result {
+        let offset = offset as usize;
+        self.check_byte_range(offset, bytes.len())?;
+        self.check_writable()?;
+        let block_size = self.block_size;
+        let mut bounce = [0; crate::PAGE_SIZE];
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = offset + done;
+            let sector = (position - position % block_size) / SECTOR_SIZE;
+            let skip = position % block_size;
+            let remaining = &bytes[done..];
+            if skip == 0 && remaining.len() >= block_size {
+                let len = remaining.len() - remaining.len() % block_size;
+                self.write_blocks(sector, &remaining[..len])?;
+                done += len;
+            } else {
+                let block_buf = bounce.get_mut(..block_size).ok_or(Error::Unsupported)?;
+                let len = remaining.len().min(block_size - skip);
+                self.read_blocks(sector, block_buf)?;
+                block_buf[skip..skip + len].copy_from_slice(&remaining[..len]);
+                self.write_blocks(sector, block_buf)?;
+                done += len;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adapts a [`VirtIOBlk`] to the block device trait used by the `embedded-sdmmc` FAT filesystem,
+/// addressing the device in 512 byte ([`SECTOR_SIZE`]) blocks.
+///
+/// The trait's methods take `&self`, so the driver is kept in a [`RefCell`]. Blocks are read and
+/// written one request at a time, and errors are returned as the driver's [`Error`]. Devices with a
+/// [`block_size`](VirtIOBlk::block_size) larger than [`SECTOR_SIZE`] can't be used, as every
+/// request fails with [`Error::InvalidParam`].
+///
+/// ```
+/// # use virtio_drivers_sel4::{Error, Hal};
+/// # use virtio_drivers_sel4::device::blk::VirtIOBlk;
+/// # use virtio_drivers_sel4::transport::Transport;
+/// use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+/// use virtio_drivers_sel4::device::blk::SdmmcBlockDevice;
+///
+/// # fn example<H: Hal, T: Transport>(blk: VirtIOBlk<H, T>) -> Result<(), Error> {
+/// let device = SdmmcBlockDevice::new(blk);
+/// let mut mbr = [Block::new()];
+/// device.read(&mut mbr, BlockIdx(0))?;
+/// println!("Device has {} blocks", device.num_blocks()?.0);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "embedded-sdmmc")]
+pub struct SdmmcBlockDevice<H: Hal, T: Transport, const MAX_QUEUES: usize = 1> {
+    blk: RefCell<VirtIOBlk<H, T, MAX_QUEUES>>,
+}
+
+#[cfg(feature = "embedded-sdmmc")]
+impl<H: Hal, T: Transport, const MAX_QUEUES: usize> SdmmcBlockDevice<H, T, MAX_QUEUES> {
+    /// Wraps the given driver.
+    pub fn new(blk: VirtIOBlk<H, T, MAX_QUEUES>) -> Self {
+        Self {
+            blk: RefCell::new(blk),
+        }
+    }
+
+    /// Returns the wrapped driver.
+    pub fn into_inner(self) -> VirtIOBlk<H, T, MAX_QUEUES> {
+        self.blk.into_inner()
+    }
+}
+
+#[cfg(feature = "embedded-sdmmc")]
+impl<H: Hal, T: Transport, const MAX_QUEUES: usize> embedded_sdmmc::BlockDevice
+    for SdmmcBlockDevice<H, T, MAX_QUEUES>
+{
+    type Error = Error;
+
+    fn read(
+        &self,
+        blocks: &mut [embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+    ) -> Result {
+        let mut blk = self.blk.borrow_mut();
+        for (block_id, block) in (start_block_idx.0 as usize..).zip(blocks) {
+            blk.read_blocks(block_id, &mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        blocks: &[embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+    ) -> Result {
+        let mut blk = self.blk.borrow_mut();
+        for (block_id, block) in (start_block_idx.0 as usize..).zip(blocks) {
+            blk.write_blocks(block_id, &block.contents)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the capacity of the device, or at most `u32::MAX` blocks as that is all the trait
+    /// can address.
+    fn num_blocks(&self) -> Result<embedded_sdmmc::BlockCount> {
+        let capacity = self.blk.borrow().capacity();
+        Ok(embedded_sdmmc::BlockCount(
+            capacity.try_into().unwrap_or(u32::MAX),
+        ))
+    }
+}
+
+/// A handle to one of the request queues of a [`VirtIOBlk`].
+///
+/// This is returned by [`VirtIOBlk::queue`]. Its methods behave like the corresponding methods of
+/// `VirtIOBlk`, but submit requests to this queue rather than queue 0. Tokens are only meaningful
+/// for the queue which returned them.
+pub struct BlkQueue<'a, H: Hal, T: Transport, const MAX_QUEUES: usize = 1> {
+    blk: &'a mut VirtIOBlk<H, T, MAX_QUEUES>,
+    index: u16,
+}
+
+impl<H: Hal, T: Transport, const MAX_QUEUES: usize> BlkQueue<'_, H, T, MAX_QUEUES> {
+    /// Returns the index of the queue.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Reads one or more blocks into the given buffer, using this queue.
+    ///
+    /// See [`VirtIOBlk::read_blocks`].
+    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        self.blk.read_blocks_on(self.index, block_id, buf)
+    }
+
+    /// Writes the given buffer to a block or blocks, using this queue.
+    ///
+    /// See [`VirtIOBlk::write_blocks`].
+    pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        self.blk.write_blocks_on(self.index, block_id, buf)
+    }
+
+    /// Submits a request to read one or more blocks to this queue, without waiting for it to
+    /// complete.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb`].
     pub unsafe fn read_blocks_nb(
         &mut self,
         block_id: usize,
@@ -253,28 +2180,15 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<u16> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::In,
-            reserved: 0,
-            sector: block_id as u64,
-        };
-        let token = self
-            .queue
-            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        self.blk
+            .read_blocks_nb_on(self.index, block_id, req, buf, resp)
     }
 
-    /// Completes a read operation which was started by `read_blocks_nb`.
+    /// Completes a read operation which was started by [`BlkQueue::read_blocks_nb`].
     ///
     /// # Safety
     ///
-    /// The same buffers must be passed in again as were passed to `read_blocks_nb` when it returned
-    /// the token.
+    /// See [`VirtIOBlk::complete_read_blocks`].
     pub unsafe fn complete_read_blocks(
         &mut self,
         token: u16,
@@ -282,52 +2196,16 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
-        resp.status.into()
-    }
-
-    /// Writes the contents of the given buffer to a block or blocks.
-    ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
-    ///
-    /// Blocks until the write is complete or there is an error.
-    pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_write(
-            BlkReq {
-                type_: ReqType::Out,
-                sector: block_id as u64,
-                ..Default::default()
-            },
-            buf,
-        )
+        self.blk
+            .complete_read_blocks_on(self.index, token, req, buf, resp)
     }
 
-    /// Submits a request to write one or more blocks, but returns immediately without waiting for
-    /// the write to complete.
-    ///
-    /// # Arguments
-    ///
-    /// * `block_id` - The identifier of the first block to write.
-    /// * `req` - A buffer which the driver can use for the request to send to the device. The
-    ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
-    ///   it needs to be valid (and not otherwise used) until the corresponding
-    ///   `complete_write_blocks` call.
-    /// * `buf` - The buffer in memory containing the data to write to the blocks. Its length must
-    ///   be a non-zero multiple of [`SECTOR_SIZE`].
-    /// * `resp` - A mutable reference to a variable provided by the caller
-    ///   to contain the status of the request. The caller can safely
-    ///   read the variable only after the request is complete.
-    ///
-    /// # Usage
-    ///
-    /// See [VirtIOBlk::read_blocks_nb].
+    /// Submits a request to write one or more blocks to this queue, without waiting for it to
+    /// complete.
     ///
     /// # Safety
     ///
-    /// See  [VirtIOBlk::read_blocks_nb].
+    /// See [`VirtIOBlk::write_blocks_nb`].
     pub unsafe fn write_blocks_nb(
         &mut self,
         block_id: usize,
@@ -335,28 +2213,15 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<u16> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::Out,
-            reserved: 0,
-            sector: block_id as u64,
-        };
-        let token = self
-            .queue
-            .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        self.blk
+            .write_blocks_nb_on(self.index, block_id, req, buf, resp)
     }
 
-    /// Completes a write operation which was started by `write_blocks_nb`.
+    /// Completes a write operation which was started by [`BlkQueue::write_blocks_nb`].
     ///
     /// # Safety
     ///
-    /// The same buffers must be passed in again as were passed to `write_blocks_nb` when it
-    /// returned the token.
+    /// See [`VirtIOBlk::complete_write_blocks`].
     pub unsafe fn complete_write_blocks(
         &mut self,
         token: u16,
@@ -364,34 +2229,19 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
-        resp.status.into()
+        self.blk
+            .complete_write_blocks_on(self.index, token, req, buf, resp)
     }
 
-    /// Fetches the token of the next completed request from the used ring and returns it, without
-    /// removing it from the used ring. If there are no pending completed requests returns `None`.
+    /// Fetches the token of the next completed request on this queue, without removing it from the
+    /// used ring.
     pub fn peek_used(&mut self) -> Option<u16> {
-        self.queue.peek_used()
-    }
-
-    /// Returns the size of the device's VirtQueue.
-    ///
-    /// This can be used to tell the caller how many channels to monitor on.
-    pub fn virt_queue_size(&self) -> u16 {
-        QUEUE_SIZE
-    }
-}
-
-impl<H: Hal, T: Transport> Drop for VirtIOBlk<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE);
+        self.blk.virt_queue(self.index).peek_used()
     }
 }
 
 #[repr(C)]
+#[cfg_attr(test, derive(Default))]
 struct BlkConfig {
     /// Number of 512 Bytes sectors
     capacity_low: Volatile<u32>,
@@ -406,9 +2256,446 @@ struct BlkConfig {
     alignment_offset: Volatile<u8>,
     min_io_size: Volatile<u16>,
     opt_io_size: Volatile<u32>,
+    writeback: Volatile<u8>,
+    unused0: Volatile<u8>,
+    num_queues: Volatile<u16>,
+    max_discard_sectors: Volatile<u32>,
+    max_discard_seg: Volatile<u32>,
+    discard_sector_alignment: Volatile<u32>,
+    max_write_zeroes_sectors: Volatile<u32>,
+    max_write_zeroes_seg: Volatile<u32>,
+    write_zeroes_may_unmap: Volatile<u8>,
+    unused1: [Volatile<u8>; 3],
+    max_secure_erase_sectors: Volatile<u32>,
+    max_secure_erase_seg: Volatile<u32>,
+    secure_erase_sector_alignment: Volatile<u32>,
     // ... ignored
 }
 
+/// The data of a discard or write zeroes request, describing a range of sectors.
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct DiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: DiscardWriteZeroesFlags,
+}
+
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(transparent)]
+struct DiscardWriteZeroesFlags(u32);
+
+bitflags! {
+    impl DiscardWriteZeroesFlags: u32 {
+        /// For write zeroes requests, the device may deallocate the sectors rather than writing
+        /// zeroes to them.
+        const UNMAP = 1 << 0;
+    }
+}
+
+/// Reads the capacity of the device, in sectors, from its configuration space.
+///
+/// # Safety
+///
+/// `config` must be a valid pointer to the device configuration space.
+unsafe fn read_capacity(config: NonNull<BlkConfig>) -> u64 {
+    // Safe because our caller promises that config is a valid pointer.
+    unsafe {
+        volread!(config, capacity_low) as u64 | (volread!(config, capacity_high) as u64) << 32
+    }
+}
+
+/// A completed request returned by [`VirtIOBlk::poll_completions`] or
+/// [`VirtIOBlk::drain_completions`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Completion {
+    /// The token which was returned when the request was submitted.
+    pub token: u16,
+    /// The cookie with which the request was submitted.
+    pub cookie: u64,
+    /// The result of the request.
+    pub result: Result,
+}
+
+/// The requests which the device hadn't finished when it was suspended by
+/// [`VirtIOBlk::prepare_suspend`], to be passed to [`VirtIOBlk::resume`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingRequests<const MAX_QUEUES: usize = 1> {
+    /// Whether each token on each queue is pending.
+    tokens: [[bool; QUEUE_SIZE as usize]; MAX_QUEUES],
+    /// The features which were negotiated before the device was suspended.
+    features: BlkFeature,
+}
+
+impl<const MAX_QUEUES: usize> PendingRequests<MAX_QUEUES> {
+    /// Returns the total number of pending requests on all queues.
+    pub fn len(&self) -> usize {
+        self.tokens
+            .iter()
+            .flatten()
+            .filter(|&&pending| pending)
+            .count()
+    }
+
+    /// Returns true if there are no pending requests.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the tokens of the pending requests on the queue with the given index.
+    pub fn tokens(&self, queue: u16) -> impl Iterator<Item = u16> + '_ {
+        self.tokens
+            .get(usize::from(queue))
+            .into_iter()
+            .flat_map(|tokens| {
+                tokens
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &pending)| pending)
+                    .map(|(token, _)| token as u16)
+            })
+    }
+}
+
+/// A non-blocking request submitted with a cookie, and the buffers needed to complete it.
+#[derive(Clone, Copy, Debug)]
+struct CookieRequest {
+    cookie: u64,
+    req: NonNull<BlkReq>,
+    buf: NonNull<[u8]>,
+    resp: NonNull<BlkResp>,
+    write: bool,
+    /// Whether the request has been aborted, so its result should be discarded.
+    aborted: bool,
+}
+
+/// A blocking request which timed out, and the staging memory which the device may still access
+/// until it completes it.
+struct QuarantinedRequest<H: Hal> {
+    queue: u16,
+    token: u16,
+    staging: Dma<H>,
+    data_len: usize,
+    write: bool,
+}
+
+/// Returns the buffers for a blocking request staged in the given memory, with `data_len` bytes of
+/// data split into segments of at most `max_segment_size`, and how many of the inputs and outputs
+/// are used.
+///
+/// # Safety
+///
+/// The staging memory must not be accessed in any other way while the buffers are alive.
+#[allow(clippy::type_complexity)]
+unsafe fn staged_buffers<H: Hal>(
+    staging: &mut Dma<H>,
+    data_len: usize,
+    write: bool,
+    max_segment_size: usize,
+) -> (
+    [&[u8]; MAX_SEGMENTS + 1],
+    usize,
+    [&mut [u8]; MAX_SEGMENTS + 1],
+    usize,
+) {
+    // Safe because our caller promises not to access the memory in any other way.
+    let memory = unsafe { &mut *staging.raw_slice().as_ptr() };
+    let (header, data) = memory.split_at_mut(STAGING_DATA_OFFSET);
+    let (req, resp) = header.split_at_mut(STAGING_RESP_OFFSET);
+    let data = &mut data[..data_len];
+    let mut inputs: [&[u8]; MAX_SEGMENTS + 1] = Default::default();
+    let mut outputs: [&mut [u8]; MAX_SEGMENTS + 1] = Default::default();
+    inputs[0] = &req[..size_of::<BlkReq>()];
+    let (mut input_count, mut output_count) = (1, 0);
+    if write {
+        for segment in data.chunks(max_segment_size) {
+            inputs[input_count] = segment;
+            input_count += 1;
+        }
+    } else {
+        for segment in data.chunks_mut(max_segment_size) {
+            outputs[output_count] = segment;
+            output_count += 1;
+        }
+    }
+    outputs[output_count] = &mut resp[..size_of::<BlkResp>()];
+    output_count += 1;
+    (inputs, input_count, outputs, output_count)
+}
+
+/// A DMA region used as the data buffer of non-blocking zero-copy block requests.
+///
+/// While a request started with [`VirtIOBlk::read_blocks_dma_nb`] or
+/// [`VirtIOBlk::write_blocks_dma_nb`] is in flight, the region can't be taken back out of the
+/// buffer. If the buffer is dropped while a request is still in flight then the region is leaked
+/// rather than being freed while the device may still be accessing it.
+#[derive(Debug)]
+pub struct BlkDmaBuffer<H: Hal> {
+    dma: ManuallyDrop<Dma<H>>,
+    /// The token of the request using the buffer, if one is in flight.
+    token: Option<u16>,
+}
+
+impl<H: Hal> BlkDmaBuffer<H> {
+    /// Wraps the given DMA region for use in non-blocking block requests.
+    ///
+    /// Requests read or write the whole region, whose size is always a multiple of
+    /// [`SECTOR_SIZE`].
+    pub fn new(dma: Dma<H>) -> Self {
+        Self {
+            dma: ManuallyDrop::new(dma),
+            token: None,
+        }
+    }
+
+    /// Returns whether a request using the buffer is still in flight.
+    pub fn in_flight(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Returns the DMA region, or `None` if a request using it is still in flight.
+    pub fn dma(&self) -> Option<&Dma<H>> {
+        if self.in_flight() {
+            None
+        } else {
+            Some(&self.dma)
+        }
+    }
+
+    /// Returns the DMA region, or the buffer itself back if a request using it is still in flight.
+    pub fn into_inner(self) -> core::result::Result<Dma<H>, Self> {
+        if self.in_flight() {
+            return Err(self);
+        }
+        let mut this = ManuallyDrop::new(self);
+        // Safe because `this` is never used or dropped again.
+        Ok(unsafe { ManuallyDrop::take(&mut this.dma) })
+    }
+
+    /// Returns the whole region as a buffer which is already shared with the device.
+    fn shared(&self, direction: BufferDirection) -> SharedBuffer {
+        SharedBuffer::new(self.dma.raw_slice(), self.dma.paddr(), direction)
+    }
+}
+
+impl<H: Hal> Drop for BlkDmaBuffer<H> {
+    fn drop(&mut self) {
+        if self.in_flight() {
+            warn!("Leaking DMA buffer of in-flight block request");
+        } else {
+            // Safe because the region isn't used again after this.
+            unsafe { ManuallyDrop::drop(&mut self.dma) }
+        }
+    }
+}
+
+/// The error type of the VirtIO block driver, reported when the device completes a request with an
+/// error status.
+///
+/// These errors are returned as [`Error::BlkDeviceError`] by both the blocking and non-blocking
+/// request methods. Previously a failed request was reported as [`Error::IoError`] or
+/// [`Error::Unsupported`]; callers matching on those should match
+/// `Error::BlkDeviceError(BlkError::IoError)` or `Error::BlkDeviceError(BlkError::Unsupported)`
+/// instead. [`Error::Unsupported`] is still returned when the driver itself rejects a request
+/// because the device didn't negotiate the required feature.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlkError {
+    /// The device reported an I/O error (`VIRTIO_BLK_S_IOERR`).
+    IoError,
+    /// The device doesn't support the request (`VIRTIO_BLK_S_UNSUPP`).
+    Unsupported,
+    /// The device returned a status byte not defined by the VirtIO specification.
+    BadStatus(u8),
+}
+
+impl Display for BlkError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::IoError => write!(f, "Device reported an I/O error"),
+            Self::Unsupported => write!(f, "Request not supported by device"),
+            Self::BadStatus(status) => write!(f, "Device returned unknown status {status}"),
+        }
+    }
+}
+
+/// I/O statistics gathered by a [`VirtIOBlk`], as returned by [`VirtIOBlk::stats`].
+///
+/// Requests are counted when they are submitted to the device, whether by a blocking method or a
+/// non-blocking one. The counters wrap around rather than overflowing.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlkStats {
+    /// The number of read requests submitted.
+    pub reads: u64,
+    /// The number of write requests submitted.
+    pub writes: u64,
+    /// The number of flush requests submitted.
+    pub flushes: u64,
+    /// The number of sectors requested by read requests.
+    pub sectors_read: u64,
+    /// The number of sectors requested by write requests.
+    pub sectors_written: u64,
+    /// The number of requests of any type which failed, either when being submitted or with an
+    /// error status from the device.
+    pub errors: u64,
+    /// The number of requests which couldn't be submitted because the queue was full.
+    pub queue_full: u64,
+    /// The number of interrupts acknowledged by [`VirtIOBlk::ack_interrupt`].
+    pub interrupts: u64,
+}
+
+impl BlkStats {
+    /// Records the submission of a request of the given type with `len` bytes of data, and the
+    /// result of submitting it (or of the whole request, for a blocking request).
+    fn record<T>(&mut self, type_: ReqType, len: usize, result: &Result<T>) {
+        if let Err(Error::QueueFull) = result {
+            self.queue_full = self.queue_full.wrapping_add(1);
+            return;
+        }
+        let sectors = (len / SECTOR_SIZE) as u64;
+        match type_ {
+            ReqType::In => {
+                self.reads = self.reads.wrapping_add(1);
+                self.sectors_read = self.sectors_read.wrapping_add(sectors);
+            }
+            ReqType::Out => {
+                self.writes = self.writes.wrapping_add(1);
+                self.sectors_written = self.sectors_written.wrapping_add(sectors);
+            }
+            ReqType::Flush => self.flushes = self.flushes.wrapping_add(1),
+            _ => {}
+        }
+        if result.is_err() {
+            self.errors = self.errors.wrapping_add(1);
+        }
+    }
+
+    /// Records the result of completing a non-blocking request.
+    fn record_completed(&mut self, result: &Result) {
+        if !matches!(result, Ok(()) | Err(Error::NotReady)) {
+            self.errors = self.errors.wrapping_add(1);
+        }
+    }
+}
+
+/// An event reported by a block device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BlkEvent {
+    /// The capacity of the device has changed, e.g. because the image was resized by the host.
+    /// Contains the new capacity in sectors.
+    CapacityChanged(u64),
+}
+
+/// Information about a block device, read from its configuration space.
+///
+/// Optional fields are `None` if the device doesn't support the corresponding feature.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlkInfo {
+    /// The capacity of the device, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    pub capacity: u64,
+    /// Whether the device is read-only.
+    pub read_only: bool,
+    /// The block size of the device in bytes, or `None` without `VIRTIO_BLK_F_BLK_SIZE`. Block IDs
+    /// are still in units of [`SECTOR_SIZE`], but reads and writes must be aligned to
+    /// [`VirtIOBlk::block_size`].
+    pub block_size: Option<u32>,
+    /// The disk-style geometry of the device, or `None` without `VIRTIO_BLK_F_GEOMETRY`.
+    pub geometry: Option<BlkGeometry>,
+    /// The I/O topology of the device, or `None` without `VIRTIO_BLK_F_TOPOLOGY`.
+    pub topology: Option<BlkTopology>,
+    /// The largest data segment in bytes which the driver will put in a single descriptor. This is
+    /// the device's `size_max` (but at least [`SECTOR_SIZE`]), or `usize::MAX` without
+    /// `VIRTIO_BLK_F_SIZE_MAX`.
+    pub max_segment_size: usize,
+    /// The largest number of data segments which the driver will put in a single request. This is
+    /// the device's `seg_max`, limited by the size of the virtqueue, or [`MAX_SEGMENTS`] without
+    /// `VIRTIO_BLK_F_SEG_MAX`.
+    pub max_segments: usize,
+}
+
+/// The disk-style geometry of a block device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlkGeometry {
+    /// The number of cylinders.
+    pub cylinders: u16,
+    /// The number of heads.
+    pub heads: u8,
+    /// The number of sectors per track.
+    pub sectors: u8,
+}
+
+/// Information on the optimal I/O alignment of a block device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlkTopology {
+    /// The number of logical blocks per physical block, as a power of 2.
+    pub physical_block_exp: u8,
+    /// The offset of the first aligned logical block.
+    pub alignment_offset: u8,
+    /// The suggested minimum I/O size, in blocks.
+    pub min_io_size: u16,
+    /// The optimal (suggested maximum) I/O size, in blocks.
+    pub opt_io_size: u32,
+}
+
+/// The cache mode of a block device, as returned by [`VirtIOBlk::cache_mode`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CacheMode {
+    /// Writes are reported as complete once they reach stable storage.
+    Writethrough,
+    /// Writes may be reported as complete while still in a volatile cache, so
+    /// [`VirtIOBlk::flush`] must be used to make sure they reach stable storage.
+    Writeback,
+}
+
+/// Waits for the device for up to [`REQUEST_TIMEOUT_POLL_US`] of the remaining timeout, or spins
+/// once if there is no timeout. Returns false once the timeout has expired.
+fn wait_for_device<H: Hal>(hal: H, remaining_us: &mut Option<u64>) -> bool {
+    match remaining_us {
+        None => {
+            spin_loop();
+            true
+        }
+        Some(0) => false,
+        Some(remaining) => {
+            let delay = (*remaining).min(REQUEST_TIMEOUT_POLL_US);
+            hal.delay_us(delay);
+            *remaining -= delay;
+            true
+        }
+    }
+}
+
+/// An estimate of the remaining lifetime of a block device, as returned by
+/// [`VirtIOBlk::lifetime`].
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct BlkLifetime {
+    /// How much of the device's reserved blocks have been consumed.
+    pub pre_eol_info: PreEolInfo,
+    /// The estimated proportion of the device's lifetime used for type A memory (e.g. SLC), in
+    /// steps of 10%, from 0x01 (0-10% used) to 0x0b (beyond its estimated lifetime), or 0 if not
+    /// defined.
+    pub device_lifetime_est_typ_a: u16,
+    /// The estimated proportion of the device's lifetime used for type B memory (e.g. MLC), in
+    /// the same format as `device_lifetime_est_typ_a`.
+    pub device_lifetime_est_typ_b: u16,
+}
+
+/// The consumption of reserved blocks reported in [`BlkLifetime::pre_eol_info`].
+#[repr(transparent)]
+#[derive(AsBytes, Copy, Clone, Debug, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct PreEolInfo(u16);
+
+impl PreEolInfo {
+    /// The value is not defined.
+    pub const UNDEFINED: PreEolInfo = PreEolInfo(0);
+    /// Less than 80% of reserved blocks are consumed.
+    pub const NORMAL: PreEolInfo = PreEolInfo(1);
+    /// 80% of reserved blocks are consumed.
+    pub const WARNING: PreEolInfo = PreEolInfo(2);
+    /// 90% of reserved blocks are consumed.
+    pub const URGENT: PreEolInfo = PreEolInfo(3);
+}
+
 /// A VirtIO block device request.
 #[repr(C)]
 #[derive(AsBytes, Debug)]
@@ -443,7 +2730,7 @@ impl BlkResp {
 }
 
 #[repr(u32)]
-#[derive(AsBytes, Debug)]
+#[derive(AsBytes, Clone, Copy, Debug)]
 enum ReqType {
     In = 0,
     Out = 1,
@@ -475,10 +2762,10 @@ impl From<RespStatus> for Result {
     fn from(status: RespStatus) -> Self {
         match status {
             RespStatus::OK => Ok(()),
-            RespStatus::IO_ERR => Err(Error::IoError),
-            RespStatus::UNSUPPORTED => Err(Error::Unsupported),
+            RespStatus::IO_ERR => Err(BlkError::IoError.into()),
+            RespStatus::UNSUPPORTED => Err(BlkError::Unsupported.into()),
             RespStatus::NOT_READY => Err(Error::NotReady),
-            _ => Err(Error::IoError),
+            RespStatus(status) => Err(BlkError::BadStatus(status).into()),
         }
     }
 }
@@ -491,6 +2778,9 @@ impl Default for BlkResp {
     }
 }
 
+/// The length in bytes of the buffer for the ID returned by [`VirtIOBlk::device_id`].
+pub const DEVICE_ID_LENGTH: usize = 20;
+
 /// The standard sector size of a VirtIO block device. Data is read and written in multiples of this
 /// size.
 pub const SECTOR_SIZE: usize = 512;
@@ -550,3 +2840,320 @@ bitflags! {
         const NOTIFICATION_DATA     = 1 << 38;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use crate::transport::{
+        fake::{FakeTransport, State},
+        DeviceType,
+    };
+    use alloc::{sync::Arc, vec::Vec};
+    use std::{sync::Mutex, thread};
+
+    fn config_space(capacity: u64) -> BlkConfig {
+        BlkConfig {
+            capacity_low: Volatile::new(capacity as u32),
+            capacity_high: Volatile::new((capacity >> 32) as u32),
+            ..Default::default()
+        }
+    }
+
+    fn fake_blk(
+        config_space: NonNull<BlkConfig>,
+        device_features: BlkFeature,
+    ) -> (
+        VirtIOBlk<FakeHal, FakeTransport<BlkConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let state = Arc::new(Mutex::new(State::new(1)));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: device_features.bits(),
+            config_space,
+            state: state.clone(),
+        };
+        (VirtIOBlk::new(transport, FakeHal::new()).unwrap(), state)
+    }
+
+    /// Plays the device for a read request of `len` bytes on queue 0, once the driver has notified
+    /// it, like [`handle_read`].
+    fn serve_read(state: &Mutex<State>, capacity: u64, len: usize) -> u64 {
+        State::wait_until_queue_notified(state, 0);
+        handle_read(&mut state.lock().unwrap(), capacity, len)
+    }
+
+    /// Plays the device for the next read request of `len` bytes on queue 0. Each sector read is
+    /// filled with the low byte of its sector number, or an I/O error is returned if the read
+    /// extends beyond `capacity`.
+    ///
+    /// Returns the sector which the request started at.
+    fn handle_read(state: &mut State, capacity: u64, len: usize) -> u64 {
+        let mut start = None;
+        state.read_write_queue::<{ QUEUE_SIZE as usize }>(0, |input| {
+            assert_eq!(input.len(), size_of::<BlkReq>());
+            assert_eq!(input[..4], (ReqType::In as u32).to_le_bytes());
+            let sector = u64::from_le_bytes(input[8..16].try_into().unwrap());
+            start = Some(sector);
+            let end = sector + (len / SECTOR_SIZE) as u64;
+            if end > capacity {
+                let mut response = vec![0; len];
+                response.push(RespStatus::IO_ERR.0);
+                response
+            } else {
+                let mut response = (sector..end)
+                    .flat_map(|sector| [sector as u8; SECTOR_SIZE])
+                    .collect::<Vec<_>>();
+                response.push(RespStatus::OK.0);
+                response
+            }
+        });
+        start.unwrap()
+    }
+
+    #[test]
+    fn capacity_changed() {
+        let mut config_space = config_space(8);
+        let config = NonNull::from(&mut config_space);
+        let (mut blk, state) = fake_blk(config, BlkFeature::empty());
+        assert_eq!(blk.capacity(), 8);
+
+        let device = {
+            let state = state.clone();
+            thread::spawn(move || serve_read(&state, 8, SECTOR_SIZE))
+        };
+        let mut buf = [0; SECTOR_SIZE];
+        assert_eq!(blk.read_blocks(12, &mut buf), Err(BlkError::IoError.into()));
+        device.join().unwrap();
+
+        // The device grows to 16 sectors and reports a configuration change.
+        // Safe because the config space is still valid.
+        unsafe {
+            volwrite!(config, capacity_low, 16);
+        }
+        state.lock().unwrap().interrupt_status = InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT;
+        assert!(blk.ack_interrupt());
+        assert_eq!(blk.poll_event(), Some(BlkEvent::CapacityChanged(16)));
+        assert_eq!(blk.poll_event(), None);
+        assert_eq!(blk.capacity(), 16);
+
+        // The sector beyond the old capacity can now be read.
+        let device = {
+            let state = state.clone();
+            thread::spawn(move || serve_read(&state, 16, SECTOR_SIZE))
+        };
+        blk.read_blocks(12, &mut buf).unwrap();
+        assert_eq!(device.join().unwrap(), 12);
+        assert_eq!(buf, [12; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn read_blocks_single_request() {
+        let mut config_space = config_space(1024);
+        let (mut blk, state) = fake_blk(NonNull::from(&mut config_space), BlkFeature::empty());
+        let mut buf = vec![0; 64 * 1024];
+
+        let device = {
+            let state = state.clone();
+            thread::spawn(move || serve_read(&state, 1024, 64 * 1024))
+        };
+        blk.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(device.join().unwrap(), 0);
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 1);
+        for (sector, data) in buf.chunks(SECTOR_SIZE).enumerate() {
+            assert_eq!(data, [sector as u8; SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn read_blocks_split_by_size_and_seg_max() {
+        let mut config_space = config_space(1024);
+        config_space.size_max = Volatile::new(4096);
+        config_space.seg_max = Volatile::new(4);
+        let (mut blk, state) = fake_blk(
+            NonNull::from(&mut config_space),
+            BlkFeature::SIZE_MAX | BlkFeature::SEG_MAX,
+        );
+        let mut buf = vec![0; 64 * 1024];
+
+        // Each request can carry 4 segments of 4 KiB, so 4 requests are needed.
+        let device = {
+            let state = state.clone();
+            thread::spawn(move || {
+                (0..4)
+                    .map(|_| serve_read(&state, 1024, 16 * 1024))
+                    .collect::<Vec<_>>()
+            })
+        };
+        blk.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(device.join().unwrap(), [0, 32, 64, 96]);
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 4);
+        for (sector, data) in buf.chunks(SECTOR_SIZE).enumerate() {
+            assert_eq!(data, [sector as u8; SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn barrier_timeout() {
+        let mut config_space = config_space(8);
+        let (mut blk, state) = fake_blk(NonNull::from(&mut config_space), BlkFeature::empty());
+        blk.set_request_timeout(Some(1000));
+        let mut req = BlkReq::default();
+        let mut buf = [0; SECTOR_SIZE];
+        let mut resp = BlkResp::default();
+
+        // Safe because the buffers stay valid and aren't accessed until the request is completed.
+        let token = unsafe { blk.read_blocks_nb(1, &mut req, &mut buf, &mut resp) }.unwrap();
+        assert_eq!(blk.barrier(), Err(Error::Timeout));
+
+        // The request is still in flight, so once the device finishes it the barrier succeeds.
+        serve_read(&state, 8, SECTOR_SIZE);
+        assert_eq!(blk.barrier(), Ok(()));
+        // Safe because these are the same buffers as were passed to `read_blocks_nb`.
+        unsafe {
+            blk.complete_read_blocks(token, &req, &mut buf, &mut resp)
+                .unwrap();
+        }
+        assert_eq!(resp.status(), RespStatus::OK);
+        assert_eq!(buf, [1; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn read_timeout_quarantines_request() {
+        let mut config_space = config_space(8);
+        let (mut blk, state) = fake_blk(NonNull::from(&mut config_space), BlkFeature::empty());
+        let counts = blk.hal.counts();
+        let (shares, dma) = (counts.live_shares(), counts.live_dma());
+        blk.set_request_timeout(Some(1000));
+
+        // The device doesn't answer in time, so the request is quarantined and the caller's buffer
+        // isn't touched.
+        let mut buf = [0; SECTOR_SIZE];
+        assert_eq!(blk.read_blocks(1, &mut buf), Err(Error::Timeout));
+        assert_eq!(blk.quarantined_requests(), 1);
+        assert_eq!(buf, [0; SECTOR_SIZE]);
+        assert_eq!(counts.live_shares(), shares + 3);
+
+        // Once the device finishes it, the next request reclaims it and the driver carries on.
+        serve_read(&state, 8, SECTOR_SIZE);
+        blk.set_request_timeout(Some(u64::MAX));
+        let server = thread::spawn(move || serve_read(&state, 8, SECTOR_SIZE));
+        blk.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(server.join().unwrap(), 2);
+        assert_eq!(buf, [2; SECTOR_SIZE]);
+        assert_eq!(blk.quarantined_requests(), 0);
+        assert_eq!(counts.live_shares(), shares);
+        // Only the staging memory kept for the next request is still allocated.
+        assert_eq!(counts.live_dma(), dma + 1);
+    }
+
+    #[test]
+    fn writeback_without_flush() {
+        let mut no_flush_config_space = BlkConfig {
+            writeback: Volatile::new(1),
+            ..config_space(8)
+        };
+        let (mut blk, state) = fake_blk(
+            NonNull::from(&mut no_flush_config_space),
+            BlkFeature::CONFIG_WCE,
+        );
+        assert_eq!(blk.write_cache(), Ok(true));
+        // The cache can't be flushed, so the barrier only waits for requests in flight and doesn't
+        // fail trying to flush it.
+        assert_eq!(blk.cache_mode(), CacheMode::Writethrough);
+        assert_eq!(blk.flush(), Err(Error::Unsupported));
+        assert_eq!(blk.barrier(), Ok(()));
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 0);
+
+        let mut flush_config_space = BlkConfig {
+            writeback: Volatile::new(1),
+            ..config_space(8)
+        };
+        let (mut blk, _) = fake_blk(
+            NonNull::from(&mut flush_config_space),
+            BlkFeature::CONFIG_WCE | BlkFeature::FLUSH,
+        );
+        assert_eq!(blk.cache_mode(), CacheMode::Writeback);
+        blk.set_write_cache(false).unwrap();
+        assert_eq!(blk.cache_mode(), CacheMode::Writethrough);
+    }
+
+    #[cfg(feature = "embedded-sdmmc")]
+    #[test]
+    fn sdmmc_block_device_read() {
+        use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+
+        let mut config_space = config_space(8);
+        let (blk, state) = fake_blk(NonNull::from(&mut config_space), BlkFeature::empty());
+        let device = SdmmcBlockDevice::new(blk);
+        assert_eq!(device.num_blocks().unwrap().0, 8);
+
+        let server = thread::spawn(move || {
+            (0..2)
+                .map(|_| serve_read(&state, 8, SECTOR_SIZE))
+                .collect::<Vec<_>>()
+        });
+        let mut blocks = [Block::new(), Block::new()];
+        device.read(&mut blocks, BlockIdx(3)).unwrap();
+        assert_eq!(server.join().unwrap(), [3, 4]);
+        assert_eq!(blocks[0].contents, [3; SECTOR_SIZE]);
+        assert_eq!(blocks[1].contents, [4; SECTOR_SIZE]);
+    }
+
+    /// Submits 64 single-sector reads in bursts of 16 with the given completion threshold. The
+    /// device finishes them one at a time, and whenever it interrupts the driver completes all the
+    /// requests which have been used so far.
+    ///
+    /// Returns the number of interrupts the device sent.
+    fn burst_interrupts(threshold: u16) -> usize {
+        let mut config_space = config_space(64);
+        let (mut blk, state) = fake_blk(
+            NonNull::from(&mut config_space),
+            BlkFeature::RING_EVENT_IDX | BlkFeature::RING_INDIRECT_DESC,
+        );
+        blk.set_completion_threshold(threshold);
+        let mut reqs: [BlkReq; 16] = Default::default();
+        let mut bufs = [[0; SECTOR_SIZE]; 16];
+        let mut resps: [BlkResp; 16] = Default::default();
+        let mut tokens = [0; 16];
+
+        for burst in 0..4 {
+            for (i, token) in tokens.iter_mut().enumerate() {
+                // Safe because the buffers stay valid and aren't accessed until the request is
+                // completed.
+                *token = unsafe {
+                    blk.read_blocks_nb(burst * 16 + i, &mut reqs[i], &mut bufs[i], &mut resps[i])
+                }
+                .unwrap();
+            }
+            let mut completed = 0;
+            for _ in 0..16 {
+                handle_read(&mut state.lock().unwrap(), 64, SECTOR_SIZE);
+                if !blk.ack_interrupt() {
+                    continue;
+                }
+                while let Some(token) = blk.peek_used() {
+                    let i = tokens.iter().position(|&t| t == token).unwrap();
+                    // Safe because these are the same buffers as were passed to `read_blocks_nb`.
+                    unsafe {
+                        blk.complete_read_blocks(token, &reqs[i], &mut bufs[i], &mut resps[i])
+                            .unwrap();
+                    }
+                    assert_eq!(bufs[i], [(burst * 16 + i) as u8; SECTOR_SIZE]);
+                    completed += 1;
+                }
+            }
+            assert_eq!(completed, 16);
+        }
+        let interrupts = state.lock().unwrap().queues[0].interrupt_count;
+        interrupts
+    }
+
+    #[test]
+    fn completion_threshold() {
+        assert_eq!(burst_interrupts(1), 64);
+        assert_eq!(burst_interrupts(16), 4);
+    }
+}
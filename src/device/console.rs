@@ -1,31 +1,58 @@
 //! Driver for VirtIO console devices.
 
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, WriteOnly};
-use crate::{Result, PAGE_SIZE};
-use alloc::boxed::Box;
+#[cfg(feature = "logger")]
+pub mod logger;
+
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::{owning::OwningQueue, VirtQueue};
+use crate::transport::{DeviceStatus, InterruptStatus, Transport};
+use crate::volatile::{volread, volwrite, ReadOnly, WriteOnly};
+use crate::{Error, Result, PAGE_SIZE};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
 use bitflags::bitflags;
+use core::fmt::{self, Display, Formatter};
+use core::future::{poll_fn, Future};
+use core::hint::spin_loop;
+use core::mem::size_of;
 use core::ptr::NonNull;
+use core::task::{Context, Poll, Waker};
+use log::{debug, warn};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
 const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
-const QUEUE_SIZE: usize = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX.union(Features::RING_INDIRECT_DESC);
+const QUEUE_CONTROL_RECEIVEQ: u16 = 2;
+const QUEUE_CONTROL_TRANSMITQ: u16 = 3;
+/// The default size of each virtqueue.
+const DEFAULT_QUEUE_SIZE: usize = 2;
+/// The size of each buffer used in the control receiveq. This must be big enough for a
+/// `PORT_NAME` control message including the name.
+const CONTROL_BUFFER_SIZE: usize = 256;
+/// The interval in microseconds at which [`VirtIOConsole::recv_timeout`] polls the device.
+pub const RECV_TIMEOUT_POLL_US: u64 = 100;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_INDIRECT_DESC)
+    .union(Features::MULTIPORT)
+    .union(Features::SIZE)
+    .union(Features::EMERG_WRITE);
 
 /// Driver for a VirtIO console device.
 ///
-/// Only a single port is allowed since `alloc` is disabled. Emergency write and cols/rows are not
-/// implemented.
+/// If the device offers `VIRTIO_CONSOLE_F_MULTIPORT` then additional ports can be accessed with
+/// [`VirtIOConsole::port`]; otherwise only port 0 is available. The methods on `VirtIOConsole`
+/// itself always use port 0.
+///
+/// `QUEUE_SIZE` is the size of the receiveq and transmitq of each port, which bounds the number of
+/// requests which may be in flight at once. It must be a power of 2, and no larger than the maximum
+/// queue size supported by the device.
 ///
 /// # Example
 ///
 /// ```
-/// # use virtio_drivers::{Error, Hal, transport::Transport};
-/// use virtio_drivers::device::console::VirtIOConsole;
-/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
-/// let mut console = VirtIOConsole::<HalImpl, _>::new(transport)?;
+/// # use virtio_drivers_sel4::{Error, Hal, transport::Transport};
+/// use virtio_drivers_sel4::device::console::VirtIOConsole;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T, hal: HalImpl) -> Result<(), Error> {
+/// let mut console = VirtIOConsole::<HalImpl, _>::new(transport, hal)?;
 ///
 /// let info = console.info();
 /// println!("VirtIO console {}x{}", info.rows, info.columns);
@@ -39,27 +66,35 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX.union(Features::RI
 /// # Ok(())
 /// # }
 /// ```
-pub struct VirtIOConsole<H: Hal, T: Transport> {
+pub struct VirtIOConsole<H: Hal, T: Transport, const QUEUE_SIZE: usize = DEFAULT_QUEUE_SIZE> {
     transport: T,
     config_space: NonNull<Config>,
-    receiveq: VirtQueue<H, QUEUE_SIZE>,
-    transmitq: VirtQueue<H, QUEUE_SIZE>,
-    queue_buf_rx: Box<[u8; PAGE_SIZE]>,
-    cursor: usize,
-    pending_len: usize,
-    /// The token of the outstanding receive request, if there is one.
-    receive_token: Option<u16>,
+    /// The ports of the device, indexed by port ID. Port 0 is always present.
+    ports: Vec<Port<H, QUEUE_SIZE>>,
+    /// The control queues, if `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated.
+    control: Option<ControlQueues<H>>,
+    negotiated_features: Features,
+    /// Whether the device has changed the console size since the last `ConsoleEvent::Resized`.
+    resized: bool,
+    /// The waker to wake when data is received on port 0, registered by `poll_recv`.
+    rx_waker: Option<Waker>,
+    /// The number of interrupts acknowledged since the statistics were last reset.
+    interrupts: u64,
 }
 
 // SAFETY: The config space can be accessed from any thread.
-unsafe impl<H: Hal, T: Transport + Send> Send for VirtIOConsole<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Send
+unsafe impl<H: Hal, T: Transport + Send, const QUEUE_SIZE: usize> Send
+    for VirtIOConsole<H, T, QUEUE_SIZE>
+where
+    VirtQueue<H, QUEUE_SIZE>: Send,
 {
 }
 
 // SAFETY: A `&VirtIOConsole` only allows reading the config space.
-unsafe impl<H: Hal, T: Transport + Sync> Sync for VirtIOConsole<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Sync
+unsafe impl<H: Hal, T: Transport + Sync, const QUEUE_SIZE: usize> Sync
+    for VirtIOConsole<H, T, QUEUE_SIZE>
+where
+    VirtQueue<H, QUEUE_SIZE>: Sync,
 {
 }
 
@@ -74,46 +109,181 @@ pub struct ConsoleInfo {
     pub max_ports: u32,
 }
 
-impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
-    /// Creates a new VirtIO console driver.
-    pub fn new(mut transport: T, hal:H) -> Result<Self> {
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
-        let config_space = transport.config_space::<Config>()?;
-        let receiveq = VirtQueue::new(
-            hal,
-            &mut transport,
-            QUEUE_RECEIVEQ_PORT_0,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let transmitq = VirtQueue::new(
-            hal,
-            &mut transport,
-            QUEUE_TRANSMITQ_PORT_0,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
+/// Statistics counters for a console device or port.
+///
+/// All counters wrap on overflow.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConsoleStats {
+    /// The number of bytes submitted to the device for transmission.
+    pub tx_bytes: u64,
+    /// The number of bytes received from the device.
+    pub rx_bytes: u64,
+    /// The number of interrupts acknowledged. This is always 0 for the statistics of a single port.
+    pub interrupts: u64,
+    /// The number of times receive buffers were given back to the device.
+    pub rx_rearms: u64,
+}
 
-        // Safe because no alignment or initialisation is required for [u8], the DMA buffer is
-        // dereferenceable, and the lifetime of the reference matches the lifetime of the DMA buffer
-        // (which we don't otherwise access).
-        let queue_buf_rx = Box::new([0; PAGE_SIZE]);
+impl ConsoleStats {
+    fn add(&mut self, other: &ConsoleStats) {
+        self.tx_bytes = self.tx_bytes.wrapping_add(other.tx_bytes);
+        self.rx_bytes = self.rx_bytes.wrapping_add(other.rx_bytes);
+        self.interrupts = self.interrupts.wrapping_add(other.interrupts);
+        self.rx_rearms = self.rx_rearms.wrapping_add(other.rx_rearms);
+    }
+}
 
-        transport.finish_init();
+bitflags! {
+    /// The causes of an interrupt, as returned by [`VirtIOConsole::ack_interrupt_detailed`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct ConsoleInterrupt: u8 {
+        /// New data has been received on at least one port.
+        const RX_DATA = 1 << 0;
+        /// The device has finished with at least one transmit request, which can be reclaimed by
+        /// [`VirtIOConsole::tx_complete`].
+        const TX_DONE = 1 << 1;
+        /// The device configuration has changed.
+        const CONFIG_CHANGED = 1 << 2;
+    }
+}
+
+/// An event reported by a console device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConsoleEvent {
+    /// The console size has changed. Contains the updated console information.
+    Resized(ConsoleInfo),
+}
+
+/// The error type of the VirtIO console driver.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConsoleError {
+    /// The port ID is not less than the maximum number of ports supported by the device.
+    InvalidPort(u32),
+    /// The device has not yet added the port.
+    PortNotReady(u32),
+    /// The requested queue size is larger than the device supports. Contains the maximum queue
+    /// size supported by the device.
+    QueueTooLarge(u32),
+}
+
+impl Display for ConsoleError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidPort(id) => write!(f, "Port {id} is not supported by the device"),
+            Self::PortNotReady(id) => write!(f, "Port {id} has not been added by the device"),
+            Self::QueueTooLarge(max) => {
+                write!(f, "Queue size is larger than the device maximum of {max}")
+            }
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIOConsole<H, T, QUEUE_SIZE> {
+    /// Creates a new VirtIO console driver.
+    pub fn new(mut transport: T, hal: H) -> Result<Self> {
+        let (negotiated_features, config_space, ports, control) = Self::init(&mut transport, hal)?;
         let mut console = VirtIOConsole {
             transport,
             config_space,
-            receiveq,
-            transmitq,
-            queue_buf_rx,
-            cursor: 0,
-            pending_len: 0,
-            receive_token: None,
+            ports,
+            control,
+            negotiated_features,
+            resized: false,
+            rx_waker: None,
+            interrupts: 0,
         };
-        console.poll_retrieve()?;
+        console.start()?;
         Ok(console)
     }
 
+    /// Resets the device and initialises it again from scratch, renegotiating features and
+    /// rebuilding all of the queues.
+    ///
+    /// This can be used to recover after an operation has returned [`Error::DeviceNeedsReset`],
+    /// without having to recreate the driver. Any data which has been received but not yet read is
+    /// lost, and the per-port statistics counters are reset, but receiving on port 0 stays paused
+    /// if it was paused before. If this fails the driver should be dropped.
+    pub fn reset_reinit(&mut self) -> Result<()> {
+        let hal = self.ports[0].hal;
+        let rx_paused = self.ports[0].rx_paused;
+        self.transport.set_status(DeviceStatus::empty());
+        self.unset_queues();
+        let (negotiated_features, config_space, ports, control) =
+            Self::init(&mut self.transport, hal)?;
+        self.negotiated_features = negotiated_features;
+        self.config_space = config_space;
+        self.ports = ports;
+        self.ports[0].rx_paused = rx_paused;
+        self.control = control;
+        self.resized = false;
+        self.start()
+    }
+
+    /// Negotiates features and sets up the queues for all ports, leaving the device ready for
+    /// `start` to be called.
+    #[allow(clippy::type_complexity)]
+    fn init(
+        transport: &mut T,
+        hal: H,
+    ) -> Result<(
+        Features,
+        NonNull<Config>,
+        Vec<Port<H, QUEUE_SIZE>>,
+        Option<ControlQueues<H>>,
+    )> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let config_space = transport.config_space::<Config>()?;
+        let indirect = negotiated_features.contains(Features::RING_INDIRECT_DESC);
+        let event_idx = negotiated_features.contains(Features::RING_EVENT_IDX);
+
+        let mut ports = Vec::new();
+        ports.push(Port::new(hal, transport, 0, indirect, event_idx)?);
+        let control = if negotiated_features.contains(Features::MULTIPORT) {
+            // Port 0 must be opened like any other port once there is a control queue to do so.
+            ports[0].open = false;
+            // Safe because config_space is a valid pointer to the device configuration space.
+            let max_ports = unsafe { volread!(config_space, max_nr_ports) };
+            let receiveq = OwningQueue::new(VirtQueue::new(
+                hal,
+                transport,
+                QUEUE_CONTROL_RECEIVEQ,
+                indirect,
+                event_idx,
+            )?)?;
+            let transmitq =
+                VirtQueue::new(hal, transport, QUEUE_CONTROL_TRANSMITQ, indirect, event_idx)?;
+            for id in 1..max_ports {
+                ports.push(Port::new(hal, transport, id, indirect, event_idx)?);
+            }
+            Some(ControlQueues {
+                receiveq,
+                transmitq,
+            })
+        } else {
+            None
+        };
+
+        transport.finish_init();
+        Ok((negotiated_features, config_space, ports, control))
+    }
+
+    /// Posts the receive buffers of all ports, and tells the device that the driver is ready if
+    /// multiport is supported.
+    fn start(&mut self) -> Result<()> {
+        for port in &mut self.ports {
+            port.poll_retrieve(&mut self.transport)?;
+        }
+        if let Some(control) = &self.control {
+            if control.receiveq.should_notify() {
+                self.transport.notify(QUEUE_CONTROL_RECEIVEQ);
+            }
+        }
+        if self.control.is_some() {
+            self.send_control(0, ControlEvent::DEVICE_READY, 1)?;
+        }
+        Ok(())
+    }
+
     /// Returns a struct with information about the console device, such as the number of rows and columns.
     pub fn info(&self) -> ConsoleInfo {
         // Safe because config_space is a valid pointer to the device configuration space.
@@ -129,97 +299,1146 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         }
     }
 
-    /// Makes a request to the device to receive data, if there is not already an outstanding
-    /// receive request or some data already received and not yet returned.
-    fn poll_retrieve(&mut self) -> Result<()> {
-        if self.receive_token.is_none() && self.cursor == self.pending_len {
-            // Safe because the buffer lasts at least as long as the queue, and there are no other
-            // outstanding requests using the buffer.
-            self.receive_token = Some(unsafe {
-                self.receiveq
-                    .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
-            }?);
-            if self.receiveq.should_notify() {
-                self.transport.notify(QUEUE_RECEIVEQ_PORT_0);
+    /// Acknowledges a pending interrupt, if any, handles any pending control messages and
+    /// completes the outstanding finished read requests.
+    ///
+    /// Returns true if new data has been received on any port. This is a wrapper around
+    /// [`VirtIOConsole::ack_interrupt_detailed`] for callers which don't care about the cause of
+    /// the interrupt.
+    pub fn ack_interrupt(&mut self) -> Result<bool> {
+        Ok(self
+            .ack_interrupt_detailed()?
+            .contains(ConsoleInterrupt::RX_DATA))
+    }
+
+    /// Acknowledges a pending interrupt, if any, handles any pending control messages and
+    /// completes the outstanding finished read requests, and returns what happened.
+    ///
+    /// If the interrupt was caused by the device changing the console size, a
+    /// [`ConsoleEvent::Resized`] will be returned by the next call to
+    /// [`VirtIOConsole::poll_event`].
+    ///
+    /// If data is available on port 0 and a waker has been registered by
+    /// [`VirtIOConsole::poll_recv`], the waker is woken.
+    ///
+    /// An empty value means that there was no interrupt pending, or that it was spurious.
+    pub fn ack_interrupt_detailed(&mut self) -> Result<ConsoleInterrupt> {
+        let status = self.transport.ack_interrupt_status();
+        let mut interrupt = ConsoleInterrupt::empty();
+        if status.is_empty() {
+            return Ok(interrupt);
+        }
+        self.interrupts = self.interrupts.wrapping_add(1);
+        if status.contains(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT) {
+            interrupt |= ConsoleInterrupt::CONFIG_CHANGED;
+            if self.negotiated_features.contains(Features::SIZE) {
+                self.resized = true;
+            }
+        }
+
+        self.poll_control()?;
+        for port in &mut self.ports {
+            if port.receive(&mut self.transport)? {
+                interrupt |= ConsoleInterrupt::RX_DATA;
+            }
+            // Leave the used entries for `tx_complete` to reclaim.
+            if port.transmitq.can_pop() {
+                interrupt |= ConsoleInterrupt::TX_DONE;
             }
         }
+        if self.ports[0].has_pending() {
+            if let Some(waker) = self.rx_waker.take() {
+                waker.wake();
+            }
+        }
+        Ok(interrupt)
+    }
+
+    /// Writes a character directly to the console, bypassing the virtqueues.
+    ///
+    /// This is intended for use in places such as panic handlers where the state of the transmit
+    /// queue may be inconsistent. Returns [`Error::Unsupported`] if `VIRTIO_CONSOLE_F_EMERG_WRITE`
+    /// was not negotiated.
+    pub fn emergency_write(&mut self, chr: u8) -> Result<()> {
+        if !self.negotiated_features.contains(Features::EMERG_WRITE) {
+            return Err(Error::Unsupported);
+        }
+        // Safe because config_space is a valid pointer to the device configuration space.
+        unsafe {
+            volwrite!(self.config_space, emerg_wr, chr.into());
+        }
         Ok(())
     }
 
-    /// Acknowledges a pending interrupt, if any, and completes the outstanding finished read
-    /// request if there is one.
+    /// Returns the next pending event from the device, if any.
     ///
-    /// Returns true if new data has been received.
-    pub fn ack_interrupt(&mut self) -> Result<bool> {
-        if !self.transport.ack_interrupt() {
-            return Ok(false);
+    /// Events are recorded by [`VirtIOConsole::ack_interrupt`].
+    pub fn poll_event(&mut self) -> Option<ConsoleEvent> {
+        if self.resized {
+            self.resized = false;
+            Some(ConsoleEvent::Resized(self.info()))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the next available character from the console, if any.
+    ///
+    /// If no data has been received this will not block but immediately return `Ok<None>`.
+    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+        self.ports[0].recv(&mut self.transport, pop)
+    }
+
+    /// Pops the next character from the console, waiting for up to approximately `timeout_us`
+    /// microseconds for one to be received.
+    ///
+    /// The device is polled every [`RECV_TIMEOUT_POLL_US`] microseconds, calling [`Hal::delay_us`]
+    /// in between, so data which arrives (with or without an interrupt) during the wait is picked
+    /// up by the next poll. Returns `Ok(None)` if nothing was received before the timeout.
+    ///
+    /// This must only be called from a non-interrupt context, as it may wait for a long time and
+    /// `Hal::delay_us` may block.
+    pub fn recv_timeout(&mut self, timeout_us: u64) -> Result<Option<u8>> {
+        let hal = self.ports[0].hal;
+        let mut waited = 0;
+        loop {
+            if let Some(ch) = self.recv(true)? {
+                return Ok(Some(ch));
+            }
+            if waited >= timeout_us {
+                return Ok(None);
+            }
+            let delay = (timeout_us - waited).min(RECV_TIMEOUT_POLL_US);
+            hal.delay_us(delay);
+            waited += delay;
+        }
+    }
+
+    /// Copies as many received bytes as are available into the given buffer.
+    ///
+    /// Returns the number of bytes copied. If no data has been received this will not block but
+    /// immediately return `Ok(0)`. Any bytes which don't fit in `buf` are kept for the next call.
+    pub fn recv_slice(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.ports[0].recv_slice(&mut self.transport, buf)
+    }
+
+    /// Returns the data which has been received on the console but not yet consumed, without
+    /// consuming it.
+    ///
+    /// This doesn't check the device for newly received data; that happens in
+    /// [`VirtIOConsole::ack_interrupt`] and the various receive methods. If data is held in more
+    /// than one receive buffer then only the first is returned, and the next becomes visible once
+    /// it has been fully consumed.
+    pub fn peek_pending(&self) -> &[u8] {
+        self.ports[0].pending()
+    }
+
+    /// Consumes the first `len` bytes of the data returned by [`VirtIOConsole::peek_pending`].
+    ///
+    /// Returns [`Error::InvalidParam`] if `len` is more than the number of pending bytes.
+    pub fn consume(&mut self, len: usize) -> Result<()> {
+        let port = &mut self.ports[0];
+        if len > port.pending().len() {
+            return Err(Error::InvalidParam);
+        }
+        port.consume_pending(&mut self.transport, len)
+    }
+
+    /// Pops the next available character from the console, or registers the waker from the given
+    /// context to be woken by [`VirtIOConsole::ack_interrupt`] once data is received.
+    ///
+    /// Only the waker from the most recent call is kept. It is fine for the waker to be woken
+    /// spuriously; the caller should just poll again.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<u8>> {
+        // Register the waker before checking for data, so that data arriving in between isn't
+        // missed.
+        match &self.rx_waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => self.rx_waker = Some(cx.waker().clone()),
+        }
+        match self.recv(true) {
+            Ok(Some(ch)) => {
+                self.rx_waker = None;
+                Poll::Ready(Ok(ch))
+            }
+            Ok(None) => Poll::Pending,
+            Err(e) => {
+                self.rx_waker = None;
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+
+    /// Returns a future which resolves to the next character received on the console.
+    ///
+    /// The future relies on [`VirtIOConsole::ack_interrupt`] being called when the device raises an
+    /// interrupt in order to be woken.
+    pub fn recv_async(&mut self) -> impl Future<Output = Result<u8>> + '_ {
+        poll_fn(move |cx| self.poll_recv(cx))
+    }
+
+    /// Sends a character to the console.
+    pub fn send(&mut self, chr: u8) -> Result<()> {
+        self.ports[0].send_slice(&mut self.transport, &[chr])
+    }
+
+    /// Sends a slice of bytes to the console.
+    ///
+    /// The data is split into buffers of at most [`PAGE_SIZE`] bytes, which are submitted to the
+    /// transmit queue as descriptor chains of up to `QUEUE_SIZE` buffers each (using indirect
+    /// descriptors if `VIRTIO_F_INDIRECT_DESC` was negotiated). The device is notified at most once
+    /// per chain rather than once per byte.
+    ///
+    /// This first waits for any requests made by [`VirtIOConsole::send_nonblocking`] to complete.
+    /// If the device sets `DEVICE_NEEDS_RESET` while we are waiting this returns
+    /// [`Error::DeviceNeedsReset`], after which [`VirtIOConsole::reset_reinit`] must be called
+    /// before the console is used again.
+    pub fn send_slice(&mut self, data: &[u8]) -> Result<()> {
+        self.ports[0].send_slice(&mut self.transport, data)
+    }
+
+    /// Starts sending a slice of bytes to the console without waiting for the device to consume
+    /// it, and returns a token identifying the request.
+    ///
+    /// The data is copied into buffers owned by the driver, so `data` may be reused as soon as this
+    /// returns. Data longer than [`PAGE_SIZE`] is split across several buffers which are submitted
+    /// as a single descriptor chain, so it must not be empty or longer than `QUEUE_SIZE` pages. If
+    /// there aren't enough free transmit buffers or descriptors for the whole of `data` this
+    /// returns [`Error::QueueFull`] rather than blocking or sending only part of it; call
+    /// [`VirtIOConsole::tx_complete`] to reclaim finished buffers.
+    pub fn send_nonblocking(&mut self, data: &[u8]) -> Result<u16> {
+        self.ports[0].send_nonblocking(&mut self.transport, data)
+    }
+
+    /// Reclaims the buffers of all transmit requests made by [`VirtIOConsole::send_nonblocking`]
+    /// which the device has finished with.
+    ///
+    /// This doesn't block. Returns the number of requests which were completed.
+    pub fn tx_complete(&mut self) -> Result<usize> {
+        self.ports[0].tx_complete()
+    }
+
+    /// Blocks until the device has finished with all transmit requests made by
+    /// [`VirtIOConsole::send_nonblocking`].
+    ///
+    /// Returns [`Error::DeviceNeedsReset`] if the device sets `DEVICE_NEEDS_RESET` while we are
+    /// waiting.
+    pub fn flush(&mut self) -> Result<()> {
+        self.ports[0].flush(&self.transport)
+    }
+
+    /// Stops giving receive buffers back to the device on port 0 once their data has been read.
+    ///
+    /// Buffers which are already posted may still be filled, but once they have all been filled
+    /// and read the device has nowhere to put new data, so the host will have to hold on to it.
+    /// This lets a slow consumer apply backpressure to the host rather than data being dropped.
+    pub fn pause_rx(&mut self) {
+        self.ports[0].rx_paused = true;
+    }
+
+    /// Resumes receiving on port 0 after [`VirtIOConsole::pause_rx`], posting all free receive
+    /// buffers to the device again.
+    pub fn resume_rx(&mut self) -> Result<()> {
+        let port = &mut self.ports[0];
+        port.rx_paused = false;
+        port.poll_retrieve(&mut self.transport)
+    }
+
+    /// Returns whether receiving on port 0 has been paused by [`VirtIOConsole::pause_rx`].
+    pub fn is_rx_paused(&self) -> bool {
+        self.ports[0].rx_paused
+    }
+
+    /// Returns the statistics counters for the device, summed over all ports.
+    pub fn stats(&self) -> ConsoleStats {
+        let mut stats = ConsoleStats {
+            interrupts: self.interrupts,
+            ..Default::default()
+        };
+        for port in &self.ports {
+            stats.add(&port.stats);
+        }
+        stats
+    }
+
+    /// Resets all statistics counters to 0.
+    pub fn reset_stats(&mut self) {
+        self.interrupts = 0;
+        for port in &mut self.ports {
+            port.stats = ConsoleStats::default();
+        }
+    }
+
+    /// Opens port 0 of the console.
+    ///
+    /// See [`ConsolePort::open`].
+    pub fn open(&mut self) -> Result<()> {
+        self.set_port_open(0, true)
+    }
+
+    /// Closes port 0 of the console.
+    ///
+    /// See [`ConsolePort::close`].
+    pub fn close(&mut self) -> Result<()> {
+        self.set_port_open(0, false)
+    }
+
+    /// Returns whether port 0 of the console is open.
+    ///
+    /// See [`ConsolePort::is_open`].
+    pub fn is_port_open(&self) -> bool {
+        self.ports[0].is_open(self.control.is_some())
+    }
+
+    /// Returns a handle to the port with the given ID.
+    ///
+    /// Port 0 is always available. Other ports are only available if
+    /// `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated and the device has added them with a
+    /// `DEVICE_ADD` control message; call [`VirtIOConsole::ack_interrupt`] to process pending
+    /// control messages.
+    pub fn port(&mut self, id: u32) -> Result<ConsolePort<'_, H, T, QUEUE_SIZE>> {
+        self.poll_control()?;
+        let port = self
+            .ports
+            .get(id as usize)
+            .ok_or(ConsoleError::InvalidPort(id))?;
+        if !port.added {
+            return Err(ConsoleError::PortNotReady(id).into());
+        }
+        Ok(ConsolePort { console: self, id })
+    }
+
+    /// Opens or closes the port with the given ID, and tells the device if the control queues are
+    /// set up.
+    fn set_port_open(&mut self, id: u32, open: bool) -> Result<()> {
+        let port = &mut self.ports[id as usize];
+        if !port.added {
+            return Err(ConsoleError::PortNotReady(id).into());
+        }
+        port.open = open;
+        if !open {
+            port.discard_pending(&mut self.transport)?;
+        }
+        if self.control.is_some() {
+            self.send_control(id, ControlEvent::PORT_OPEN, open.into())?;
+        }
+        Ok(())
+    }
+
+    /// Handles all pending control messages from the device, if the control queues are set up.
+    fn poll_control(&mut self) -> Result<()> {
+        loop {
+            let Some(control) = &mut self.control else {
+                return Ok(());
+            };
+            let message = control.receiveq.poll(&mut self.transport, |buffer| {
+                let Some(header) = ControlMessage::read_from_prefix(buffer) else {
+                    warn!(
+                        "Ignoring truncated control message of {} bytes",
+                        buffer.len()
+                    );
+                    return Ok(None);
+                };
+                let payload = buffer[size_of::<ControlMessage>()..].to_vec();
+                Ok(Some((header, payload)))
+            })?;
+            let Some((message, payload)) = message else {
+                return Ok(());
+            };
+            self.handle_control(message, payload)?;
+        }
+    }
+
+    /// Handles a single control message received from the device.
+    fn handle_control(&mut self, message: ControlMessage, payload: Vec<u8>) -> Result<()> {
+        let id = message.id;
+        match message.event {
+            ControlEvent::DEVICE_ADD => {
+                let Some(port) = self.ports.get_mut(id as usize) else {
+                    warn!("Device added port {} beyond max_nr_ports", id);
+                    return self.send_control(id, ControlEvent::PORT_READY, 0);
+                };
+                port.added = true;
+                self.send_control(id, ControlEvent::PORT_READY, 1)?;
+            }
+            ControlEvent::DEVICE_REMOVE => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    // Port 0 always exists, so only forget the extra state.
+                    port.added = id == 0;
+                    port.open = false;
+                    port.host_connected = false;
+                    port.is_console = false;
+                    port.name = None;
+                }
+            }
+            ControlEvent::CONSOLE_PORT => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    port.is_console = true;
+                    port.open = true;
+                    self.send_control(id, ControlEvent::PORT_OPEN, 1)?;
+                }
+            }
+            ControlEvent::PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    port.host_connected = message.value != 0;
+                }
+            }
+            ControlEvent::RESIZE if id == 0 => {
+                self.resized = true;
+            }
+            ControlEvent::PORT_NAME => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    port.name = String::from_utf8(payload).ok();
+                }
+            }
+            event => {
+                debug!("Ignoring console control event {:?} for port {}", event, id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a control message to the device.
+    fn send_control(&mut self, id: u32, event: ControlEvent, value: u16) -> Result<()> {
+        let control = self.control.as_mut().ok_or(Error::Unsupported)?;
+        let message = ControlMessage { id, event, value };
+        add_notify_wait_pop(
+            &mut control.transmitq,
+            &mut self.transport,
+            QUEUE_CONTROL_TRANSMITQ,
+            &[message.as_bytes()],
+        )?;
+        Ok(())
+    }
+
+    /// Clears the device's pointers to all of the queues.
+    fn unset_queues(&mut self) {
+        for port in &self.ports {
+            self.transport.queue_unset(port.receiveq_index);
+            self.transport.queue_unset(port.transmitq_index);
+        }
+        if self.control.is_some() {
+            self.transport.queue_unset(QUEUE_CONTROL_RECEIVEQ);
+            self.transport.queue_unset(QUEUE_CONTROL_TRANSMITQ);
+        }
+    }
+}
+
+/// Writes formatted text to port 0 of the console.
+///
+/// Strings are sent as-is using [`VirtIOConsole::send_slice`], without any newline translation.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers_sel4::{Hal, transport::Transport};
+/// use core::fmt::Write;
+/// use virtio_drivers_sel4::device::console::VirtIOConsole;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(console: &mut VirtIOConsole<HalImpl, T>) -> core::fmt::Result {
+/// writeln!(console, "Hello {}!", "console")?;
+/// # Ok(())
+/// # }
+/// ```
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> fmt::Write for VirtIOConsole<H, T, QUEUE_SIZE> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.send_slice(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> embedded_io::ErrorType
+    for VirtIOConsole<H, T, QUEUE_SIZE>
+{
+    type Error = Error;
+}
+
+/// Reads from port 0 of the console.
+///
+/// Note that unlike most implementations of [`embedded_io::Read`], `read` never blocks: it returns
+/// whatever bytes have already been received, or 0 if there are none. Use
+/// [`embedded_io::ReadReady`] to check whether there is data available first.
+#[cfg(feature = "embedded-io")]
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> embedded_io::Read
+    for VirtIOConsole<H, T, QUEUE_SIZE>
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.recv_slice(buf)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> embedded_io::ReadReady
+    for VirtIOConsole<H, T, QUEUE_SIZE>
+{
+    fn read_ready(&mut self) -> Result<bool> {
+        let port = &mut self.ports[0];
+        port.receive(&mut self.transport)?;
+        Ok(port.has_pending())
+    }
+}
+
+/// Writes to port 0 of the console.
+///
+/// Writes are synchronous, but `flush` also waits for any earlier
+/// [`VirtIOConsole::send_nonblocking`] requests to complete.
+#[cfg(feature = "embedded-io")]
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> embedded_io::Write
+    for VirtIOConsole<H, T, QUEUE_SIZE>
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send_slice(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        VirtIOConsole::flush(self)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> embedded_io::WriteReady
+    for VirtIOConsole<H, T, QUEUE_SIZE>
+{
+    fn write_ready(&mut self) -> Result<bool> {
+        let port = &mut self.ports[0];
+        port.tx_complete()?;
+        Ok(port.transmitq.available_desc() > 0)
+    }
+}
+
+/// Writes a character to a console device using only its transport, bypassing the virtqueues.
+///
+/// Unlike [`VirtIOConsole::emergency_write`] this doesn't need a driver instance, so it can be used
+/// before the driver has been initialised or after it has been broken or dropped. As allowed by the
+/// VirtIO specification, it only checks that the device offers `VIRTIO_CONSOLE_F_EMERG_WRITE`, not
+/// that the feature has been negotiated. Returns [`Error::Unsupported`] if it is not offered.
+pub fn emergency_write_transport<T: Transport>(transport: &mut T, chr: u8) -> Result<()> {
+    if !Features::from_bits_truncate(transport.read_device_features())
+        .contains(Features::EMERG_WRITE)
+    {
+        return Err(Error::Unsupported);
+    }
+    let config_space = transport.config_space::<Config>()?;
+    // Safe because config_space is a valid pointer to the device configuration space.
+    unsafe {
+        volwrite!(config_space, emerg_wr, chr.into());
+    }
+    Ok(())
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIOConsole<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops writing to the receive buffers, then reclaim all
+        // requests, whether or not it had completed them, so that their buffers are unshared
+        // before they are freed.
+        self.transport.set_status(DeviceStatus::empty());
+        for port in &mut self.ports {
+            if let Err(e) = port.finish_receive() {
+                warn!(
+                    "Failed to reclaim receive buffers of port {}: {}",
+                    port.id, e
+                );
+            }
+            if let Err(e) = port.tx_complete() {
+                warn!(
+                    "Failed to reclaim transmit buffers of port {}: {}",
+                    port.id, e
+                );
+            }
+            if let Err(e) = port.reclaim_pending() {
+                warn!(
+                    "Failed to reclaim pending buffers of port {}: {}",
+                    port.id, e
+                );
+            }
         }
 
-        self.finish_receive()
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.unset_queues();
+    }
+}
+
+/// A handle to a single port of a multiport console device.
+///
+/// This is returned by [`VirtIOConsole::port`].
+pub struct ConsolePort<'a, H: Hal, T: Transport, const QUEUE_SIZE: usize = DEFAULT_QUEUE_SIZE> {
+    console: &'a mut VirtIOConsole<H, T, QUEUE_SIZE>,
+    id: u32,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> ConsolePort<'_, H, T, QUEUE_SIZE> {
+    /// Returns the ID of the port.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the name of the port, if the device has sent one.
+    pub fn name(&self) -> Option<&str> {
+        self.port().name.as_deref()
+    }
+
+    /// Returns whether the device has indicated that this port is a console port.
+    pub fn is_console(&self) -> bool {
+        self.port().is_console
+    }
+
+    /// Returns whether the host has opened its end of the port.
+    pub fn is_host_connected(&self) -> bool {
+        self.port().host_connected
+    }
+
+    /// Returns the statistics counters for the port.
+    pub fn stats(&self) -> ConsoleStats {
+        self.port().stats
+    }
+
+    /// Opens the port, telling the device with a `PORT_OPEN` control message if
+    /// `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated.
+    ///
+    /// Ports which the device marks as console ports are opened automatically, as is port 0 if
+    /// multiport is not supported. Data received on a port while it is closed is discarded.
+    pub fn open(&mut self) -> Result<()> {
+        self.console.set_port_open(self.id, true)
+    }
+
+    /// Closes the port, telling the device with a `PORT_OPEN` control message if
+    /// `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated.
+    ///
+    /// Any data which has been received but not yet read is discarded, as is any data received
+    /// until the port is opened again.
+    pub fn close(&mut self) -> Result<()> {
+        self.console.set_port_open(self.id, false)
+    }
+
+    /// Returns whether the port is open.
+    ///
+    /// If `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated this is only true once the host has also
+    /// opened its end of the port, as reported by a `PORT_OPEN` control message from the device.
+    pub fn is_open(&self) -> bool {
+        self.port().is_open(self.console.control.is_some())
+    }
+
+    /// Returns the next available character from the port, if any.
+    ///
+    /// If no data has been received this will not block but immediately return `Ok<None>`.
+    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+        let console = &mut *self.console;
+        console.ports[self.id as usize].recv(&mut console.transport, pop)
+    }
+
+    /// Copies as many received bytes as are available into the given buffer.
+    ///
+    /// Returns the number of bytes copied, which will be 0 if no data has been received.
+    pub fn recv_slice(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let console = &mut *self.console;
+        console.ports[self.id as usize].recv_slice(&mut console.transport, buf)
+    }
+
+    /// Sends a character to the port.
+    pub fn send(&mut self, chr: u8) -> Result<()> {
+        self.send_slice(&[chr])
+    }
+
+    /// Sends a slice of bytes to the port.
+    pub fn send_slice(&mut self, data: &[u8]) -> Result<()> {
+        let console = &mut *self.console;
+        console.ports[self.id as usize].send_slice(&mut console.transport, data)
+    }
+
+    fn port(&self) -> &Port<H, QUEUE_SIZE> {
+        &self.console.ports[self.id as usize]
+    }
+}
+
+/// The queues and receive state of a single console port.
+struct Port<H: Hal, const QUEUE_SIZE: usize> {
+    id: u32,
+    receiveq_index: u16,
+    transmitq_index: u16,
+    receiveq: VirtQueue<H, QUEUE_SIZE>,
+    transmitq: VirtQueue<H, QUEUE_SIZE>,
+    /// The receive buffers, one for each descriptor in the receiveq.
+    rx_buffers: Vec<RxBuffer<H>>,
+    /// The indices in `rx_buffers` of the buffers which have been filled by the device, in the order
+    /// in which they were received.
+    rx_filled: VecDeque<usize>,
+    /// The offset of the next byte to return in the first filled buffer.
+    cursor: usize,
+    /// Whether the device has added the port. Port 0 is always added.
+    added: bool,
+    /// Whether the driver has opened the port. Data received while this is false is discarded.
+    open: bool,
+    /// Whether the host has opened its end of the port.
+    host_connected: bool,
+    /// Whether the device has marked this port as a console port.
+    is_console: bool,
+    /// The name of the port, as sent by the device.
+    name: Option<String>,
+    /// Whether receive buffers should be kept from the device rather than posted when they are
+    /// free.
+    rx_paused: bool,
+    /// Buffers used by `send_nonblocking`, allocated as needed up to `QUEUE_SIZE`. All buffers in
+    /// the same descriptor chain have the same token.
+    tx_buffers: Vec<TxBuffer<H>>,
+    stats: ConsoleStats,
+    hal: H,
+}
+
+/// A page of DMA memory used for receiving data.
+struct RxBuffer<H: Hal> {
+    dma: Dma<H>,
+    /// The number of bytes which the device wrote to the buffer, if it is filled.
+    len: usize,
+    state: RxState,
+}
+
+impl<H: Hal> RxBuffer<H> {
+    fn new(hal: H) -> Result<Self> {
+        Ok(Self {
+            dma: Dma::new(hal, 1, BufferDirection::DeviceToDriver)?,
+            len: 0,
+            state: RxState::Free,
+        })
+    }
+
+    fn buf(&self) -> &[u8] {
+        // Safe because the DMA region is valid for as long as `self.dma` is, and the device only
+        // writes to it while the buffer is posted, during which we don't call this.
+        unsafe { self.dma.raw_slice().as_ref() }
+    }
+
+    fn buf_mut(&mut self) -> &mut [u8] {
+        // Safe because the DMA region is valid for as long as `self.dma` is, and we have a unique
+        // reference to it.
+        unsafe { self.dma.raw_slice().as_mut() }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum RxState {
+    /// The buffer is neither posted to the device nor holding received data.
+    Free,
+    /// The buffer has been added to the receiveq with the given token.
+    Posted(u16),
+    /// The device has written data to the buffer which has not yet all been returned.
+    Filled,
+}
+
+/// A page of DMA memory used for non-blocking transmission.
+struct TxBuffer<H: Hal> {
+    dma: Dma<H>,
+    len: usize,
+    /// The token of the transmit request using the buffer, if it is in flight.
+    token: Option<u16>,
+}
+
+impl<H: Hal> TxBuffer<H> {
+    fn new(hal: H) -> Result<Self> {
+        Ok(Self {
+            dma: Dma::new(hal, 1, BufferDirection::DriverToDevice)?,
+            len: 0,
+            token: None,
+        })
+    }
+
+    fn data(&self) -> &[u8] {
+        // Safe because the DMA region is valid for as long as `self.dma` is, and the device only
+        // reads from it.
+        unsafe { &self.dma.raw_slice().as_ref()[..self.len] }
+    }
+
+    fn set_data(&mut self, data: &[u8]) {
+        // Safe because the DMA region is valid for as long as `self.dma` is, and the buffer isn't
+        // in flight so the device isn't accessing it.
+        unsafe { self.dma.raw_slice().as_mut()[..data.len()].copy_from_slice(data) };
+        self.len = data.len();
+    }
+}
+
+impl<H: Hal, const QUEUE_SIZE: usize> Port<H, QUEUE_SIZE> {
+    /// Sets up the receiveq and transmitq for the port with the given ID.
+    fn new<T: Transport>(
+        hal: H,
+        transport: &mut T,
+        id: u32,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        let (receiveq_index, transmitq_index) = if id == 0 {
+            (QUEUE_RECEIVEQ_PORT_0, QUEUE_TRANSMITQ_PORT_0)
+        } else {
+            let receiveq_index = u16::try_from(2 * id + 2).map_err(|_| Error::InvalidParam)?;
+            (receiveq_index, receiveq_index + 1)
+        };
+        for index in [receiveq_index, transmitq_index] {
+            let max = transport.max_queue_size(index);
+            if max < QUEUE_SIZE as u32 {
+                return Err(ConsoleError::QueueTooLarge(max).into());
+            }
+        }
+        let receiveq = VirtQueue::new(hal, transport, receiveq_index, indirect, event_idx)?;
+        let transmitq = VirtQueue::new(hal, transport, transmitq_index, indirect, event_idx)?;
+
+        let rx_buffers = (0..QUEUE_SIZE)
+            .map(|_| RxBuffer::new(hal))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            id,
+            receiveq_index,
+            transmitq_index,
+            receiveq,
+            transmitq,
+            rx_buffers,
+            rx_filled: VecDeque::with_capacity(QUEUE_SIZE),
+            cursor: 0,
+            added: id == 0,
+            open: id == 0,
+            host_connected: false,
+            is_console: false,
+            name: None,
+            rx_paused: false,
+            tx_buffers: Vec::new(),
+            stats: ConsoleStats::default(),
+            hal,
+        })
+    }
+
+    /// Adds all receive buffers which are not already posted to the device or holding received
+    /// data to the receiveq, and notifies the device if necessary.
+    ///
+    /// Does nothing if receiving is paused.
+    fn poll_retrieve(&mut self, transport: &mut impl Transport) -> Result<()> {
+        if self.rx_paused {
+            return Ok(());
+        }
+        let mut added = false;
+        for rx in &mut self.rx_buffers {
+            if rx.state == RxState::Free {
+                // Safe because the buffer lasts at least as long as the queue, and is not accessed
+                // until the request is completed by `finish_receive`.
+                let token = unsafe { self.receiveq.add(&[], &mut [rx.buf_mut()]) }?;
+                rx.state = RxState::Posted(token);
+                added = true;
+            }
+        }
+        if added {
+            self.stats.rx_rearms = self.stats.rx_rearms.wrapping_add(1);
+            if self.receiveq.should_notify() {
+                transport.notify(self.receiveq_index);
+            }
+        }
+        Ok(())
     }
 
-    /// If there is an outstanding receive request and it has finished, completes it.
+    /// Completes all outstanding receive requests which the device has finished.
     ///
     /// Returns true if new data has been received.
     fn finish_receive(&mut self) -> Result<bool> {
         let mut flag = false;
-        if let Some(receive_token) = self.receive_token {
-            if self.receive_token == self.receiveq.peek_used() {
-                // Safe because we are passing the same buffer as we passed to `VirtQueue::add` in
-                // `poll_retrieve` and it is still valid.
-                let len = unsafe {
-                    self.receiveq.pop_used(
-                        receive_token,
-                        &[],
-                        &mut [self.queue_buf_rx.as_mut_slice()],
-                    )?
-                };
-                flag = true;
-                assert_ne!(len, 0);
-                self.cursor = 0;
-                self.pending_len = len as usize;
-                // Clear `receive_token` so that when the buffer is used up the next call to
-                // `poll_retrieve` will add a new pending request.
-                self.receive_token.take();
+        while let Some(token) = self.receiveq.peek_used() {
+            let index = self
+                .rx_buffers
+                .iter()
+                .position(|rx| rx.state == RxState::Posted(token))
+                .ok_or(Error::WrongToken)?;
+            let rx = &mut self.rx_buffers[index];
+            // Safe because we are passing the same buffer as we passed to `VirtQueue::add` in
+            // `poll_retrieve` and it is still valid.
+            let len = unsafe { self.receiveq.pop_used(token, &[], &mut [rx.buf_mut()])? };
+            if len == 0 {
+                // Nothing was received, so give the buffer straight back to the device next time.
+                rx.state = RxState::Free;
+                continue;
             }
+            rx.len = len as usize;
+            rx.state = RxState::Filled;
+            self.stats.rx_bytes = self.stats.rx_bytes.wrapping_add(len.into());
+            self.rx_filled.push_back(index);
+            flag = true;
         }
         Ok(flag)
     }
 
-    /// Returns the next available character from the console, if any.
+    /// Completes all outstanding receive requests which the device has finished, and discards the
+    /// data if the port has not been added or is closed.
     ///
-    /// If no data has been received this will not block but immediately return `Ok<None>`.
-    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
-        self.finish_receive()?;
-        if self.cursor == self.pending_len {
-            return Ok(None);
+    /// Returns true if new data has been received and kept.
+    fn receive(&mut self, transport: &mut impl Transport) -> Result<bool> {
+        if !self.finish_receive()? {
+            return Ok(false);
+        }
+        if self.added && self.open {
+            return Ok(true);
         }
-        let ch = self.queue_buf_rx[self.cursor];
+        debug!(
+            "Discarding data received on port {} while it is not open",
+            self.id
+        );
+        self.discard_pending(transport)?;
+        Ok(false)
+    }
+
+    /// Returns whether the port is open at both ends, or just at the driver's end if there is no
+    /// control queue for the host to report its end.
+    fn is_open(&self, multiport: bool) -> bool {
+        self.open && (self.host_connected || !multiport)
+    }
+
+    /// Returns whether there is any received data which has not yet been returned.
+    fn has_pending(&self) -> bool {
+        !self.rx_filled.is_empty()
+    }
+
+    /// Returns the received data in the first filled buffer which has not yet been returned.
+    fn pending(&self) -> &[u8] {
+        match self.rx_filled.front() {
+            Some(&index) => {
+                let rx = &self.rx_buffers[index];
+                &rx.buf()[self.cursor..rx.len]
+            }
+            None => &[],
+        }
+    }
+
+    /// Marks the first `len` bytes returned by `pending` as consumed, and if that empties the first
+    /// filled buffer gives it back to the device.
+    fn consume_pending(&mut self, transport: &mut impl Transport, len: usize) -> Result<()> {
+        let Some(&index) = self.rx_filled.front() else {
+            return Ok(());
+        };
+        self.cursor += len;
+        if self.cursor == self.rx_buffers[index].len {
+            self.rx_buffers[index].state = RxState::Free;
+            self.rx_filled.pop_front();
+            self.cursor = 0;
+            self.poll_retrieve(transport)?;
+        }
+        Ok(())
+    }
+
+    /// Throws away any data which has been received but not yet returned, and gives the buffers
+    /// back to the device.
+    fn discard_pending(&mut self, transport: &mut impl Transport) -> Result<()> {
+        for index in self.rx_filled.drain(..) {
+            self.rx_buffers[index].state = RxState::Free;
+        }
+        self.cursor = 0;
+        self.poll_retrieve(transport)
+    }
+
+    fn recv(&mut self, transport: &mut impl Transport, pop: bool) -> Result<Option<u8>> {
+        self.receive(transport)?;
+        let Some(&ch) = self.pending().first() else {
+            return Ok(None);
+        };
         if pop {
-            self.cursor += 1;
-            self.poll_retrieve()?;
+            self.consume_pending(transport, 1)?;
         }
         Ok(Some(ch))
     }
 
-    /// Sends a character to the console.
-    pub fn send(&mut self, chr: u8) -> Result<()> {
-        let buf: [u8; 1] = [chr];
-        self.transmitq
-            .add_notify_wait_pop(&[&buf], &mut [], &mut self.transport)?;
+    fn recv_slice(&mut self, transport: &mut impl Transport, buf: &mut [u8]) -> Result<usize> {
+        self.receive(transport)?;
+        let mut copied = 0;
+        while copied < buf.len() && self.has_pending() {
+            let pending = self.pending();
+            let len = pending.len().min(buf.len() - copied);
+            buf[copied..copied + len].copy_from_slice(&pending[..len]);
+            copied += len;
+            self.consume_pending(transport, len)?;
+        }
+        Ok(copied)
+    }
+
+    fn send_slice(&mut self, transport: &mut impl Transport, data: &[u8]) -> Result<()> {
+        // `add_notify_wait_pop` assumes that there are no other requests in flight.
+        self.flush(transport)?;
+        for chain in data.chunks(QUEUE_SIZE * PAGE_SIZE) {
+            let inputs = chain.chunks(PAGE_SIZE).collect::<Vec<_>>();
+            add_notify_wait_pop(
+                &mut self.transmitq,
+                transport,
+                self.transmitq_index,
+                &inputs,
+            )?;
+            self.stats.tx_bytes = self.stats.tx_bytes.wrapping_add(chain.len() as u64);
+        }
+        Ok(())
+    }
+
+    fn send_nonblocking(&mut self, transport: &mut impl Transport, data: &[u8]) -> Result<u16> {
+        if data.is_empty() || data.len() > QUEUE_SIZE * PAGE_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let needed = data.len().div_ceil(PAGE_SIZE);
+        let free = self
+            .tx_buffers
+            .iter()
+            .filter(|tx| tx.token.is_none())
+            .count();
+        for _ in free..needed {
+            if self.tx_buffers.len() == QUEUE_SIZE {
+                break;
+            }
+            self.tx_buffers.push(TxBuffer::new(self.hal)?);
+        }
+        let indices = self
+            .tx_buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.token.is_none())
+            .map(|(index, _)| index)
+            .take(needed)
+            .collect::<Vec<_>>();
+        if indices.len() < needed {
+            check_needs_reset(transport)?;
+            return Err(Error::QueueFull);
+        }
+        for (&index, chunk) in indices.iter().zip(data.chunks(PAGE_SIZE)) {
+            self.tx_buffers[index].set_data(chunk);
+        }
+        let inputs = indices
+            .iter()
+            .map(|&index| self.tx_buffers[index].data())
+            .collect::<Vec<_>>();
+        // Safe because the buffers last as long as the queue, and are not accessed again until the
+        // request is completed by `tx_complete`.
+        let token = match unsafe { self.transmitq.add(&inputs, &mut []) } {
+            Ok(token) => token,
+            Err(Error::QueueFull) => {
+                check_needs_reset(transport)?;
+                return Err(Error::QueueFull);
+            }
+            Err(e) => return Err(e),
+        };
+        for &index in &indices {
+            self.tx_buffers[index].token = Some(token);
+        }
+        self.stats.tx_bytes = self.stats.tx_bytes.wrapping_add(data.len() as u64);
+        if self.transmitq.should_notify() {
+            transport.notify(self.transmitq_index);
+        }
+        Ok(token)
+    }
+
+    fn tx_complete(&mut self) -> Result<usize> {
+        let mut completed = 0;
+        while let Some(token) = self.transmitq.peek_used() {
+            // The buffers of a chain were added in order of their index in `tx_buffers`, so this
+            // gives them in the same order as they were passed to `VirtQueue::add`.
+            let inputs = self
+                .tx_buffers
+                .iter()
+                .filter(|tx| tx.token == Some(token))
+                .map(|tx| tx.data())
+                .collect::<Vec<_>>();
+            if inputs.is_empty() {
+                return Err(Error::WrongToken);
+            }
+            // Safe because we are passing the same buffers as we passed to `VirtQueue::add` in
+            // `send_nonblocking` and they are still valid.
+            unsafe {
+                self.transmitq.pop_used(token, &inputs, &mut [])?;
+            }
+            for tx in &mut self.tx_buffers {
+                if tx.token == Some(token) {
+                    tx.token = None;
+                }
+            }
+            completed += 1;
+        }
+        Ok(completed)
+    }
+
+    fn flush(&mut self, transport: &impl Transport) -> Result<()> {
+        while self.tx_buffers.iter().any(|tx| tx.token.is_some()) {
+            if self.tx_complete()? == 0 {
+                check_needs_reset(transport)?;
+                spin_loop();
+            }
+        }
+        Ok(())
+    }
+
+    /// Reclaims the buffers of all requests which the device has not completed, once it has been
+    /// reset so that it will never complete them.
+    fn reclaim_pending(&mut self) -> Result<()> {
+        for rx in &mut self.rx_buffers {
+            if let RxState::Posted(token) = rx.state {
+                // Safe because we are passing the same buffer as we passed to `VirtQueue::add` in
+                // `poll_retrieve`, and the device has been reset.
+                unsafe { self.receiveq.pop_pending(token, &[], &mut [rx.buf_mut()])? };
+                rx.state = RxState::Free;
+            }
+        }
+        while let Some(token) = self.tx_buffers.iter().find_map(|tx| tx.token) {
+            let inputs = self
+                .tx_buffers
+                .iter()
+                .filter(|tx| tx.token == Some(token))
+                .map(|tx| tx.data())
+                .collect::<Vec<_>>();
+            // Safe because we are passing the same buffers as we passed to `VirtQueue::add` in
+            // `send_nonblocking`, and the device has been reset.
+            unsafe { self.transmitq.pop_pending(token, &inputs, &mut [])? };
+            for tx in &mut self.tx_buffers {
+                if tx.token == Some(token) {
+                    tx.token = None;
+                }
+            }
+        }
         Ok(())
     }
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOConsole<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
-        self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
+/// Returns [`Error::DeviceNeedsReset`] if the device has set `DEVICE_NEEDS_RESET` in its status.
+fn check_needs_reset(transport: &impl Transport) -> Result<()> {
+    if transport
+        .get_status()
+        .contains(DeviceStatus::DEVICE_NEEDS_RESET)
+    {
+        Err(Error::DeviceNeedsReset)
+    } else {
+        Ok(())
     }
 }
 
+/// Like [`VirtQueue::add_notify_wait_pop`] for device-readable buffers only, but gives up with
+/// [`Error::DeviceNeedsReset`] rather than spinning forever if the device sets
+/// `DEVICE_NEEDS_RESET` while we are waiting.
+///
+/// In that case the request is left on the queue, so the queue must not be used again until the
+/// device has been reset.
+fn add_notify_wait_pop<H: Hal, const SIZE: usize>(
+    queue: &mut VirtQueue<H, SIZE>,
+    transport: &mut impl Transport,
+    queue_index: u16,
+    inputs: &[&[u8]],
+) -> Result<u32> {
+    // Safe because we don't return until the same token has been popped, or the device is broken
+    // and must be reset before the queue is used again.
+    let token = unsafe { queue.add(inputs, &mut []) }?;
+    if queue.should_notify() {
+        transport.notify(queue_index);
+    }
+    while !queue.can_pop() {
+        check_needs_reset(transport)?;
+        spin_loop();
+    }
+    // Safe because these are the same buffers as we passed to `add` above and they are still
+    // valid.
+    unsafe { queue.pop_used(token, inputs, &mut []) }
+}
+
+/// The control queues used if `VIRTIO_CONSOLE_F_MULTIPORT` is negotiated.
+struct ControlQueues<H: Hal> {
+    receiveq: OwningQueue<H, DEFAULT_QUEUE_SIZE, CONTROL_BUFFER_SIZE>,
+    transmitq: VirtQueue<H, DEFAULT_QUEUE_SIZE>,
+}
+
 #[repr(C)]
 struct Config {
     cols: ReadOnly<u16>,
@@ -228,6 +1447,32 @@ struct Config {
     emerg_wr: WriteOnly<u32>,
 }
 
+/// A control message sent on the control receiveq or transmitq.
+///
+/// Ref: 5.3.6.2 Multiport Device Operation
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, FromBytes, FromZeroes)]
+struct ControlMessage {
+    id: u32,
+    event: ControlEvent,
+    value: u16,
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq, FromBytes, FromZeroes)]
+struct ControlEvent(u16);
+
+impl ControlEvent {
+    const DEVICE_READY: ControlEvent = ControlEvent(0);
+    const DEVICE_ADD: ControlEvent = ControlEvent(1);
+    const DEVICE_REMOVE: ControlEvent = ControlEvent(2);
+    const PORT_READY: ControlEvent = ControlEvent(3);
+    const CONSOLE_PORT: ControlEvent = ControlEvent(4);
+    const RESIZE: ControlEvent = ControlEvent(5);
+    const PORT_OPEN: ControlEvent = ControlEvent(6);
+    const PORT_NAME: ControlEvent = ControlEvent(7);
+}
+
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
     struct Features: u64 {
@@ -252,3 +1497,130 @@ bitflags! {
         const NOTIFICATION_DATA     = 1 << 38;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use crate::transport::{
+        fake::{FakeTransport, State},
+        DeviceType,
+    };
+    use alloc::sync::Arc;
+    use std::{sync::Mutex, thread};
+
+    fn config_space() -> Config {
+        Config {
+            cols: ReadOnly::new(80),
+            rows: ReadOnly::new(25),
+            max_nr_ports: ReadOnly::new(1),
+            emerg_wr: WriteOnly::default(),
+        }
+    }
+
+    fn fake_console(
+        config_space: &mut Config,
+        hal: FakeHal,
+    ) -> (
+        VirtIOConsole<FakeHal, FakeTransport<Config>>,
+        Arc<Mutex<State>>,
+    ) {
+        let state = Arc::new(Mutex::new(State::new(2)));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        let console = VirtIOConsole::new(transport, hal).unwrap();
+        (console, state)
+    }
+
+    #[test]
+    fn send_slice_page() {
+        let mut config_space = config_space();
+        let (mut console, state) = fake_console(&mut config_space, FakeHal::new());
+        let data = [0x42; PAGE_SIZE];
+
+        let device = {
+            let state = state.clone();
+            thread::spawn(move || {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMITQ_PORT_0);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<DEFAULT_QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0)
+            })
+        };
+        console.send_slice(&data).unwrap();
+        assert_eq!(device.join().unwrap(), data);
+        assert_eq!(
+            state.lock().unwrap().queues[usize::from(QUEUE_TRANSMITQ_PORT_0)].notify_count,
+            1
+        );
+    }
+
+    #[test]
+    fn drop_reclaims_buffers() {
+        let mut config_space = config_space();
+        let hal = FakeHal::new();
+        let (console, state) = fake_console(&mut config_space, hal);
+
+        // The device fills one receive buffer, which is never read, and leaves the other posted.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<DEFAULT_QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"hi");
+        drop(console);
+
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(hal.counts().live_shares(), 0);
+        assert_eq!(hal.counts().live_dma(), 0);
+    }
+
+    #[test]
+    fn buffers_shared() {
+        let mut config_space = config_space();
+        let hal = FakeHal::new();
+        let (mut console, state) = fake_console(&mut config_space, hal);
+        console.send_nonblocking(&[0x42; PAGE_SIZE + 1]).unwrap();
+
+        let state = state.lock().unwrap();
+        let rx_buffers = state.available_buffers::<DEFAULT_QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0);
+        let tx_buffers = state.available_buffers::<DEFAULT_QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+        assert_eq!(rx_buffers.len(), DEFAULT_QUEUE_SIZE);
+        assert_eq!(tx_buffers.len(), 2);
+        for (paddr, len) in rx_buffers.into_iter().chain(tx_buffers) {
+            assert!(hal.counts().is_shared(paddr, len));
+        }
+    }
+
+    #[test]
+    fn send_slice_multiple_chains() {
+        let mut config_space = config_space();
+        let (mut console, state) = fake_console(&mut config_space, FakeHal::new());
+        let data = (0..3 * PAGE_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        // With 2 descriptors in the transmitq, the data is sent as a chain of 2 pages and then a
+        // chain of 1 page.
+        let device = {
+            let state = state.clone();
+            thread::spawn(move || {
+                let mut received = Vec::new();
+                for _ in 0..2 {
+                    State::wait_until_queue_notified(&state, QUEUE_TRANSMITQ_PORT_0);
+                    received.extend(
+                        state
+                            .lock()
+                            .unwrap()
+                            .read_from_queue::<DEFAULT_QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0),
+                    );
+                }
+                received
+            })
+        };
+        console.send_slice(&data).unwrap();
+        assert_eq!(device.join().unwrap(), data);
+    }
+}
@@ -0,0 +1,155 @@
+//! A [`log::Log`] implementation which writes log records to a VirtIO console.
+
+use super::{VirtIOConsole, DEFAULT_QUEUE_SIZE};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use alloc::boxed::Box;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lock_api::{Mutex, RawMutex};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// The maximum length of a single formatted log line. Longer lines are truncated.
+const LINE_BUFFER_SIZE: usize = 256;
+
+/// A logger which writes records to a [`VirtIOConsole`], guarded by the lock type `M`.
+///
+/// Records are formatted as `[LEVEL target] message` and queued with
+/// [`VirtIOConsole::send_nonblocking`]. If there is no free transmit buffer, or the console is
+/// already locked (e.g. because the driver itself logged while handling a request), the record is
+/// dropped and counted rather than blocking.
+pub struct ConsoleLogger<
+    M: RawMutex,
+    H: Hal,
+    T: Transport,
+    const QUEUE_SIZE: usize = DEFAULT_QUEUE_SIZE,
+> {
+    console: Mutex<M, VirtIOConsole<H, T, QUEUE_SIZE>>,
+    dropped: AtomicUsize,
+}
+
+impl<M: RawMutex, H: Hal, T: Transport, const QUEUE_SIZE: usize>
+    ConsoleLogger<M, H, T, QUEUE_SIZE>
+{
+    /// Creates a new logger which writes to the given console.
+    pub fn new(console: VirtIOConsole<H, T, QUEUE_SIZE>) -> Self {
+        Self {
+            console: Mutex::new(console),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of log records which have been dropped because the console was busy or
+    /// its transmit queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the lock guarding the underlying console, e.g. to read input from it.
+    pub fn console(&self) -> &Mutex<M, VirtIOConsole<H, T, QUEUE_SIZE>> {
+        &self.console
+    }
+
+    fn drop_record(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<M, H, T, const QUEUE_SIZE: usize> Log for ConsoleLogger<M, H, T, QUEUE_SIZE>
+where
+    M: RawMutex + Send + Sync,
+    H: Hal,
+    T: Transport + Send,
+{
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = LineBuffer::default();
+        // Formatting only fails once the buffer is full, in which case the line is truncated.
+        if writeln!(
+            line,
+            "[{} {}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        )
+        .is_err()
+        {
+            line.buf[LINE_BUFFER_SIZE - 1] = b'\n';
+        }
+        let Some(mut console) = self.console.try_lock() else {
+            self.drop_record();
+            return;
+        };
+        if console
+            .tx_complete()
+            .and_then(|_| console.send_nonblocking(line.as_bytes()))
+            .is_err()
+        {
+            self.drop_record();
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.console.lock().flush();
+    }
+}
+
+/// Creates a [`ConsoleLogger`] for the given console and installs it as the global logger, with
+/// the given maximum level.
+///
+/// The logger is leaked so that it lives for the rest of the program, and is returned so that the
+/// caller can still access the console and the dropped record counter.
+pub fn init_logger<M, H, T, const QUEUE_SIZE: usize>(
+    console: VirtIOConsole<H, T, QUEUE_SIZE>,
+    max_level: LevelFilter,
+) -> Result<&'static ConsoleLogger<M, H, T, QUEUE_SIZE>, SetLoggerError>
+where
+    M: RawMutex + Send + Sync + 'static,
+    H: Hal + 'static,
+    T: Transport + Send + 'static,
+{
+    let logger = Box::leak(Box::new(ConsoleLogger::new(console)));
+    log::set_logger(logger)?;
+    log::set_max_level(max_level);
+    Ok(logger)
+}
+
+/// A fixed-size buffer for formatting a single log line without allocating.
+struct LineBuffer {
+    buf: [u8; LINE_BUFFER_SIZE],
+    len: usize,
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self {
+            buf: [0; LINE_BUFFER_SIZE],
+            len: 0,
+        }
+    }
+}
+
+impl LineBuffer {
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(LINE_BUFFER_SIZE - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        if count < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Batches of GPU control commands, which are sent to the device with a single
+//! notification.
+
+use super::{
+    Command, CtrlHeader, Rect, ResourceFlush, ResourceHandle, TransferToHost2D, VirtIOGpu,
+    FENCE_REQUEST_SIZE, QUEUE_TRANSMIT,
+};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use core::hint::spin_loop;
+use core::mem::size_of;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The largest number of commands which can be added to a [`GpuBatch`].
+pub const MAX_BATCH_COMMANDS: usize = 8;
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Starts a batch of control commands, which are sent to the device
+    /// together by [`GpuBatch::submit`].
+    ///
+    /// Returns [`Error::AlreadyUsed`] if a batch has been submitted but not yet
+    /// waited for with [`VirtIOGpu::wait_batch`].
+    pub fn begin_batch(&mut self) -> Result<GpuBatch<'_, H, T>> {
+        if self.batch_len != 0 {
+            return Err(Error::AlreadyUsed);
+        }
+        Ok(GpuBatch { gpu: self, len: 0 })
+    }
+
+    /// Waits for the device to finish every command of the submitted batch,
+    /// and returns their results in the order in which they were added.
+    ///
+    /// Fenced commands which finish in the meantime are reaped as by
+    /// [`VirtIOGpu::poll_fences`]. Returns [`Error::NotReady`] if no batch has
+    /// been submitted.
+    pub fn wait_batch(&mut self) -> Result<BatchResults> {
+        if self.batch_len == 0 {
+            return Err(Error::NotReady);
+        }
+        loop {
+            self.poll_fences()?;
+            if self.batch_slots[..self.batch_len]
+                .iter()
+                .all(|slot| slot.token.is_none())
+            {
+                break;
+            }
+            spin_loop();
+        }
+        let mut results = [Ok(()); MAX_BATCH_COMMANDS];
+        for (result, slot) in results.iter_mut().zip(&self.batch_slots[..self.batch_len]) {
+            *result = slot.result;
+        }
+        let len = self.batch_len;
+        self.batch_len = 0;
+        Ok(BatchResults { results, len })
+    }
+
+    /// Pops the given token if it belongs to a command of the submitted batch,
+    /// and returns whether it did.
+    pub(super) fn pop_batch_command(&mut self, token: u16) -> Result<bool> {
+        let Some(slot) = self.batch_slots[..self.batch_len]
+            .iter_mut()
+            .find(|slot| slot.token == Some(token))
+        else {
+            return Ok(false);
+        };
+        // Safe because these are the same buffers as were passed to `add` with the token, and they
+        // haven't been accessed since.
+        unsafe {
+            self.control_queue
+                .pop_used(token, &[&slot.send[..slot.len]], &mut [&mut slot.recv])?;
+        }
+        slot.token = None;
+        slot.result = CtrlHeader::read_from_prefix(&slot.recv)
+            .unwrap()
+            .check_type(Command::OK_NODATA);
+        Ok(true)
+    }
+}
+
+/// A batch of control commands being built, returned by
+/// [`VirtIOGpu::begin_batch`].
+///
+/// Commands are only sent to the device by [`GpuBatch::submit`], so dropping
+/// the batch discards them. The device may run them in any order, but their
+/// results are returned by [`VirtIOGpu::wait_batch`] in the order in which they
+/// were added.
+pub struct GpuBatch<'a, H: Hal, T: Transport> {
+    gpu: &'a mut VirtIOGpu<H, T>,
+    /// The number of commands which have been added.
+    len: usize,
+}
+
+impl<H: Hal, T: Transport> GpuBatch<'_, H, T> {
+    /// Adds a command to copy the given rectangle of a resource's backing
+    /// memory to the host.
+    ///
+    /// Returns [`Error::QueueFull`] if the batch already has
+    /// [`MAX_BATCH_COMMANDS`] commands.
+    pub fn transfer_to_host_2d(
+        &mut self,
+        resource: ResourceHandle,
+        rect: Rect,
+        offset: u64,
+    ) -> Result {
+        self.gpu.resource_info(resource)?;
+        self.push(TransferToHost2D {
+            header: CtrlHeader::with_type(Command::TRANSFER_TO_HOST_2D),
+            rect,
+            offset,
+            resource_id: resource.id,
+            _padding: 0,
+        })
+    }
+
+    /// Adds a command to flush the given rectangle of a resource to the
+    /// screen.
+    ///
+    /// Returns [`Error::QueueFull`] if the batch already has
+    /// [`MAX_BATCH_COMMANDS`] commands.
+    pub fn resource_flush(&mut self, resource: ResourceHandle, rect: Rect) -> Result {
+        self.gpu.resource_info(resource)?;
+        self.push(ResourceFlush {
+            header: CtrlHeader::with_type(Command::RESOURCE_FLUSH),
+            rect,
+            resource_id: resource.id,
+            _padding: 0,
+        })
+    }
+
+    /// Returns the number of commands which have been added.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no commands have been added.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds all the commands to the control queue, and notifies the device
+    /// once.
+    ///
+    /// Wait for them to finish with [`VirtIOGpu::wait_batch`]. If adding a
+    /// command fails, the error is returned and only the commands before it
+    /// are sent, which must still be waited for.
+    pub fn submit(self) -> Result {
+        let gpu = self.gpu;
+        let mut result = Ok(());
+        for slot in &mut gpu.batch_slots[..self.len] {
+            // Safe because the buffers are in `batch_slots`, which lives as long as the queue, and
+            // aren't accessed again until the token is popped by `pop_batch_command`.
+            match unsafe {
+                gpu.control_queue
+                    .add(&[&slot.send[..slot.len]], &mut [&mut slot.recv])
+            } {
+                Ok(token) => {
+                    slot.token = Some(token);
+                    gpu.batch_len += 1;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if gpu.batch_len != 0 && gpu.control_queue.should_notify() {
+            gpu.transport.notify(QUEUE_TRANSMIT);
+        }
+        result
+    }
+
+    /// Writes the given request into the next slot of the batch.
+    fn push<Req: AsBytes>(&mut self, req: Req) -> Result {
+        let slot = self
+            .gpu
+            .batch_slots
+            .get_mut(self.len)
+            .ok_or(Error::QueueFull)?;
+        req.write_to_prefix(&mut slot.send[..]).unwrap();
+        slot.len = size_of::<Req>();
+        slot.result = Ok(());
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// The results of the commands of a batch, returned by
+/// [`VirtIOGpu::wait_batch`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BatchResults {
+    results: [Result; MAX_BATCH_COMMANDS],
+    len: usize,
+}
+
+impl BatchResults {
+    /// Returns the result of each command, in the order in which they were
+    /// added to the batch.
+    pub fn as_slice(&self) -> &[Result] {
+        &self.results[..self.len]
+    }
+
+    /// Returns the first error of any command, or `Ok` if they all succeeded.
+    pub fn check(&self) -> Result {
+        self.as_slice()
+            .iter()
+            .copied()
+            .find(Result::is_err)
+            .unwrap_or(Ok(()))
+    }
+}
+
+/// The buffers for one command of a batch.
+pub(super) struct BatchSlot {
+    /// The request, which the device may still be reading.
+    send: [u8; FENCE_REQUEST_SIZE],
+    /// The response, which the device may still be writing.
+    recv: [u8; size_of::<CtrlHeader>()],
+    /// The length of the request in the send buffer.
+    len: usize,
+    /// The token of the command while it is in flight.
+    token: Option<u16>,
+    /// The result of the command, once the device has finished it.
+    result: Result,
+}
+
+impl BatchSlot {
+    pub(super) const EMPTY: Self = Self {
+        send: [0; FENCE_REQUEST_SIZE],
+        recv: [0; size_of::<CtrlHeader>()],
+        len: 0,
+        token: None,
+        result: Ok(()),
+    };
+}
@@ -0,0 +1,2666 @@
+//! Driver for VirtIO GPU devices.
+
+mod batch;
+#[cfg(feature = "virgl")]
+mod virgl;
+
+pub use self::batch::{BatchResults, GpuBatch, MAX_BATCH_COMMANDS};
+#[cfg(feature = "virgl")]
+pub use self::virgl::{ContextHandle, Resource3DParams};
+
+use self::batch::BatchSlot;
+
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::VirtQueue;
+use crate::transport::{InterruptStatus, Transport};
+use crate::volatile::{volread, volwrite, ReadOnly, Volatile, WriteOnly};
+use crate::{pages, Error, Result, PAGE_SIZE};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::fmt::{self, Display, Formatter};
+use core::hint::spin_loop;
+use core::mem::{size_of, size_of_val};
+use core::ptr::NonNull;
+use log::{info, warn};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+const QUEUE_SIZE: u16 = 2;
+#[cfg(not(feature = "virgl"))]
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_INDIRECT_DESC)
+    .union(Features::EDID)
+    .union(Features::RESOURCE_BLOB);
+#[cfg(feature = "virgl")]
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_INDIRECT_DESC)
+    .union(Features::EDID)
+    .union(Features::RESOURCE_BLOB)
+    .union(Features::VIRGL);
+
+/// A virtio based graphics adapter.
+///
+/// It can operate in 2D mode and in 3D (virgl) mode.
+/// 3D mode will offload rendering ops to the host gpu and therefore requires
+/// a gpu with 3D support on the host machine.
+/// In 2D mode the virtio-gpu device provides support for ARGB Hardware cursors
+/// and multiple scanouts (aka heads).
+pub struct VirtIOGpu<H: Hal, T: Transport> {
+    transport: T,
+    hal: H,
+    /// The features which were negotiated with the device.
+    negotiated_features: Features,
+    config: NonNull<Config>,
+    /// The number of scanouts which the device supports.
+    num_scanouts: u32,
+    /// Whether the device has reported a display change which hasn't yet been
+    /// returned by [`VirtIOGpu::poll_event`].
+    display_changed: bool,
+    /// The frame buffer of each scanout which has been set up.
+    framebuffers: [Option<Framebuffer<H>>; MAX_SCANOUTS],
+    /// The resources which have been created with
+    /// [`VirtIOGpu::create_resource_2d`] and not yet destroyed.
+    resources: Vec<ResourceInfo>,
+    /// The resource ID to try next when creating a resource.
+    next_resource_id: u32,
+    /// DMA area of cursor image buffer.
+    cursor_buffer_dma: Option<Dma<H>>,
+    /// Queue for sending control commands.
+    control_queue: VirtQueue<H, CONTROL_QUEUE_SIZE>,
+    /// Queue for sending cursor commands.
+    cursor_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    /// Send buffer for queue.
+    queue_buf_send: Box<[u8]>,
+    /// Recv buffer for queue.
+    queue_buf_recv: Box<[u8]>,
+    /// Send buffer for the cursor queue, which the device may still be reading.
+    cursor_buf_send: Box<[u8]>,
+    /// The token of the cursor command which the device hasn't used yet, if any.
+    cursor_token: Option<u16>,
+    /// Buffers for fenced commands which may be in flight at the same time.
+    fence_slots: Box<[FenceSlot; FENCE_SLOTS]>,
+    /// The fence ID to give the next fenced command.
+    next_fence_id: u64,
+    /// Buffers for the commands of a batch.
+    batch_slots: Box<[BatchSlot; MAX_BATCH_COMMANDS]>,
+    /// The number of commands of the submitted batch, which haven't yet been
+    /// returned by [`VirtIOGpu::wait_batch`].
+    batch_len: usize,
+    /// The 3D contexts which have been created and not yet destroyed.
+    #[cfg(feature = "virgl")]
+    contexts: Vec<u32>,
+}
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Create a new VirtIO-Gpu driver.
+    pub fn new(mut transport: T, hal: H) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+
+        // read configuration space
+        let config_space = transport.config_space::<Config>()?;
+        let num_scanouts = unsafe {
+            let events_read = volread!(config_space, events_read);
+            let num_scanouts = volread!(config_space, num_scanouts);
+            info!(
+                "events_read: {:#x}, num_scanouts: {:#x}",
+                events_read, num_scanouts
+            );
+            num_scanouts.clamp(1, MAX_SCANOUTS as u32)
+        };
+
+        let control_queue = VirtQueue::new_clamped(
+            hal,
+            &mut transport,
+            QUEUE_TRANSMIT,
+            negotiated_features.contains(Features::RING_INDIRECT_DESC),
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+        let cursor_queue = VirtQueue::new(
+            hal,
+            &mut transport,
+            QUEUE_CURSOR,
+            negotiated_features.contains(Features::RING_INDIRECT_DESC),
+            negotiated_features.contains(Features::RING_EVENT_IDX),
+        )?;
+
+        let queue_buf_send = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
+        let queue_buf_recv = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
+        let cursor_buf_send = FromZeroes::new_box_slice_zeroed(size_of::<UpdateCursor>());
+
+        transport.finish_init();
+
+        Ok(VirtIOGpu {
+            transport,
+            hal,
+            negotiated_features,
+            config: config_space,
+            num_scanouts,
+            display_changed: false,
+            framebuffers: [const { None }; MAX_SCANOUTS],
+            resources: Vec::new(),
+            next_resource_id: 1,
+            cursor_buffer_dma: None,
+            control_queue,
+            cursor_queue,
+            queue_buf_send,
+            queue_buf_recv,
+            cursor_buf_send,
+            cursor_token: None,
+            fence_slots: Box::new([const { FenceSlot::EMPTY }; FENCE_SLOTS]),
+            next_fence_id: 1,
+            batch_slots: Box::new([const { BatchSlot::EMPTY }; MAX_BATCH_COMMANDS]),
+            batch_len: 0,
+            #[cfg(feature = "virgl")]
+            contexts: Vec::new(),
+        })
+    }
+
+    /// Acknowledge interrupt.
+    ///
+    /// If the interrupt was caused by displays being added, removed or resized,
+    /// the event is cleared in the device and a [`GpuEvent::DisplayChanged`]
+    /// will be returned by the next call to [`VirtIOGpu::poll_event`].
+    pub fn ack_interrupt(&mut self) -> bool {
+        let status = self.transport.ack_interrupt_status();
+        if status.contains(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT) {
+            // Safe because config is a valid pointer to the device configuration space.
+            unsafe {
+                let events = volread!(self.config, events_read);
+                if events & EVENT_DISPLAY != 0 {
+                    volwrite!(self.config, events_clear, EVENT_DISPLAY);
+                    self.display_changed = true;
+                }
+            }
+        }
+        !status.is_empty()
+    }
+
+    /// Returns the next pending event from the device, if any.
+    ///
+    /// Events are recorded by [`VirtIOGpu::ack_interrupt`]. After a
+    /// [`GpuEvent::DisplayChanged`], [`VirtIOGpu::resolution`] and
+    /// [`VirtIOGpu::scanouts`] fetch the new display information from the
+    /// device.
+    pub fn poll_event(&mut self) -> Option<GpuEvent> {
+        if self.display_changed {
+            self.display_changed = false;
+            Some(GpuEvent::DisplayChanged)
+        } else {
+            None
+        }
+    }
+
+    /// Get the resolution (width, height).
+    pub fn resolution(&mut self) -> Result<(u32, u32)> {
+        let display_info = self.get_display_info()?;
+        let rect = display_info.pmodes[SCANOUT_ID as usize].rect;
+        Ok((rect.width, rect.height))
+    }
+
+    /// Returns the number of scanouts (displays) which the device supports.
+    pub fn num_scanouts(&self) -> u32 {
+        self.num_scanouts
+    }
+
+    /// Asks the device for the current configuration of each of its scanouts.
+    pub fn scanouts(&mut self) -> Result<impl Iterator<Item = ScanoutInfo>> {
+        let display_info = self.get_display_info()?;
+        Ok((0..self.num_scanouts)
+            .zip(display_info.pmodes)
+            .map(|(id, mode)| ScanoutInfo {
+                id,
+                enabled: mode.enabled != 0,
+                rect: mode.rect,
+                flags: mode.flags,
+            }))
+    }
+
+    /// Fills `out` with the configuration of each scanout, including the
+    /// physical size of its display when it is known, and returns how many
+    /// were filled in.
+    ///
+    /// These are the first `out.len()` scanouts, or all of them if `out` is
+    /// long enough. The physical size is read from the EDID of each enabled
+    /// scanout, if the device supports `VIRTIO_GPU_F_EDID`; a scanout whose
+    /// EDID the device fails to provide or which doesn't give a size just has
+    /// no physical size.
+    pub fn display_info(&mut self, out: &mut [DisplayInfo]) -> Result<usize> {
+        let display_info = self.get_display_info()?;
+        let edid_supported = self.negotiated_features.contains(Features::EDID);
+        let mut count = 0;
+        let scanouts = (0..self.num_scanouts).zip(display_info.pmodes);
+        for (info, (id, mode)) in out.iter_mut().zip(scanouts) {
+            let enabled = mode.enabled != 0;
+            let size_mm = if enabled && edid_supported {
+                match self.edid(id) {
+                    Ok(edid) => edid.physical_size_mm(),
+                    Err(Error::GpuResponse(_)) => None,
+                    Err(e) => return Err(e),
+                }
+            } else {
+                None
+            };
+            *info = DisplayInfo {
+                id,
+                enabled,
+                rect: mode.rect,
+                width_mm: size_mm.map(|(width, _)| width),
+                height_mm: size_mm.map(|(_, height)| height),
+            };
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns the number of capability sets which the device provides.
+    pub fn num_capsets(&self) -> u32 {
+        // Safe because config is a valid pointer to the device configuration space.
+        unsafe { volread!(self.config, num_capsets) }
+    }
+
+    /// Returns information about the capability set with the given index,
+    /// which must be less than [`VirtIOGpu::num_capsets`].
+    pub fn capset_info(&mut self, index: u32) -> Result<CapsetInfo> {
+        if index >= self.num_capsets() {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: RespCapsetInfo = self.request(GetCapsetInfo {
+            header: CtrlHeader::with_type(Command::GET_CAPSET_INFO),
+            capset_index: index,
+            _padding: 0,
+        })?;
+        rsp.header.check_type(Command::OK_CAPSET_INFO)?;
+        Ok(CapsetInfo {
+            id: rsp.capset_id,
+            max_version: rsp.capset_max_version,
+            max_size: rsp.capset_max_size,
+        })
+    }
+
+    /// Reads the given version of the capability set with the given ID into
+    /// `buf`, and returns its length.
+    ///
+    /// Returns [`Error::InvalidParam`] if the device doesn't provide the
+    /// capability set or version, or if `buf` is shorter than its
+    /// [`CapsetInfo::max_size`]. Returns [`Error::IoError`] if the device
+    /// returns more than `max_size` bytes.
+    pub fn capset(&mut self, id: u32, version: u32, buf: &mut [u8]) -> Result<usize> {
+        let info = (0..self.num_capsets())
+            .map(|index| self.capset_info(index))
+            .find(|info| info.as_ref().map_or(true, |info| info.id == id))
+            .ok_or(Error::InvalidParam)??;
+        let max_size = info.max_size as usize;
+        if version > info.max_version || buf.len() < max_size || max_size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let (rsp, len) = self.request_into(
+            GetCapset {
+                header: CtrlHeader::with_type(Command::GET_CAPSET),
+                capset_id: id,
+                capset_version: version,
+            },
+            &mut buf[..max_size],
+        )?;
+        rsp.check_type(Command::OK_CAPSET)?;
+        len.checked_sub(OK_RESPONSE_HEADER_LEN)
+            .filter(|&len| len <= max_size)
+            .ok_or(Error::IoError)
+    }
+
+    /// Fetches the EDID of the display connected to the given scanout.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support
+    /// `VIRTIO_GPU_F_EDID`.
+    pub fn edid(&mut self, scanout_id: u32) -> Result<EdidBlob> {
+        if !self.negotiated_features.contains(Features::EDID) {
+            return Err(Error::Unsupported);
+        }
+        self.check_scanout(scanout_id)?;
+        let rsp: RespEdid = self.request(GetEdid {
+            header: CtrlHeader::with_type(Command::GET_EDID),
+            scanout: scanout_id,
+            _padding: 0,
+        })?;
+        rsp.header.check_type(Command::OK_EDID)?;
+        let len = rsp.size as usize;
+        if len > EDID_MAX_SIZE {
+            return Err(Error::IoError);
+        }
+        Ok(EdidBlob {
+            data: rsp.edid,
+            len,
+        })
+    }
+
+    /// Setup framebuffer
+    ///
+    /// This is the same as [`VirtIOGpu::setup_scanout`] for scanout 0.
+    pub fn setup_framebuffer(&mut self, hal: H) -> Result<&mut [u8]> {
+        self.setup_scanout(hal, SCANOUT_ID)
+    }
+
+    /// Sets up a frame buffer for the given scanout, with its preferred
+    /// resolution, and returns it.
+    ///
+    /// Each scanout has its own resource and backing memory. Returns
+    /// [`Error::InvalidParam`] if the scanout doesn't exist,
+    /// [`Error::NotReady`] if it isn't enabled, or [`Error::AlreadyUsed`] if
+    /// its frame buffer has already been set up. If the scanout was disabled
+    /// with [`VirtIOGpu::disable_scanout`], it is enabled again instead and
+    /// its existing frame buffer is returned.
+    pub fn setup_scanout(&mut self, hal: H, scanout_id: u32) -> Result<&mut [u8]> {
+        self.setup_scanout_with_format(hal, scanout_id, PixelFormat::B8G8R8A8UNORM)
+    }
+
+    /// Sets up a frame buffer for scanout 0 with the given pixel format.
+    ///
+    /// This is the same as [`VirtIOGpu::setup_scanout_with_format`] for
+    /// scanout 0.
+    pub fn setup_framebuffer_with_format(
+        &mut self,
+        hal: H,
+        format: PixelFormat,
+    ) -> Result<&mut [u8]> {
+        self.setup_scanout_with_format(hal, SCANOUT_ID, format)
+    }
+
+    /// Sets up a frame buffer for the given scanout like
+    /// [`VirtIOGpu::setup_scanout`], but with the given pixel format.
+    pub fn setup_scanout_with_format(
+        &mut self,
+        hal: H,
+        scanout_id: u32,
+        format: PixelFormat,
+    ) -> Result<&mut [u8]> {
+        self.check_scanout(scanout_id)?;
+        if let Some(framebuffer) = &self.framebuffers[scanout_id as usize] {
+            if framebuffer.enabled || framebuffer.format != format {
+                return Err(Error::AlreadyUsed);
+            }
+            self.enable_scanout(scanout_id)?;
+            let framebuffer = self.framebuffers[scanout_id as usize].as_ref().unwrap();
+            return Ok(unsafe { framebuffer.memory.as_ptr().as_mut() });
+        }
+        // get display info
+        let display_info = self.get_display_info()?;
+        let mode = display_info.pmodes[scanout_id as usize];
+        info!("scanout {} => {:?}", scanout_id, mode);
+        if mode.enabled == 0 || mode.rect.width == 0 || mode.rect.height == 0 {
+            return Err(Error::NotReady);
+        }
+        let rect = mode.rect;
+        let (resource, dma) = self.create_backed_resource(hal, rect, format)?;
+
+        // map frame buffer to screen
+        if let Err(e) = self.set_scanout(rect, scanout_id, resource.id) {
+            // The original error is more useful than any from cleaning up.
+            let _ = self.destroy_resource(resource);
+            return Err(e);
+        }
+
+        let buf = unsafe { dma.raw_slice().as_mut() };
+        self.framebuffers[scanout_id as usize] = Some(Framebuffer {
+            rect,
+            format,
+            resource,
+            memory: FramebufferMemory::Owned(dma),
+            back: None,
+            blob: false,
+            enabled: true,
+        });
+        Ok(buf)
+    }
+
+    /// Sets up a frame buffer for scanout 0 in memory provided by the caller.
+    ///
+    /// This is the same as [`VirtIOGpu::setup_scanout_in`] for scanout 0.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOGpu::setup_scanout_in`].
+    pub unsafe fn setup_framebuffer_in(
+        &mut self,
+        paddr: u64,
+        buf: NonNull<[u8]>,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+    ) -> Result {
+        // Safe because our caller promises the same things as we require.
+        unsafe { self.setup_scanout_in(SCANOUT_ID, paddr, buf, format, width, height) }
+    }
+
+    /// Sets up a frame buffer of the given format and size for a scanout,
+    /// backed by the given memory rather than memory allocated by the driver,
+    /// e.g. so that it can be shared with another protection domain.
+    ///
+    /// `paddr` is the address of `buf` as seen by the device. The driver never
+    /// frees the memory, even when it is dropped. Returns
+    /// [`Error::InvalidParam`] if the memory is too small for the frame buffer,
+    /// or [`Error::AlreadyUsed`] if the scanout's frame buffer has already been
+    /// set up.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid memory, physically contiguous at `paddr`, which the
+    /// device may access. It must not be freed or otherwise reused until the
+    /// `VirtIOGpu` has been dropped, as the device may access it until then.
+    pub unsafe fn setup_scanout_in(
+        &mut self,
+        scanout_id: u32,
+        paddr: u64,
+        buf: NonNull<[u8]>,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+    ) -> Result {
+        self.check_scanout(scanout_id)?;
+        if self.framebuffers[scanout_id as usize].is_some() {
+            return Err(Error::AlreadyUsed);
+        }
+        let length = u32::try_from(buf.len()).map_err(|_| Error::InvalidParam)?;
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let resource = self.create_resource_2d(format, width, height)?;
+        // Safe because our caller promises that the memory lives as long as the device.
+        let result =
+            unsafe { self.attach_backing_entries(resource, &[MemEntry::new(paddr, length)]) }
+                .and_then(|()| self.set_scanout(rect, scanout_id, resource.id));
+        if let Err(e) = result {
+            let _ = self.destroy_resource(resource);
+            return Err(e);
+        }
+        self.framebuffers[scanout_id as usize] = Some(Framebuffer {
+            rect,
+            format,
+            resource,
+            memory: FramebufferMemory::Borrowed(buf),
+            back: None,
+            blob: false,
+            enabled: true,
+        });
+        Ok(())
+    }
+
+    /// Blanks the given scanout, by detaching it from any resource.
+    ///
+    /// The scanout's frame buffer, if it has been set up, is kept, and is
+    /// displayed again by [`VirtIOGpu::enable_scanout`],
+    /// [`VirtIOGpu::setup_scanout`] or [`VirtIOGpu::present_scanout`].
+    pub fn disable_scanout(&mut self, scanout_id: u32) -> Result {
+        self.check_scanout(scanout_id)?;
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        };
+        self.set_scanout(rect, scanout_id, 0)?;
+        if let Some(framebuffer) = &mut self.framebuffers[scanout_id as usize] {
+            framebuffer.enabled = false;
+        }
+        Ok(())
+    }
+
+    /// Displays the frame buffer of the given scanout again, after it was
+    /// disabled with [`VirtIOGpu::disable_scanout`].
+    ///
+    /// Returns [`Error::NotReady`] if its frame buffer hasn't been set up.
+    pub fn enable_scanout(&mut self, scanout_id: u32) -> Result {
+        self.check_scanout(scanout_id)?;
+        let framebuffer = self.framebuffers[scanout_id as usize]
+            .as_ref()
+            .ok_or(Error::NotReady)?;
+        let (rect, format, resource_id) = (
+            framebuffer.rect,
+            framebuffer.format,
+            framebuffer.resource.id,
+        );
+        if framebuffer.blob {
+            let stride = rect.width * format.bytes_per_pixel();
+            self.set_scanout_blob(rect, scanout_id, resource_id, format, stride)?;
+        } else {
+            self.set_scanout(rect, scanout_id, resource_id)?;
+        }
+        self.framebuffers[scanout_id as usize]
+            .as_mut()
+            .unwrap()
+            .enabled = true;
+        Ok(())
+    }
+
+    /// Returns whether the frame buffer of the given scanout has been set up
+    /// and is being displayed, i.e. it hasn't been disabled.
+    pub fn is_scanout_enabled(&self, scanout_id: u32) -> bool {
+        self.framebuffers
+            .get(scanout_id as usize)
+            .and_then(Option::as_ref)
+            .is_some_and(|framebuffer| framebuffer.enabled)
+    }
+
+    /// Returns the pixel format of the frame buffer of the given scanout, or
+    /// `None` if it hasn't been set up.
+    pub fn scanout_format(&self, scanout_id: u32) -> Option<PixelFormat> {
+        self.framebuffers
+            .get(scanout_id as usize)?
+            .as_ref()
+            .map(|framebuffer| framebuffer.format)
+    }
+
+    /// Sets up a frame buffer for the given scanout as a blob resource of
+    /// `size` bytes, and returns it.
+    ///
+    /// The memory is shared with the host rather than copied, so flushing the
+    /// frame buffer doesn't transfer it with `TRANSFER_TO_HOST_2D`. Returns
+    /// [`Error::Unsupported`] if the device doesn't support
+    /// `VIRTIO_GPU_F_RESOURCE_BLOB`, [`Error::InvalidParam`] if `size` is too
+    /// small for the scanout's preferred resolution, and otherwise fails like
+    /// [`VirtIOGpu::setup_scanout`]. Blob frame buffers can't be double
+    /// buffered.
+    pub fn create_blob_scanout(&mut self, size: usize, scanout_id: u32) -> Result<&mut [u8]> {
+        self.check_blob()?;
+        self.check_scanout(scanout_id)?;
+        if self.framebuffers[scanout_id as usize].is_some() {
+            return Err(Error::AlreadyUsed);
+        }
+        let display_info = self.get_display_info()?;
+        let mode = display_info.pmodes[scanout_id as usize];
+        info!("scanout {} => {:?}", scanout_id, mode);
+        if mode.enabled == 0 || mode.rect.width == 0 || mode.rect.height == 0 {
+            return Err(Error::NotReady);
+        }
+        let rect = mode.rect;
+        let format = PixelFormat::B8G8R8A8UNORM;
+        let stride = rect.width * format.bytes_per_pixel();
+        let length = u32::try_from(size).map_err(|_| Error::InvalidParam)?;
+        if size < stride as usize * rect.height as usize {
+            return Err(Error::InvalidParam);
+        }
+        let dma = Dma::new(self.hal, pages(size), BufferDirection::DriverToDevice)?;
+        let resource = self.create_blob_resource(
+            0,
+            BLOB_MEM_GUEST,
+            BLOB_FLAG_USE_SHAREABLE,
+            0,
+            size as u64,
+            &[MemEntry::new(dma.paddr() as u64, length)],
+        )?;
+        if let Err(e) = self.set_scanout_blob(rect, scanout_id, resource.id, format, stride) {
+            let _ = self.destroy_resource(resource);
+            return Err(e);
+        }
+
+        let buf = unsafe { dma.raw_slice().as_mut() };
+        self.framebuffers[scanout_id as usize] = Some(Framebuffer {
+            rect,
+            format,
+            resource,
+            memory: FramebufferMemory::Owned(dma),
+            back: None,
+            blob: true,
+            enabled: true,
+        });
+        Ok(buf)
+    }
+
+    /// Returns [`Error::Unsupported`] if `VIRTIO_GPU_F_RESOURCE_BLOB` wasn't
+    /// negotiated.
+    fn check_blob(&self) -> Result {
+        if self.negotiated_features.contains(Features::RESOURCE_BLOB) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    /// Creates a blob resource with the given backing memory, which may be
+    /// empty for host memory.
+    fn create_blob_resource(
+        &mut self,
+        ctx_id: u32,
+        blob_mem: u32,
+        blob_flags: u32,
+        blob_id: u64,
+        size: u64,
+        entries: &[MemEntry],
+    ) -> Result<ResourceHandle> {
+        if size == 0 || size_of::<ResourceCreateBlob>() + size_of_val(entries) > PAGE_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let id = self.allocate_resource_id();
+        ResourceCreateBlob {
+            header: CtrlHeader::with_context(Command::RESOURCE_CREATE_BLOB, ctx_id),
+            resource_id: id,
+            blob_mem,
+            blob_flags,
+            nr_entries: entries.len() as u32,
+            blob_id,
+            size,
+        }
+        .write_to_prefix(&mut self.queue_buf_send)
+        .unwrap();
+        let start = size_of::<ResourceCreateBlob>();
+        let len = start + size_of_val(entries);
+        self.queue_buf_send[start..len].copy_from_slice(entries.as_bytes());
+        let rsp: CtrlHeader = self.request_prefix(len)?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.resources.push(ResourceInfo {
+            id,
+            min_backing_len: 0,
+            has_backing: !entries.is_empty(),
+            blob: Some(BlobInfo {
+                size,
+                mappable: blob_flags & BLOB_FLAG_USE_MAPPABLE != 0,
+                mapped: false,
+            }),
+        });
+        Ok(ResourceHandle { id })
+    }
+
+    /// Creates a resource the size of the given rectangle, with newly
+    /// allocated backing memory.
+    fn create_backed_resource(
+        &mut self,
+        hal: H,
+        rect: Rect,
+        format: PixelFormat,
+    ) -> Result<(ResourceHandle, Dma<H>)> {
+        // alloc continuous pages for the frame buffer
+        let size = rect.width as usize * rect.height as usize * format.bytes_per_pixel() as usize;
+        let dma = Dma::new(hal, pages(size), BufferDirection::DriverToDevice)?;
+
+        // create resource 2d
+        let resource = self.create_resource_2d(format, rect.width, rect.height)?;
+
+        // Safe because the DMA region is returned to be kept with the resource, which the caller
+        // destroys before dropping it.
+        if let Err(e) = unsafe { self.attach_backing(resource, &dma) } {
+            let _ = self.destroy_resource(resource);
+            return Err(e);
+        }
+        Ok((resource, dma))
+    }
+
+    /// Sets up double buffering for scanout 0.
+    ///
+    /// This is the same as [`VirtIOGpu::setup_scanout_double_buffering`] for
+    /// scanout 0.
+    pub fn setup_double_buffering(&mut self, hal: H) -> Result {
+        self.setup_scanout_double_buffering(hal, SCANOUT_ID)
+    }
+
+    /// Allocates a second frame buffer for the given scanout, to draw into
+    /// while the first is being displayed.
+    ///
+    /// The scanout's frame buffer is set up first if it hasn't been already.
+    /// Draw into [`VirtIOGpu::scanout_back_buffer`] and then show it with
+    /// [`VirtIOGpu::present_scanout`]. Returns [`Error::AlreadyUsed`] if the
+    /// scanout is already double buffered, or [`Error::Unsupported`] if its
+    /// frame buffer is a blob.
+    pub fn setup_scanout_double_buffering(&mut self, hal: H, scanout_id: u32) -> Result {
+        self.check_scanout(scanout_id)?;
+        if self.framebuffers[scanout_id as usize].is_none() {
+            self.setup_scanout(hal, scanout_id)?;
+        }
+        let framebuffer = self.framebuffers[scanout_id as usize].as_ref().unwrap();
+        if framebuffer.blob {
+            return Err(Error::Unsupported);
+        }
+        if framebuffer.back.is_some() {
+            return Err(Error::AlreadyUsed);
+        }
+        let (rect, format) = (framebuffer.rect, framebuffer.format);
+        let (resource, dma) = self.create_backed_resource(hal, rect, format)?;
+        let back = (resource, FramebufferMemory::Owned(dma));
+        self.framebuffers[scanout_id as usize]
+            .as_mut()
+            .unwrap()
+            .back = Some(back);
+        Ok(())
+    }
+
+    /// Returns the back buffer of scanout 0.
+    ///
+    /// This is the same as [`VirtIOGpu::scanout_back_buffer`] for scanout 0.
+    pub fn back_buffer(&mut self) -> Result<&mut [u8]> {
+        self.scanout_back_buffer(SCANOUT_ID)
+    }
+
+    /// Returns the frame buffer of the given scanout which isn't currently
+    /// being displayed, to draw the next frame into.
+    ///
+    /// After [`VirtIOGpu::present_scanout`] this is the buffer which was
+    /// displayed before, so it still holds the frame before last. Returns
+    /// [`Error::NotReady`] if double buffering hasn't been set up.
+    pub fn scanout_back_buffer(&mut self, scanout_id: u32) -> Result<&mut [u8]> {
+        self.check_scanout(scanout_id)?;
+        let (_, memory) = self.framebuffers[scanout_id as usize]
+            .as_ref()
+            .and_then(|framebuffer| framebuffer.back.as_ref())
+            .ok_or(Error::NotReady)?;
+        Ok(unsafe { memory.as_ptr().as_mut() })
+    }
+
+    /// Presents the back buffer of scanout 0.
+    ///
+    /// This is the same as [`VirtIOGpu::present_scanout`] for scanout 0.
+    pub fn present(&mut self) -> Result {
+        self.present_scanout(SCANOUT_ID)
+    }
+
+    /// Displays the back buffer of the given scanout, and makes the buffer
+    /// which was displayed the new back buffer.
+    ///
+    /// This waits for the device to respond to each command, so once it
+    /// returns the device has finished with the new back buffer and it may be
+    /// drawn into. Returns [`Error::NotReady`] if double buffering hasn't been
+    /// set up.
+    pub fn present_scanout(&mut self, scanout_id: u32) -> Result {
+        self.check_scanout(scanout_id)?;
+        let framebuffer = self.framebuffers[scanout_id as usize]
+            .as_ref()
+            .ok_or(Error::NotReady)?;
+        let rect = framebuffer.rect;
+        let (back_resource, _) = framebuffer.back.as_ref().ok_or(Error::NotReady)?;
+        let back_id = back_resource.id;
+        self.transfer_to_host_2d(rect, 0, back_id)?;
+        self.set_scanout(rect, scanout_id, back_id)?;
+        self.resource_flush(rect, back_id)?;
+
+        let framebuffer = self.framebuffers[scanout_id as usize].as_mut().unwrap();
+        framebuffer.enabled = true;
+        let (back_resource, back_memory) = framebuffer.back.as_mut().unwrap();
+        core::mem::swap(&mut framebuffer.resource, back_resource);
+        core::mem::swap(&mut framebuffer.memory, back_memory);
+        Ok(())
+    }
+
+    /// Flush framebuffer to screen.
+    ///
+    /// This is the same as [`VirtIOGpu::flush_scanout`] for scanout 0.
+    pub fn flush(&mut self) -> Result {
+        self.flush_scanout(SCANOUT_ID)
+    }
+
+    /// Flushes the frame buffer of the given scanout to the screen, without
+    /// touching any other scanouts.
+    ///
+    /// Returns [`Error::NotReady`] if its frame buffer hasn't been set up.
+    pub fn flush_scanout(&mut self, scanout_id: u32) -> Result {
+        self.check_scanout(scanout_id)?;
+        let framebuffer = self.framebuffers[scanout_id as usize]
+            .as_ref()
+            .ok_or(Error::NotReady)?;
+        let (rect, resource_id) = (framebuffer.rect, framebuffer.resource.id);
+        // copy data from guest to host, unless the host shares the memory
+        if !framebuffer.blob {
+            self.transfer_to_host_2d(rect, 0, resource_id)?;
+        }
+        // flush data to screen
+        self.resource_flush(rect, resource_id)?;
+        Ok(())
+    }
+
+    /// Creates a 2D resource on the device with the given format and size.
+    ///
+    /// The resource has no backing memory until
+    /// [`VirtIOGpu::attach_backing`] is called.
+    pub fn create_resource_2d(
+        &mut self,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<ResourceHandle> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let id = self.allocate_resource_id();
+        self.resource_create_2d(id, format, width, height)?;
+        self.resources.push(ResourceInfo {
+            id,
+            min_backing_len: width as usize * height as usize * format.bytes_per_pixel() as usize,
+            has_backing: false,
+            blob: None,
+        });
+        Ok(ResourceHandle { id })
+    }
+
+    /// Attaches the given DMA region as the backing memory of a resource.
+    ///
+    /// The region must be large enough for the whole resource. Returns
+    /// [`Error::InvalidParam`] if the resource has been destroyed or the region
+    /// is too small, or [`Error::AlreadyUsed`] if it already has backing
+    /// memory.
+    ///
+    /// # Safety
+    ///
+    /// The DMA region must not be dropped until the backing is detached with
+    /// [`VirtIOGpu::detach_backing`] or the resource is destroyed, as the
+    /// device may access it until then.
+    pub unsafe fn attach_backing(&mut self, resource: ResourceHandle, mem: &Dma<H>) -> Result {
+        let length = u32::try_from(mem.raw_slice().len()).map_err(|_| Error::InvalidParam)?;
+        // Safe because our caller promises the same things as we require.
+        unsafe {
+            self.attach_backing_entries(resource, &[MemEntry::new(mem.paddr() as u64, length)])
+        }
+    }
+
+    /// Attaches the given list of physical memory regions as the backing
+    /// memory of a resource, so that it needn't be physically contiguous.
+    ///
+    /// The device treats the regions as one buffer, in order, so transfers
+    /// work the same as with a single region. Their total length must be
+    /// large enough for the whole resource. Returns [`Error::InvalidParam`] if
+    /// the resource has been destroyed, the regions are too small, or there are
+    /// more than [`MAX_MEM_ENTRIES`] of them, or [`Error::AlreadyUsed`] if it
+    /// already has backing memory.
+    ///
+    /// # Safety
+    ///
+    /// The regions must be valid memory which the device may access, and must
+    /// not be freed or reused until the backing is detached with
+    /// [`VirtIOGpu::detach_backing`] or the resource is destroyed.
+    pub unsafe fn attach_backing_entries(
+        &mut self,
+        resource: ResourceHandle,
+        entries: &[MemEntry],
+    ) -> Result {
+        let info = self.resource_info(resource)?;
+        if info.has_backing {
+            return Err(Error::AlreadyUsed);
+        }
+        let length: usize = entries.iter().map(|entry| entry.length as usize).sum();
+        if entries.is_empty() || entries.len() > MAX_MEM_ENTRIES || length < info.min_backing_len {
+            return Err(Error::InvalidParam);
+        }
+        self.resource_attach_backing(resource.id, entries)?;
+        self.resource_info_mut(resource)?.has_backing = true;
+        Ok(())
+    }
+
+    /// Detaches the backing memory of a resource, after which its DMA region
+    /// may be freed.
+    ///
+    /// Returns [`Error::InvalidParam`] if the resource has been destroyed, or
+    /// [`Error::NotReady`] if it has no backing memory.
+    pub fn detach_backing(&mut self, resource: ResourceHandle) -> Result {
+        if !self.resource_info(resource)?.has_backing {
+            return Err(Error::NotReady);
+        }
+        self.resource_detach_backing(resource.id)?;
+        self.resource_info_mut(resource)?.has_backing = false;
+        Ok(())
+    }
+
+    /// Destroys a resource, detaching its backing memory if it has any and
+    /// unmapping it if it is a mapped blob.
+    ///
+    /// Returns [`Error::InvalidParam`] if the resource has already been
+    /// destroyed.
+    pub fn destroy_resource(&mut self, resource: ResourceHandle) -> Result {
+        let index = self
+            .resources
+            .iter()
+            .position(|info| info.id == resource.id)
+            .ok_or(Error::InvalidParam)?;
+        if self.resources[index].blob.is_some_and(|blob| blob.mapped) {
+            self.resource_unmap_blob(resource.id)?;
+        }
+        self.resource_unref(resource.id)?;
+        self.resources.swap_remove(index);
+        Ok(())
+    }
+
+    /// Returns the tracked state of the given resource, or
+    /// [`Error::InvalidParam`] if it has been destroyed.
+    fn resource_info(&self, resource: ResourceHandle) -> Result<&ResourceInfo> {
+        self.resources
+            .iter()
+            .find(|info| info.id == resource.id)
+            .ok_or(Error::InvalidParam)
+    }
+
+    fn resource_info_mut(&mut self, resource: ResourceHandle) -> Result<&mut ResourceInfo> {
+        self.resources
+            .iter_mut()
+            .find(|info| info.id == resource.id)
+            .ok_or(Error::InvalidParam)
+    }
+
+    /// Picks a resource ID which isn't in use.
+    fn allocate_resource_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_resource_id;
+            self.next_resource_id = self.next_resource_id.wrapping_add(1);
+            if id != 0
+                && id != RESOURCE_ID_CURSOR
+                && !self.resources.iter().any(|info| info.id == id)
+            {
+                return id;
+            }
+        }
+    }
+
+    /// Flushes only the given rectangle of the frame buffer of scanout 0 to
+    /// the screen.
+    ///
+    /// This is the same as [`VirtIOGpu::flush_scanout_region`] for scanout 0.
+    pub fn flush_region(&mut self, rect: Rect) -> Result {
+        self.flush_scanout_region(SCANOUT_ID, rect)
+    }
+
+    /// Flushes only the given rectangle of the frame buffer of a scanout to the
+    /// screen, so that small updates don't transfer the whole frame buffer.
+    ///
+    /// The rectangle is clipped to the frame buffer. Returns
+    /// [`Error::InvalidParam`] if it is empty or entirely outside the frame
+    /// buffer, or [`Error::NotReady`] if the frame buffer hasn't been set up.
+    pub fn flush_scanout_region(&mut self, scanout_id: u32, rect: Rect) -> Result {
+        self.check_scanout(scanout_id)?;
+        let framebuffer = self.framebuffers[scanout_id as usize]
+            .as_ref()
+            .ok_or(Error::NotReady)?;
+        let (fb_rect, resource_id) = (framebuffer.rect, framebuffer.resource.id);
+        let bytes_per_pixel = framebuffer.format.bytes_per_pixel();
+        let blob = framebuffer.blob;
+        let rect = clip_rect(rect, fb_rect.width, fb_rect.height).ok_or(Error::InvalidParam)?;
+        if !blob {
+            // The offset of the rectangle's first pixel in the backing memory.
+            let offset = (u64::from(rect.y) * u64::from(fb_rect.width) + u64::from(rect.x))
+                * u64::from(bytes_per_pixel);
+            self.transfer_to_host_2d(rect, offset, resource_id)?;
+        }
+        self.resource_flush(rect, resource_id)?;
+        Ok(())
+    }
+
+    /// Returns the resource which is currently displayed on the given scanout,
+    /// or `None` if its frame buffer hasn't been set up.
+    pub fn scanout_resource(&self, scanout_id: u32) -> Option<ResourceHandle> {
+        self.framebuffers
+            .get(scanout_id as usize)?
+            .as_ref()
+            .map(|framebuffer| framebuffer.resource)
+    }
+
+    /// Starts copying the given rectangle of a resource's backing memory to
+    /// the host, without waiting for it to finish, and returns the ID of a
+    /// fence which is signalled once it has.
+    ///
+    /// If too many fenced commands are already in flight, this first waits for
+    /// one of them to finish.
+    pub fn transfer_to_host_2d_fenced(
+        &mut self,
+        resource: ResourceHandle,
+        rect: Rect,
+        offset: u64,
+    ) -> Result<u64> {
+        self.resource_info(resource)?;
+        self.request_fenced(Command::TRANSFER_TO_HOST_2D, |header| TransferToHost2D {
+            header,
+            rect,
+            offset,
+            resource_id: resource.id,
+            _padding: 0,
+        })
+    }
+
+    /// Starts flushing the given rectangle of a resource to the screen,
+    /// without waiting for it to finish, and returns the ID of a fence which is
+    /// signalled once it has.
+    ///
+    /// If too many fenced commands are already in flight, this first waits for
+    /// one of them to finish.
+    pub fn resource_flush_fenced(&mut self, resource: ResourceHandle, rect: Rect) -> Result<u64> {
+        self.resource_info(resource)?;
+        self.request_fenced(Command::RESOURCE_FLUSH, |header| ResourceFlush {
+            header,
+            rect,
+            resource_id: resource.id,
+            _padding: 0,
+        })
+    }
+
+    /// Reaps the responses to any fenced commands which the device has
+    /// finished, and returns how many there were.
+    ///
+    /// A command which the device reports as failed is logged, and its fence
+    /// is still signalled. The result of each command is kept for
+    /// [`VirtIOGpu::fence_result`] until enough later fenced commands have
+    /// been started to need its buffers again. Any finished commands of a submitted batch are
+    /// reaped too, for [`VirtIOGpu::wait_batch`] to return.
+    pub fn poll_fences(&mut self) -> Result<usize> {
+        let mut count = 0;
+        while let Some(token) = self.control_queue.peek_used() {
+            let Some(slot) = self
+                .fence_slots
+                .iter_mut()
+                .find(|slot| slot.pending.is_some_and(|pending| pending.token == token))
+            else {
+                if self.pop_batch_command(token)? {
+                    continue;
+                }
+                break;
+            };
+            let pending = slot.pending.unwrap();
+            // Safe because these are the same buffers as were passed to `add` with the token, and
+            // they haven't been accessed since.
+            unsafe {
+                self.control_queue.pop_used(
+                    token,
+                    &[&slot.send[..pending.len]],
+                    &mut [&mut slot.recv],
+                )?;
+            }
+            slot.pending = None;
+            let rsp = CtrlHeader::read_from_prefix(&slot.recv).unwrap();
+            let result = rsp.check_type(Command::OK_NODATA);
+            if let Err(e) = result {
+                warn!("Fenced command {} failed: {}", pending.fence_id, e);
+            }
+            slot.completed = Some((pending.fence_id, result));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns whether the given fence has been signalled, as of the last call
+    /// to [`VirtIOGpu::poll_fences`].
+    pub fn is_fence_signalled(&self, fence_id: u64) -> bool {
+        fence_id < self.next_fence_id
+            && !self.fence_slots.iter().any(|slot| {
+                slot.pending
+                    .is_some_and(|pending| pending.fence_id == fence_id)
+            })
+    }
+
+    /// Returns the result of the command which was given the fence, as of the
+    /// last call to [`VirtIOGpu::poll_fences`].
+    ///
+    /// Returns `None` if the fence hasn't been signalled yet, or if its result
+    /// is no longer kept because its buffers have been used for a later fenced
+    /// command. The buffers of the oldest results are used first.
+    pub fn fence_result(&self, fence_id: u64) -> Option<Result> {
+        self.fence_slots.iter().find_map(|slot| {
+            slot.completed
+                .filter(|&(id, _)| id == fence_id)
+                .map(|(_, result)| result)
+        })
+    }
+
+    /// Blocks until the given fence has been signalled, and returns the result
+    /// of the command which was given it.
+    ///
+    /// Returns [`Error::InvalidParam`] if no command has been given the fence,
+    /// or if it was signalled so long ago that its result is no longer kept,
+    /// as described for [`VirtIOGpu::fence_result`].
+    pub fn wait_fence(&mut self, fence_id: u64) -> Result {
+        if fence_id == 0 || fence_id >= self.next_fence_id {
+            return Err(Error::InvalidParam);
+        }
+        loop {
+            self.poll_fences()?;
+            if self.is_fence_signalled(fence_id) {
+                return self
+                    .fence_result(fence_id)
+                    .unwrap_or(Err(Error::InvalidParam));
+            }
+            spin_loop();
+        }
+    }
+
+    /// Returns [`Error::InvalidParam`] if the device doesn't have the given
+    /// scanout.
+    fn check_scanout(&self, scanout_id: u32) -> Result {
+        if scanout_id < self.num_scanouts {
+            Ok(())
+        } else {
+            Err(Error::InvalidParam)
+        }
+    }
+
+    /// Set the pointer shape and position.
+    ///
+    /// This is the same as [`VirtIOGpu::create_cursor`] followed by
+    /// [`VirtIOGpu::update_cursor`] on scanout 0.
+    pub fn setup_cursor(
+        &mut self,
+        hal: H,
+        cursor_image: &[u8],
+        pos_x: u32,
+        pos_y: u32,
+        hot_x: u32,
+        hot_y: u32,
+    ) -> Result {
+        let cursor = self.create_cursor(hal, cursor_image)?;
+        self.update_cursor(Some(cursor), SCANOUT_ID, (pos_x, pos_y), (hot_x, hot_y))
+    }
+
+    /// Uploads the given 64x64 ARGB image as the cursor resource, and returns
+    /// a handle to pass to [`VirtIOGpu::update_cursor`].
+    ///
+    /// The resource is created the first time; later calls replace its image,
+    /// which takes effect on the next call to `update_cursor`.
+    pub fn create_cursor(&mut self, hal: H, cursor_image: &[u8]) -> Result<CursorResource> {
+        let size = CURSOR_RECT.width * CURSOR_RECT.height * 4;
+        if cursor_image.len() != size as usize {
+            return Err(Error::InvalidParam);
+        }
+        if self.cursor_buffer_dma.is_none() {
+            let cursor_buffer_dma =
+                Dma::new(hal, pages(size as usize), BufferDirection::DriverToDevice)?;
+            self.resource_create_2d(
+                RESOURCE_ID_CURSOR,
+                PixelFormat::B8G8R8A8UNORM,
+                CURSOR_RECT.width,
+                CURSOR_RECT.height,
+            )?;
+            self.resource_attach_backing(
+                RESOURCE_ID_CURSOR,
+                &[MemEntry::new(cursor_buffer_dma.paddr() as u64, size)],
+            )?;
+            self.cursor_buffer_dma = Some(cursor_buffer_dma);
+        }
+        let cursor_buffer_dma = self.cursor_buffer_dma.as_ref().unwrap();
+        let buf = unsafe { cursor_buffer_dma.raw_slice().as_mut() };
+        buf[..cursor_image.len()].copy_from_slice(cursor_image);
+        self.transfer_to_host_2d(CURSOR_RECT, 0, RESOURCE_ID_CURSOR)?;
+        Ok(CursorResource {
+            resource_id: RESOURCE_ID_CURSOR,
+        })
+    }
+
+    /// Sets the pointer shape and position on the given scanout, or hides the
+    /// pointer if `resource` is `None`.
+    ///
+    /// `hotspot` is the point within the cursor image which is placed at `pos`.
+    pub fn update_cursor(
+        &mut self,
+        resource: Option<CursorResource>,
+        scanout_id: u32,
+        pos: (u32, u32),
+        hotspot: (u32, u32),
+    ) -> Result {
+        self.check_scanout(scanout_id)?;
+        let resource_id = resource.map_or(0, |resource| resource.resource_id);
+        self.cursor_command(
+            Command::UPDATE_CURSOR,
+            resource_id,
+            scanout_id,
+            pos,
+            hotspot,
+        )
+    }
+
+    /// Move the pointer on the given scanout without updating the shape.
+    pub fn move_cursor(&mut self, scanout_id: u32, pos: (u32, u32)) -> Result {
+        self.check_scanout(scanout_id)?;
+        self.cursor_command(
+            Command::MOVE_CURSOR,
+            RESOURCE_ID_CURSOR,
+            scanout_id,
+            pos,
+            (0, 0),
+        )
+    }
+
+    /// Send a request to the device and block for a response.
+    fn request<Req: AsBytes, Rsp: FromBytes>(&mut self, req: Req) -> Result<Rsp> {
+        req.write_to_prefix(&mut self.queue_buf_send).unwrap();
+        self.control_queue.add_notify_wait_pop(
+            &[&self.queue_buf_send],
+            &mut [&mut self.queue_buf_recv],
+            &mut self.transport,
+        )?;
+        Ok(Rsp::read_from_prefix(&self.queue_buf_recv).unwrap())
+    }
+
+    /// Send a request to the device and block for a response, which is split
+    /// between the response header and `out`.
+    ///
+    /// Returns the header and the total length of the response.
+    fn request_into<Req: AsBytes>(
+        &mut self,
+        req: Req,
+        out: &mut [u8],
+    ) -> Result<(CtrlHeader, usize)> {
+        req.write_to_prefix(&mut self.queue_buf_send).unwrap();
+        let len = self.control_queue.add_notify_wait_pop(
+            &[&self.queue_buf_send[..size_of::<Req>()]],
+            &mut [&mut self.queue_buf_recv[..OK_RESPONSE_HEADER_LEN], out],
+            &mut self.transport,
+        )?;
+        let rsp = CtrlHeader::read_from_prefix(&self.queue_buf_recv).unwrap();
+        Ok((rsp, len as usize))
+    }
+
+    /// Send the first `len` bytes of the send buffer as a request to the device
+    /// and block for a response.
+    fn request_prefix<Rsp: FromBytes>(&mut self, len: usize) -> Result<Rsp> {
+        self.control_queue.add_notify_wait_pop(
+            &[&self.queue_buf_send[..len]],
+            &mut [&mut self.queue_buf_recv],
+            &mut self.transport,
+        )?;
+        Ok(Rsp::read_from_prefix(&self.queue_buf_recv).unwrap())
+    }
+
+    /// Send a request with the fence flag and a new fence ID to the device,
+    /// without waiting for a response, and return the fence ID.
+    ///
+    /// `make_req` is given the header to put in the request.
+    fn request_fenced<Req: AsBytes>(
+        &mut self,
+        command: Command,
+        make_req: impl FnOnce(CtrlHeader) -> Req,
+    ) -> Result<u64> {
+        let index = loop {
+            // Reuse the slot whose result is the oldest, so that results are
+            // kept for as long as possible.
+            if let Some((index, _)) = self
+                .fence_slots
+                .iter()
+                .enumerate()
+                .filter(|(_, slot)| slot.pending.is_none())
+                .min_by_key(|(_, slot)| slot.completed.map_or(0, |(fence_id, _)| fence_id))
+            {
+                break index;
+            }
+            if self.poll_fences()? == 0 {
+                spin_loop();
+            }
+        };
+        let fence_id = self.next_fence_id;
+        let req = make_req(CtrlHeader {
+            flags: GPU_FLAG_FENCE,
+            fence_id,
+            ..CtrlHeader::with_type(command)
+        });
+        let len = size_of::<Req>();
+        let slot = &mut self.fence_slots[index];
+        req.write_to_prefix(&mut slot.send[..]).unwrap();
+        // Safe because the buffers are in `fence_slots`, which lives as long as the queue, and
+        // aren't accessed again until the token is popped by `poll_fences`.
+        let token = unsafe {
+            self.control_queue
+                .add(&[&slot.send[..len]], &mut [&mut slot.recv])?
+        };
+        if self.control_queue.should_notify() {
+            self.transport.notify(QUEUE_TRANSMIT);
+        }
+        slot.pending = Some(PendingFence {
+            token,
+            fence_id,
+            len,
+        });
+        self.next_fence_id += 1;
+        Ok(fence_id)
+    }
+
+    /// Send a mouse cursor operation request to the device without waiting for
+    /// it to be used.
+    ///
+    /// Cursor commands have no response, so only one is kept in flight: if the
+    /// device hasn't yet used the previous one, this waits for it first.
+    fn cursor_request<Req: AsBytes>(&mut self, req: Req) -> Result {
+        if let Some(token) = self.cursor_token.take() {
+            while !self.cursor_queue.can_pop() {
+                spin_loop();
+            }
+            // Safe because this is the same buffer as was passed to `add` with the token, and it
+            // hasn't been accessed since.
+            unsafe {
+                self.cursor_queue
+                    .pop_used(token, &[&self.cursor_buf_send], &mut [])?;
+            }
+        }
+        req.write_to_prefix(&mut self.cursor_buf_send).unwrap();
+        // Safe because the buffer lives as long as the queue, and isn't accessed again until the
+        // token is popped above.
+        let token = unsafe { self.cursor_queue.add(&[&self.cursor_buf_send], &mut [])? };
+        if self.cursor_queue.should_notify() {
+            self.transport.notify(QUEUE_CURSOR);
+        }
+        self.cursor_token = Some(token);
+        Ok(())
+    }
+
+    fn get_display_info(&mut self) -> Result<RespDisplayInfo> {
+        let info: RespDisplayInfo =
+            self.request(CtrlHeader::with_type(Command::GET_DISPLAY_INFO))?;
+        info.header.check_type(Command::OK_DISPLAY_INFO)?;
+        Ok(info)
+    }
+
+    fn resource_create_2d(
+        &mut self,
+        resource_id: u32,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+    ) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceCreate2D {
+            header: CtrlHeader::with_type(Command::RESOURCE_CREATE_2D),
+            resource_id,
+            format,
+            width,
+            height,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn set_scanout(&mut self, rect: Rect, scanout_id: u32, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(SetScanout {
+            header: CtrlHeader::with_type(Command::SET_SCANOUT),
+            rect,
+            scanout_id,
+            resource_id,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_flush(&mut self, rect: Rect, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceFlush {
+            header: CtrlHeader::with_type(Command::RESOURCE_FLUSH),
+            rect,
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn transfer_to_host_2d(&mut self, rect: Rect, offset: u64, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(TransferToHost2D {
+            header: CtrlHeader::with_type(Command::TRANSFER_TO_HOST_2D),
+            rect,
+            offset,
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    /// Sends RESOURCE_ATTACH_BACKING with the given entries, which must fit in
+    /// the send buffer after the header.
+    fn resource_attach_backing(&mut self, resource_id: u32, entries: &[MemEntry]) -> Result {
+        ResourceAttachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_ATTACH_BACKING),
+            resource_id,
+            nr_entries: entries.len() as u32,
+        }
+        .write_to_prefix(&mut self.queue_buf_send)
+        .unwrap();
+        let start = size_of::<ResourceAttachBacking>();
+        let len = start + size_of_val(entries);
+        self.queue_buf_send[start..len].copy_from_slice(entries.as_bytes());
+        let rsp: CtrlHeader = self.request_prefix(len)?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_detach_backing(&mut self, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceDetachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_DETACH_BACKING),
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn set_scanout_blob(
+        &mut self,
+        rect: Rect,
+        scanout_id: u32,
+        resource_id: u32,
+        format: PixelFormat,
+        stride: u32,
+    ) -> Result {
+        let rsp: CtrlHeader = self.request(SetScanoutBlob {
+            header: CtrlHeader::with_type(Command::SET_SCANOUT_BLOB),
+            rect,
+            scanout_id,
+            resource_id,
+            width: rect.width,
+            height: rect.height,
+            format,
+            _padding: 0,
+            strides: [stride, 0, 0, 0],
+            offsets: [0; 4],
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_unmap_blob(&mut self, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceUnmapBlob {
+            header: CtrlHeader::with_type(Command::RESOURCE_UNMAP_BLOB),
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_unref(&mut self, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceUnref {
+            header: CtrlHeader::with_type(Command::RESOURCE_UNREF),
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn cursor_command(
+        &mut self,
+        command: Command,
+        resource_id: u32,
+        scanout_id: u32,
+        (x, y): (u32, u32),
+        (hot_x, hot_y): (u32, u32),
+    ) -> Result {
+        self.cursor_request(UpdateCursor {
+            header: CtrlHeader::with_type(command),
+            pos: CursorPos {
+                scanout_id,
+                x,
+                y,
+                _padding: 0,
+            },
+            resource_id,
+            hot_x,
+            hot_y,
+            _padding: 0,
+        })
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOGpu<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.queue_unset(QUEUE_TRANSMIT);
+        self.transport.queue_unset(QUEUE_CURSOR);
+    }
+}
+
+#[repr(C)]
+struct Config {
+    /// Signals pending events to the driver。
+    events_read: ReadOnly<u32>,
+
+    /// Clears pending events in the device.
+    events_clear: WriteOnly<u32>,
+
+    /// Specifies the maximum number of scanouts supported by the device.
+    ///
+    /// Minimum value is 1, maximum value is 16.
+    num_scanouts: Volatile<u32>,
+
+    /// Specifies the maximum number of capability sets supported by the
+    /// device.
+    num_capsets: ReadOnly<u32>,
+}
+
+/// Display configuration has changed.
+const EVENT_DISPLAY: u32 = 1 << 0;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct Features: u64 {
+        /// virgl 3D mode is supported.
+        const VIRGL                 = 1 << 0;
+        /// EDID is supported.
+        const EDID                  = 1 << 1;
+        /// Assigning resources UUIDs for export to other virtio devices is
+        /// supported.
+        const RESOURCE_UUID         = 1 << 2;
+        /// Creating and using size-based blob resources is supported.
+        const RESOURCE_BLOB         = 1 << 3;
+        /// Multiple context types and synchronization timelines are supported.
+        const CONTEXT_INIT          = 1 << 4;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq, FromBytes, FromZeroes)]
+struct Command(u32);
+
+impl Command {
+    const GET_DISPLAY_INFO: Command = Command(0x100);
+    const RESOURCE_CREATE_2D: Command = Command(0x101);
+    const RESOURCE_UNREF: Command = Command(0x102);
+    const SET_SCANOUT: Command = Command(0x103);
+    const RESOURCE_FLUSH: Command = Command(0x104);
+    const TRANSFER_TO_HOST_2D: Command = Command(0x105);
+    const RESOURCE_ATTACH_BACKING: Command = Command(0x106);
+    const RESOURCE_DETACH_BACKING: Command = Command(0x107);
+    const GET_CAPSET_INFO: Command = Command(0x108);
+    const GET_CAPSET: Command = Command(0x109);
+    const GET_EDID: Command = Command(0x10a);
+    const RESOURCE_ASSIGN_UUID: Command = Command(0x10b);
+    const RESOURCE_CREATE_BLOB: Command = Command(0x10c);
+    const SET_SCANOUT_BLOB: Command = Command(0x10d);
+
+    const CTX_CREATE: Command = Command(0x200);
+    const CTX_DESTROY: Command = Command(0x201);
+    const CTX_ATTACH_RESOURCE: Command = Command(0x202);
+    const CTX_DETACH_RESOURCE: Command = Command(0x203);
+    const RESOURCE_CREATE_3D: Command = Command(0x204);
+    const TRANSFER_TO_HOST_3D: Command = Command(0x205);
+    const TRANSFER_FROM_HOST_3D: Command = Command(0x206);
+    const SUBMIT_3D: Command = Command(0x207);
+    const RESOURCE_MAP_BLOB: Command = Command(0x208);
+    const RESOURCE_UNMAP_BLOB: Command = Command(0x209);
+
+    const UPDATE_CURSOR: Command = Command(0x300);
+    const MOVE_CURSOR: Command = Command(0x301);
+
+    const OK_NODATA: Command = Command(0x1100);
+    const OK_DISPLAY_INFO: Command = Command(0x1101);
+    const OK_CAPSET_INFO: Command = Command(0x1102);
+    const OK_CAPSET: Command = Command(0x1103);
+    const OK_EDID: Command = Command(0x1104);
+    const OK_RESOURCE_UUID: Command = Command(0x1105);
+    const OK_MAP_INFO: Command = Command(0x1106);
+
+    const ERR_UNSPEC: Command = Command(0x1200);
+    const ERR_OUT_OF_MEMORY: Command = Command(0x1201);
+    const ERR_INVALID_SCANOUT_ID: Command = Command(0x1202);
+    const ERR_INVALID_RESOURCE_ID: Command = Command(0x1203);
+    const ERR_INVALID_CONTEXT_ID: Command = Command(0x1204);
+    const ERR_INVALID_PARAMETER: Command = Command(0x1205);
+}
+
+const GPU_FLAG_FENCE: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Clone, Copy, FromBytes, FromZeroes)]
+struct CtrlHeader {
+    hdr_type: Command,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    _padding: u32,
+}
+
+impl CtrlHeader {
+    fn with_type(hdr_type: Command) -> CtrlHeader {
+        CtrlHeader {
+            hdr_type,
+            flags: 0,
+            fence_id: 0,
+            ctx_id: 0,
+            _padding: 0,
+        }
+    }
+
+    /// Returns a header for a command in the given 3D context.
+    fn with_context(hdr_type: Command, ctx_id: u32) -> CtrlHeader {
+        CtrlHeader {
+            ctx_id,
+            ..CtrlHeader::with_type(hdr_type)
+        }
+    }
+
+    /// Return error if the type is not same as expected.
+    ///
+    /// Error responses from the device are returned as the corresponding
+    /// [`GpuError`].
+    fn check_type(&self, expected: Command) -> Result {
+        if self.hdr_type == expected {
+            return Ok(());
+        }
+        let error = match self.hdr_type {
+            Command::ERR_UNSPEC => GpuError::Unspec,
+            Command::ERR_OUT_OF_MEMORY => GpuError::OutOfMemory,
+            Command::ERR_INVALID_SCANOUT_ID => GpuError::InvalidScanoutId,
+            Command::ERR_INVALID_RESOURCE_ID => GpuError::InvalidResourceId,
+            Command::ERR_INVALID_CONTEXT_ID => GpuError::InvalidContextId,
+            Command::ERR_INVALID_PARAMETER => GpuError::InvalidParameter,
+            _ => return Err(Error::IoError),
+        };
+        Err(error.into())
+    }
+}
+
+/// A rectangle on a scanout or within a resource, in pixels.
+#[repr(C)]
+#[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct Rect {
+    /// The left edge.
+    pub x: u32,
+    /// The top edge.
+    pub y: u32,
+    /// The width.
+    pub width: u32,
+    /// The height.
+    pub height: u32,
+}
+
+/// Clips the given rectangle to a resource of the given size, or returns
+/// `None` if nothing is left.
+fn clip_rect(rect: Rect, width: u32, height: u32) -> Option<Rect> {
+    if rect.width == 0 || rect.height == 0 || rect.x >= width || rect.y >= height {
+        return None;
+    }
+    Some(Rect {
+        x: rect.x,
+        y: rect.y,
+        width: rect.width.min(width - rect.x),
+        height: rect.height.min(height - rect.y),
+    })
+}
+
+/// Information about a capability set, as returned by
+/// [`VirtIOGpu::capset_info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CapsetInfo {
+    /// The ID of the capability set, e.g. 1 for virgl, 2 for virgl2, 3 for
+    /// gfxstream, 4 for venus or 5 for cross-domain.
+    pub id: u32,
+    /// The latest version of the capability set which the device supports.
+    pub max_version: u32,
+    /// The largest size of any version of the capability set, in bytes.
+    pub max_size: u32,
+}
+
+/// An error response from a GPU device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GpuError {
+    /// The device failed the command without saying why.
+    Unspec,
+    /// The device ran out of memory. The command may succeed if it is retried
+    /// after freeing some resources.
+    OutOfMemory,
+    /// The command referred to a scanout which the device doesn't have.
+    InvalidScanoutId,
+    /// The command referred to a resource which doesn't exist.
+    InvalidResourceId,
+    /// The command referred to a 3D context which doesn't exist.
+    InvalidContextId,
+    /// A parameter of the command was invalid.
+    InvalidParameter,
+}
+
+impl Display for GpuError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Unspec => write!(f, "Unspecified error"),
+            Self::OutOfMemory => write!(f, "Device out of memory"),
+            Self::InvalidScanoutId => write!(f, "Invalid scanout ID"),
+            Self::InvalidResourceId => write!(f, "Invalid resource ID"),
+            Self::InvalidContextId => write!(f, "Invalid context ID"),
+            Self::InvalidParameter => write!(f, "Invalid parameter"),
+        }
+    }
+}
+
+/// An event reported by a GPU device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GpuEvent {
+    /// A display has been connected, disconnected or resized, so the
+    /// configuration of the scanouts should be fetched again.
+    DisplayChanged,
+}
+
+/// The configuration of a scanout, as returned by [`VirtIOGpu::scanouts`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScanoutInfo {
+    /// The index of the scanout.
+    pub id: u32,
+    /// Whether a display is connected to the scanout.
+    pub enabled: bool,
+    /// The preferred position and size of the scanout.
+    pub rect: Rect,
+    /// Flags reported by the device, currently unused.
+    pub flags: u32,
+}
+
+/// The configuration of a scanout and its display, as returned by
+/// [`VirtIOGpu::display_info`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DisplayInfo {
+    /// The index of the scanout.
+    pub id: u32,
+    /// Whether a display is connected to the scanout.
+    pub enabled: bool,
+    /// The preferred position and size of the scanout.
+    pub rect: Rect,
+    /// The width of the display's image in millimetres, if known.
+    pub width_mm: Option<u32>,
+    /// The height of the display's image in millimetres, if known.
+    pub height_mm: Option<u32>,
+}
+
+/// The raw EDID of a display, as returned by [`VirtIOGpu::edid`].
+#[derive(Clone)]
+pub struct EdidBlob {
+    data: [u8; EDID_MAX_SIZE],
+    len: usize,
+}
+
+impl EdidBlob {
+    /// Returns the EDID bytes which the device provided.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Returns the width and height of the display's image in millimetres,
+    /// from the first detailed timing descriptor, or failing that from the
+    /// basic display parameters in centimetres.
+    ///
+    /// Returns `None` if the EDID is invalid or doesn't give a size, e.g.
+    /// because it is a projector.
+    pub fn physical_size_mm(&self) -> Option<(u32, u32)> {
+        edid_physical_size_mm(self.as_bytes())
+    }
+}
+
+/// The fixed header at the start of every EDID.
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+/// The offset of the first detailed timing descriptor in an EDID.
+const EDID_DETAILED_TIMING_OFFSET: usize = 54;
+/// The length of the base EDID block.
+const EDID_BLOCK_LEN: usize = 128;
+
+/// Parses the physical size of a display from its EDID.
+fn edid_physical_size_mm(edid: &[u8]) -> Option<(u32, u32)> {
+    if edid.len() < EDID_BLOCK_LEN || edid[..EDID_HEADER.len()] != EDID_HEADER {
+        return None;
+    }
+    let descriptor = &edid[EDID_DETAILED_TIMING_OFFSET..EDID_DETAILED_TIMING_OFFSET + 18];
+    // A descriptor with a pixel clock of 0 is a display descriptor rather than a timing.
+    if descriptor[0] != 0 || descriptor[1] != 0 {
+        let width = u32::from(descriptor[12]) | u32::from(descriptor[14] >> 4) << 8;
+        let height = u32::from(descriptor[13]) | u32::from(descriptor[14] & 0xf) << 8;
+        if width != 0 && height != 0 {
+            return Some((width, height));
+        }
+    }
+    // The basic display parameters give the size in centimetres.
+    let (width_cm, height_cm) = (u32::from(edid[21]), u32::from(edid[22]));
+    if width_cm != 0 && height_cm != 0 {
+        Some((width_cm * 10, height_cm * 10))
+    } else {
+        None
+    }
+}
+
+impl core::fmt::Debug for EdidBlob {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("EdidBlob")
+            .field("data", &self.as_bytes())
+            .finish()
+    }
+}
+
+/// A handle to a cursor image which has been uploaded to the device with
+/// [`VirtIOGpu::create_cursor`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CursorResource {
+    resource_id: u32,
+}
+
+/// The buffers for one fenced command.
+struct FenceSlot {
+    /// The request, which the device may still be reading.
+    send: [u8; FENCE_REQUEST_SIZE],
+    /// The response, which the device may still be writing.
+    recv: [u8; size_of::<CtrlHeader>()],
+    /// The command which is in flight, if any.
+    pending: Option<PendingFence>,
+    /// The fence ID and result of the last command which finished.
+    completed: Option<(u64, Result)>,
+}
+
+impl FenceSlot {
+    const EMPTY: Self = Self {
+        send: [0; FENCE_REQUEST_SIZE],
+        recv: [0; size_of::<CtrlHeader>()],
+        pending: None,
+        completed: None,
+    };
+}
+
+/// A fenced command which the device hasn't yet finished.
+#[derive(Clone, Copy, Debug)]
+struct PendingFence {
+    token: u16,
+    fence_id: u64,
+    /// The length of the request in the slot's send buffer.
+    len: usize,
+}
+
+/// A handle to a 2D resource created with [`VirtIOGpu::create_resource_2d`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ResourceHandle {
+    id: u32,
+}
+
+/// The state of a resource, as tracked by the driver.
+#[derive(Debug)]
+struct ResourceInfo {
+    id: u32,
+    /// The smallest backing memory which covers the whole resource, if the
+    /// driver knows it.
+    min_backing_len: usize,
+    has_backing: bool,
+    /// The state of the resource if it is a blob.
+    blob: Option<BlobInfo>,
+}
+
+/// The state of a blob resource, as tracked by the driver.
+#[derive(Clone, Copy, Debug)]
+struct BlobInfo {
+    size: u64,
+    /// Whether the blob was created with `VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE`.
+    mappable: bool,
+    /// Whether the blob is currently mapped into the host-visible shared
+    /// memory region.
+    mapped: bool,
+}
+
+/// A frame buffer which has been set up for a scanout.
+struct Framebuffer<H: Hal> {
+    rect: Rect,
+    format: PixelFormat,
+    resource: ResourceHandle,
+    /// Memory area of frame buffer.
+    memory: FramebufferMemory<H>,
+    /// The resource and memory area which isn't being displayed, if the
+    /// scanout is double buffered.
+    back: Option<(ResourceHandle, FramebufferMemory<H>)>,
+    /// Whether the resource is a blob which shares its memory with the host,
+    /// so needn't be transferred.
+    blob: bool,
+    /// Whether the scanout is showing the frame buffer, rather than having
+    /// been disabled with [`VirtIOGpu::disable_scanout`].
+    enabled: bool,
+}
+
+/// The backing memory of a frame buffer.
+enum FramebufferMemory<H: Hal> {
+    /// Allocated by the driver, and freed when the frame buffer is dropped.
+    Owned(Dma<H>),
+    /// Provided by the caller of [`VirtIOGpu::setup_scanout_in`], who remains
+    /// responsible for freeing it.
+    Borrowed(NonNull<[u8]>),
+}
+
+impl<H: Hal> FramebufferMemory<H> {
+    fn as_ptr(&self) -> NonNull<[u8]> {
+        match self {
+            Self::Owned(dma) => dma.raw_slice(),
+            Self::Borrowed(buf) => *buf,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, FromZeroes)]
+struct RespDisplayInfo {
+    header: CtrlHeader,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes, FromZeroes)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceCreate2D {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+}
+
+/// The pixel format of a 2D resource.
+///
+/// These are all the 2D formats which the VirtIO specification defines, each
+/// of which has 4 bytes per pixel.
+#[repr(u32)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// 32-bit blue, green, red, alpha.
+    B8G8R8A8UNORM = 1,
+    /// 32-bit blue, green, red, with the last byte unused.
+    B8G8R8X8UNORM = 2,
+    /// 32-bit alpha, red, green, blue.
+    A8R8G8B8UNORM = 3,
+    /// 32-bit red, green, blue, with the first byte unused.
+    X8R8G8B8UNORM = 4,
+    /// 32-bit red, green, blue, alpha.
+    R8G8B8A8UNORM = 67,
+    /// 32-bit blue, green, red, with the first byte unused.
+    X8B8G8R8UNORM = 68,
+    /// 32-bit alpha, blue, green, red.
+    A8B8G8R8UNORM = 121,
+    /// 32-bit red, green, blue, with the last byte unused.
+    R8G8B8X8UNORM = 134,
+}
+
+impl PixelFormat {
+    /// Returns the number of bytes which each pixel takes in memory.
+    pub const fn bytes_per_pixel(self) -> u32 {
+        match self {
+            Self::B8G8R8A8UNORM
+            | Self::B8G8R8X8UNORM
+            | Self::A8R8G8B8UNORM
+            | Self::X8R8G8B8UNORM
+            | Self::R8G8B8A8UNORM
+            | Self::X8B8G8R8UNORM
+            | Self::A8B8G8R8UNORM
+            | Self::R8G8B8X8UNORM => 4,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceDetachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceUnref {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceAttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32, // followed by this many `MemEntry`s
+}
+
+/// A region of physical memory, one of those passed to
+/// [`VirtIOGpu::attach_backing_entries`].
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemEntry {
+    /// The physical address of the start of the region, as seen by the device.
+    pub addr: u64,
+    /// The length of the region in bytes.
+    pub length: u32,
+    _padding: u32,
+}
+
+impl MemEntry {
+    /// Returns an entry for the region of the given length at the given
+    /// physical address.
+    pub const fn new(addr: u64, length: u32) -> Self {
+        Self {
+            addr,
+            length,
+            _padding: 0,
+        }
+    }
+}
+
+/// The blob's memory is guest memory, passed as backing entries.
+const BLOB_MEM_GUEST: u32 = 1;
+/// The blob's memory is host memory allocated by a 3D context.
+const BLOB_MEM_HOST3D: u32 = 2;
+
+/// The blob may be mapped into the host-visible shared memory region.
+const BLOB_FLAG_USE_MAPPABLE: u32 = 1 << 0;
+/// The blob may be shared with the host, e.g. as a scanout.
+const BLOB_FLAG_USE_SHAREABLE: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceCreateBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    blob_mem: u32,
+    blob_flags: u32,
+    nr_entries: u32, // followed by this many `MemEntry`s
+    blob_id: u64,
+    size: u64,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct SetScanoutBlob {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    _padding: u32,
+    strides: [u32; 4],
+    offsets: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceMapBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+    offset: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, FromZeroes)]
+struct RespMapInfo {
+    header: CtrlHeader,
+    map_info: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceUnmapBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+/// The largest number of entries which can be passed to
+/// [`VirtIOGpu::attach_backing_entries`] at once.
+pub const MAX_MEM_ENTRIES: usize =
+    (PAGE_SIZE - size_of::<ResourceAttachBacking>()) / size_of::<MemEntry>();
+
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct TransferToHost2D {
+    header: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct GetCapsetInfo {
+    header: CtrlHeader,
+    capset_index: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, FromZeroes)]
+struct RespCapsetInfo {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_max_version: u32,
+    capset_max_size: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct GetCapset {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_version: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Clone, Copy)]
+struct GetEdid {
+    header: CtrlHeader,
+    scanout: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, FromZeroes)]
+struct RespEdid {
+    header: CtrlHeader,
+    size: u32,
+    _padding: u32,
+    edid: [u8; EDID_MAX_SIZE],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Clone, Copy)]
+struct CursorPos {
+    scanout_id: u32,
+    x: u32,
+    y: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Clone, Copy)]
+struct UpdateCursor {
+    header: CtrlHeader,
+    pos: CursorPos,
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    _padding: u32,
+}
+
+const QUEUE_TRANSMIT: u16 = 0;
+const QUEUE_CURSOR: u16 = 1;
+
+/// The length of a response header without any data.
+const OK_RESPONSE_HEADER_LEN: usize = size_of::<CtrlHeader>();
+
+/// The size of the control queue, which must have room for a blocking request
+/// as well as every fenced command and a whole batch.
+const CONTROL_QUEUE_SIZE: usize = 32;
+
+/// The number of fenced commands which can be in flight at the same time.
+const FENCE_SLOTS: usize = 4;
+
+/// The size of the buffer for each fenced or batched request, which must fit
+/// any of them.
+const FENCE_REQUEST_SIZE: usize = 64;
+
+/// The maximum number of scanouts which a device can have.
+const MAX_SCANOUTS: usize = 16;
+
+/// The maximum size of the EDID which the device can provide.
+const EDID_MAX_SIZE: usize = 1024;
+
+/// The ID of the shared memory region into which blobs are mapped.
+const SHM_ID_HOST_VISIBLE: u8 = 1;
+
+const SCANOUT_ID: u32 = 0;
+const RESOURCE_ID_CURSOR: u32 = 0xdade;
+
+const CURSOR_RECT: Rect = Rect {
+    x: 0,
+    y: 0,
+    width: 64,
+    height: 64,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use crate::transport::fake::{FakeTransport, State};
+    use crate::transport::DeviceType;
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    /// The resolution of the fake device's only scanout.
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+
+    const FULL_RECT: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: WIDTH,
+        height: HEIGHT,
+    };
+
+    fn config_space() -> Config {
+        Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        }
+    }
+
+    fn fake_gpu(
+        config_space: NonNull<Config>,
+    ) -> (VirtIOGpu<FakeHal, FakeTransport<Config>>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State::new(2)));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space,
+            state: state.clone(),
+        };
+        (VirtIOGpu::new(transport, FakeHal::new()).unwrap(), state)
+    }
+
+    /// Returns the fake device's successful response to the given control
+    /// command.
+    fn respond(command: &[u8]) -> Vec<u8> {
+        let header = CtrlHeader::read_from_prefix(command).unwrap();
+        if header.hdr_type == Command::GET_DISPLAY_INFO {
+            let mut response = CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                .as_bytes()
+                .to_vec();
+            response.extend_from_slice(FULL_RECT.as_bytes());
+            // The scanout is enabled.
+            response.extend_from_slice(&1u32.to_le_bytes());
+            response.resize(size_of::<RespDisplayInfo>(), 0);
+            response
+        } else {
+            CtrlHeader::with_type(Command::OK_NODATA)
+                .as_bytes()
+                .to_vec()
+        }
+    }
+
+    /// Plays the device for the next `count` control commands, in order,
+    /// answering each with `respond`, and returns the commands.
+    fn serve_with(
+        state: &Mutex<State>,
+        count: usize,
+        respond: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> Vec<Vec<u8>> {
+        let mut commands = Vec::new();
+        while commands.len() < count {
+            let served = state
+                .lock()
+                .unwrap()
+                .try_read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |command| {
+                    let response = respond(&command);
+                    commands.push(command);
+                    response
+                });
+            if !served {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        commands
+    }
+
+    /// Runs `f` while another thread plays the device for `count` control
+    /// commands, and returns its result along with the commands.
+    fn with_device<R>(
+        state: &Arc<Mutex<State>>,
+        count: usize,
+        f: impl FnOnce() -> R,
+    ) -> (R, Vec<Vec<u8>>) {
+        let state = state.clone();
+        let server = thread::spawn(move || serve_with(&state, count, respond));
+        let result = f();
+        (result, server.join().unwrap())
+    }
+
+    fn command_type(command: &[u8]) -> Command {
+        CtrlHeader::read_from_prefix(command).unwrap().hdr_type
+    }
+
+    /// Sets up the frame buffer of scanout 0, and returns its resource.
+    fn setup_framebuffer(
+        gpu: &mut VirtIOGpu<FakeHal, FakeTransport<Config>>,
+        state: &Arc<Mutex<State>>,
+    ) -> ResourceHandle {
+        let hal = gpu.hal;
+        let (result, commands) = with_device(state, 4, || gpu.setup_framebuffer(hal).map(|_| ()));
+        result.unwrap();
+        assert_eq!(
+            commands.iter().map(|c| command_type(c)).collect::<Vec<_>>(),
+            [
+                Command::GET_DISPLAY_INFO,
+                Command::RESOURCE_CREATE_2D,
+                Command::RESOURCE_ATTACH_BACKING,
+                Command::SET_SCANOUT
+            ]
+        );
+        gpu.scanout_resource(0).unwrap()
+    }
+
+    #[test]
+    fn fenced_commands() {
+        let mut config_space = config_space();
+        let (mut gpu, state) = fake_gpu(NonNull::from(&mut config_space));
+        let (resource, _) = with_device(&state, 1, || {
+            gpu.create_resource_2d(PixelFormat::B8G8R8A8UNORM, WIDTH, HEIGHT)
+        });
+        let resource = resource.unwrap();
+
+        let transfer = gpu
+            .transfer_to_host_2d_fenced(resource, FULL_RECT, 0)
+            .unwrap();
+        let flush = gpu.resource_flush_fenced(resource, FULL_RECT).unwrap();
+        assert_ne!(transfer, flush);
+        assert_eq!(gpu.poll_fences(), Ok(0));
+        assert!(!gpu.is_fence_signalled(transfer));
+        assert_eq!(gpu.fence_result(transfer), None);
+
+        // The device finishes the transfer, but fails the flush.
+        let commands = serve_with(&state, 2, |command| {
+            if command_type(command) == Command::RESOURCE_FLUSH {
+                CtrlHeader::with_type(Command::ERR_INVALID_RESOURCE_ID)
+                    .as_bytes()
+                    .to_vec()
+            } else {
+                respond(command)
+            }
+        });
+        for (command, fence_id) in commands.iter().zip([transfer, flush]) {
+            let header = CtrlHeader::read_from_prefix(command).unwrap();
+            assert_eq!(header.flags, GPU_FLAG_FENCE);
+            assert_eq!(header.fence_id, fence_id);
+        }
+        assert_eq!(gpu.poll_fences(), Ok(2));
+        assert!(gpu.is_fence_signalled(transfer));
+        assert!(gpu.is_fence_signalled(flush));
+        assert_eq!(gpu.fence_result(transfer), Some(Ok(())));
+        let invalid_resource = Err(Error::GpuResponse(GpuError::InvalidResourceId));
+        assert_eq!(gpu.fence_result(flush), Some(invalid_resource));
+        assert_eq!(gpu.wait_fence(flush), invalid_resource);
+        assert_eq!(gpu.wait_fence(transfer), Ok(()));
+        assert_eq!(gpu.wait_fence(0), Err(Error::InvalidParam));
+        assert_eq!(gpu.wait_fence(flush + 1), Err(Error::InvalidParam));
+
+        // Waiting blocks until the device finishes the command.
+        let next = gpu.resource_flush_fenced(resource, FULL_RECT).unwrap();
+        let (result, _) = with_device(&state, 1, || gpu.wait_fence(next));
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn batch_interleaved_with_blocking_request() {
+        let mut config_space = config_space();
+        let (mut gpu, state) = fake_gpu(NonNull::from(&mut config_space));
+        let (resource, _) = with_device(&state, 1, || {
+            gpu.create_resource_2d(PixelFormat::B8G8R8A8UNORM, WIDTH, HEIGHT)
+        });
+        let resource = resource.unwrap();
+
+        let mut batch = gpu.begin_batch().unwrap();
+        batch.transfer_to_host_2d(resource, FULL_RECT, 0).unwrap();
+        batch.resource_flush(resource, FULL_RECT).unwrap();
+        assert_eq!(batch.len(), 2);
+        batch.submit().unwrap();
+        assert!(matches!(gpu.begin_batch(), Err(Error::AlreadyUsed)));
+
+        // The device finishes the batch, failing the flush, before a blocking
+        // command sent after it.
+        let server = {
+            let state = state.clone();
+            thread::spawn(move || {
+                serve_with(&state, 3, |command| {
+                    if command_type(command) == Command::RESOURCE_FLUSH {
+                        CtrlHeader::with_type(Command::ERR_UNSPEC)
+                            .as_bytes()
+                            .to_vec()
+                    } else {
+                        respond(command)
+                    }
+                })
+            })
+        };
+        let other = gpu
+            .create_resource_2d(PixelFormat::B8G8R8A8UNORM, WIDTH, HEIGHT)
+            .unwrap();
+        let commands = server.join().unwrap();
+        assert_eq!(
+            commands.iter().map(|c| command_type(c)).collect::<Vec<_>>(),
+            [
+                Command::TRANSFER_TO_HOST_2D,
+                Command::RESOURCE_FLUSH,
+                Command::RESOURCE_CREATE_2D
+            ]
+        );
+        assert_ne!(other, resource);
+
+        // The batch's results were kept while the blocking command was waited for.
+        let results = gpu.wait_batch().unwrap();
+        let unspec = Err(Error::GpuResponse(GpuError::Unspec));
+        assert_eq!(results.as_slice(), [Ok(()), unspec]);
+        assert_eq!(results.check(), unspec);
+        assert_eq!(gpu.wait_batch(), Err(Error::NotReady));
+        assert!(gpu.begin_batch().is_ok());
+    }
+
+    #[test]
+    fn flush_scanout_region_offset() {
+        let mut config_space = config_space();
+        let (mut gpu, state) = fake_gpu(NonNull::from(&mut config_space));
+        let resource = setup_framebuffer(&mut gpu, &state);
+
+        let check_flush = |gpu: &mut VirtIOGpu<_, _>, rect, expected: Rect| {
+            let (result, commands) = with_device(&state, 2, || gpu.flush_scanout_region(0, rect));
+            result.unwrap();
+            let transfer = TransferToHost2D::read_from_prefix(&commands[0]).unwrap();
+            assert_eq!(transfer.header.hdr_type, Command::TRANSFER_TO_HOST_2D);
+            assert_eq!(transfer.rect, expected);
+            assert_eq!(
+                transfer.offset,
+                u64::from((expected.y * WIDTH + expected.x) * 4)
+            );
+            assert_eq!(transfer.resource_id, resource.id);
+            let flush = ResourceFlush::read_from_prefix(&commands[1]).unwrap();
+            assert_eq!(flush.header.hdr_type, Command::RESOURCE_FLUSH);
+            assert_eq!(flush.rect, expected);
+            assert_eq!(flush.resource_id, resource.id);
+        };
+        let rect = Rect {
+            x: 3,
+            y: 5,
+            width: 10,
+            height: 4,
+        };
+        check_flush(&mut gpu, rect, rect);
+        // A rectangle hanging off the bottom right corner is clipped.
+        let rect = Rect {
+            x: WIDTH - 4,
+            y: HEIGHT - 2,
+            width: 10,
+            height: 10,
+        };
+        let clipped = Rect {
+            width: 4,
+            height: 2,
+            ..rect
+        };
+        check_flush(&mut gpu, rect, clipped);
+        let outside = Rect {
+            x: WIDTH,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(
+            gpu.flush_scanout_region(0, outside),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn disable_and_enable_scanout() {
+        let mut config_space = config_space();
+        let (mut gpu, state) = fake_gpu(NonNull::from(&mut config_space));
+        assert_eq!(gpu.enable_scanout(0), Err(Error::NotReady));
+        assert_eq!(gpu.enable_scanout(1), Err(Error::InvalidParam));
+        let resource = setup_framebuffer(&mut gpu, &state);
+        assert!(gpu.is_scanout_enabled(0));
+
+        let (result, commands) = with_device(&state, 1, || gpu.disable_scanout(0));
+        result.unwrap();
+        let set_scanout = SetScanout::read_from_prefix(&commands[0]).unwrap();
+        assert_eq!(set_scanout.header.hdr_type, Command::SET_SCANOUT);
+        assert_eq!(set_scanout.rect, Rect::default());
+        assert_eq!(set_scanout.scanout_id, 0);
+        assert_eq!(set_scanout.resource_id, 0);
+        assert!(!gpu.is_scanout_enabled(0));
+        assert_eq!(gpu.scanout_resource(0), Some(resource));
+
+        let (result, commands) = with_device(&state, 1, || gpu.enable_scanout(0));
+        result.unwrap();
+        let set_scanout = SetScanout::read_from_prefix(&commands[0]).unwrap();
+        assert_eq!(set_scanout.header.hdr_type, Command::SET_SCANOUT);
+        assert_eq!(set_scanout.rect, FULL_RECT);
+        assert_eq!(set_scanout.resource_id, resource.id);
+        assert!(gpu.is_scanout_enabled(0));
+    }
+
+    #[test]
+    fn present_swaps_buffers() {
+        let mut config_space = config_space();
+        let (mut gpu, state) = fake_gpu(NonNull::from(&mut config_space));
+        let hal = gpu.hal;
+        let (result, _) = with_device(&state, 4, || gpu.setup_framebuffer(hal).map(|fb| fb[0] = 1));
+        result.unwrap();
+        assert_eq!(gpu.present(), Err(Error::NotReady));
+        let (result, _) = with_device(&state, 2, || gpu.setup_double_buffering(hal));
+        result.unwrap();
+        gpu.back_buffer().unwrap()[0] = 2;
+        let front = gpu.scanout_resource(0).unwrap();
+        let back = gpu.framebuffers[0]
+            .as_ref()
+            .unwrap()
+            .back
+            .as_ref()
+            .unwrap()
+            .0;
+        assert_ne!(front, back);
+
+        let (result, commands) = with_device(&state, 3, || gpu.present());
+        result.unwrap();
+        let transfer = TransferToHost2D::read_from_prefix(&commands[0]).unwrap();
+        assert_eq!(transfer.header.hdr_type, Command::TRANSFER_TO_HOST_2D);
+        assert_eq!(transfer.resource_id, back.id);
+        let set_scanout = SetScanout::read_from_prefix(&commands[1]).unwrap();
+        assert_eq!(set_scanout.header.hdr_type, Command::SET_SCANOUT);
+        assert_eq!(set_scanout.resource_id, back.id);
+        let flush = ResourceFlush::read_from_prefix(&commands[2]).unwrap();
+        assert_eq!(flush.header.hdr_type, Command::RESOURCE_FLUSH);
+        assert_eq!(flush.resource_id, back.id);
+
+        // The buffer which was displayed is now the one to draw into.
+        assert_eq!(gpu.scanout_resource(0), Some(back));
+        assert_eq!(gpu.back_buffer().unwrap()[0], 1);
+
+        let (result, _) = with_device(&state, 3, || gpu.present());
+        result.unwrap();
+        assert_eq!(gpu.scanout_resource(0), Some(front));
+        assert_eq!(gpu.back_buffer().unwrap()[0], 2);
+    }
+
+    #[test]
+    fn destroy_resource_twice() {
+        let mut config_space = config_space();
+        let (mut gpu, state) = fake_gpu(NonNull::from(&mut config_space));
+        let (resource, _) = with_device(&state, 1, || {
+            gpu.create_resource_2d(PixelFormat::B8G8R8A8UNORM, WIDTH, HEIGHT)
+        });
+        let resource = resource.unwrap();
+
+        let (result, commands) = with_device(&state, 1, || gpu.destroy_resource(resource));
+        result.unwrap();
+        assert_eq!(command_type(&commands[0]), Command::RESOURCE_UNREF);
+
+        // The resource is gone, so nothing more is sent to the device.
+        let notify_count = state.lock().unwrap().queues[0].notify_count;
+        assert_eq!(gpu.destroy_resource(resource), Err(Error::InvalidParam));
+        assert_eq!(gpu.detach_backing(resource), Err(Error::InvalidParam));
+        assert_eq!(
+            gpu.transfer_to_host_2d_fenced(resource, FULL_RECT, 0),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, notify_count);
+    }
+
+    #[test]
+    fn clip_rect_to_resource() {
+        let rect = |x, y, width, height| Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert_eq!(
+            clip_rect(rect(10, 20, 30, 40), 100, 100),
+            Some(rect(10, 20, 30, 40))
+        );
+        assert_eq!(
+            clip_rect(rect(90, 80, 30, 40), 100, 100),
+            Some(rect(90, 80, 10, 20))
+        );
+        assert_eq!(
+            clip_rect(rect(0, 0, u32::MAX, u32::MAX), 100, 50),
+            Some(rect(0, 0, 100, 50))
+        );
+        assert_eq!(clip_rect(rect(10, 10, 0, 5), 100, 100), None);
+        assert_eq!(clip_rect(rect(100, 10, 5, 5), 100, 100), None);
+    }
+
+    #[test]
+    fn error_responses() {
+        let check = |hdr_type, expected| CtrlHeader::with_type(hdr_type).check_type(expected);
+        assert_eq!(check(Command::OK_NODATA, Command::OK_NODATA), Ok(()));
+        assert_eq!(
+            check(Command::ERR_OUT_OF_MEMORY, Command::OK_NODATA),
+            Err(Error::GpuResponse(GpuError::OutOfMemory))
+        );
+        assert_eq!(
+            check(Command::ERR_INVALID_RESOURCE_ID, Command::OK_NODATA),
+            Err(Error::GpuResponse(GpuError::InvalidResourceId))
+        );
+        assert_eq!(
+            check(Command::OK_EDID, Command::OK_NODATA),
+            Err(Error::IoError)
+        );
+    }
+
+    #[test]
+    fn edid_size() {
+        let mut edid = [0; EDID_BLOCK_LEN];
+        edid[..8].copy_from_slice(&EDID_HEADER);
+        assert_eq!(edid_physical_size_mm(&edid), None);
+        // 60 x 34 cm from the basic display parameters.
+        edid[21] = 60;
+        edid[22] = 34;
+        assert_eq!(edid_physical_size_mm(&edid), Some((600, 340)));
+        // 597 x 336 mm from a detailed timing descriptor.
+        edid[54] = 0x02;
+        edid[55] = 0x3a;
+        edid[66] = 0x55;
+        edid[67] = 0x50;
+        edid[68] = 0x21;
+        assert_eq!(edid_physical_size_mm(&edid), Some((597, 336)));
+        edid[0] = 0xff;
+        assert_eq!(edid_physical_size_mm(&edid), None);
+        assert_eq!(edid_physical_size_mm(&EDID_HEADER), None);
+    }
+}
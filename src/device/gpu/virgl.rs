@@ -0,0 +1,350 @@
+//! 3D (virgl) support for VirtIO GPU devices.
+//!
+//! The driver doesn't interpret the command streams sent with
+//! [`VirtIOGpu::submit_3d`]; it only moves them and the resources which they use
+//! to the device.
+
+use super::{
+    Command, CtrlHeader, Features, ResourceHandle, ResourceInfo, ResourceMapBlob, RespMapInfo,
+    VirtIOGpu, BLOB_FLAG_USE_MAPPABLE, BLOB_MEM_HOST3D, SHM_ID_HOST_VISIBLE,
+};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, PhysAddr, Result, PAGE_SIZE};
+use core::mem::size_of;
+use core::ptr::NonNull;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The maximum length of the debug name of a 3D context.
+const CONTEXT_NAME_MAX_LEN: usize = 64;
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Returns whether the device supports 3D acceleration (`VIRTIO_GPU_F_VIRGL`).
+    pub fn virgl_supported(&self) -> bool {
+        self.negotiated_features.contains(Features::VIRGL)
+    }
+
+    /// Creates a 3D rendering context, with the given name for debugging.
+    ///
+    /// Returns [`Error::InvalidParam`] if the name is longer than 64 bytes.
+    pub fn create_context(&mut self, name: &[u8]) -> Result<ContextHandle> {
+        self.check_virgl()?;
+        if name.len() > CONTEXT_NAME_MAX_LEN {
+            return Err(Error::InvalidParam);
+        }
+        let id = (1..)
+            .find(|id| !self.contexts.contains(id))
+            .ok_or(Error::InvalidParam)?;
+        let mut debug_name = [0; CONTEXT_NAME_MAX_LEN];
+        debug_name[..name.len()].copy_from_slice(name);
+        let rsp: CtrlHeader = self.request(CtxCreate {
+            header: CtrlHeader::with_context(Command::CTX_CREATE, id),
+            nlen: name.len() as u32,
+            context_init: 0,
+            debug_name,
+        })?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.contexts.push(id);
+        Ok(ContextHandle { id })
+    }
+
+    /// Destroys a 3D rendering context.
+    ///
+    /// Returns [`Error::InvalidParam`] if it has already been destroyed.
+    pub fn destroy_context(&mut self, context: ContextHandle) -> Result {
+        let index = self.context_index(context)?;
+        let rsp: CtrlHeader =
+            self.request(CtrlHeader::with_context(Command::CTX_DESTROY, context.id))?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.contexts.swap_remove(index);
+        Ok(())
+    }
+
+    /// Creates a 3D resource with the given parameters.
+    ///
+    /// Backing memory can be attached with [`VirtIOGpu::attach_backing`] as for
+    /// 2D resources, but the driver doesn't check its size.
+    pub fn create_resource_3d(&mut self, params: &Resource3DParams) -> Result<ResourceHandle> {
+        self.check_virgl()?;
+        let id = self.allocate_resource_id();
+        let rsp: CtrlHeader = self.request(ResourceCreate3D {
+            header: CtrlHeader::with_type(Command::RESOURCE_CREATE_3D),
+            resource_id: id,
+            target: params.target,
+            format: params.format,
+            bind: params.bind,
+            width: params.width,
+            height: params.height,
+            depth: params.depth,
+            array_size: params.array_size,
+            last_level: params.last_level,
+            nr_samples: params.nr_samples,
+            flags: params.flags,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.resources.push(ResourceInfo {
+            id,
+            min_backing_len: 0,
+            has_backing: false,
+            blob: None,
+        });
+        Ok(ResourceHandle { id })
+    }
+
+    /// Creates a blob resource of `size` bytes in host memory, from the object
+    /// which the context's command stream created with the given blob ID.
+    ///
+    /// The blob can be mapped into the guest with [`VirtIOGpu::map_blob`].
+    /// Returns [`Error::Unsupported`] if the device doesn't support
+    /// `VIRTIO_GPU_F_RESOURCE_BLOB`.
+    pub fn create_blob_3d(
+        &mut self,
+        context: ContextHandle,
+        blob_id: u64,
+        size: u64,
+    ) -> Result<ResourceHandle> {
+        self.check_blob()?;
+        self.context_index(context)?;
+        self.create_blob_resource(
+            context.id,
+            BLOB_MEM_HOST3D,
+            BLOB_FLAG_USE_MAPPABLE,
+            blob_id,
+            size,
+            &[],
+        )
+    }
+
+    /// Maps a blob resource into the device's host-visible shared memory
+    /// region at the given offset, and returns the mapping.
+    ///
+    /// The offset within the region is chosen by the caller, and must be page
+    /// aligned. The mapping stays valid until [`VirtIOGpu::unmap_blob`] or
+    /// [`VirtIOGpu::destroy_resource`] is called. Returns
+    /// [`Error::Unsupported`] if the device has no host-visible region,
+    /// [`Error::InvalidParam`] if the resource isn't a mappable blob or doesn't
+    /// fit in the region at the offset, or [`Error::AlreadyUsed`] if it is
+    /// already mapped.
+    pub fn map_blob(&mut self, resource: ResourceHandle, offset: u64) -> Result<NonNull<[u8]>> {
+        let blob = self
+            .resource_info(resource)?
+            .blob
+            .filter(|blob| blob.mappable)
+            .ok_or(Error::InvalidParam)?;
+        if blob.mapped {
+            return Err(Error::AlreadyUsed);
+        }
+        let region = self
+            .transport
+            .shared_memory_region(SHM_ID_HOST_VISIBLE)
+            .ok_or(Error::Unsupported)?;
+        if !offset.is_multiple_of(PAGE_SIZE as u64)
+            || offset
+                .checked_add(blob.size)
+                .is_none_or(|end| end > region.len)
+        {
+            return Err(Error::InvalidParam);
+        }
+        let size = usize::try_from(blob.size).map_err(|_| Error::InvalidParam)?;
+        let rsp: RespMapInfo = self.request(ResourceMapBlob {
+            header: CtrlHeader::with_type(Command::RESOURCE_MAP_BLOB),
+            resource_id: resource.id,
+            _padding: 0,
+            offset,
+        })?;
+        rsp.header.check_type(Command::OK_MAP_INFO)?;
+        self.resource_info_mut(resource)?
+            .blob
+            .as_mut()
+            .unwrap()
+            .mapped = true;
+        // Safe because the blob is within the shared memory region, which is device memory.
+        let vaddr = unsafe {
+            self.hal
+                .mmio_phys_to_virt(region.paddr + offset as PhysAddr, size)
+        };
+        Ok(NonNull::slice_from_raw_parts(vaddr, size))
+    }
+
+    /// Unmaps a blob resource which was mapped with [`VirtIOGpu::map_blob`].
+    ///
+    /// Returns [`Error::InvalidParam`] if the resource has been destroyed, or
+    /// [`Error::NotReady`] if it isn't mapped.
+    pub fn unmap_blob(&mut self, resource: ResourceHandle) -> Result {
+        if !self
+            .resource_info(resource)?
+            .blob
+            .is_some_and(|blob| blob.mapped)
+        {
+            return Err(Error::NotReady);
+        }
+        self.resource_unmap_blob(resource.id)?;
+        self.resource_info_mut(resource)?
+            .blob
+            .as_mut()
+            .unwrap()
+            .mapped = false;
+        Ok(())
+    }
+
+    /// Makes a resource available to the command streams of a context.
+    pub fn attach_resource(&mut self, context: ContextHandle, resource: ResourceHandle) -> Result {
+        self.context_resource_command(Command::CTX_ATTACH_RESOURCE, context, resource)
+    }
+
+    /// Removes a resource from a context.
+    pub fn detach_resource(&mut self, context: ContextHandle, resource: ResourceHandle) -> Result {
+        self.context_resource_command(Command::CTX_DETACH_RESOURCE, context, resource)
+    }
+
+    /// Submits a buffer of 3D commands to be run in the given context, and
+    /// waits for the device to accept it.
+    pub fn submit_3d(&mut self, context: ContextHandle, commands: &[u8]) -> Result {
+        self.context_index(context)?;
+        let size = u32::try_from(commands.len()).map_err(|_| Error::InvalidParam)?;
+        let rsp: CtrlHeader = self.request_with_data(
+            CmdSubmit {
+                header: CtrlHeader::with_context(Command::SUBMIT_3D, context.id),
+                size,
+                _padding: 0,
+            },
+            commands,
+        )?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    /// Returns [`Error::Unsupported`] if `VIRTIO_GPU_F_VIRGL` wasn't negotiated.
+    fn check_virgl(&self) -> Result {
+        if self.virgl_supported() {
+            Ok(())
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    /// Returns the index of the given context in `contexts`, or
+    /// [`Error::InvalidParam`] if it has been destroyed.
+    fn context_index(&self, context: ContextHandle) -> Result<usize> {
+        self.contexts
+            .iter()
+            .position(|&id| id == context.id)
+            .ok_or(Error::InvalidParam)
+    }
+
+    fn context_resource_command(
+        &mut self,
+        command: Command,
+        context: ContextHandle,
+        resource: ResourceHandle,
+    ) -> Result {
+        self.context_index(context)?;
+        self.resource_info(resource)?;
+        let rsp: CtrlHeader = self.request(CtxResource {
+            header: CtrlHeader::with_context(command, context.id),
+            resource_id: resource.id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    /// Send a request followed by the given data to the device and block for a
+    /// response.
+    fn request_with_data<Req: AsBytes, Rsp: FromBytes>(
+        &mut self,
+        req: Req,
+        data: &[u8],
+    ) -> Result<Rsp> {
+        let len = size_of::<Req>();
+        req.write_to_prefix(&mut self.queue_buf_send).unwrap();
+        let send = &self.queue_buf_send[..len];
+        let inputs: &[&[u8]] = if data.is_empty() {
+            &[send]
+        } else {
+            &[send, data]
+        };
+        self.control_queue.add_notify_wait_pop(
+            inputs,
+            &mut [&mut self.queue_buf_recv],
+            &mut self.transport,
+        )?;
+        Ok(Rsp::read_from_prefix(&self.queue_buf_recv).unwrap())
+    }
+}
+
+/// A handle to a 3D context created with [`VirtIOGpu::create_context`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ContextHandle {
+    id: u32,
+}
+
+/// The parameters of a 3D resource, as passed to
+/// [`VirtIOGpu::create_resource_3d`].
+///
+/// The values are those of the Gallium interface used by the command streams.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Resource3DParams {
+    /// The kind of resource, e.g. a buffer or a 2D texture.
+    pub target: u32,
+    /// The Gallium pixel format.
+    pub format: u32,
+    /// How the resource will be bound to the pipeline.
+    pub bind: u32,
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The depth in pixels.
+    pub depth: u32,
+    /// The number of array layers.
+    pub array_size: u32,
+    /// The index of the last mipmap level.
+    pub last_level: u32,
+    /// The number of samples per pixel.
+    pub nr_samples: u32,
+    /// Flags for the resource.
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct CtxCreate {
+    header: CtrlHeader,
+    nlen: u32,
+    context_init: u32,
+    debug_name: [u8; CONTEXT_NAME_MAX_LEN],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct CtxResource {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceCreate3D {
+    header: CtrlHeader,
+    resource_id: u32,
+    target: u32,
+    format: u32,
+    bind: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+    array_size: u32,
+    last_level: u32,
+    nr_samples: u32,
+    flags: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct CmdSubmit {
+    header: CtrlHeader,
+    size: u32,
+    _padding: u32,
+}
@@ -0,0 +1,552 @@
+//! Typed evdev event codes, and decoding of raw input events into them.
+
+use super::{InputEvent, EV_ABS, EV_KEY, EV_REL, EV_SYN, SYN_REPORT};
+
+/// Defines an enum of evdev codes, with an `Unknown` variant for any other code, and conversions
+/// to and from the raw code.
+macro_rules! evdev_codes {
+    (
+        $(#[$meta:meta])*
+        $name:ident {
+            $($variant:ident = $value:literal => $evdev_name:ident,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+        pub enum $name {
+            $(
+                #[doc = concat!("`", stringify!($evdev_name), "`")]
+                $variant,
+            )*
+            /// A code without a variant of its own.
+            ///
+            /// Converting a raw code never gives this for a code which does have a variant.
+            Unknown(u16),
+        }
+
+        impl From<u16> for $name {
+            fn from(code: u16) -> Self {
+                match code {
+                    $($value => Self::$variant,)*
+                    _ => Self::Unknown(code),
+                }
+            }
+        }
+
+        impl From<$name> for u16 {
+            fn from(code: $name) -> u16 {
+                match code {
+                    $($name::$variant => $value,)*
+                    $name::Unknown(code) => code,
+                }
+            }
+        }
+    };
+}
+
+evdev_codes! {
+    /// A key or button code, for `EV_KEY` events.
+    Key {
+            Reserved = 0 => KEY_RESERVED,
+            Esc = 1 => KEY_ESC,
+            Key1 = 2 => KEY_1,
+            Key2 = 3 => KEY_2,
+            Key3 = 4 => KEY_3,
+            Key4 = 5 => KEY_4,
+            Key5 = 6 => KEY_5,
+            Key6 = 7 => KEY_6,
+            Key7 = 8 => KEY_7,
+            Key8 = 9 => KEY_8,
+            Key9 = 10 => KEY_9,
+            Key0 = 11 => KEY_0,
+            Minus = 12 => KEY_MINUS,
+            Equal = 13 => KEY_EQUAL,
+            Backspace = 14 => KEY_BACKSPACE,
+            Tab = 15 => KEY_TAB,
+            Q = 16 => KEY_Q,
+            W = 17 => KEY_W,
+            E = 18 => KEY_E,
+            R = 19 => KEY_R,
+            T = 20 => KEY_T,
+            Y = 21 => KEY_Y,
+            U = 22 => KEY_U,
+            I = 23 => KEY_I,
+            O = 24 => KEY_O,
+            P = 25 => KEY_P,
+            Leftbrace = 26 => KEY_LEFTBRACE,
+            Rightbrace = 27 => KEY_RIGHTBRACE,
+            Enter = 28 => KEY_ENTER,
+            Leftctrl = 29 => KEY_LEFTCTRL,
+            A = 30 => KEY_A,
+            S = 31 => KEY_S,
+            D = 32 => KEY_D,
+            F = 33 => KEY_F,
+            G = 34 => KEY_G,
+            H = 35 => KEY_H,
+            J = 36 => KEY_J,
+            K = 37 => KEY_K,
+            L = 38 => KEY_L,
+            Semicolon = 39 => KEY_SEMICOLON,
+            Apostrophe = 40 => KEY_APOSTROPHE,
+            Grave = 41 => KEY_GRAVE,
+            Leftshift = 42 => KEY_LEFTSHIFT,
+            Backslash = 43 => KEY_BACKSLASH,
+            Z = 44 => KEY_Z,
+            X = 45 => KEY_X,
+            C = 46 => KEY_C,
+            V = 47 => KEY_V,
+            B = 48 => KEY_B,
+            N = 49 => KEY_N,
+            M = 50 => KEY_M,
+            Comma = 51 => KEY_COMMA,
+            Dot = 52 => KEY_DOT,
+            Slash = 53 => KEY_SLASH,
+            Rightshift = 54 => KEY_RIGHTSHIFT,
+            Kpasterisk = 55 => KEY_KPASTERISK,
+            Leftalt = 56 => KEY_LEFTALT,
+            Space = 57 => KEY_SPACE,
+            Capslock = 58 => KEY_CAPSLOCK,
+            F1 = 59 => KEY_F1,
+            F2 = 60 => KEY_F2,
+            F3 = 61 => KEY_F3,
+            F4 = 62 => KEY_F4,
+            F5 = 63 => KEY_F5,
+            F6 = 64 => KEY_F6,
+            F7 = 65 => KEY_F7,
+            F8 = 66 => KEY_F8,
+            F9 = 67 => KEY_F9,
+            F10 = 68 => KEY_F10,
+            Numlock = 69 => KEY_NUMLOCK,
+            Scrolllock = 70 => KEY_SCROLLLOCK,
+            Kp7 = 71 => KEY_KP7,
+            Kp8 = 72 => KEY_KP8,
+            Kp9 = 73 => KEY_KP9,
+            Kpminus = 74 => KEY_KPMINUS,
+            Kp4 = 75 => KEY_KP4,
+            Kp5 = 76 => KEY_KP5,
+            Kp6 = 77 => KEY_KP6,
+            Kpplus = 78 => KEY_KPPLUS,
+            Kp1 = 79 => KEY_KP1,
+            Kp2 = 80 => KEY_KP2,
+            Kp3 = 81 => KEY_KP3,
+            Kp0 = 82 => KEY_KP0,
+            Kpdot = 83 => KEY_KPDOT,
+            Zenkakuhankaku = 85 => KEY_ZENKAKUHANKAKU,
+            Key102nd = 86 => KEY_102ND,
+            F11 = 87 => KEY_F11,
+            F12 = 88 => KEY_F12,
+            Ro = 89 => KEY_RO,
+            Katakana = 90 => KEY_KATAKANA,
+            Hiragana = 91 => KEY_HIRAGANA,
+            Henkan = 92 => KEY_HENKAN,
+            Katakanahiragana = 93 => KEY_KATAKANAHIRAGANA,
+            Muhenkan = 94 => KEY_MUHENKAN,
+            Kpjpcomma = 95 => KEY_KPJPCOMMA,
+            Kpenter = 96 => KEY_KPENTER,
+            Rightctrl = 97 => KEY_RIGHTCTRL,
+            Kpslash = 98 => KEY_KPSLASH,
+            Sysrq = 99 => KEY_SYSRQ,
+            Rightalt = 100 => KEY_RIGHTALT,
+            Linefeed = 101 => KEY_LINEFEED,
+            Home = 102 => KEY_HOME,
+            Up = 103 => KEY_UP,
+            Pageup = 104 => KEY_PAGEUP,
+            Left = 105 => KEY_LEFT,
+            Right = 106 => KEY_RIGHT,
+            End = 107 => KEY_END,
+            Down = 108 => KEY_DOWN,
+            Pagedown = 109 => KEY_PAGEDOWN,
+            Insert = 110 => KEY_INSERT,
+            Delete = 111 => KEY_DELETE,
+            Macro = 112 => KEY_MACRO,
+            Mute = 113 => KEY_MUTE,
+            Volumedown = 114 => KEY_VOLUMEDOWN,
+            Volumeup = 115 => KEY_VOLUMEUP,
+            Power = 116 => KEY_POWER,
+            Kpequal = 117 => KEY_KPEQUAL,
+            Kpplusminus = 118 => KEY_KPPLUSMINUS,
+            Pause = 119 => KEY_PAUSE,
+            Scale = 120 => KEY_SCALE,
+            Kpcomma = 121 => KEY_KPCOMMA,
+            Hangeul = 122 => KEY_HANGEUL,
+            Hanja = 123 => KEY_HANJA,
+            Yen = 124 => KEY_YEN,
+            Leftmeta = 125 => KEY_LEFTMETA,
+            Rightmeta = 126 => KEY_RIGHTMETA,
+            Compose = 127 => KEY_COMPOSE,
+            Stop = 128 => KEY_STOP,
+            Again = 129 => KEY_AGAIN,
+            Props = 130 => KEY_PROPS,
+            Undo = 131 => KEY_UNDO,
+            Front = 132 => KEY_FRONT,
+            Copy = 133 => KEY_COPY,
+            Open = 134 => KEY_OPEN,
+            Paste = 135 => KEY_PASTE,
+            Find = 136 => KEY_FIND,
+            Cut = 137 => KEY_CUT,
+            Help = 138 => KEY_HELP,
+            Menu = 139 => KEY_MENU,
+            Calc = 140 => KEY_CALC,
+            Setup = 141 => KEY_SETUP,
+            Sleep = 142 => KEY_SLEEP,
+            Wakeup = 143 => KEY_WAKEUP,
+            File = 144 => KEY_FILE,
+            Sendfile = 145 => KEY_SENDFILE,
+            Deletefile = 146 => KEY_DELETEFILE,
+            Xfer = 147 => KEY_XFER,
+            Prog1 = 148 => KEY_PROG1,
+            Prog2 = 149 => KEY_PROG2,
+            Www = 150 => KEY_WWW,
+            Msdos = 151 => KEY_MSDOS,
+            Screenlock = 152 => KEY_SCREENLOCK,
+            RotateDisplay = 153 => KEY_ROTATE_DISPLAY,
+            Cyclewindows = 154 => KEY_CYCLEWINDOWS,
+            Mail = 155 => KEY_MAIL,
+            Bookmarks = 156 => KEY_BOOKMARKS,
+            Computer = 157 => KEY_COMPUTER,
+            Back = 158 => KEY_BACK,
+            Forward = 159 => KEY_FORWARD,
+            Closecd = 160 => KEY_CLOSECD,
+            Ejectcd = 161 => KEY_EJECTCD,
+            Ejectclosecd = 162 => KEY_EJECTCLOSECD,
+            Nextsong = 163 => KEY_NEXTSONG,
+            Playpause = 164 => KEY_PLAYPAUSE,
+            Previoussong = 165 => KEY_PREVIOUSSONG,
+            Stopcd = 166 => KEY_STOPCD,
+            Record = 167 => KEY_RECORD,
+            Rewind = 168 => KEY_REWIND,
+            Phone = 169 => KEY_PHONE,
+            Iso = 170 => KEY_ISO,
+            Config = 171 => KEY_CONFIG,
+            Homepage = 172 => KEY_HOMEPAGE,
+            Refresh = 173 => KEY_REFRESH,
+            Exit = 174 => KEY_EXIT,
+            Move = 175 => KEY_MOVE,
+            Edit = 176 => KEY_EDIT,
+            Scrollup = 177 => KEY_SCROLLUP,
+            Scrolldown = 178 => KEY_SCROLLDOWN,
+            Kpleftparen = 179 => KEY_KPLEFTPAREN,
+            Kprightparen = 180 => KEY_KPRIGHTPAREN,
+            New = 181 => KEY_NEW,
+            Redo = 182 => KEY_REDO,
+            F13 = 183 => KEY_F13,
+            F14 = 184 => KEY_F14,
+            F15 = 185 => KEY_F15,
+            F16 = 186 => KEY_F16,
+            F17 = 187 => KEY_F17,
+            F18 = 188 => KEY_F18,
+            F19 = 189 => KEY_F19,
+            F20 = 190 => KEY_F20,
+            F21 = 191 => KEY_F21,
+            F22 = 192 => KEY_F22,
+            F23 = 193 => KEY_F23,
+            F24 = 194 => KEY_F24,
+            Playcd = 200 => KEY_PLAYCD,
+            Pausecd = 201 => KEY_PAUSECD,
+            Prog3 = 202 => KEY_PROG3,
+            Prog4 = 203 => KEY_PROG4,
+            AllApplications = 204 => KEY_ALL_APPLICATIONS,
+            Suspend = 205 => KEY_SUSPEND,
+            Close = 206 => KEY_CLOSE,
+            Play = 207 => KEY_PLAY,
+            Fastforward = 208 => KEY_FASTFORWARD,
+            Bassboost = 209 => KEY_BASSBOOST,
+            Print = 210 => KEY_PRINT,
+            Hp = 211 => KEY_HP,
+            Camera = 212 => KEY_CAMERA,
+            Sound = 213 => KEY_SOUND,
+            Question = 214 => KEY_QUESTION,
+            Email = 215 => KEY_EMAIL,
+            Chat = 216 => KEY_CHAT,
+            Search = 217 => KEY_SEARCH,
+            Connect = 218 => KEY_CONNECT,
+            Finance = 219 => KEY_FINANCE,
+            Sport = 220 => KEY_SPORT,
+            Shop = 221 => KEY_SHOP,
+            Alterase = 222 => KEY_ALTERASE,
+            Cancel = 223 => KEY_CANCEL,
+            Brightnessdown = 224 => KEY_BRIGHTNESSDOWN,
+            Brightnessup = 225 => KEY_BRIGHTNESSUP,
+            Media = 226 => KEY_MEDIA,
+            Switchvideomode = 227 => KEY_SWITCHVIDEOMODE,
+            Kbdillumtoggle = 228 => KEY_KBDILLUMTOGGLE,
+            Kbdillumdown = 229 => KEY_KBDILLUMDOWN,
+            Kbdillumup = 230 => KEY_KBDILLUMUP,
+            Send = 231 => KEY_SEND,
+            Reply = 232 => KEY_REPLY,
+            Forwardmail = 233 => KEY_FORWARDMAIL,
+            Save = 234 => KEY_SAVE,
+            Documents = 235 => KEY_DOCUMENTS,
+            Battery = 236 => KEY_BATTERY,
+            Bluetooth = 237 => KEY_BLUETOOTH,
+            Wlan = 238 => KEY_WLAN,
+            Uwb = 239 => KEY_UWB,
+            KeyUnknown = 240 => KEY_UNKNOWN,
+            VideoNext = 241 => KEY_VIDEO_NEXT,
+            VideoPrev = 242 => KEY_VIDEO_PREV,
+            BrightnessCycle = 243 => KEY_BRIGHTNESS_CYCLE,
+            BrightnessAuto = 244 => KEY_BRIGHTNESS_AUTO,
+            DisplayOff = 245 => KEY_DISPLAY_OFF,
+            Wwan = 246 => KEY_WWAN,
+            Rfkill = 247 => KEY_RFKILL,
+            Micmute = 248 => KEY_MICMUTE,
+            Btn0 = 0x100 => BTN_0,
+            Btn1 = 0x101 => BTN_1,
+            Btn2 = 0x102 => BTN_2,
+            Btn3 = 0x103 => BTN_3,
+            Btn4 = 0x104 => BTN_4,
+            Btn5 = 0x105 => BTN_5,
+            Btn6 = 0x106 => BTN_6,
+            Btn7 = 0x107 => BTN_7,
+            Btn8 = 0x108 => BTN_8,
+            Btn9 = 0x109 => BTN_9,
+            BtnLeft = 0x110 => BTN_LEFT,
+            BtnRight = 0x111 => BTN_RIGHT,
+            BtnMiddle = 0x112 => BTN_MIDDLE,
+            BtnSide = 0x113 => BTN_SIDE,
+            BtnExtra = 0x114 => BTN_EXTRA,
+            BtnForward = 0x115 => BTN_FORWARD,
+            BtnBack = 0x116 => BTN_BACK,
+            BtnTask = 0x117 => BTN_TASK,
+            BtnTrigger = 0x120 => BTN_TRIGGER,
+            BtnThumb = 0x121 => BTN_THUMB,
+            BtnThumb2 = 0x122 => BTN_THUMB2,
+            BtnTop = 0x123 => BTN_TOP,
+            BtnTop2 = 0x124 => BTN_TOP2,
+            BtnPinkie = 0x125 => BTN_PINKIE,
+            BtnBase = 0x126 => BTN_BASE,
+            BtnBase2 = 0x127 => BTN_BASE2,
+            BtnBase3 = 0x128 => BTN_BASE3,
+            BtnBase4 = 0x129 => BTN_BASE4,
+            BtnBase5 = 0x12a => BTN_BASE5,
+            BtnBase6 = 0x12b => BTN_BASE6,
+            BtnDead = 0x12f => BTN_DEAD,
+            BtnSouth = 0x130 => BTN_SOUTH,
+            BtnEast = 0x131 => BTN_EAST,
+            BtnC = 0x132 => BTN_C,
+            BtnNorth = 0x133 => BTN_NORTH,
+            BtnWest = 0x134 => BTN_WEST,
+            BtnZ = 0x135 => BTN_Z,
+            BtnTl = 0x136 => BTN_TL,
+            BtnTr = 0x137 => BTN_TR,
+            BtnTl2 = 0x138 => BTN_TL2,
+            BtnTr2 = 0x139 => BTN_TR2,
+            BtnSelect = 0x13a => BTN_SELECT,
+            BtnStart = 0x13b => BTN_START,
+            BtnMode = 0x13c => BTN_MODE,
+            BtnThumbl = 0x13d => BTN_THUMBL,
+            BtnThumbr = 0x13e => BTN_THUMBR,
+            BtnToolPen = 0x140 => BTN_TOOL_PEN,
+            BtnToolRubber = 0x141 => BTN_TOOL_RUBBER,
+            BtnToolBrush = 0x142 => BTN_TOOL_BRUSH,
+            BtnToolPencil = 0x143 => BTN_TOOL_PENCIL,
+            BtnToolAirbrush = 0x144 => BTN_TOOL_AIRBRUSH,
+            BtnToolFinger = 0x145 => BTN_TOOL_FINGER,
+            BtnToolMouse = 0x146 => BTN_TOOL_MOUSE,
+            BtnToolLens = 0x147 => BTN_TOOL_LENS,
+            BtnToolQuinttap = 0x148 => BTN_TOOL_QUINTTAP,
+            BtnStylus3 = 0x149 => BTN_STYLUS3,
+            BtnTouch = 0x14a => BTN_TOUCH,
+            BtnStylus = 0x14b => BTN_STYLUS,
+            BtnStylus2 = 0x14c => BTN_STYLUS2,
+            BtnToolDoubletap = 0x14d => BTN_TOOL_DOUBLETAP,
+            BtnToolTripletap = 0x14e => BTN_TOOL_TRIPLETAP,
+            BtnToolQuadtap = 0x14f => BTN_TOOL_QUADTAP,
+            BtnGearDown = 0x150 => BTN_GEAR_DOWN,
+            BtnGearUp = 0x151 => BTN_GEAR_UP,
+            BtnDpadUp = 0x220 => BTN_DPAD_UP,
+            BtnDpadDown = 0x221 => BTN_DPAD_DOWN,
+            BtnDpadLeft = 0x222 => BTN_DPAD_LEFT,
+            BtnDpadRight = 0x223 => BTN_DPAD_RIGHT,
+    }
+}
+
+evdev_codes! {
+    /// A relative axis, for `EV_REL` events.
+    RelAxis {
+            X = 0x00 => REL_X,
+            Y = 0x01 => REL_Y,
+            Z = 0x02 => REL_Z,
+            Rx = 0x03 => REL_RX,
+            Ry = 0x04 => REL_RY,
+            Rz = 0x05 => REL_RZ,
+            Hwheel = 0x06 => REL_HWHEEL,
+            Dial = 0x07 => REL_DIAL,
+            Wheel = 0x08 => REL_WHEEL,
+            Misc = 0x09 => REL_MISC,
+            WheelHiRes = 0x0b => REL_WHEEL_HI_RES,
+            HwheelHiRes = 0x0c => REL_HWHEEL_HI_RES,
+    }
+}
+
+evdev_codes! {
+    /// An absolute axis, for `EV_ABS` events.
+    AbsAxis {
+            X = 0x00 => ABS_X,
+            Y = 0x01 => ABS_Y,
+            Z = 0x02 => ABS_Z,
+            Rx = 0x03 => ABS_RX,
+            Ry = 0x04 => ABS_RY,
+            Rz = 0x05 => ABS_RZ,
+            Throttle = 0x06 => ABS_THROTTLE,
+            Rudder = 0x07 => ABS_RUDDER,
+            Wheel = 0x08 => ABS_WHEEL,
+            Gas = 0x09 => ABS_GAS,
+            Brake = 0x0a => ABS_BRAKE,
+            Hat0x = 0x10 => ABS_HAT0X,
+            Hat0y = 0x11 => ABS_HAT0Y,
+            Hat1x = 0x12 => ABS_HAT1X,
+            Hat1y = 0x13 => ABS_HAT1Y,
+            Hat2x = 0x14 => ABS_HAT2X,
+            Hat2y = 0x15 => ABS_HAT2Y,
+            Hat3x = 0x16 => ABS_HAT3X,
+            Hat3y = 0x17 => ABS_HAT3Y,
+            Pressure = 0x18 => ABS_PRESSURE,
+            Distance = 0x19 => ABS_DISTANCE,
+            TiltX = 0x1a => ABS_TILT_X,
+            TiltY = 0x1b => ABS_TILT_Y,
+            ToolWidth = 0x1c => ABS_TOOL_WIDTH,
+            Volume = 0x20 => ABS_VOLUME,
+            Profile = 0x21 => ABS_PROFILE,
+            Misc = 0x28 => ABS_MISC,
+            MtSlot = 0x2f => ABS_MT_SLOT,
+            MtTouchMajor = 0x30 => ABS_MT_TOUCH_MAJOR,
+            MtTouchMinor = 0x31 => ABS_MT_TOUCH_MINOR,
+            MtWidthMajor = 0x32 => ABS_MT_WIDTH_MAJOR,
+            MtWidthMinor = 0x33 => ABS_MT_WIDTH_MINOR,
+            MtOrientation = 0x34 => ABS_MT_ORIENTATION,
+            MtPositionX = 0x35 => ABS_MT_POSITION_X,
+            MtPositionY = 0x36 => ABS_MT_POSITION_Y,
+            MtToolType = 0x37 => ABS_MT_TOOL_TYPE,
+            MtBlobId = 0x38 => ABS_MT_BLOB_ID,
+            MtTrackingId = 0x39 => ABS_MT_TRACKING_ID,
+            MtPressure = 0x3a => ABS_MT_PRESSURE,
+            MtDistance = 0x3b => ABS_MT_DISTANCE,
+            MtToolX = 0x3c => ABS_MT_TOOL_X,
+            MtToolY = 0x3d => ABS_MT_TOOL_Y,
+    }
+}
+
+/// An input event decoded by [`InputEvent::decode`].
+///
+/// Converting it back into an [`InputEvent`] gives the original raw event.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodedEvent {
+    /// A key or button was pressed or released.
+    ///
+    /// Autorepeat events, with a value of 2, are decoded as [`DecodedEvent::Other`].
+    Key {
+        /// The key or button.
+        code: Key,
+        /// Whether it was pressed rather than released.
+        pressed: bool,
+    },
+    /// A relative axis moved, e.g. of a mouse.
+    RelMotion {
+        /// The axis.
+        axis: RelAxis,
+        /// How far it moved.
+        delta: i32,
+    },
+    /// An absolute axis moved, e.g. of a tablet.
+    AbsMotion {
+        /// The axis.
+        axis: AbsAxis,
+        /// Its new value.
+        value: i32,
+    },
+    /// A `SYN_REPORT`, which ends a group of events which happened at the same time.
+    Sync,
+    /// Any other event.
+    Other {
+        /// The event type.
+        ty: u16,
+        /// The event code.
+        code: u16,
+        /// The event value.
+        value: u32,
+    },
+}
+
+impl InputEvent {
+    /// Decodes the event's type, code and value.
+    pub fn decode(&self) -> DecodedEvent {
+        let (ty, code, value) = (self.event_type, self.code, self.value);
+        match ty {
+            _ if ty == u16::from(EV_KEY) && value <= 1 => DecodedEvent::Key {
+                code: code.into(),
+                pressed: value == 1,
+            },
+            _ if ty == u16::from(EV_REL) => DecodedEvent::RelMotion {
+                axis: code.into(),
+                delta: value as i32,
+            },
+            _ if ty == u16::from(EV_ABS) => DecodedEvent::AbsMotion {
+                axis: code.into(),
+                value: value as i32,
+            },
+            EV_SYN if code == SYN_REPORT && value == 0 => DecodedEvent::Sync,
+            _ => DecodedEvent::Other { ty, code, value },
+        }
+    }
+}
+
+impl From<DecodedEvent> for InputEvent {
+    fn from(event: DecodedEvent) -> Self {
+        let (event_type, code, value) = match event {
+            DecodedEvent::Key { code, pressed } => (EV_KEY.into(), code.into(), pressed.into()),
+            DecodedEvent::RelMotion { axis, delta } => (EV_REL.into(), axis.into(), delta as u32),
+            DecodedEvent::AbsMotion { axis, value } => (EV_ABS.into(), axis.into(), value as u32),
+            DecodedEvent::Sync => (EV_SYN, SYN_REPORT, 0),
+            DecodedEvent::Other { ty, code, value } => (ty, code, value),
+        };
+        Self {
+            event_type,
+            code,
+            value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let events = [
+            (EV_KEY.into(), 30, 1),
+            (EV_KEY.into(), 0x110, 0),
+            (EV_KEY.into(), 30, 2),
+            (EV_KEY.into(), 0x2ff, 1),
+            (EV_REL.into(), 8, -1i32 as u32),
+            (EV_ABS.into(), 0x35, 1234),
+            (EV_SYN, SYN_REPORT, 0),
+            (EV_SYN, 3, 0),
+            (0x11, 1, 1),
+        ];
+        for (event_type, code, value) in events {
+            let event = InputEvent {
+                event_type,
+                code,
+                value,
+            };
+            assert_eq!(InputEvent::from(event.decode()), event);
+        }
+        assert_eq!(
+            InputEvent {
+                event_type: EV_KEY.into(),
+                code: 30,
+                value: 1,
+            }
+            .decode(),
+            DecodedEvent::Key {
+                code: Key::A,
+                pressed: true,
+            }
+        );
+        assert_eq!(Key::from(0x14a), Key::BtnTouch);
+        assert_eq!(RelAxis::from(0x0d), RelAxis::Unknown(0x0d));
+        assert_eq!(u16::from(AbsAxis::MtTrackingId), 0x39);
+    }
+}
@@ -0,0 +1,850 @@
+//! Driver for VirtIO input devices.
+
+mod codes;
+pub mod mt;
+mod pointer;
+
+pub use self::codes::{AbsAxis, DecodedEvent, Key, RelAxis};
+pub use self::pointer::PointerTransform;
+
+use super::common::Feature;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::volatile::{volread, volwrite, ReadOnly, VolatileReadable, WriteOnly};
+use crate::Error;
+use alloc::{boxed::Box, string::String};
+use bitflags::bitflags;
+use core::cmp::min;
+use core::mem::size_of;
+use core::ptr::{addr_of, NonNull};
+use core::task::{Context, Poll, Waker};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Virtual human interface devices such as keyboards, mice and tablets.
+///
+/// An instance of the virtio device represents one such input device.
+/// Device behavior mirrors that of the evdev layer in Linux,
+/// making pass-through implementations on top of evdev easy.
+///
+/// `QUEUE_SIZE` is the size of the event queue, which is the number of events the device can
+/// send before the driver takes them from the queue. It must be a power of two no larger than the
+/// device allows. Each slot costs 8 bytes for the event buffer, 8 bytes in the driver's ring of
+/// pending events and 26 bytes of DMA memory for the queue itself.
+///
+/// [`VirtIOInput::ack_interrupt`] moves events from the queue into a ring of up to `QUEUE_SIZE`
+/// pending events and gives their buffers straight back to the device, so the device can keep
+/// sending events while they wait to be popped. If the ring is full, further events are dropped
+/// and counted by [`VirtIOInput::dropped_events`].
+pub struct VirtIOInput<H: Hal, T: Transport, const QUEUE_SIZE: usize = DEFAULT_QUEUE_SIZE> {
+    transport: T,
+    event_queue: VirtQueue<H, QUEUE_SIZE>,
+    status_queue: VirtQueue<H, STATUS_QUEUE_SIZE>,
+    event_buf: Box<[InputEvent; QUEUE_SIZE]>,
+    /// Events which have been taken from the event queue but not yet popped.
+    pending: EventRing<QUEUE_SIZE>,
+    /// The number of events which were dropped because `pending` was full.
+    dropped_events: u64,
+    /// Buffers for status events, which the device may still be reading.
+    status_buf: Box<[InputEvent; STATUS_QUEUE_SIZE]>,
+    /// The status buffer which was added to the status queue with each token, until the device
+    /// has used it.
+    status_token_slots: [Option<u16>; STATUS_QUEUE_SIZE],
+    /// The waker to wake from [`VirtIOInput::ack_interrupt`] once an event arrives.
+    event_waker: Option<Waker>,
+    config: NonNull<Config>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIOInput<H, T, QUEUE_SIZE> {
+    /// Create a new VirtIO-Input driver.
+    ///
+    /// Returns [`Error::InvalidParam`] if the device's event queue is smaller than `QUEUE_SIZE`.
+    pub fn new(mut transport: T, hal: H) -> Result<Self, Error> {
+        let mut event_buf = Box::new([InputEvent::default(); QUEUE_SIZE]);
+
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+
+        let config = transport.config_space::<Config>()?;
+
+        let mut event_queue = VirtQueue::new(
+            hal,
+            &mut transport,
+            QUEUE_EVENT,
+            negotiated_features.contains(Feature::RING_INDIRECT_DESC),
+            negotiated_features.contains(Feature::RING_EVENT_IDX),
+        )?;
+        let status_queue = VirtQueue::new(
+            hal,
+            &mut transport,
+            QUEUE_STATUS,
+            negotiated_features.contains(Feature::RING_INDIRECT_DESC),
+            negotiated_features.contains(Feature::RING_EVENT_IDX),
+        )?;
+        for (i, event) in event_buf.as_mut().iter_mut().enumerate() {
+            // Safe because the buffer lasts as long as the queue.
+            let token = unsafe { event_queue.add(&[], &mut [event.as_bytes_mut()])? };
+            assert_eq!(token, i as u16);
+        }
+        if event_queue.should_notify() {
+            transport.notify(QUEUE_EVENT);
+        }
+
+        transport.finish_init();
+
+        Ok(VirtIOInput {
+            transport,
+            event_queue,
+            status_queue,
+            event_buf,
+            pending: EventRing::new(),
+            dropped_events: 0,
+            status_buf: Box::new([InputEvent::default(); STATUS_QUEUE_SIZE]),
+            status_token_slots: [None; STATUS_QUEUE_SIZE],
+            event_waker: None,
+            config,
+        })
+    }
+
+    /// Acknowledge interrupt and process events.
+    ///
+    /// This takes any new events from the event queue and posts their buffers back to the device.
+    /// If any events are pending, it wakes the waker registered with
+    /// [`VirtIOInput::register_waker`].
+    pub fn ack_interrupt(&mut self) -> bool {
+        let acked = self.transport.ack_interrupt();
+        self.reap_events();
+        if !self.pending.is_empty() {
+            if let Some(waker) = self.event_waker.take() {
+                waker.wake();
+            }
+        }
+        acked
+    }
+
+    /// Registers a waker to be woken by [`VirtIOInput::ack_interrupt`] once an event arrives,
+    /// replacing any previous one.
+    ///
+    /// The waker is only woken once, so it must be registered again after each wakeup.
+    pub fn register_waker(&mut self, waker: &Waker) {
+        match &self.event_waker {
+            Some(old) if old.will_wake(waker) => {}
+            _ => self.event_waker = Some(waker.clone()),
+        }
+    }
+
+    /// Pops the next pending event, or else registers the task's waker to be woken by
+    /// [`VirtIOInput::ack_interrupt`] and returns [`Poll::Pending`].
+    pub fn poll_event(&mut self, cx: &mut Context) -> Poll<InputEvent> {
+        if let Some(event) = self.pop_pending_event() {
+            return Poll::Ready(event);
+        }
+        self.register_waker(cx.waker());
+        // Check again, in case an event arrived and the interrupt was acknowledged before the
+        // waker was registered.
+        match self.pop_pending_event() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Pop the pending event.
+    pub fn pop_pending_event(&mut self) -> Option<InputEvent> {
+        let mut event = [InputEvent::default()];
+        if self.pop_pending_events(&mut event) == 1 {
+            Some(event[0])
+        } else {
+            None
+        }
+    }
+
+    /// Pops as many pending events as fit in `out`, in the order in which the device sent them,
+    /// and returns how many there were.
+    ///
+    /// This is cheaper than calling [`VirtIOInput::pop_pending_event`] for each event of a burst.
+    /// Events which don't fit in `out` are left for the next call.
+    pub fn pop_pending_events(&mut self, out: &mut [InputEvent]) -> usize {
+        self.reap_events();
+        let mut count = 0;
+        for out_event in out.iter_mut() {
+            let Some(event) = self.pending.pop() else {
+                break;
+            };
+            *out_event = event;
+            count += 1;
+        }
+        count
+    }
+
+    /// Returns the number of events which the device sent but which were dropped, because too
+    /// many events were waiting to be popped.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events
+    }
+
+    /// Moves all used events from the event queue to `pending`, and posts their buffers back to
+    /// the device with at most one notification.
+    fn reap_events(&mut self) {
+        let mut reposted = false;
+        while let Some(token) = self.event_queue.peek_used() {
+            let event = &mut self.event_buf[token as usize];
+            // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it
+            // is still valid.
+            if unsafe {
+                self.event_queue
+                    .pop_used(token, &[], &mut [event.as_bytes_mut()])
+            }
+            .is_err()
+            {
+                break;
+            }
+            if !self.pending.push(*event) {
+                self.dropped_events += 1;
+            }
+            // requeue
+            // Safe because buffer lasts as long as the queue.
+            let Ok(new_token) = (unsafe { self.event_queue.add(&[], &mut [event.as_bytes_mut()]) })
+            else {
+                break;
+            };
+            // This only works because nothing happen between `pop_used` and `add` that affects
+            // the list of free descriptors in the queue, so `add` reuses the descriptor which
+            // was just freed by `pop_used`.
+            assert_eq!(new_token, token);
+            reposted = true;
+        }
+        if reposted && self.event_queue.should_notify() {
+            self.transport.notify(QUEUE_EVENT);
+        }
+    }
+
+    /// Sends an event to the device on the status queue, e.g. to light a keyboard LED.
+    ///
+    /// This doesn't wait for the device to use the event. Returns [`Error::QueueFull`] if the
+    /// device hasn't yet used the events which were sent before, so there is no room for another.
+    pub fn send_status(&mut self, event_type: u16, code: u16, value: u32) -> Result<(), Error> {
+        self.reclaim_status_buffers()?;
+        let slot = (0..STATUS_QUEUE_SIZE as u16)
+            .find(|slot| !self.status_token_slots.contains(&Some(*slot)))
+            .ok_or(Error::QueueFull)?;
+        let event = &mut self.status_buf[usize::from(slot)];
+        *event = InputEvent {
+            event_type,
+            code,
+            value,
+        };
+        // Safe because the buffer lasts as long as the queue, and isn't accessed again until it is
+        // popped by `reclaim_status_buffers`.
+        let token = unsafe { self.status_queue.add(&[event.as_bytes()], &mut [])? };
+        self.status_token_slots[usize::from(token)] = Some(slot);
+        if self.status_queue.should_notify() {
+            self.transport.notify(QUEUE_STATUS);
+        }
+        Ok(())
+    }
+
+    /// Sets the state of the device's LEDs, e.g. caps lock, by sending an `EV_LED` event for each
+    /// of them followed by a `SYN_REPORT`.
+    ///
+    /// Like [`VirtIOInput::send_status`] this doesn't wait for the device.
+    pub fn set_leds(&mut self, leds: LedFlags) -> Result<(), Error> {
+        for (code, led) in [
+            LedFlags::NUM_LOCK,
+            LedFlags::CAPS_LOCK,
+            LedFlags::SCROLL_LOCK,
+            LedFlags::COMPOSE,
+            LedFlags::KANA,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            self.send_status(EV_LED, code as u16, leds.contains(led).into())?;
+        }
+        self.send_status(EV_SYN, SYN_REPORT, 0)
+    }
+
+    /// Pops any status buffers which the device has used, so they can be reused.
+    fn reclaim_status_buffers(&mut self) -> Result<(), Error> {
+        while let Some(token) = self.status_queue.peek_used() {
+            let slot = self.status_token_slots[usize::from(token)]
+                .take()
+                .ok_or(Error::WrongToken)?;
+            let event = &self.status_buf[usize::from(slot)];
+            // Safe because we are passing the same buffer as we passed to `VirtQueue::add`.
+            unsafe {
+                self.status_queue
+                    .pop_used(token, &[event.as_bytes()], &mut [])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Query a specific piece of information by `select` and `subsel`, and write
+    /// result to `out`, return the result size.
+    pub fn query_config_select(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+        out: &mut [u8],
+    ) -> u8 {
+        let size;
+        // Safe because config points to a valid MMIO region for the config space.
+        unsafe {
+            volwrite!(self.config, select, select as u8);
+            volwrite!(self.config, subsel, subsel);
+            size = volread!(self.config, size);
+            let size_to_copy = min(usize::from(size), out.len());
+            for (i, out_item) in out.iter_mut().take(size_to_copy).enumerate() {
+                *out_item = addr_of!((*self.config.as_ptr()).data[i]).vread();
+            }
+        }
+        size
+    }
+
+    /// Queries a specific piece of information by `select` and `subsel`, allocates a sufficiently
+    /// large byte buffer for it, and returns it.
+    fn query_config_select_alloc(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+    ) -> Result<Box<[u8]>, Error> {
+        // Safe because config points to a valid MMIO region for the config space.
+        unsafe {
+            volwrite!(self.config, select, select as u8);
+            volwrite!(self.config, subsel, subsel);
+            let size = usize::from(volread!(self.config, size));
+            if size > CONFIG_DATA_MAX_LENGTH {
+                return Err(Error::IoError);
+            }
+            let mut buf = u8::new_box_slice_zeroed(size);
+            for i in 0..size {
+                buf[i] = addr_of!((*self.config.as_ptr()).data[i]).vread();
+            }
+            Ok(buf)
+        }
+    }
+
+    /// Queries a specific piece of information by `select` and `subsel` into a newly-allocated
+    /// buffer, and tries to convert it to a string.
+    ///
+    /// Returns an error if it is not valid UTF-8.
+    fn query_config_string(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+    ) -> Result<String, Error> {
+        Ok(String::from_utf8(
+            self.query_config_select_alloc(select, subsel)?.into(),
+        )?)
+    }
+
+    /// Queries and returns the name of the device, or an error if it is not valid UTF-8.
+    pub fn name(&mut self) -> Result<String, Error> {
+        self.query_config_string(InputConfigSelect::IdName, 0)
+    }
+
+    /// Queries and returns the serial number of the device, or an error if it is not valid UTF-8.
+    pub fn serial_number(&mut self) -> Result<String, Error> {
+        self.query_config_string(InputConfigSelect::IdSerial, 0)
+    }
+
+    /// Queries the name of the device into `buf` without allocating, and returns its length.
+    ///
+    /// This is the non-allocating counterpart of [`VirtIOInput::name`]. Returns
+    /// [`Error::InvalidParam`] if `buf` is too short for it.
+    pub fn name_into(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.query_config_into(InputConfigSelect::IdName, 0, buf)
+    }
+
+    /// Queries the serial number of the device into `buf` without allocating, and returns its
+    /// length.
+    ///
+    /// This is the non-allocating counterpart of [`VirtIOInput::serial_number`]. Returns
+    /// [`Error::InvalidParam`] if `buf` is too short for it.
+    pub fn serial(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.query_config_into(InputConfigSelect::IdSerial, 0, buf)
+    }
+
+    /// Queries a specific piece of information by `select` and `subsel` into `buf`, and returns
+    /// its size.
+    ///
+    /// Returns an error rather than truncating it if `buf` is too short.
+    fn query_config_into(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let size = usize::from(self.query_config_select(select, subsel, buf));
+        if size > CONFIG_DATA_MAX_LENGTH {
+            Err(Error::IoError)
+        } else if size > buf.len() {
+            Err(Error::InvalidParam)
+        } else {
+            Ok(size)
+        }
+    }
+
+    /// Queries and returns the ID information of the device.
+    pub fn ids(&mut self) -> Result<DevIDs, Error> {
+        let mut ids = DevIDs::default();
+        let size = self.query_config_select(InputConfigSelect::IdDevids, 0, ids.as_bytes_mut());
+        if usize::from(size) == size_of::<DevIDs>() {
+            Ok(ids)
+        } else {
+            Err(Error::IoError)
+        }
+    }
+
+    /// Queries and returns the input properties of the device.
+    pub fn prop_bits(&mut self) -> Result<Box<[u8]>, Error> {
+        self.query_config_select_alloc(InputConfigSelect::PropBits, 0)
+    }
+
+    /// Queries and returns a bitmap of supported event codes for the given event type.
+    ///
+    /// If the event type is not supported an empty slice will be returned.
+    pub fn ev_bits(&mut self, event_type: u8) -> Result<Box<[u8]>, Error> {
+        self.query_config_select_alloc(InputConfigSelect::EvBits, event_type)
+    }
+
+    /// Queries and returns the set of event types which the device supports, e.g. `EV_KEY` or
+    /// `EV_ABS`.
+    pub fn supported_event_types(&mut self) -> EvBitmap {
+        let mut bitmap = EvBitmap::default();
+        for event_type in 0..EV_CNT as u8 {
+            if self.query_config_select(InputConfigSelect::EvBits, event_type, &mut []) != 0 {
+                bitmap.bits[usize::from(event_type / 8)] |= 1 << (event_type % 8);
+            }
+        }
+        bitmap
+    }
+
+    /// Queries and returns the set of key and button codes which the device supports.
+    pub fn supported_keys(&mut self) -> Result<KeyBitmap, Error> {
+        self.ev_bitmap(EV_KEY)
+    }
+
+    /// Queries and returns the set of relative axes which the device supports, e.g. those of a
+    /// mouse.
+    pub fn supported_rel_axes(&mut self) -> Result<RelBitmap, Error> {
+        self.ev_bitmap(EV_REL)
+    }
+
+    /// Queries and returns the set of absolute axes which the device supports, e.g. those of a
+    /// tablet or touchscreen.
+    pub fn supported_axes(&mut self) -> Result<AbsBitmap, Error> {
+        self.ev_bitmap(EV_ABS)
+    }
+
+    /// Queries the bitmap of supported codes for the given event type.
+    ///
+    /// Returns an error if the device returns a bitmap longer than the codes of the event type.
+    fn ev_bitmap<const LEN: usize>(&mut self, event_type: u8) -> Result<InputBitmap<LEN>, Error> {
+        let mut bitmap = InputBitmap::default();
+        let size =
+            self.query_config_select(InputConfigSelect::EvBits, event_type, &mut bitmap.bits);
+        if usize::from(size) <= LEN {
+            Ok(bitmap)
+        } else {
+            Err(Error::IoError)
+        }
+    }
+
+    /// Queries and returns information about the given axis of the device.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't have the axis.
+    pub fn abs_info(&mut self, axis: u8) -> Result<AbsInfo, Error> {
+        let mut info = AbsInfo::default();
+        let size = self.query_config_select(InputConfigSelect::AbsInfo, axis, info.as_bytes_mut());
+        if size == 0 {
+            Err(Error::Unsupported)
+        } else if usize::from(size) == size_of::<AbsInfo>() {
+            Ok(info)
+        } else {
+            Err(Error::IoError)
+        }
+    }
+
+    /// Scales a value of the given absolute axis to the range `0..target_range`, e.g. to map a
+    /// tablet's coordinates onto screen pixels.
+    ///
+    /// This queries the axis each time; use [`AbsInfo::scale`] to scale many values.
+    pub fn scale_abs(&mut self, axis: u8, value: u32, target_range: u32) -> Result<u32, Error> {
+        Ok(self.abs_info(axis)?.scale(value, target_range))
+    }
+}
+
+// SAFETY: The config space can be accessed from any thread.
+unsafe impl<H: Hal, T: Transport + Send, const QUEUE_SIZE: usize> Send
+    for VirtIOInput<H, T, QUEUE_SIZE>
+where
+    VirtQueue<H, QUEUE_SIZE>: Send,
+    VirtQueue<H, STATUS_QUEUE_SIZE>: Send,
+{
+}
+
+// SAFETY: An '&VirtIOInput` can't do anything, all methods take `&mut self`.
+unsafe impl<H: Hal, T: Transport + Sync, const QUEUE_SIZE: usize> Sync
+    for VirtIOInput<H, T, QUEUE_SIZE>
+where
+    VirtQueue<H, QUEUE_SIZE>: Sync,
+    VirtQueue<H, STATUS_QUEUE_SIZE>: Sync,
+{
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIOInput<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.queue_unset(QUEUE_EVENT);
+        self.transport.queue_unset(QUEUE_STATUS);
+    }
+}
+
+const CONFIG_DATA_MAX_LENGTH: usize = 128;
+
+/// Select value used for [`VirtIOInput::query_config_select()`].
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum InputConfigSelect {
+    /// Returns the name of the device, in u.string. subsel is zero.
+    IdName = 0x01,
+    /// Returns the serial number of the device, in u.string. subsel is zero.
+    IdSerial = 0x02,
+    /// Returns ID information of the device, in u.ids. subsel is zero.
+    IdDevids = 0x03,
+    /// Returns input properties of the device, in u.bitmap. subsel is zero.
+    /// Individual bits in the bitmap correspond to INPUT_PROP_* constants used
+    /// by the underlying evdev implementation.
+    PropBits = 0x10,
+    /// subsel specifies the event type using EV_* constants in the underlying
+    /// evdev implementation. If size is non-zero the event type is supported
+    /// and a bitmap of supported event codes is returned in u.bitmap. Individual
+    /// bits in the bitmap correspond to implementation-defined input event codes,
+    /// for example keys or pointing device axes.
+    EvBits = 0x11,
+    /// subsel specifies the absolute axis using ABS_* constants in the underlying
+    /// evdev implementation. Information about the axis will be returned in u.abs.
+    AbsInfo = 0x12,
+}
+
+#[repr(C)]
+struct Config {
+    select: WriteOnly<u8>,
+    subsel: WriteOnly<u8>,
+    size: ReadOnly<u8>,
+    _reserved: [ReadOnly<u8>; 5],
+    data: [ReadOnly<u8>; CONFIG_DATA_MAX_LENGTH],
+}
+
+/// Information about an axis of an input device, typically a joystick.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, PartialEq, FromBytes, FromZeroes)]
+pub struct AbsInfo {
+    /// The minimum value for the axis.
+    pub min: u32,
+    /// The maximum value for the axis.
+    pub max: u32,
+    /// The fuzz value used to filter noise from the event stream.
+    pub fuzz: u32,
+    /// The size of the dead zone; values less than this will be reported as 0.
+    pub flat: u32,
+    /// The resolution for values reported for the axis.
+    pub res: u32,
+}
+
+impl AbsInfo {
+    /// Scales a value of the axis to the range `0..target_range`, so that `min` maps to 0 and
+    /// `max` maps to `target_range - 1`.
+    ///
+    /// The limits and the value are treated as signed, as in evdev, and values outside the limits
+    /// are clamped to them. If `min` is greater than `max` the axis is inverted. Returns 0 if
+    /// `target_range` is 0 or the axis has no range.
+    pub fn scale(&self, value: u32, target_range: u32) -> u32 {
+        let (min, max) = (i64::from(self.min as i32), i64::from(self.max as i32));
+        if max == min || target_range == 0 {
+            return 0;
+        }
+        let value = i64::from(value as i32).clamp(min.min(max), min.max(max));
+        // If the range is inverted, the numerator and denominator are both negative.
+        ((value - min) * (i64::from(target_range) - 1) / (max - min)) as u32
+    }
+}
+
+/// The identifiers of a VirtIO input device.
+#[repr(C)]
+#[derive(AsBytes, Clone, Debug, Default, Eq, PartialEq, FromBytes, FromZeroes)]
+pub struct DevIDs {
+    /// The bustype identifier.
+    pub bustype: u16,
+    /// The vendor identifier.
+    pub vendor: u16,
+    /// The product identifier.
+    pub product: u16,
+    /// The version identifier.
+    pub version: u16,
+}
+
+/// A bitmap of evdev codes supported by an input device, `LEN` bytes long.
+///
+/// Bits past the end of the bitmap which the device returned are clear.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InputBitmap<const LEN: usize> {
+    bits: [u8; LEN],
+}
+
+impl<const LEN: usize> InputBitmap<LEN> {
+    /// Returns whether the given code is in the bitmap.
+    pub fn contains(&self, code: u16) -> bool {
+        self.bits
+            .get(usize::from(code / 8))
+            .is_some_and(|byte| byte & (1 << (code % 8)) != 0)
+    }
+
+    /// Returns whether no codes are in the bitmap.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&byte| byte == 0)
+    }
+
+    /// Returns an iterator over the codes in the bitmap, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..(LEN * 8) as u16).filter(|&code| self.contains(code))
+    }
+
+    /// Returns the raw bitmap, in which bit `n % 8` of byte `n / 8` is set for code `n`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+impl<const LEN: usize> Default for InputBitmap<LEN> {
+    fn default() -> Self {
+        Self { bits: [0; LEN] }
+    }
+}
+
+/// The set of event types (`EV_*`) supported by a device, as returned by
+/// [`VirtIOInput::supported_event_types`].
+pub type EvBitmap = InputBitmap<{ EV_CNT / 8 }>;
+/// The set of key and button codes (`KEY_*` and `BTN_*`) supported by a device, as returned by
+/// [`VirtIOInput::supported_keys`].
+pub type KeyBitmap = InputBitmap<{ KEY_CNT / 8 }>;
+/// The set of relative axes (`REL_*`) supported by a device, as returned by
+/// [`VirtIOInput::supported_rel_axes`].
+pub type RelBitmap = InputBitmap<{ REL_CNT / 8 }>;
+/// The set of absolute axes (`ABS_*`) supported by a device, as returned by
+/// [`VirtIOInput::supported_axes`].
+pub type AbsBitmap = InputBitmap<{ ABS_CNT / 8 }>;
+
+/// Both queues use the same `virtio_input_event` struct. `type`, `code` and `value`
+/// are filled according to the Linux input layer (evdev) interface.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct InputEvent {
+    /// Event type.
+    pub event_type: u16,
+    /// Event code.
+    pub code: u16,
+    /// Event value.
+    pub value: u32,
+}
+
+/// A fixed-size ring of events, oldest first.
+struct EventRing<const LEN: usize> {
+    events: Box<[InputEvent; LEN]>,
+    /// The index of the oldest event.
+    head: usize,
+    /// The number of events in the ring.
+    len: usize,
+}
+
+impl<const LEN: usize> EventRing<LEN> {
+    fn new() -> Self {
+        Self {
+            events: Box::new([InputEvent::default(); LEN]),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds an event after the newest one, and returns whether there was room for it.
+    fn push(&mut self, event: InputEvent) -> bool {
+        if self.len == LEN {
+            return false;
+        }
+        self.events[(self.head + self.len) % LEN] = event;
+        self.len += 1;
+        true
+    }
+
+    /// Removes and returns the oldest event.
+    fn pop(&mut self) -> Option<InputEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % LEN;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+bitflags! {
+    /// The states of the LEDs of an input device, as set by [`VirtIOInput::set_leds`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct LedFlags: u8 {
+        /// Num lock.
+        const NUM_LOCK = 1 << 0;
+        /// Caps lock.
+        const CAPS_LOCK = 1 << 1;
+        /// Scroll lock.
+        const SCROLL_LOCK = 1 << 2;
+        /// Compose.
+        const COMPOSE = 1 << 3;
+        /// Kana.
+        const KANA = 1 << 4;
+    }
+}
+
+/// The evdev event type which separates groups of events.
+const EV_SYN: u16 = 0x00;
+/// The evdev event type for LEDs, whose codes are the bit positions in [`LedFlags`].
+const EV_LED: u16 = 0x11;
+/// The code of an `EV_SYN` event which marks the end of a group of events.
+const SYN_REPORT: u16 = 0;
+/// The evdev event type for keys and buttons.
+const EV_KEY: u8 = 0x01;
+/// The evdev event type for relative axes.
+const EV_REL: u8 = 0x02;
+/// The evdev event type for absolute axes.
+const EV_ABS: u8 = 0x03;
+
+/// The number of evdev event types.
+const EV_CNT: usize = 0x20;
+/// The number of evdev key and button codes.
+const KEY_CNT: usize = 0x300;
+/// The number of evdev relative axes.
+const REL_CNT: usize = 0x10;
+/// The number of evdev absolute axes.
+const ABS_CNT: usize = 0x40;
+
+const QUEUE_EVENT: u16 = 0;
+const QUEUE_STATUS: u16 = 1;
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX.union(Feature::RING_INDIRECT_DESC);
+
+/// The default size of the event queue.
+pub const DEFAULT_QUEUE_SIZE: usize = 32;
+/// The size of the status queue.
+const STATUS_QUEUE_SIZE: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+
+    #[test]
+    fn scale_abs_info() {
+        let info = AbsInfo {
+            min: 0,
+            max: 32767,
+            ..Default::default()
+        };
+        assert_eq!(info.scale(0, 1920), 0);
+        assert_eq!(info.scale(32767, 1920), 1919);
+        assert_eq!(info.scale(16384, 1920), 959);
+        assert_eq!(info.scale(40000, 1920), 1919);
+        assert_eq!(info.scale(100, 0), 0);
+
+        let signed = AbsInfo {
+            min: -100i32 as u32,
+            max: 100,
+            ..Default::default()
+        };
+        assert_eq!(signed.scale(-100i32 as u32, 201), 0);
+        assert_eq!(signed.scale(0, 201), 100);
+        assert_eq!(signed.scale(-200i32 as u32, 201), 0);
+        assert_eq!(AbsInfo::default().scale(5, 100), 0);
+
+        let inverted = AbsInfo {
+            min: 100,
+            max: 0,
+            ..Default::default()
+        };
+        assert_eq!(inverted.scale(100, 101), 0);
+        assert_eq!(inverted.scale(0, 101), 100);
+        assert_eq!(inverted.scale(25, 101), 75);
+        assert_eq!(inverted.scale(200, 101), 0);
+    }
+
+    #[test]
+    fn event_ring() {
+        let event = |code| InputEvent {
+            event_type: 0,
+            code,
+            value: 0,
+        };
+        let mut ring = EventRing::<2>::new();
+        assert_eq!(ring.pop(), None);
+        assert!(ring.push(event(1)));
+        assert!(ring.push(event(2)));
+        assert!(!ring.push(event(3)));
+        assert_eq!(ring.pop(), Some(event(1)));
+        assert!(ring.push(event(4)));
+        assert_eq!(ring.pop(), Some(event(2)));
+        assert_eq!(ring.pop(), Some(event(4)));
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn burst_without_loss() {
+        const QUEUE_SIZE: usize = 64;
+        let mut config_space = Config {
+            select: WriteOnly::default(),
+            subsel: WriteOnly::default(),
+            size: ReadOnly::new(0),
+            _reserved: Default::default(),
+            data: [const { ReadOnly::new(0) }; CONFIG_DATA_MAX_LENGTH],
+        };
+        let state = Arc::new(Mutex::new(State::new(2)));
+        let transport = FakeTransport {
+            device_type: DeviceType::Input,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut input =
+            VirtIOInput::<FakeHal, _, QUEUE_SIZE>::new(transport, FakeHal::new()).unwrap();
+
+        // The device sends a burst of events before the driver gets to any of them.
+        let event = |value| InputEvent {
+            event_type: 3,
+            code: 0,
+            value,
+        };
+        for value in 0..50 {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event(value).as_bytes());
+        }
+
+        input.ack_interrupt();
+        let mut events = [InputEvent::default(); QUEUE_SIZE];
+        assert_eq!(input.pop_pending_events(&mut events), 50);
+        assert!((0..50).map(event).eq(events[..50].iter().copied()));
+        assert_eq!(input.dropped_events(), 0);
+    }
+}
@@ -0,0 +1,270 @@
+//! Decoding of touchscreen events into touch points.
+//!
+//! Touchscreens send their contacts with the evdev multitouch protocol B: `ABS_MT_SLOT` selects a
+//! slot, the following `ABS_MT_*` events update the contact in it, and `SYN_REPORT` ends the
+//! frame. [`MultiTouchDecoder`] reassembles these into a [`TouchPoint`] for each slot which
+//! changed.
+
+use super::{InputEvent, EV_ABS, EV_KEY, EV_SYN, SYN_REPORT};
+
+/// Selects the slot which the following `ABS_MT_*` events apply to.
+const ABS_MT_SLOT: u16 = 0x2f;
+/// The X position of the contact in the current slot.
+const ABS_MT_POSITION_X: u16 = 0x35;
+/// The Y position of the contact in the current slot.
+const ABS_MT_POSITION_Y: u16 = 0x36;
+/// The ID of the contact in the current slot, or -1 when it is lifted.
+const ABS_MT_TRACKING_ID: u16 = 0x39;
+/// The X position of a single-touch device.
+const ABS_X: u16 = 0x00;
+/// The Y position of a single-touch device.
+const ABS_Y: u16 = 0x01;
+/// Whether a single-touch device is being touched.
+const BTN_TOUCH: u16 = 0x14a;
+
+/// The state of a contact, as reported by [`MultiTouchDecoder::push`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TouchPoint {
+    /// The slot of the contact.
+    pub slot: usize,
+    /// The ID which the device gave the contact. When the contact is released this is still the
+    /// ID it had.
+    pub tracking_id: i32,
+    /// The X position, in the units of the device's `ABS_MT_POSITION_X` axis.
+    pub x: i32,
+    /// The Y position, in the units of the device's `ABS_MT_POSITION_Y` axis.
+    pub y: i32,
+    /// Whether the contact is touching, rather than having been released.
+    pub pressed: bool,
+}
+
+/// The state of one slot of a [`MultiTouchDecoder`].
+#[derive(Clone, Copy, Debug, Default)]
+struct Slot {
+    point: TouchPoint,
+    /// Whether the slot has changed since the last `SYN_REPORT`.
+    changed: bool,
+}
+
+/// Keeps track of the contacts of a touchscreen, with up to `MAX_SLOTS` at a time, from its raw
+/// events.
+///
+/// Events for slots beyond `MAX_SLOTS` are ignored. Devices which only use the legacy
+/// single-touch protocol (`ABS_X`, `ABS_Y` and `BTN_TOUCH`) are reported as slot 0; once a
+/// device sends any `ABS_MT_*` event, its legacy events are ignored, as they only emulate the
+/// first contact.
+#[derive(Clone, Debug)]
+pub struct MultiTouchDecoder<const MAX_SLOTS: usize = 10> {
+    slots: [Slot; MAX_SLOTS],
+    /// The slot which `ABS_MT_*` events currently apply to, which may be beyond `MAX_SLOTS`.
+    current_slot: usize,
+    /// Whether the device has sent any multitouch events.
+    multitouch: bool,
+}
+
+impl<const MAX_SLOTS: usize> MultiTouchDecoder<MAX_SLOTS> {
+    /// Creates a decoder with no contacts.
+    pub fn new() -> Self {
+        Self {
+            slots: [Slot::default(); MAX_SLOTS],
+            current_slot: 0,
+            multitouch: false,
+        }
+    }
+
+    /// Returns the current state of the contact in the given slot, or `None` if the slot is beyond
+    /// `MAX_SLOTS`.
+    pub fn slot(&self, slot: usize) -> Option<TouchPoint> {
+        self.slots.get(slot).map(|slot| slot.point)
+    }
+
+    /// Processes an event from the device.
+    ///
+    /// On a `SYN_REPORT` this returns the state of each slot which changed since the previous one,
+    /// in slot order; for any other event the iterator is empty.
+    pub fn push(&mut self, event: &InputEvent) -> impl Iterator<Item = TouchPoint> + '_ {
+        let report = event.event_type == EV_SYN && event.code == SYN_REPORT;
+        if event.event_type == u16::from(EV_ABS) {
+            self.push_abs(event.code, event.value as i32);
+        } else if event.event_type == u16::from(EV_KEY)
+            && event.code == BTN_TOUCH
+            && !self.multitouch
+        {
+            self.update(0, |point| point.pressed = event.value != 0);
+        }
+        self.slots
+            .iter_mut()
+            .filter(move |_| report)
+            .filter(|slot| slot.changed)
+            .map(|slot| {
+                slot.changed = false;
+                slot.point
+            })
+    }
+
+    fn push_abs(&mut self, code: u16, value: i32) {
+        match code {
+            ABS_MT_SLOT => {
+                self.multitouch = true;
+                self.current_slot = usize::try_from(value).unwrap_or(usize::MAX);
+            }
+            ABS_MT_TRACKING_ID => {
+                self.multitouch = true;
+                self.update(self.current_slot, |point| {
+                    if value < 0 {
+                        point.pressed = false;
+                    } else {
+                        point.tracking_id = value;
+                        point.pressed = true;
+                    }
+                });
+            }
+            ABS_MT_POSITION_X => {
+                self.multitouch = true;
+                self.update(self.current_slot, |point| point.x = value);
+            }
+            ABS_MT_POSITION_Y => {
+                self.multitouch = true;
+                self.update(self.current_slot, |point| point.y = value);
+            }
+            ABS_X if !self.multitouch => self.update(0, |point| point.x = value),
+            ABS_Y if !self.multitouch => self.update(0, |point| point.y = value),
+            _ => {}
+        }
+    }
+
+    /// Updates the contact in the given slot, if it is within `MAX_SLOTS`.
+    fn update(&mut self, slot: usize, f: impl FnOnce(&mut TouchPoint)) {
+        if let Some(state) = self.slots.get_mut(slot) {
+            f(&mut state.point);
+            state.point.slot = slot;
+            state.changed = true;
+        }
+    }
+}
+
+impl<const MAX_SLOTS: usize> Default for MultiTouchDecoder<MAX_SLOTS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn event(event_type: u8, code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            event_type: event_type.into(),
+            code,
+            value: value as u32,
+        }
+    }
+
+    fn push_all<const MAX_SLOTS: usize>(
+        decoder: &mut MultiTouchDecoder<MAX_SLOTS>,
+        events: &[InputEvent],
+    ) -> Vec<TouchPoint> {
+        let mut points = Vec::new();
+        for event in events {
+            points.extend(decoder.push(event));
+        }
+        points
+    }
+
+    const SYN: InputEvent = InputEvent {
+        event_type: EV_SYN,
+        code: SYN_REPORT,
+        value: 0,
+    };
+
+    #[test]
+    fn two_contacts() {
+        let mut decoder = MultiTouchDecoder::<2>::new();
+        let points = push_all(
+            &mut decoder,
+            &[
+                event(EV_ABS, ABS_MT_SLOT, 0),
+                event(EV_ABS, ABS_MT_TRACKING_ID, 7),
+                event(EV_ABS, ABS_MT_POSITION_X, 10),
+                event(EV_ABS, ABS_MT_POSITION_Y, 20),
+                event(EV_ABS, ABS_MT_SLOT, 1),
+                event(EV_ABS, ABS_MT_TRACKING_ID, 8),
+                event(EV_ABS, ABS_MT_POSITION_X, 30),
+                event(EV_ABS, ABS_MT_POSITION_Y, 40),
+                // Slot 5 is beyond the maximum, so is ignored.
+                event(EV_ABS, ABS_MT_SLOT, 5),
+                event(EV_ABS, ABS_MT_TRACKING_ID, 9),
+                // Emulated single touch is ignored.
+                event(EV_ABS, ABS_X, 10),
+                SYN,
+            ],
+        );
+        assert_eq!(
+            points,
+            [
+                TouchPoint {
+                    slot: 0,
+                    tracking_id: 7,
+                    x: 10,
+                    y: 20,
+                    pressed: true,
+                },
+                TouchPoint {
+                    slot: 1,
+                    tracking_id: 8,
+                    x: 30,
+                    y: 40,
+                    pressed: true,
+                },
+            ]
+        );
+
+        let points = push_all(
+            &mut decoder,
+            &[
+                event(EV_ABS, ABS_MT_SLOT, 0),
+                event(EV_ABS, ABS_MT_TRACKING_ID, -1),
+                SYN,
+            ],
+        );
+        assert_eq!(
+            points,
+            [TouchPoint {
+                slot: 0,
+                tracking_id: 7,
+                x: 10,
+                y: 20,
+                pressed: false,
+            }]
+        );
+        assert!(decoder.slot(1).unwrap().pressed);
+        assert_eq!(decoder.slot(2), None);
+    }
+
+    #[test]
+    fn single_touch() {
+        let mut decoder = MultiTouchDecoder::<2>::new();
+        let points = push_all(
+            &mut decoder,
+            &[
+                event(EV_KEY, BTN_TOUCH, 1),
+                event(EV_ABS, ABS_X, 100),
+                event(EV_ABS, ABS_Y, 200),
+                SYN,
+                SYN,
+            ],
+        );
+        assert_eq!(
+            points,
+            [TouchPoint {
+                slot: 0,
+                tracking_id: 0,
+                x: 100,
+                y: 200,
+                pressed: true,
+            }]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Scaling of absolute pointer coordinates to screen pixels.
+
+use super::{AbsAxis, AbsInfo, VirtIOInput};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::Error;
+
+/// Maps the coordinates of an absolute pointing device, such as a tablet, to pixels of a screen.
+///
+/// The minimum of each axis maps to the first pixel and the maximum to the last, so an axis whose
+/// minimum is greater than its maximum is inverted. Coordinates beyond the axis limits are clamped
+/// to the edges of the screen, and every coordinate of an axis with no range maps to 0.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PointerTransform {
+    x: AbsInfo,
+    y: AbsInfo,
+    screen_w: u32,
+    screen_h: u32,
+}
+
+impl PointerTransform {
+    /// Creates a transform from the given X and Y axes to a screen of the given size in pixels.
+    pub fn new(abs_info_x: &AbsInfo, abs_info_y: &AbsInfo, screen_w: u32, screen_h: u32) -> Self {
+        Self {
+            x: *abs_info_x,
+            y: *abs_info_y,
+            screen_w,
+            screen_h,
+        }
+    }
+
+    /// Creates a transform from the `ABS_X` and `ABS_Y` axes of the given device to a screen of
+    /// the given size in pixels.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't have both axes.
+    pub fn from_device<H: Hal, T: Transport, const QUEUE_SIZE: usize>(
+        input: &mut VirtIOInput<H, T, QUEUE_SIZE>,
+        screen_w: u32,
+        screen_h: u32,
+    ) -> Result<Self, Error> {
+        let abs_info_x = input.abs_info(u16::from(AbsAxis::X) as u8)?;
+        let abs_info_y = input.abs_info(u16::from(AbsAxis::Y) as u8)?;
+        Ok(Self::new(&abs_info_x, &abs_info_y, screen_w, screen_h))
+    }
+
+    /// Maps the given coordinates of the device to a pixel of the screen.
+    pub fn map(&self, x: i32, y: i32) -> (u32, u32) {
+        (
+            self.x.scale(x as u32, self.screen_w),
+            self.y.scale(y as u32, self.screen_h),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axis(min: i32, max: i32) -> AbsInfo {
+        AbsInfo {
+            min: min as u32,
+            max: max as u32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn map() {
+        let transform = PointerTransform::new(&axis(0, 32767), &axis(32767, 0), 1920, 1080);
+        assert_eq!(transform.map(0, 0), (0, 1079));
+        assert_eq!(transform.map(32767, 32767), (1919, 0));
+        assert_eq!(transform.map(16384, 16384), (959, 539));
+        assert_eq!(transform.map(-5, 40000), (0, 0));
+
+        let empty = PointerTransform::new(&axis(10, 10), &axis(0, 100), 1920, 0);
+        assert_eq!(empty.map(10, 50), (0, 0));
+    }
+}
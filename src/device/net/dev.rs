@@ -1,8 +1,16 @@
-use alloc::vec;
+use alloc::{vec, vec::Vec};
+use core::array;
+use core::hint::spin_loop;
+use core::mem::size_of;
+use core::task::{Context, Poll, Waker};
 
-use super::net_buf::{RxBuffer, TxBuffer};
-use super::{EthernetAddress, VirtIONetRaw};
+use super::net_buf::{RxBuffer, TxBuffer, TxFrame};
+use super::{
+    EthernetAddress, LinkSpeed, NetEvent, NetInterrupt, NetStats, RssConfig, VirtIONetRaw,
+    VirtioNetHdr, DEFAULT_MTU, ETHERNET_HEADER_LEN,
+};
 use crate::{hal::Hal, transport::Transport, Error, Result};
+use log::warn;
 
 /// Driver for a VirtIO network device.
 ///
@@ -17,34 +25,267 @@ use crate::{hal::Hal, transport::Transport, Error, Result};
 /// Empty buffers are placed in one virtqueue for receiving packets, and
 /// outgoing packets are enqueued into another for transmission in that order.
 /// A third command queue is used to control advanced filtering features.
-pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
-    inner: VirtIONetRaw<H, T, QUEUE_SIZE>,
-    rx_buffers: [Option<RxBuffer>; QUEUE_SIZE],
+///
+/// With `VIRTIO_NET_F_MQ`, receive buffers are posted on up to `MAX_PAIRS`
+/// queue pairs, and [`VirtIONet::receive`] takes packets from each of them in
+/// turn. Packets are always sent on the first pair.
+pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize = 1> {
+    inner: VirtIONetRaw<H, T, QUEUE_SIZE, MAX_PAIRS>,
+    rx_buffers: [[Option<RxBuffer>; QUEUE_SIZE]; MAX_PAIRS],
+    /// The length with which receive buffers were allocated.
+    buf_len: usize,
+    /// A packet merged from several receive buffers, and the number of buffers
+    /// which it is still waiting for.
+    partial: Option<(RxBuffer, usize)>,
+    /// The queue pair to look for received packets on first.
+    next_pair: u16,
+    /// Transmit buffers which aren't in use, allocated up front so that
+    /// [`VirtIONet::poll_send`] doesn't allocate.
+    tx_free: Vec<Vec<u8>>,
+    /// The buffer holding the header and packet of each transmission started
+    /// by [`VirtIONet::poll_send`], by token, until the device has finished
+    /// with it.
+    tx_pending: [Option<Vec<u8>>; QUEUE_SIZE],
+    /// Wakers to wake from [`VirtIONet::wake_from_interrupt`].
+    rx_waker: Option<Waker>,
+    tx_waker: Option<Waker>,
+    link_waker: Option<Waker>,
+    /// Whether the link was up when the link waker was last checked.
+    waker_link_up: bool,
 }
 
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize>
+    VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>
+{
     /// Create a new VirtIO-Net driver.
+    ///
+    /// Receive buffers are allocated with length `buf_len`, or
+    /// [`VirtIONetRaw::min_rx_buffer_len`] if that is larger, e.g. because the
+    /// device reported a large MTU.
     pub fn new(transport: T, hal: H, buf_len: usize) -> Result<Self> {
         let mut inner = VirtIONetRaw::new(transport, hal)?;
+        let buf_len = buf_len
+            .max(inner.min_rx_buffer_len())
+            .next_multiple_of(size_of::<usize>());
 
-        const NONE_BUF: Option<RxBuffer> = None;
-        let mut rx_buffers = [NONE_BUF; QUEUE_SIZE];
-        for (i, rx_buf_place) in rx_buffers.iter_mut().enumerate() {
-            let mut rx_buf = RxBuffer::new(i, buf_len);
-            // Safe because the buffer lives as long as the queue.
-            let token = unsafe { inner.receive_begin(rx_buf.as_bytes_mut())? };
-            assert_eq!(token, i as u16);
-            *rx_buf_place = Some(rx_buf);
+        let mut rx_buffers: [[Option<RxBuffer>; QUEUE_SIZE]; MAX_PAIRS] =
+            array::from_fn(|_| array::from_fn(|_| None));
+        for pair in 0..inner.num_pairs() {
+            let mut queue_pair = inner.queue_pair(pair)?;
+            let rx_queue_size = usize::from(queue_pair.rx_queue_size());
+            for (i, rx_buf_place) in rx_buffers[usize::from(pair)]
+                .iter_mut()
+                .take(rx_queue_size)
+                .enumerate()
+            {
+                let mut rx_buf = RxBuffer::new(pair, i, buf_len);
+                // Safe because the buffer lives as long as the queue.
+                let token = unsafe { queue_pair.receive_begin(rx_buf.as_bytes_mut())? };
+                assert_eq!(token, i as u16);
+                *rx_buf_place = Some(rx_buf);
+            }
         }
 
-        Ok(VirtIONet { inner, rx_buffers })
+        let tx_buf_len =
+            inner.hdr_len() + usize::from(inner.mtu().unwrap_or(DEFAULT_MTU)) + ETHERNET_HEADER_LEN;
+        let tx_free = (0..inner.tx_queue_size())
+            .map(|_| Vec::with_capacity(tx_buf_len))
+            .collect();
+
+        let waker_link_up = inner.link_up();
+        Ok(VirtIONet {
+            inner,
+            rx_buffers,
+            buf_len,
+            partial: None,
+            next_pair: 0,
+            tx_free,
+            tx_pending: array::from_fn(|_| None),
+            rx_waker: None,
+            tx_waker: None,
+            link_waker: None,
+            waker_link_up,
+        })
+    }
+
+    /// Resets the device and initialises it again in place, e.g. after its
+    /// backend has restarted.
+    ///
+    /// The receive buffers stay posted to the device, and a packet which was
+    /// partly received from several merged buffers is dropped, as are packets
+    /// which [`VirtIONet::poll_send`] had started to send. Wakers are
+    /// woken as by [`VirtIONet::wake_from_interrupt`], so tasks waiting to
+    /// send can carry on. See [`VirtIONetRaw::reset`] for the details and
+    /// errors.
+    pub fn reset(&mut self) -> Result {
+        if let Some((rx_buf, remaining)) = self.partial.take() {
+            // Drop the rest of the merged packet which the device had already
+            // received, so that it isn't mistaken for the start of a packet.
+            let pair = rx_buf.pair;
+            self.recycle_rx_buffer(rx_buf)?;
+            for _ in 0..remaining {
+                let Some(token) = self.inner.poll_receive_on(pair) else {
+                    break;
+                };
+                let mut next_buf = self.take_rx_buffer(pair, token)?;
+                // Safe because `token` == `next_buf.idx`, we are passing the
+                // same buffer as we passed to `VirtQueue::add` and it is still
+                // valid.
+                unsafe {
+                    self.inner
+                        .queue_pair(pair)?
+                        .receive_complete_merged(token, next_buf.as_bytes_mut())?;
+                }
+                self.recycle_rx_buffer(next_buf)?;
+            }
+        }
+        let dropped = self.inner.reset()?;
+        // The device no longer has the transmit buffers which it hadn't sent.
+        for token in dropped.tokens(0) {
+            let Some(tx_buf) = self.tx_pending[usize::from(token)].take() else {
+                continue;
+            };
+            // Safe because `tx_buf` is the same buffer as was passed to
+            // `transmit_begin` for this token.
+            unsafe { self.inner.transmit_reclaim(token, &tx_buf) }?;
+            self.tx_free.push(tx_buf);
+        }
+        if self.buf_len < self.inner.min_rx_buffer_len() {
+            warn!(
+                "receive buffers of {} bytes are too small for the new MTU {:?}",
+                self.buf_len,
+                self.inner.mtu()
+            );
+        }
+        self.wake_from_interrupt();
+        Ok(())
+    }
+
+    /// Returns the number of queue pairs in use.
+    ///
+    /// See [`VirtIONetRaw::num_pairs`].
+    pub fn num_pairs(&self) -> u16 {
+        self.inner.num_pairs()
     }
 
     /// Acknowledge interrupt.
+    ///
+    /// See [`VirtIONetRaw::ack_interrupt`].
     pub fn ack_interrupt(&mut self) -> bool {
         self.inner.ack_interrupt()
     }
 
+    /// Acknowledges an interrupt, and returns what caused it.
+    ///
+    /// See [`VirtIONetRaw::ack_interrupt_detailed`].
+    pub fn ack_interrupt_detailed(&mut self) -> NetInterrupt {
+        self.inner.ack_interrupt_detailed()
+    }
+
+    /// Handles an interrupt from the device, for use with async network
+    /// stacks.
+    ///
+    /// It acknowledges the interrupt, then calls
+    /// [`VirtIONet::wake_from_interrupt`]. Returns whether the interrupt was
+    /// for this device.
+    pub fn handle_interrupt(&mut self) -> bool {
+        if !self.ack_interrupt() {
+            return false;
+        }
+        self.wake_from_interrupt();
+        true
+    }
+
+    /// Wakes the tasks waiting for whatever the device has done, to be called
+    /// after [`VirtIONet::ack_interrupt`].
+    ///
+    /// This wakes the waker registered with [`VirtIONet::register_rx_waker`]
+    /// or [`VirtIONet::poll_receive`] if a packet has been received, the one
+    /// registered with [`VirtIONet::register_tx_waker`] or
+    /// [`VirtIONet::poll_send`] if a packet can be sent, and the one
+    /// registered with [`VirtIONet::register_link_waker`] if the link state
+    /// changed. Each waker is only taken if its condition holds, so e.g. a
+    /// task waiting to send isn't forgotten when a packet is received.
+    ///
+    /// Transmit buffers of packets sent by [`VirtIONet::poll_send`] which the
+    /// device has finished with are reclaimed first.
+    pub fn wake_from_interrupt(&mut self) {
+        if let Err(e) = self.reap_transmitted() {
+            warn!("Failed to reclaim transmit buffers: {}", e);
+        }
+        if self.can_recv() {
+            if let Some(waker) = self.rx_waker.take() {
+                waker.wake();
+            }
+        }
+        if self.can_send() {
+            if let Some(waker) = self.tx_waker.take() {
+                waker.wake();
+            }
+        }
+        let link_up = self.link_up();
+        if link_up != self.waker_link_up {
+            self.waker_link_up = link_up;
+            if let Some(waker) = self.link_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Registers a waker to be woken by [`VirtIONet::wake_from_interrupt`]
+    /// once a packet has been received, replacing any previous one.
+    pub fn register_rx_waker(&mut self, waker: &Waker) {
+        register_waker(&mut self.rx_waker, waker);
+    }
+
+    /// Registers a waker to be woken by [`VirtIONet::wake_from_interrupt`]
+    /// once a packet can be sent, replacing any previous one.
+    pub fn register_tx_waker(&mut self, waker: &Waker) {
+        register_waker(&mut self.tx_waker, waker);
+    }
+
+    /// Registers a waker to be woken by [`VirtIONet::wake_from_interrupt`]
+    /// once the link state changes, replacing any previous one.
+    pub fn register_link_waker(&mut self, waker: &Waker) {
+        register_waker(&mut self.link_waker, waker);
+    }
+
+    /// Returns the traffic statistics gathered by the driver.
+    ///
+    /// See [`VirtIONetRaw::stats`].
+    pub fn stats(&self) -> NetStats {
+        self.inner.stats()
+    }
+
+    /// Resets all the traffic statistics to zero.
+    pub fn reset_stats(&mut self) {
+        self.inner.reset_stats()
+    }
+
+    /// Returns whether the link is up.
+    pub fn link_up(&self) -> bool {
+        self.inner.link_up()
+    }
+
+    /// Returns the link speed and duplex mode, if the device reports them.
+    ///
+    /// See [`VirtIONetRaw::link_speed`].
+    pub fn link_speed(&self) -> Option<LinkSpeed> {
+        self.inner.link_speed()
+    }
+
+    /// Returns the next pending event from the device, if any.
+    pub fn poll_event(&mut self) -> Option<NetEvent> {
+        self.inner.poll_event()
+    }
+
+    /// Tells the device that the driver has announced its presence.
+    ///
+    /// See [`VirtIONetRaw::ack_announce`].
+    pub fn ack_announce(&mut self) -> Result {
+        self.inner.ack_announce()
+    }
+
     /// Disable interrupts.
     pub fn disable_interrupts(&mut self) {
         self.inner.disable_interrupts()
@@ -60,14 +301,149 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         self.inner.mac_address()
     }
 
-    /// Whether can send packet.
+    /// Configures how the device hashes received packets, for receive-side
+    /// scaling or hash reports.
+    ///
+    /// See [`VirtIONetRaw::configure_rss`].
+    pub fn configure_rss(&mut self, config: RssConfig) -> Result {
+        self.inner.configure_rss(config)
+    }
+
+    /// Sets how long the device may delay transmit completion interrupts.
+    ///
+    /// See [`VirtIONetRaw::set_tx_coalescing`].
+    pub fn set_tx_coalescing(&mut self, usecs: u32, max_packets: u32) -> Result {
+        self.inner.set_tx_coalescing(usecs, max_packets)
+    }
+
+    /// Sets how long the device may delay receive interrupts.
+    ///
+    /// See [`VirtIONetRaw::set_rx_coalescing`].
+    pub fn set_rx_coalescing(&mut self, usecs: u32, max_packets: u32) -> Result {
+        self.inner.set_rx_coalescing(usecs, max_packets)
+    }
+
+    /// Returns the MTU reported by the device, if any.
+    ///
+    /// See [`VirtIONetRaw::mtu`].
+    pub fn mtu(&self) -> Option<u16> {
+        self.inner.mtu()
+    }
+
+    /// Returns whether the device can complete partial checksums of
+    /// transmitted packets.
+    ///
+    /// See [`VirtIONetRaw::tx_checksum_offload`].
+    pub fn tx_checksum_offload(&self) -> bool {
+        self.inner.tx_checksum_offload()
+    }
+
+    /// Returns whether the device may validate the checksums of received
+    /// packets.
+    ///
+    /// See [`VirtIONetRaw::rx_checksum_offload`].
+    pub fn rx_checksum_offload(&self) -> bool {
+        self.inner.rx_checksum_offload()
+    }
+
+    /// Returns the maximum length of an Ethernet frame without a VLAN tag, as
+    /// network stacks count their MTU.
+    pub(super) fn max_frame_len(&self) -> usize {
+        usize::from(self.mtu().unwrap_or(DEFAULT_MTU)) + ETHERNET_HEADER_LEN
+    }
+
+    /// Returns the number of frames which can be received before the device
+    /// runs out of receive buffers.
+    pub(super) fn max_burst_size(&self) -> usize {
+        let buffers_per_frame =
+            (self.inner.hdr_len() + self.max_frame_len()).div_ceil(self.buf_len);
+        let rx_buffers: usize = (0..self.num_pairs())
+            .map(|pair| usize::from(self.inner.rx_queue_size_on(pair)))
+            .sum();
+        (rx_buffers / buffers_per_frame).max(1)
+    }
+
+    /// Sets the MAC address of the device.
+    ///
+    /// See [`VirtIONetRaw::set_mac`].
+    pub fn set_mac(&mut self, mac: EthernetAddress) -> Result {
+        self.inner.set_mac(mac)
+    }
+
+    /// Enables or disables promiscuous mode.
+    ///
+    /// See [`VirtIONetRaw::set_promiscuous`].
+    pub fn set_promiscuous(&mut self, on: bool) -> Result {
+        self.inner.set_promiscuous(on)
+    }
+
+    /// Enables or disables receiving all multicast packets.
+    ///
+    /// See [`VirtIONetRaw::set_all_multicast`].
+    pub fn set_all_multicast(&mut self, on: bool) -> Result {
+        self.inner.set_all_multicast(on)
+    }
+
+    /// Enables or disables dropping all broadcast packets.
+    ///
+    /// See [`VirtIONetRaw::set_no_broadcast`].
+    pub fn set_no_broadcast(&mut self, on: bool) -> Result {
+        self.inner.set_no_broadcast(on)
+    }
+
+    /// Enables or disables dropping all unicast packets.
+    ///
+    /// See [`VirtIONetRaw::set_no_unicast`].
+    pub fn set_no_unicast(&mut self, on: bool) -> Result {
+        self.inner.set_no_unicast(on)
+    }
+
+    /// Installs filter tables of unicast and multicast addresses to receive
+    /// packets for.
+    ///
+    /// See [`VirtIONetRaw::set_mac_filter`].
+    pub fn set_mac_filter(
+        &mut self,
+        unicast: &[EthernetAddress],
+        multicast: &[EthernetAddress],
+    ) -> Result {
+        self.inner.set_mac_filter(unicast, multicast)
+    }
+
+    /// Adds a VLAN ID to the device's VLAN filter.
+    ///
+    /// See [`VirtIONetRaw::add_vlan`].
+    pub fn add_vlan(&mut self, vid: u16) -> Result {
+        self.inner.add_vlan(vid)
+    }
+
+    /// Removes a VLAN ID from the device's VLAN filter.
+    ///
+    /// See [`VirtIONetRaw::del_vlan`].
+    pub fn del_vlan(&mut self, vid: u16) -> Result {
+        self.inner.del_vlan(vid)
+    }
+
+    /// Whether a packet can be sent by [`VirtIONet::poll_send`] without
+    /// waiting.
     pub fn can_send(&self) -> bool {
-        self.inner.can_send()
+        self.inner.can_transmit() && !self.tx_free.is_empty()
     }
 
     /// Whether can receive packet.
     pub fn can_recv(&self) -> bool {
-        self.inner.poll_receive().is_some()
+        self.partial.is_some() || self.poll_receive_any(&RxBatch::unbounded()).is_some()
+    }
+
+    /// Finds a queue pair with a completed receive buffer which the batch may
+    /// still take, starting from `next_pair`, and returns the pair and the
+    /// token.
+    fn poll_receive_any(&self, batch: &RxBatch<MAX_PAIRS>) -> Option<(u16, u16)> {
+        let num_pairs = self.inner.num_pairs();
+        (0..num_pairs)
+            .map(|i| (self.next_pair + i) % num_pairs)
+            .filter(|&pair| batch.ready[usize::from(pair)] > 0)
+            .find_map(|pair| Some((pair, self.inner.poll_receive_on(pair)?)))
     }
 
     /// Receives a [`RxBuffer`] from network. If currently no data, returns an
@@ -75,51 +451,512 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     ///
     /// It will try to pop a buffer that completed data reception in the
     /// NIC queue.
+    ///
+    /// If `VIRTIO_NET_F_MRG_RXBUF` was negotiated and the packet was merged
+    /// from several receive buffers, the rest of it is copied into the first
+    /// buffer, which grows to fit, and the others are recycled. If the device
+    /// hasn't finished with all of them yet, returns [`Error::NotReady`] and
+    /// carries on with the same packet next time.
+    ///
+    /// With several queue pairs, each call starts looking on the pair after
+    /// the one which the previous packet came from.
     pub fn receive(&mut self) -> Result<RxBuffer> {
-        if let Some(token) = self.inner.poll_receive() {
-            let mut rx_buf = self.rx_buffers[token as usize]
-                .take()
-                .ok_or(Error::WrongToken)?;
-            if token != rx_buf.idx {
-                return Err(Error::WrongToken);
+        let mut batch = RxBatch::unbounded();
+        let result = self.receive_in(&mut batch);
+        self.notify_posted(&batch);
+        result
+    }
+
+    /// Receives a packet like [`VirtIONet::receive`], but only from the
+    /// completed buffers which `batch` may still take, and recycles the rest
+    /// of a merged packet without notifying the device.
+    fn receive_in(&mut self, batch: &mut RxBatch<MAX_PAIRS>) -> Result<RxBuffer> {
+        let (mut rx_buf, mut remaining) = match self.partial.take() {
+            Some(partial) => partial,
+            None => {
+                let (pair, token) = self.poll_receive_any(batch).ok_or(Error::NotReady)?;
+                self.next_pair = (pair + 1) % self.inner.num_pairs();
+                batch.ready[usize::from(pair)] -= 1;
+                let mut rx_buf = self.take_rx_buffer(pair, token)?;
+                // Safe because `token` == `rx_buf.idx`, we are passing the same
+                // buffer as we passed to `VirtQueue::add` and it is still valid.
+                let (hdr_len, pkt_len) = unsafe {
+                    self.inner
+                        .queue_pair(pair)?
+                        .receive_complete(token, rx_buf.as_bytes_mut())?
+                };
+                rx_buf.hdr_len = hdr_len;
+                rx_buf.set_packet_len(pkt_len);
+                let remaining = self.inner.num_buffers(rx_buf.as_bytes()) - 1;
+                (rx_buf, remaining)
             }
+        };
 
-            // Safe because `token` == `rx_buf.idx`, we are passing the same
+        // The rest of a merged packet is always on the same queue pair.
+        let pair = rx_buf.pair;
+        while remaining > 0 {
+            let token = match batch.ready[usize::from(pair)] {
+                0 => None,
+                _ => self.inner.poll_receive_on(pair),
+            };
+            let Some(token) = token else {
+                self.partial = Some((rx_buf, remaining));
+                return Err(Error::NotReady);
+            };
+            batch.ready[usize::from(pair)] -= 1;
+            let mut next_buf = self.take_rx_buffer(pair, token)?;
+            // Safe because `token` == `next_buf.idx`, we are passing the same
             // buffer as we passed to `VirtQueue::add` and it is still valid.
-            let (_hdr_len, pkt_len) =
-                unsafe { self.inner.receive_complete(token, rx_buf.as_bytes_mut())? };
-            rx_buf.set_packet_len(pkt_len);
-            Ok(rx_buf)
-        } else {
-            Err(Error::NotReady)
+            let len = unsafe {
+                self.inner
+                    .queue_pair(pair)?
+                    .receive_complete_merged(token, next_buf.as_bytes_mut())?
+            };
+            rx_buf.append_packet(&next_buf.as_bytes()[..len]);
+            self.post_rx_buffer(next_buf)?;
+            batch.posted[usize::from(pair)] = true;
+            remaining -= 1;
+        }
+        rx_buf.complete_checksum();
+        Ok(rx_buf)
+    }
+
+    /// Notifies the device of the receive buffers which were posted during
+    /// the given batch, once for each queue pair.
+    fn notify_posted(&mut self, batch: &RxBatch<MAX_PAIRS>) {
+        for (pair, _) in (0..).zip(batch.posted).filter(|&(_, posted)| posted) {
+            self.inner.notify_rx_on(pair);
+        }
+    }
+
+    /// Takes the receive buffer for the given token on the given queue pair,
+    /// which the device has finished with.
+    fn take_rx_buffer(&mut self, pair: u16, token: u16) -> Result<RxBuffer> {
+        let rx_buf = self.rx_buffers[usize::from(pair)][token as usize]
+            .take()
+            .ok_or(Error::WrongToken)?;
+        if token != rx_buf.idx {
+            return Err(Error::WrongToken);
+        }
+        Ok(rx_buf)
+    }
+
+    /// Receives a packet like [`VirtIONet::receive`], or registers the task's
+    /// waker to be woken by [`VirtIONet::wake_from_interrupt`] and returns
+    /// [`Poll::Pending`] if none is ready.
+    pub fn poll_receive(&mut self, cx: &mut Context) -> Poll<Result<RxBuffer>> {
+        match self.receive() {
+            Err(Error::NotReady) => {
+                self.register_rx_waker(cx.waker());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
         }
     }
 
     /// Gives back the ownership of `rx_buf`, and recycles it for next use.
     ///
-    /// It will add the buffer back to the NIC queue.
-    pub fn recycle_rx_buffer(&mut self, mut rx_buf: RxBuffer) -> Result {
+    /// It will add the buffer back to the NIC queue of the queue pair which it
+    /// came from.
+    pub fn recycle_rx_buffer(&mut self, rx_buf: RxBuffer) -> Result {
+        let pair = rx_buf.pair;
+        self.post_rx_buffer(rx_buf)?;
+        self.inner.notify_rx_on(pair);
+        Ok(())
+    }
+
+    /// Receives up to `max` packets which are ready, passing each to `f` and
+    /// then recycling its buffer, and returns how many there were.
+    ///
+    /// This is like calling [`receive`](Self::receive) and
+    /// [`recycle_rx_buffer`](Self::recycle_rx_buffer) in a loop, except that
+    /// the used ring of each queue pair is only checked once, at the start, so
+    /// packets which arrive meanwhile are left for the next call, and the
+    /// device is notified of the recycled buffers at most once per queue pair,
+    /// after the whole batch.
+    pub fn for_each_received(
+        &mut self,
+        max: usize,
+        mut f: impl FnMut(&mut RxBuffer),
+    ) -> Result<usize> {
+        let mut batch = RxBatch {
+            ready: array::from_fn(|pair| match u16::try_from(pair) {
+                Ok(pair) if pair < self.num_pairs() => self.inner.rx_returned_on(pair),
+                _ => 0,
+            }),
+            posted: [false; MAX_PAIRS],
+        };
+        let mut count = 0;
+        let mut result = Ok(());
+        while count < max {
+            let mut rx_buf = match self.receive_in(&mut batch) {
+                Ok(rx_buf) => rx_buf,
+                Err(Error::NotReady) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            f(&mut rx_buf);
+            count += 1;
+            let pair = rx_buf.pair;
+            if let Err(e) = self.post_rx_buffer(rx_buf) {
+                result = Err(e);
+                break;
+            }
+            batch.posted[usize::from(pair)] = true;
+        }
+        self.notify_posted(&batch);
+        result.map(|()| count)
+    }
+
+    /// Adds `rx_buf` back to the receive queue of the queue pair which it came
+    /// from, without notifying the device.
+    fn post_rx_buffer(&mut self, mut rx_buf: RxBuffer) -> Result {
+        // Shrink the buffer back to its original size, in case it grew to hold
+        // a merged packet.
+        rx_buf.buf.truncate(self.buf_len / size_of::<usize>());
         // Safe because we take the ownership of `rx_buf` back to `rx_buffers`,
         // it lives as long as the queue.
-        let new_token = unsafe { self.inner.receive_begin(rx_buf.as_bytes_mut()) }?;
+        let new_token = unsafe {
+            self.inner
+                .add_rx_buffer_on(rx_buf.pair, rx_buf.as_bytes_mut())
+        }?;
+        let rx_buffers = &mut self.rx_buffers[usize::from(rx_buf.pair)];
         // `rx_buffers[new_token]` is expected to be `None` since it was taken
         // away at `Self::receive()` and has not been added back.
-        if self.rx_buffers[new_token as usize].is_some() {
+        if rx_buffers[new_token as usize].is_some() {
             return Err(Error::WrongToken);
         }
         rx_buf.idx = new_token;
-        self.rx_buffers[new_token as usize] = Some(rx_buf);
+        rx_buffers[new_token as usize] = Some(rx_buf);
         Ok(())
     }
 
     /// Allocate a new buffer for transmitting.
     pub fn new_tx_buffer(&self, buf_len: usize) -> TxBuffer {
-        TxBuffer(vec![0; buf_len])
+        TxBuffer {
+            packet: vec![0; buf_len],
+            header: VirtioNetHdr::default(),
+        }
     }
 
     /// Sends a [`TxBuffer`] to the network, and blocks until the request
     /// completed.
+    ///
+    /// If packets sent by [`VirtIONet::poll_send`] leave no room in the
+    /// transmit queue, it first waits for the device to finish with some of
+    /// them. Returns [`Error::Unsupported`] if the buffer asks for an offload
+    /// which the device doesn't support.
     pub fn send(&mut self, tx_buf: TxBuffer) -> Result {
-        self.inner.send(tx_buf.packet())
+        while !self.inner.can_send() {
+            self.reap_transmitted()?;
+            spin_loop();
+        }
+        self.inner.send_with_header(&tx_buf.header, tx_buf.packet())
+    }
+
+    /// Starts sending a [`TxBuffer`] if there is room in the transmit queue,
+    /// or else registers the task's waker to be woken by
+    /// [`VirtIONet::wake_from_interrupt`] and returns [`Poll::Pending`].
+    ///
+    /// Unlike [`VirtIONet::send`] it doesn't wait for the device: the header
+    /// and packet are copied into one of the transmit buffers allocated with
+    /// the device, which is reclaimed once the device has finished with it, by
+    /// [`VirtIONet::wake_from_interrupt`] or a later call to this. A packet
+    /// longer than the device's MTU, e.g. one to be segmented by the device,
+    /// grows the buffer.
+    ///
+    /// The buffer is borrowed rather than taken, so that it can be passed in
+    /// again once the task is woken.
+    pub fn poll_send(&mut self, cx: &mut Context, tx_buf: &TxBuffer) -> Poll<Result> {
+        if let Err(e) = self.reap_transmitted() {
+            return Poll::Ready(Err(e));
+        }
+        if !self.can_send() {
+            self.register_tx_waker(cx.waker());
+            return Poll::Pending;
+        }
+        Poll::Ready(self.transmit(&tx_buf.header, tx_buf.packet()))
+    }
+
+    /// Copies the given header and packet into a free transmit buffer, and
+    /// submits it to the device without waiting for it to be sent.
+    fn transmit(&mut self, header: &VirtioNetHdr, packet: &[u8]) -> Result {
+        let mut tx_frame = self.take_tx_frame().ok_or(Error::QueueFull)?;
+        tx_frame.packet_mut(packet.len()).copy_from_slice(packet);
+        self.send_tx_frame(tx_frame, header)
+    }
+
+    /// Takes a free transmit buffer to be filled in place and passed to
+    /// [`VirtIONet::send_tx_frame`], or returns `None` if there are none.
+    pub(super) fn take_tx_frame(&mut self) -> Option<TxFrame> {
+        let mut buf = self.tx_free.pop()?;
+        buf.clear();
+        Some(TxFrame {
+            buf,
+            header_len: self.inner.hdr_len(),
+        })
+    }
+
+    /// Gives back a transmit buffer taken with [`VirtIONet::take_tx_frame`]
+    /// without sending it.
+    pub(super) fn recycle_tx_frame(&mut self, tx_frame: TxFrame) {
+        self.tx_free.push(tx_frame.buf);
+    }
+
+    /// Submits the packet in a transmit buffer taken with
+    /// [`VirtIONet::take_tx_frame`] with the given header, without waiting
+    /// for it to be sent. The buffer is given back if this fails.
+    pub(super) fn send_tx_frame(&mut self, mut tx_frame: TxFrame, header: &VirtioNetHdr) -> Result {
+        let result = self
+            .inner
+            .fill_buffer_header_with(&mut tx_frame.buf, header)
+            // Safe because the buffer is kept in `tx_pending` until the device
+            // has finished with it, and its heap allocation doesn't move.
+            .and_then(|_| unsafe { self.inner.transmit_begin(&tx_frame.buf) });
+        match result {
+            Ok(token) => {
+                self.tx_pending[usize::from(token)] = Some(tx_frame.buf);
+                Ok(())
+            }
+            Err(e) => {
+                self.recycle_tx_frame(tx_frame);
+                Err(e)
+            }
+        }
+    }
+
+    /// Reclaims the transmit buffers of all packets started by
+    /// [`VirtIONet::send_tx_frame`] which the device has finished with.
+    pub(super) fn reap_transmitted(&mut self) -> Result {
+        while let Some(token) = self.inner.poll_transmit() {
+            let tx_buf = self.tx_pending[usize::from(token)]
+                .take()
+                .ok_or(Error::WrongToken)?;
+            // Safe because this is the buffer which was passed to
+            // `transmit_begin` for the token.
+            let result = unsafe { self.inner.transmit_complete(token, &tx_buf) };
+            // The device has finished with the buffer either way.
+            self.tx_free.push(tx_buf);
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// The receive buffers which a batch of receptions may take, and those which
+/// it has posted back.
+struct RxBatch<const MAX_PAIRS: usize> {
+    /// The number of completed receive buffers which may still be taken from
+    /// each queue pair.
+    ready: [usize; MAX_PAIRS],
+    /// Whether buffers have been posted back to each queue pair without
+    /// notifying the device.
+    posted: [bool; MAX_PAIRS],
+}
+
+impl<const MAX_PAIRS: usize> RxBatch<MAX_PAIRS> {
+    /// Returns a batch which may take every completed receive buffer.
+    fn unbounded() -> Self {
+        Self {
+            ready: [usize::MAX; MAX_PAIRS],
+            posted: [false; MAX_PAIRS],
+        }
+    }
+}
+
+/// Stores the given waker in `slot`, unless it would wake the same task as the
+/// one already there.
+fn register_waker(slot: &mut Option<Waker>, waker: &Waker) {
+    match slot {
+        Some(old) if old.will_wake(waker) => {}
+        _ => *slot = Some(waker.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{fake_transport, Config, Features, Flags, RxChecksum, NET_HDR_SIZE};
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use alloc::sync::Arc;
+    use core::ptr::NonNull;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+    use zerocopy::AsBytes;
+
+    const QUEUE_SIZE: usize = 4;
+
+    /// A waker which records whether it was woken.
+    #[derive(Default)]
+    struct FlagWaker(AtomicBool);
+
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn poll_send_without_waiting() {
+        let mut config_space = Config::default();
+        let (transport, state) = fake_transport(
+            NonNull::from(&mut config_space),
+            Features::empty(),
+            QUEUE_SIZE,
+        );
+        let mut net =
+            VirtIONet::<FakeHal, _, QUEUE_SIZE>::new(transport, FakeHal::new(), 2048).unwrap();
+        let flag = Arc::new(FlagWaker::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let tx_buf = TxBuffer::from(&[42; 60]);
+
+        // Every packet is submitted without the device sending any of them, until the transmit
+        // queue is full.
+        for _ in 0..QUEUE_SIZE {
+            assert_eq!(net.poll_send(&mut cx, &tx_buf), Poll::Ready(Ok(())));
+        }
+        assert_eq!(net.poll_send(&mut cx, &tx_buf), Poll::Pending);
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        // Once the device sends a packet, its buffer is reclaimed on the interrupt path.
+        let sent = state.lock().unwrap().read_from_queue::<QUEUE_SIZE>(1);
+        assert_eq!(sent.len(), NET_HDR_SIZE + 60);
+        assert_eq!(&sent[NET_HDR_SIZE..], tx_buf.packet());
+        net.wake_from_interrupt();
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert_eq!(net.poll_send(&mut cx, &tx_buf), Poll::Ready(Ok(())));
+        assert_eq!(net.poll_send(&mut cx, &tx_buf), Poll::Pending);
+    }
+
+    #[test]
+    fn for_each_received_notifies_once() {
+        let mut config_space = Config::default();
+        let (transport, state) = fake_transport(
+            NonNull::from(&mut config_space),
+            Features::empty(),
+            QUEUE_SIZE,
+        );
+        let mut net =
+            VirtIONet::<FakeHal, _, QUEUE_SIZE>::new(transport, FakeHal::new(), 2048).unwrap();
+        let receive_packet = |byte: u8| {
+            let mut frame = vec![0; NET_HDR_SIZE];
+            frame.extend_from_slice(&[byte; 60]);
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(0, &frame);
+        };
+        let notify_count = || state.lock().unwrap().queues[0].notify_count;
+
+        // Receiving packets one at a time notifies the device for each of them.
+        let start = notify_count();
+        for byte in 0..3 {
+            receive_packet(byte);
+            let rx_buf = net.receive().unwrap();
+            assert_eq!(rx_buf.packet(), [byte; 60]);
+            net.recycle_rx_buffer(rx_buf).unwrap();
+        }
+        assert_eq!(notify_count(), start + 3);
+
+        // A batch only notifies once, and leaves packets which arrive during it for the next one.
+        for byte in 3..6 {
+            receive_packet(byte);
+        }
+        let start = notify_count();
+        let mut received = Vec::new();
+        let count = net
+            .for_each_received(QUEUE_SIZE, |rx_buf| {
+                if received.is_empty() {
+                    receive_packet(6);
+                }
+                received.push(rx_buf.packet()[0]);
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(received, [3, 4, 5]);
+        assert_eq!(notify_count(), start + 1);
+
+        received.clear();
+        assert_eq!(
+            net.for_each_received(QUEUE_SIZE, |rx_buf| received.push(rx_buf.packet()[0])),
+            Ok(1)
+        );
+        assert_eq!(received, [6]);
+        assert_eq!(notify_count(), start + 2);
+    }
+
+    #[test]
+    fn reset_reclaims_dropped_sends() {
+        let mut config_space = Config::default();
+        let (transport, state) = fake_transport(
+            NonNull::from(&mut config_space),
+            Features::empty(),
+            QUEUE_SIZE,
+        );
+        let hal = FakeHal::new();
+        let mut net = VirtIONet::<FakeHal, _, QUEUE_SIZE>::new(transport, hal, 2048).unwrap();
+        let waker = Waker::from(Arc::new(FlagWaker::default()));
+        let mut cx = Context::from_waker(&waker);
+        let tx_buf = TxBuffer::from(&[42; 60]);
+        let baseline = hal.counts().live_shares();
+
+        // The device sends one packet and is reset before it gets to the others.
+        for _ in 0..3 {
+            assert_eq!(net.poll_send(&mut cx, &tx_buf), Poll::Ready(Ok(())));
+        }
+        assert_eq!(hal.counts().live_shares(), baseline + 3);
+        state.lock().unwrap().read_from_queue::<QUEUE_SIZE>(1);
+        net.reset().unwrap();
+
+        // All the transmit buffers are unshared and can be used again.
+        assert_eq!(hal.counts().live_shares(), baseline);
+        for _ in 0..QUEUE_SIZE {
+            assert_eq!(net.poll_send(&mut cx, &tx_buf), Poll::Ready(Ok(())));
+        }
+        assert_eq!(net.poll_send(&mut cx, &tx_buf), Poll::Pending);
+    }
+
+    #[test]
+    fn receive_completes_partial_checksum() {
+        let mut config_space = Config::default();
+        let (transport, state) = fake_transport(
+            NonNull::from(&mut config_space),
+            Features::GUEST_CSUM,
+            QUEUE_SIZE,
+        );
+        let mut net =
+            VirtIONet::<FakeHal, _, QUEUE_SIZE>::new(transport, FakeHal::new(), 2048).unwrap();
+
+        // The device leaves the checksum from byte 4 to be stored at byte 6, which holds the sum
+        // of the pseudo-header.
+        let header = VirtioNetHdr {
+            flags: Flags::NEEDS_CSUM,
+            csum_start: 4,
+            csum_offset: 2,
+            ..Default::default()
+        };
+        let mut frame = header.as_bytes().to_vec();
+        frame.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0x12, 0x34, 9, 10, 11]);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(0, &frame);
+
+        let rx_buf = net.receive().unwrap();
+        assert_eq!(rx_buf.csum_state(), RxChecksum::Valid);
+        // The checksum is right if the words it covers add up to all ones with the pseudo-header.
+        let mut sum: u32 = rx_buf.packet()[4..]
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+            .sum::<u32>()
+            + 0x1234;
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        assert_eq!(sum, 0xffff);
+        assert_eq!(rx_buf.packet()[..6], [1, 2, 3, 4, 5, 6]);
     }
 }
@@ -1,12 +1,24 @@
-use super::{Config, EthernetAddress, Features, VirtioNetHdr};
-use super::{MIN_BUFFER_LEN, NET_HDR_SIZE, QUEUE_RECEIVE, QUEUE_TRANSMIT, SUPPORTED_FEATURES};
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
+use super::{
+    coalescing_command_data, complete_rx_checksum, receive_queue_index, rx_hash,
+    transmit_queue_index, vlan_command_data, Config, CtrlClass, CtrlHdr, EthernetAddress, Features,
+    Flags, GsoType, HashTypes, LinkSpeed, NetError, NetEvent, NetInterrupt, NetStats, RssConfig,
+    RxHash, Status, VirtioNetHdr, VirtioNetHdrHash, VirtioNetHdrMrgRxbuf, CTRL_ACK_ERR,
+    CTRL_ACK_OK, CTRL_ANNOUNCE_ACK, CTRL_MAC_ADDR_SET, CTRL_MAC_TABLE_SET, CTRL_MQ_HASH_CONFIG,
+    CTRL_MQ_RSS_CONFIG, CTRL_MQ_VQ_PAIRS_SET, CTRL_NOTF_COAL_RX_SET, CTRL_NOTF_COAL_TX_SET,
+    CTRL_QUEUE_SIZE, CTRL_RX_ALLMULTI, CTRL_RX_NOBCAST, CTRL_RX_NOUNI, CTRL_RX_PROMISC,
+    CTRL_VLAN_ADD, CTRL_VLAN_DEL, MAX_BUFFER_LEN, MAX_CTRL_DATA, MAX_ETHERNET_HEADER_LEN,
+    MAX_GSO_PACKET_LEN, MIN_BUFFER_LEN, NET_HDR_SIZE, SUPPORTED_FEATURES,
+};
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::{SharedBuffer, VirtQueue};
+use crate::transport::{DeviceStatus, InterruptStatus, Transport};
 use crate::volatile::volread;
-use crate::{Error, Result};
+use crate::{pages, Error, Result};
+use core::array;
+use core::mem::size_of;
+use core::ptr::NonNull;
 use log::{debug, info, warn};
-use zerocopy::AsBytes;
+use zerocopy::{AsBytes, FromBytes};
 
 /// Raw driver for a VirtIO network device.
 ///
@@ -15,71 +27,503 @@ use zerocopy::AsBytes;
 /// management. For more higher-level functions such as receive buffer backing,
 /// see [`VirtIONet`].
 ///
+/// `QUEUE_SIZE` is the maximum size of each receive and transmit queue. If the
+/// device doesn't support queues that large they are made as large as it
+/// allows instead, as returned by [`VirtIONetRaw::rx_queue_size`] and
+/// [`VirtIONetRaw::tx_queue_size`].
+///
+/// If the device supports `VIRTIO_NET_F_MQ`, up to `MAX_PAIRS` pairs of
+/// receive and transmit queues are set up, clamped to the number the device
+/// reports. The methods on `VirtIONetRaw` itself all use pair 0; use
+/// [`VirtIONetRaw::queue_pair`] to use the others.
+///
 /// [`VirtIONet`]: super::VirtIONet
-pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize = 1> {
     transport: T,
+    config: NonNull<Config>,
+    features: Features,
+    /// The length of the header before each packet, which depends on whether
+    /// `VIRTIO_NET_F_MRG_RXBUF` was negotiated.
+    hdr_len: usize,
     mac: EthernetAddress,
-    recv_queue: VirtQueue<H, QUEUE_SIZE>,
-    send_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// The MTU reported by the device, if `VIRTIO_NET_F_MTU` was negotiated.
+    mtu: Option<u16>,
+    /// The link speed and duplex mode, if `VIRTIO_NET_F_SPEED_DUPLEX` was
+    /// negotiated.
+    link_speed: Option<LinkSpeed>,
+    /// Whether the link was up when the status was last read.
+    link_up: bool,
+    /// Whether the link status has changed since the last call to `poll_event`.
+    link_changed: bool,
+    /// Whether the device has asked for an announcement since the last call to `poll_event`.
+    announce: bool,
+    /// The receive queue of each queue pair, of which the first `num_pairs` are
+    /// set up.
+    recv_queues: [Option<VirtQueue<H, QUEUE_SIZE>>; MAX_PAIRS],
+    /// The transmit queue of each queue pair, of which the first `num_pairs`
+    /// are set up.
+    send_queues: [Option<VirtQueue<H, QUEUE_SIZE>>; MAX_PAIRS],
+    num_pairs: u16,
+    /// The control queue, if `VIRTIO_NET_F_CTRL_VQ` was negotiated.
+    ctrl_queue: Option<VirtQueue<H, CTRL_QUEUE_SIZE>>,
+    /// The index of the control queue, which comes after all the queue pairs
+    /// which the device supports.
+    ctrl_queue_index: u16,
+    hal: H,
+    /// Headers for packets sent with [`VirtIONetRaw::send_borrowed`], one slot
+    /// per outstanding packet, allocated on first use.
+    borrowed_headers: Option<Dma<H>>,
+    /// The token and payload of each outstanding packet sent with
+    /// [`VirtIONetRaw::send_borrowed`], by header slot.
+    borrowed: [Option<(u16, &'static [u8])>; QUEUE_SIZE],
+    stats: NetStats,
 }
 
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZE> {
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize>
+    VirtIONetRaw<H, T, QUEUE_SIZE, MAX_PAIRS>
+{
     /// Create a new VirtIO-Net driver.
     pub fn new(mut transport: T, hal: H) -> Result<Self> {
+        assert_ne!(MAX_PAIRS, 0, "VirtIONetRaw needs at least one queue pair");
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
         info!("negotiated_features {:?}", negotiated_features);
         // read configuration space
         let config = transport.config_space::<Config>()?;
-        let mac;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            mac = volread!(config, mac);
-            debug!(
-                "Got MAC={:02x?}, status={:?}",
-                mac,
-                volread!(config, status)
-            );
+        let (mac, mtu) = read_mac_and_mtu(config, negotiated_features);
+        let device_pairs = read_device_pairs(config, negotiated_features);
+        let num_pairs = device_pairs.min(MAX_PAIRS.try_into().unwrap_or(u16::MAX));
+        info!("using {} of {} queue pairs", num_pairs, device_pairs);
+
+        let mut send_queues = array::from_fn(|_| None);
+        let mut recv_queues = array::from_fn(|_| None);
+        for pair in 0..num_pairs {
+            send_queues[usize::from(pair)] = Some(VirtQueue::new_clamped(
+                hal,
+                &mut transport,
+                transmit_queue_index(pair),
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+            )?);
+            recv_queues[usize::from(pair)] = Some(VirtQueue::new_clamped(
+                hal,
+                &mut transport,
+                receive_queue_index(pair),
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+            )?);
         }
-        let send_queue = VirtQueue::new(
-            hal,
-            &mut transport,
-            QUEUE_TRANSMIT,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let recv_queue = VirtQueue::new(
-            hal,
-            &mut transport,
-            QUEUE_RECEIVE,
-            negotiated_features.contains(Features::RING_INDIRECT_DESC),
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
+        let ctrl_queue_index = device_pairs * 2;
+        let ctrl_queue = if negotiated_features.contains(Features::CTRL_VQ) {
+            Some(VirtQueue::new(
+                hal,
+                &mut transport,
+                ctrl_queue_index,
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+            )?)
+        } else {
+            None
+        };
 
         transport.finish_init();
 
-        Ok(VirtIONetRaw {
+        let mut net = VirtIONetRaw {
             transport,
+            config,
+            features: negotiated_features,
+            hdr_len: if negotiated_features.contains(Features::HASH_REPORT) {
+                size_of::<VirtioNetHdrHash>()
+            } else if negotiated_features.contains(Features::MRG_RXBUF) {
+                size_of::<VirtioNetHdrMrgRxbuf>()
+            } else {
+                NET_HDR_SIZE
+            },
             mac,
-            recv_queue,
-            send_queue,
-        })
+            mtu,
+            link_speed: None,
+            link_up: true,
+            link_changed: false,
+            announce: false,
+            recv_queues,
+            send_queues,
+            num_pairs,
+            ctrl_queue,
+            ctrl_queue_index,
+            hal,
+            borrowed_headers: None,
+            borrowed: [None; QUEUE_SIZE],
+            stats: NetStats::default(),
+        };
+        net.link_up = net.read_link_up();
+        net.link_speed = net.read_link_speed();
+        if num_pairs > 1 {
+            if let Err(e) = net.ctrl_command(
+                CtrlClass::MQ,
+                CTRL_MQ_VQ_PAIRS_SET,
+                &[&num_pairs.to_le_bytes()],
+            ) {
+                warn!("failed to enable {} queue pairs, using 1: {}", num_pairs, e);
+                net.unset_pairs_from(1);
+            }
+        }
+        Ok(net)
+    }
+
+    /// Resets the device and initialises it again in place, e.g. after its
+    /// backend has restarted and stopped using the queues.
+    ///
+    /// The device must offer the same features and number of queue pairs as
+    /// before, or [`NetError::FeaturesChanged`] is returned and the device is
+    /// left reset. All the queues are set up again with the same memory, and
+    /// receive buffers which were posted and not yet completed are made
+    /// available to the device again with the same tokens. Packets which were
+    /// still being transmitted are dropped: payloads sent with
+    /// [`send_borrowed`](Self::send_borrowed) are released, and the tokens of
+    /// those sent with [`transmit_begin`](Self::transmit_begin) are returned,
+    /// so that their buffers can be reclaimed with
+    /// [`transmit_reclaim`](Self::transmit_reclaim). Transmissions which the
+    /// device had completed before it was reset can still be completed as
+    /// usual.
+    ///
+    /// The MAC address, MTU and link status are read again, as they may have
+    /// changed. Settings made with control commands, such as filters, are lost
+    /// and must be made again.
+    pub fn reset(&mut self) -> Result<DroppedTransmits<QUEUE_SIZE, MAX_PAIRS>> {
+        self.transport.set_status(DeviceStatus::empty());
+        let negotiated_features = self.transport.begin_init(SUPPORTED_FEATURES);
+        let device_pairs = read_device_pairs(self.config, negotiated_features);
+        if negotiated_features != self.features || device_pairs * 2 != self.ctrl_queue_index {
+            warn!(
+                "network device features changed from {:?} to {:?} across reset",
+                self.features, negotiated_features
+            );
+            self.transport.set_status(DeviceStatus::empty());
+            return Err(NetError::FeaturesChanged.into());
+        }
+        let dropped = match self.restart_queues() {
+            Ok(dropped) => dropped,
+            Err(e) => {
+                self.transport.set_status(DeviceStatus::empty());
+                return Err(e);
+            }
+        };
+        self.transport.finish_init();
+
+        (self.mac, self.mtu) = read_mac_and_mtu(self.config, self.features);
+        self.config_changed();
+        for pair in 0..self.num_pairs {
+            self.notify_rx_on(pair);
+        }
+        if self.num_pairs > 1 {
+            if let Err(e) = self.ctrl_command(
+                CtrlClass::MQ,
+                CTRL_MQ_VQ_PAIRS_SET,
+                &[&self.num_pairs.to_le_bytes()],
+            ) {
+                warn!(
+                    "failed to enable {} queue pairs, using 1: {}",
+                    self.num_pairs, e
+                );
+                self.unset_pairs_from(1);
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Sets up all the queues with the transport again after the device has
+    /// been reset, as described for [`reset`](Self::reset).
+    fn restart_queues(&mut self) -> Result<DroppedTransmits<QUEUE_SIZE, MAX_PAIRS>> {
+        let mut dropped = DroppedTransmits {
+            tokens: [[false; QUEUE_SIZE]; MAX_PAIRS],
+        };
+        for pair in 0..self.num_pairs {
+            let recv_queue = self.recv_queues[usize::from(pair)]
+                .as_mut()
+                .expect("queue pair not set up");
+            let tokens = recv_queue.pending_tokens()?;
+            recv_queue.restart(&mut self.transport, &tokens)?;
+
+            let mut pending = self.send_queue(pair).pending_tokens()?;
+            if pair == 0 {
+                for slot in 0..QUEUE_SIZE {
+                    let Some((token, payload)) = self.borrowed[slot] else {
+                        continue;
+                    };
+                    if !pending[usize::from(token)] {
+                        // The device finished with it before the reset, so it
+                        // is left to be reaped by `poll_transmit_done`.
+                        continue;
+                    }
+                    self.borrowed[slot] = None;
+                    pending[usize::from(token)] = false;
+                    // Safe because the slot was allocated and written by
+                    // `send_borrowed`.
+                    let header_buf = unsafe { self.borrowed_header(slot)?.as_ref() };
+                    let send_queue = self.send_queue(0);
+                    // Safe because these are the same buffers as were passed
+                    // to `add` for this token, and the device has been reset.
+                    if payload.is_empty() {
+                        unsafe { send_queue.pop_pending(token, &[header_buf], &mut []) }?;
+                    } else {
+                        unsafe { send_queue.pop_pending(token, &[header_buf, payload], &mut []) }?;
+                    }
+                }
+            }
+            // The remaining chains stay in use until the caller reclaims them.
+            self.send_queues[usize::from(pair)]
+                .as_mut()
+                .expect("queue pair not set up")
+                .restart(&mut self.transport, &[false; QUEUE_SIZE])?;
+            dropped.tokens[usize::from(pair)] = pending;
+        }
+        if let Some(ctrl_queue) = &mut self.ctrl_queue {
+            ctrl_queue.restart(&mut self.transport, &[false; CTRL_QUEUE_SIZE])?;
+        }
+        Ok(dropped)
+    }
+
+    /// Stops using all queue pairs from the given index onwards.
+    fn unset_pairs_from(&mut self, first_pair: u16) {
+        for pair in first_pair..self.num_pairs {
+            self.transport.queue_unset(transmit_queue_index(pair));
+            self.transport.queue_unset(receive_queue_index(pair));
+            self.send_queues[usize::from(pair)] = None;
+            self.recv_queues[usize::from(pair)] = None;
+        }
+        self.num_pairs = self.num_pairs.min(first_pair);
+    }
+
+    /// Returns the number of queue pairs which have been set up.
+    ///
+    /// This is 1 unless the device supports `VIRTIO_NET_F_MQ`, and is never
+    /// more than `MAX_PAIRS`.
+    pub fn num_pairs(&self) -> u16 {
+        self.num_pairs
+    }
+
+    /// Returns the size of the receive queue of pair 0, which is the number of
+    /// receive buffers it can hold.
+    ///
+    /// This is `QUEUE_SIZE` unless the device's maximum is smaller.
+    pub fn rx_queue_size(&self) -> u16 {
+        self.rx_queue_size_on(0)
+    }
+
+    /// Returns the size of the transmit queue of pair 0.
+    ///
+    /// This is `QUEUE_SIZE` unless the device's maximum is smaller.
+    pub fn tx_queue_size(&self) -> u16 {
+        self.send_queues[0].as_ref().map_or(0, VirtQueue::size)
+    }
+
+    pub(super) fn rx_queue_size_on(&self, pair: u16) -> u16 {
+        self.recv_queues[usize::from(pair)]
+            .as_ref()
+            .map_or(0, VirtQueue::size)
+    }
+
+    /// Returns a handle to the queue pair with the given index, which must be
+    /// less than [`VirtIONetRaw::num_pairs`].
+    pub fn queue_pair(
+        &mut self,
+        index: u16,
+    ) -> Result<NetQueuePair<'_, H, T, QUEUE_SIZE, MAX_PAIRS>> {
+        if index >= self.num_pairs {
+            return Err(Error::InvalidParam);
+        }
+        Ok(NetQueuePair { net: self, index })
+    }
+
+    /// Returns the transmit queue of the given pair, which must already have
+    /// been checked.
+    fn send_queue(&mut self, pair: u16) -> &mut VirtQueue<H, QUEUE_SIZE> {
+        self.send_queues[usize::from(pair)]
+            .as_mut()
+            .expect("queue pair not set up")
+    }
+
+    /// Returns the receive queue of the given pair, which must already have
+    /// been checked.
+    fn recv_queue(&mut self, pair: u16) -> &mut VirtQueue<H, QUEUE_SIZE> {
+        self.recv_queues[usize::from(pair)]
+            .as_mut()
+            .expect("queue pair not set up")
     }
 
     /// Acknowledge interrupt.
+    ///
+    /// If the interrupt was caused by a configuration change the status is
+    /// read again, and any changes are returned by the next calls to
+    /// [`poll_event`](Self::poll_event), including a request from the device
+    /// to announce the driver's presence.
     pub fn ack_interrupt(&mut self) -> bool {
-        self.transport.ack_interrupt()
+        !self.ack_interrupt_status().is_empty()
+    }
+
+    /// Acknowledges an interrupt like [`ack_interrupt`](Self::ack_interrupt),
+    /// and returns what caused it.
+    ///
+    /// The transport only reports whether some queue was used, so
+    /// [`NetInterrupt::RX_USED`] and [`NetInterrupt::TX_USED`] are found by
+    /// checking the used rings of all the queue pairs. The result is empty if
+    /// the interrupt wasn't for this device.
+    pub fn ack_interrupt_detailed(&mut self) -> NetInterrupt {
+        let status = self.ack_interrupt_status();
+        let mut causes = NetInterrupt::empty();
+        if status.contains(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT) {
+            causes |= NetInterrupt::CONFIG_CHANGED;
+        }
+        if status.contains(InterruptStatus::QUEUE_INTERRUPT) {
+            if self.recv_queues.iter().flatten().any(VirtQueue::can_pop) {
+                causes |= NetInterrupt::RX_USED;
+            }
+            if self.send_queues.iter().flatten().any(VirtQueue::can_pop) {
+                causes |= NetInterrupt::TX_USED;
+            }
+        }
+        causes
+    }
+
+    /// Acknowledges an interrupt with the transport, handles any
+    /// configuration change, and returns the transport's interrupt status.
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        let status = self.transport.ack_interrupt_status();
+        if status.contains(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT) {
+            self.config_changed();
+        }
+        if !status.is_empty() {
+            self.stats.interrupts = self.stats.interrupts.wrapping_add(1);
+        }
+        status
+    }
+
+    /// Returns the traffic statistics which the driver has gathered since it
+    /// was created or [`VirtIONetRaw::reset_stats`] was last called.
+    pub fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    /// Resets all the traffic statistics to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats = NetStats::default();
+    }
+
+    /// Reads the status from the configuration space after it has changed.
+    fn config_changed(&mut self) {
+        self.link_speed = self.read_link_speed();
+        let link_up = self.read_link_up();
+        if link_up != self.link_up {
+            info!("link is {}", if link_up { "up" } else { "down" });
+            self.link_up = link_up;
+            self.link_changed = true;
+        }
+        if self.features.contains(Features::GUEST_ANNOUNCE)
+            && self.read_status().contains(Status::ANNOUNCE)
+        {
+            self.announce = true;
+        }
+    }
+
+    /// Reads the status field from the configuration space, or returns an
+    /// empty status if `VIRTIO_NET_F_STATUS` wasn't negotiated.
+    fn read_status(&self) -> Status {
+        if self.features.contains(Features::STATUS) {
+            // Safe because config points to a valid MMIO region for the config space.
+            unsafe { volread!(self.config, status) }
+        } else {
+            Status::empty()
+        }
+    }
+
+    /// Reads whether the link is up from the configuration space. Without
+    /// `VIRTIO_NET_F_STATUS`, the link is assumed to always be up.
+    fn read_link_up(&self) -> bool {
+        !self.features.contains(Features::STATUS) || self.read_status().contains(Status::LINK_UP)
+    }
+
+    /// Reads the link speed and duplex mode from the configuration space, if
+    /// `VIRTIO_NET_F_SPEED_DUPLEX` was negotiated.
+    fn read_link_speed(&self) -> Option<LinkSpeed> {
+        if !self.features.contains(Features::SPEED_DUPLEX) {
+            return None;
+        }
+        // Safe because config points to a valid MMIO region for the config space.
+        let (speed, duplex) =
+            unsafe { (volread!(self.config, speed), volread!(self.config, duplex)) };
+        Some(LinkSpeed::from_config(speed, duplex))
+    }
+
+    /// Returns the link speed and duplex mode reported by the device, or
+    /// `None` if it doesn't support `VIRTIO_NET_F_SPEED_DUPLEX`.
+    ///
+    /// Like [`link_up`](Self::link_up), this is updated by
+    /// [`ack_interrupt`](Self::ack_interrupt) when the device reports a
+    /// configuration change.
+    pub fn link_speed(&self) -> Option<LinkSpeed> {
+        self.link_speed
+    }
+
+    /// Returns whether the link is up.
+    ///
+    /// This is updated by [`ack_interrupt`](Self::ack_interrupt) when the
+    /// device reports a configuration change. It is always true if the device
+    /// doesn't support `VIRTIO_NET_F_STATUS`.
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    /// Returns the next pending event from the device, if any.
+    ///
+    /// Events are recorded by [`ack_interrupt`](Self::ack_interrupt).
+    pub fn poll_event(&mut self) -> Option<NetEvent> {
+        if self.link_changed {
+            self.link_changed = false;
+            Some(NetEvent::LinkChanged(self.link_up))
+        } else if self.announce {
+            self.announce = false;
+            Some(NetEvent::AnnounceRequested)
+        } else {
+            None
+        }
+    }
+
+    /// Tells the device that the driver has announced its presence, with the
+    /// `VIRTIO_NET_CTRL_ANNOUNCE_ACK` control command, after sending
+    /// gratuitous packets in response to [`NetEvent::AnnounceRequested`].
+    ///
+    /// This clears the announcement bit in the device's status. Returns
+    /// [`Error::Unsupported`] if `VIRTIO_NET_F_GUEST_ANNOUNCE` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// command.
+    pub fn ack_announce(&mut self) -> Result {
+        if !self.features.contains(Features::GUEST_ANNOUNCE) {
+            return Err(Error::Unsupported);
+        }
+        self.ctrl_command(CtrlClass::ANNOUNCE, CTRL_ANNOUNCE_ACK, &[])
     }
 
     /// Disable interrupts.
     pub fn disable_interrupts(&mut self) {
-        self.send_queue.set_dev_notify(false);
-        self.recv_queue.set_dev_notify(false);
+        for queue in self
+            .send_queues
+            .iter_mut()
+            .chain(&mut self.recv_queues)
+            .flatten()
+        {
+            queue.set_dev_notify(false);
+        }
     }
 
     /// Enable interrupts.
     pub fn enable_interrupts(&mut self) {
-        self.send_queue.set_dev_notify(true);
-        self.recv_queue.set_dev_notify(true);
+        for queue in self
+            .send_queues
+            .iter_mut()
+            .chain(&mut self.recv_queues)
+            .flatten()
+        {
+            queue.set_dev_notify(true);
+        }
     }
 
     /// Get MAC address.
@@ -87,14 +531,383 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         self.mac
     }
 
+    /// Returns the MTU reported by the device, if `VIRTIO_NET_F_MTU` was
+    /// negotiated.
+    ///
+    /// This doesn't include the Ethernet header.
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    /// Returns the minimum length of each receive buffer, including the
+    /// header.
+    ///
+    /// With mergeable receive buffers a packet may span several buffers, so
+    /// they only need to be large enough for the header. Otherwise each
+    /// buffer must be large enough for a whole packet of the device's MTU.
+    pub fn min_rx_buffer_len(&self) -> usize {
+        if self.features.contains(Features::MRG_RXBUF) {
+            self.hdr_len
+        } else if let Some(mtu) = self.mtu {
+            MIN_BUFFER_LEN.max(self.hdr_len + MAX_ETHERNET_HEADER_LEN + usize::from(mtu))
+        } else {
+            MIN_BUFFER_LEN
+        }
+    }
+
+    /// Returns the length of the header which precedes each transmitted
+    /// packet and each received packet.
+    ///
+    /// This is the size of [`VirtioNetHdrHash`] if
+    /// `VIRTIO_NET_F_HASH_REPORT` was negotiated, of [`VirtioNetHdrMrgRxbuf`]
+    /// if `VIRTIO_NET_F_MRG_RXBUF` was negotiated, or of [`VirtioNetHdr`]
+    /// otherwise.
+    pub fn hdr_len(&self) -> usize {
+        self.hdr_len
+    }
+
+    /// Returns whether the device can complete partial checksums of
+    /// transmitted packets, set with [`VirtioNetHdr::set_partial_csum`], i.e.
+    /// `VIRTIO_NET_F_CSUM` was negotiated.
+    pub fn tx_checksum_offload(&self) -> bool {
+        self.features.contains(Features::CSUM)
+    }
+
+    /// Returns whether the device may validate the checksums of received
+    /// packets, or leave them partial, i.e. `VIRTIO_NET_F_GUEST_CSUM` was
+    /// negotiated.
+    ///
+    /// Even then, the device needn't validate every packet, so those whose
+    /// [`RxChecksum`](super::RxChecksum) is `Unverified` must still be
+    /// checked. Partial checksums are completed by
+    /// [`receive_complete`](Self::receive_complete), so callers which don't
+    /// look at the header get the same packets as without the offload, except
+    /// for packets merged from several buffers, whose checksum must be
+    /// completed by the caller once they have been put together.
+    pub fn rx_checksum_offload(&self) -> bool {
+        self.features.contains(Features::GUEST_CSUM)
+    }
+
+    /// Sets the MAC address of the device, with the `VIRTIO_NET_CTRL_MAC_ADDR_SET`
+    /// control command.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_MAC_ADDR` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// address.
+    pub fn set_mac(&mut self, mac: EthernetAddress) -> Result {
+        if !self.features.contains(Features::CTL_MAC_ADDR) {
+            return Err(Error::Unsupported);
+        }
+        self.ctrl_command(CtrlClass::MAC, CTRL_MAC_ADDR_SET, &[&mac])?;
+        self.mac = mac;
+        Ok(())
+    }
+
+    /// Enables or disables promiscuous mode, in which the device receives all
+    /// packets regardless of their destination.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_RX` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// command.
+    pub fn set_promiscuous(&mut self, on: bool) -> Result {
+        self.ctrl_rx(Features::CTRL_RX, CTRL_RX_PROMISC, on)
+    }
+
+    /// Enables or disables receiving all multicast packets.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_RX` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// command.
+    pub fn set_all_multicast(&mut self, on: bool) -> Result {
+        self.ctrl_rx(Features::CTRL_RX, CTRL_RX_ALLMULTI, on)
+    }
+
+    /// Enables or disables dropping all broadcast packets.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_RX_EXTRA` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// command.
+    pub fn set_no_broadcast(&mut self, on: bool) -> Result {
+        self.ctrl_rx(Features::CTRL_RX_EXTRA, CTRL_RX_NOBCAST, on)
+    }
+
+    /// Enables or disables dropping all unicast packets.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_RX_EXTRA` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// command.
+    pub fn set_no_unicast(&mut self, on: bool) -> Result {
+        self.ctrl_rx(Features::CTRL_RX_EXTRA, CTRL_RX_NOUNI, on)
+    }
+
+    /// Sends a `VIRTIO_NET_CTRL_RX` class command to turn an RX mode on or
+    /// off, if the given feature was negotiated.
+    fn ctrl_rx(&mut self, feature: Features, command: u8, on: bool) -> Result {
+        if !self.features.contains(feature) {
+            return Err(Error::Unsupported);
+        }
+        self.ctrl_command(CtrlClass::RX, command, &[&[on.into()]])
+    }
+
+    /// Installs filter tables of unicast and multicast addresses to receive
+    /// packets for, with the `VIRTIO_NET_CTRL_MAC_TABLE_SET` control command.
+    ///
+    /// Packets for the device's own MAC address and broadcast packets are
+    /// still received, so empty tables mean only those are received, unless
+    /// promiscuous or all-multicast mode is enabled.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_RX` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// tables.
+    pub fn set_mac_filter(
+        &mut self,
+        unicast: &[EthernetAddress],
+        multicast: &[EthernetAddress],
+    ) -> Result {
+        if !self.features.contains(Features::CTRL_RX) {
+            return Err(Error::Unsupported);
+        }
+        let unicast_entries = u32::try_from(unicast.len()).map_err(|_| Error::InvalidParam)?;
+        let multicast_entries = u32::try_from(multicast.len()).map_err(|_| Error::InvalidParam)?;
+        self.ctrl_command(
+            CtrlClass::MAC,
+            CTRL_MAC_TABLE_SET,
+            &[
+                &unicast_entries.to_le_bytes(),
+                unicast.as_bytes(),
+                &multicast_entries.to_le_bytes(),
+                multicast.as_bytes(),
+            ],
+        )
+    }
+
+    /// Adds a VLAN ID to the device's VLAN filter, so that it receives packets
+    /// tagged with it.
+    ///
+    /// Returns [`Error::InvalidParam`] if `vid` is not less than 4096,
+    /// [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_VLAN` wasn't negotiated,
+    /// or [`NetError::CommandFailed`] if the device rejects the command.
+    pub fn add_vlan(&mut self, vid: u16) -> Result {
+        self.ctrl_vlan(CTRL_VLAN_ADD, vid)
+    }
+
+    /// Removes a VLAN ID from the device's VLAN filter.
+    ///
+    /// Returns [`Error::InvalidParam`] if `vid` is not less than 4096,
+    /// [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_VLAN` wasn't negotiated,
+    /// or [`NetError::CommandFailed`] if the device rejects the command.
+    pub fn del_vlan(&mut self, vid: u16) -> Result {
+        self.ctrl_vlan(CTRL_VLAN_DEL, vid)
+    }
+
+    /// Sends a `VIRTIO_NET_CTRL_VLAN` class command for the given VLAN ID.
+    fn ctrl_vlan(&mut self, command: u8, vid: u16) -> Result {
+        let data = vlan_command_data(vid)?;
+        if !self.features.contains(Features::CTRL_VLAN) {
+            return Err(Error::Unsupported);
+        }
+        self.ctrl_command(CtrlClass::VLAN, command, &[&data])
+    }
+
+    /// Returns the maximum length of the indirection table for receive-side
+    /// scaling, or 0 if `VIRTIO_NET_F_RSS` wasn't negotiated.
+    pub fn rss_max_indirection_table_len(&self) -> u16 {
+        if !self.features.contains(Features::RSS) {
+            return 0;
+        }
+        // Safe because config points to a valid MMIO region for the config space.
+        unsafe { volread!(self.config, rss_max_indirection_table_length) }
+    }
+
+    /// Returns the types of hash which the device supports for receive-side
+    /// scaling and hash reports, or none if neither `VIRTIO_NET_F_RSS` nor
+    /// `VIRTIO_NET_F_HASH_REPORT` was negotiated.
+    pub fn supported_hash_types(&self) -> HashTypes {
+        if !self
+            .features
+            .intersects(Features::RSS | Features::HASH_REPORT)
+        {
+            return HashTypes::empty();
+        }
+        // Safe because config points to a valid MMIO region for the config space.
+        HashTypes::from_bits_truncate(unsafe { volread!(self.config, supported_hash_types) })
+    }
+
+    /// Configures how the device hashes received packets, with the
+    /// `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` control command if `VIRTIO_NET_F_RSS`
+    /// was negotiated, or else `VIRTIO_NET_CTRL_MQ_HASH_CONFIG` to only
+    /// configure hash reports.
+    ///
+    /// With receive-side scaling, each hashed packet is received on the queue
+    /// pair given by the entry of the indirection table indexed by the low
+    /// bits of its hash. Without it, the indirection table and unclassified
+    /// queue are ignored.
+    ///
+    /// Returns [`Error::Unsupported`] if neither `VIRTIO_NET_F_RSS` nor
+    /// `VIRTIO_NET_F_HASH_REPORT` was negotiated, [`Error::InvalidParam`] if
+    /// the configuration asks for hash types or a key size which the device
+    /// doesn't support, or an indirection table which isn't a power of two no
+    /// longer than [`rss_max_indirection_table_len`] or refers to a queue pair
+    /// which isn't set up, or [`NetError::CommandFailed`] if the device
+    /// rejects the command.
+    ///
+    /// [`rss_max_indirection_table_len`]: Self::rss_max_indirection_table_len
+    pub fn configure_rss(&mut self, config: RssConfig) -> Result {
+        if !self
+            .features
+            .intersects(Features::RSS | Features::HASH_REPORT)
+        {
+            return Err(Error::Unsupported);
+        }
+        if !self.supported_hash_types().contains(config.hash_types) {
+            warn!("Unsupported hash types {:?}", config.hash_types);
+            return Err(Error::InvalidParam);
+        }
+        // Safe because config points to a valid MMIO region for the config space.
+        let max_key_size = unsafe { volread!(self.config, rss_max_key_size) };
+        let key_len = u8::try_from(config.key.len())
+            .ok()
+            .filter(|&key_len| key_len <= max_key_size)
+            .ok_or(Error::InvalidParam)?;
+
+        if !self.features.contains(Features::RSS) {
+            let mut hash_config = [0; 13];
+            hash_config[..4].copy_from_slice(&config.hash_types.bits().to_le_bytes());
+            hash_config[12] = key_len;
+            return self.ctrl_command(
+                CtrlClass::MQ,
+                CTRL_MQ_HASH_CONFIG,
+                &[&hash_config, config.key],
+            );
+        }
+
+        let table_len = config.indirection_table.len();
+        if !table_len.is_power_of_two()
+            || table_len > usize::from(self.rss_max_indirection_table_len())
+        {
+            warn!("Invalid RSS indirection table length {}", table_len);
+            return Err(Error::InvalidParam);
+        }
+        if config
+            .indirection_table
+            .iter()
+            .chain([&config.unclassified_queue])
+            .any(|&pair| pair >= self.num_pairs)
+        {
+            return Err(Error::InvalidParam);
+        }
+        let mut rss_header = [0; 8];
+        rss_header[..4].copy_from_slice(&config.hash_types.bits().to_le_bytes());
+        // The length is a power of two no more than a `u16`, so this can't truncate.
+        rss_header[4..6].copy_from_slice(&((table_len - 1) as u16).to_le_bytes());
+        rss_header[6..].copy_from_slice(&config.unclassified_queue.to_le_bytes());
+        let mut rss_tail = [0; 3];
+        rss_tail[..2].copy_from_slice(&self.num_pairs.to_le_bytes());
+        rss_tail[2] = key_len;
+        self.ctrl_command(
+            CtrlClass::MQ,
+            CTRL_MQ_RSS_CONFIG,
+            &[
+                &rss_header,
+                config.indirection_table.as_bytes(),
+                &rss_tail,
+                config.key,
+            ],
+        )
+    }
+
+    /// Asks the device to delay transmit completion interrupts until
+    /// `max_packets` packets have been sent or `usecs` microseconds have
+    /// passed since the first of them, with the
+    /// `VIRTIO_NET_CTRL_NOTF_COAL_TX_SET` control command.
+    ///
+    /// Setting both to 0 disables coalescing.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_NOTF_COAL` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// command.
+    pub fn set_tx_coalescing(&mut self, usecs: u32, max_packets: u32) -> Result {
+        self.ctrl_coalescing(CTRL_NOTF_COAL_TX_SET, usecs, max_packets)
+    }
+
+    /// Asks the device to delay receive interrupts until `max_packets`
+    /// packets have been received or `usecs` microseconds have passed since
+    /// the first of them, with the `VIRTIO_NET_CTRL_NOTF_COAL_RX_SET` control
+    /// command.
+    ///
+    /// Setting both to 0 disables coalescing.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_NOTF_COAL` wasn't
+    /// negotiated, or [`NetError::CommandFailed`] if the device rejects the
+    /// command.
+    pub fn set_rx_coalescing(&mut self, usecs: u32, max_packets: u32) -> Result {
+        self.ctrl_coalescing(CTRL_NOTF_COAL_RX_SET, usecs, max_packets)
+    }
+
+    /// Sends a `VIRTIO_NET_CTRL_NOTF_COAL` class command with the given
+    /// parameters.
+    fn ctrl_coalescing(&mut self, command: u8, usecs: u32, max_packets: u32) -> Result {
+        if !self.features.contains(Features::NOTF_COAL) {
+            return Err(Error::Unsupported);
+        }
+        let data = coalescing_command_data(usecs, max_packets);
+        self.ctrl_command(CtrlClass::NOTF_COAL, command, &[&data])
+    }
+
+    /// Sends a command on the control queue with the given command-specific
+    /// data, and waits for the device to acknowledge it.
+    ///
+    /// The data may be split across several buffers, up to [`MAX_CTRL_DATA`],
+    /// which are sent one after the other. Empty buffers are skipped.
+    ///
+    /// Returns [`Error::Unsupported`] if there is no control queue.
+    fn ctrl_command(&mut self, class: CtrlClass, command: u8, data: &[&[u8]]) -> Result {
+        assert!(data.len() <= MAX_CTRL_DATA);
+        let ctrl_queue = self.ctrl_queue.as_mut().ok_or(Error::Unsupported)?;
+        let header = CtrlHdr { class, command };
+        let mut inputs: [&[u8]; MAX_CTRL_DATA + 1] = [&[]; MAX_CTRL_DATA + 1];
+        inputs[0] = header.as_bytes();
+        let mut num_inputs = 1;
+        for buffer in data.iter().filter(|buffer| !buffer.is_empty()) {
+            inputs[num_inputs] = buffer;
+            num_inputs += 1;
+        }
+        let mut ack = [CTRL_ACK_ERR];
+        ctrl_queue.add_notify_wait_pop(
+            &inputs[..num_inputs],
+            &mut [&mut ack],
+            &mut self.transport,
+        )?;
+        match ack[0] {
+            CTRL_ACK_OK => Ok(()),
+            CTRL_ACK_ERR => Err(NetError::CommandFailed.into()),
+            ack => Err(NetError::BadAck(ack).into()),
+        }
+    }
+
     /// Whether can send packet.
     pub fn can_send(&self) -> bool {
-        self.send_queue.available_desc() >= 2
+        self.can_send_on(0)
+    }
+
+    fn can_send_on(&self, pair: u16) -> bool {
+        self.send_queues[usize::from(pair)]
+            .as_ref()
+            .is_some_and(|queue| queue.available_desc() >= 2)
+    }
+
+    /// Whether the first transmit queue has room for a buffer holding both
+    /// the header and the packet, as passed to
+    /// [`transmit_begin`](Self::transmit_begin).
+    pub(super) fn can_transmit(&self) -> bool {
+        self.send_queues[0]
+            .as_ref()
+            .is_some_and(|queue| queue.available_desc() >= 1)
     }
 
     /// Whether the length of the receive buffer is valid.
-    fn check_rx_buf_len(rx_buf: &[u8]) -> Result<()> {
-        if rx_buf.len() < MIN_BUFFER_LEN {
+    fn check_rx_buf_len(&self, rx_buf: &[u8]) -> Result<()> {
+        if rx_buf.len() < self.min_rx_buffer_len() {
             warn!("Receive buffer len {} is too small", rx_buf.len());
             Err(Error::InvalidParam)
         } else {
@@ -102,26 +915,106 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         }
     }
 
-    /// Whether the length of the transmit buffer is valid.
-    fn check_tx_buf_len(tx_buf: &[u8]) -> Result<()> {
-        if tx_buf.len() < NET_HDR_SIZE {
-            warn!("Transmit buffer len {} is too small", tx_buf.len());
-            Err(Error::InvalidParam)
+    /// Whether the transmit buffer, including its header, is valid.
+    fn check_tx_buf(&self, tx_buf: &[u8]) -> Result<()> {
+        let header = match VirtioNetHdr::read_from_prefix(tx_buf) {
+            Some(header) if tx_buf.len() >= self.hdr_len => header,
+            _ => {
+                warn!("Transmit buffer len {} is too small", tx_buf.len());
+                return Err(Error::InvalidParam);
+            }
+        };
+        self.check_tx_header(&header, tx_buf.len() - self.hdr_len)
+    }
+
+    /// Checks that the device supports the offloads requested by the given
+    /// transmit header, and that the packet isn't too long for them.
+    ///
+    /// Packets which aren't segmented by the device must fit in the device's
+    /// MTU, if it reported one, or else [`NetError::PacketTooLong`] is
+    /// returned.
+    fn check_tx_header(&self, header: &VirtioNetHdr, packet_len: usize) -> Result<()> {
+        if header.flags.contains(Flags::NEEDS_CSUM) && !self.features.contains(Features::CSUM) {
+            warn!("Device doesn't support transmit checksum offload");
+            return Err(Error::Unsupported);
+        }
+        let max_packet_len = if header.gso_type == GsoType::NONE {
+            if let Some(mtu) = self.mtu {
+                let max = usize::from(mtu) + MAX_ETHERNET_HEADER_LEN;
+                if packet_len > max {
+                    warn!("Transmit packet len {} is larger than the MTU", packet_len);
+                    return Err(NetError::PacketTooLong {
+                        len: packet_len,
+                        max,
+                    }
+                    .into());
+                }
+            }
+            MAX_BUFFER_LEN - self.hdr_len
         } else {
-            Ok(())
+            let feature = match header.gso_type.without_ecn() {
+                GsoType::TCPV4 => Features::HOST_TSO4,
+                GsoType::TCPV6 => Features::HOST_TSO6,
+                GsoType::UDP => Features::HOST_UFO,
+                GsoType::UDP_L4 => Features::HOST_USO,
+                _ => return Err(Error::InvalidParam),
+            };
+            let ecn = header.gso_type.0 & GsoType::ECN.0 != 0;
+            if !self.features.contains(feature)
+                || (ecn && !self.features.contains(Features::HOST_ECN))
+            {
+                warn!("Device doesn't support GSO type {:?}", header.gso_type);
+                return Err(Error::Unsupported);
+            }
+            if !header.flags.contains(Flags::NEEDS_CSUM) || header.gso_size == 0 {
+                return Err(Error::InvalidParam);
+            }
+            MAX_GSO_PACKET_LEN
+        };
+        if packet_len > max_packet_len {
+            warn!("Transmit packet len {} is too large", packet_len);
+            return Err(Error::InvalidParam);
         }
+        Ok(())
     }
 
     /// Fill the header of the `buffer` with [`VirtioNetHdr`].
     ///
     /// If the `buffer` is not large enough, it returns [`Error::InvalidParam`].
     pub fn fill_buffer_header(&self, buffer: &mut [u8]) -> Result<usize> {
-        if buffer.len() < NET_HDR_SIZE {
+        self.fill_buffer_header_with(buffer, &VirtioNetHdr::default())
+    }
+
+    /// Fill the header of the `buffer` with the given [`VirtioNetHdr`], e.g.
+    /// one which requests checksum offload.
+    ///
+    /// If the `buffer` is not large enough, it returns [`Error::InvalidParam`].
+    /// If the header requests an offload which the device doesn't support, it
+    /// returns [`Error::Unsupported`].
+    pub fn fill_buffer_header_with(
+        &self,
+        buffer: &mut [u8],
+        header: &VirtioNetHdr,
+    ) -> Result<usize> {
+        if buffer.len() < self.hdr_len {
             return Err(Error::InvalidParam);
         }
-        let header = VirtioNetHdr::default();
-        buffer[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
-        Ok(NET_HDR_SIZE)
+        self.check_tx_header(header, buffer.len() - self.hdr_len)?;
+        buffer[..self.hdr_len].copy_from_slice(&self.tx_header(header).as_bytes()[..self.hdr_len]);
+        Ok(self.hdr_len)
+    }
+
+    /// Returns the given transmit header, with `num_buffers` and the hash
+    /// fields set to 0. Only the first [`hdr_len`](Self::hdr_len) bytes
+    /// should be sent.
+    fn tx_header(&self, header: &VirtioNetHdr) -> VirtioNetHdrHash {
+        VirtioNetHdrHash {
+            hdr: VirtioNetHdrMrgRxbuf {
+                hdr: header.clone(),
+                num_buffers: 0,
+            },
+            ..Default::default()
+        }
     }
 
     /// Submits a request to transmit a buffer immediately without waiting for
@@ -130,7 +1023,8 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// It will submit request to the VirtIO net device and return a token
     /// identifying the position of the first descriptor in the chain. If there
     /// are not enough descriptors to allocate, then it returns
-    /// [`Error::QueueFull`].
+    /// [`Error::QueueFull`]. If the header requests an offload which the
+    /// device doesn't support, it returns [`Error::Unsupported`].
     ///
     /// The caller needs to fill the `tx_buf` with a header by calling
     /// [`fill_buffer_header`] before transmission. Then it calls [`poll_transmit`]
@@ -149,19 +1043,28 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// [`poll_transmit`]: Self::poll_transmit
     /// [`transmit_complete`]: Self::transmit_complete
     pub unsafe fn transmit_begin(&mut self, tx_buf: &[u8]) -> Result<u16> {
-        Self::check_tx_buf_len(tx_buf)?;
-        let token = self.send_queue.add(&[tx_buf], &mut [])?;
-        if self.send_queue.should_notify() {
-            self.transport.notify(QUEUE_TRANSMIT);
+        self.transmit_begin_on(0, tx_buf)
+    }
+
+    unsafe fn transmit_begin_on(&mut self, pair: u16, tx_buf: &[u8]) -> Result<u16> {
+        self.check_tx_buf(tx_buf)?;
+        let send_queue = self.send_queue(pair);
+        let result = send_queue.add(&[tx_buf], &mut []);
+        if result.is_ok() && send_queue.should_notify() {
+            self.transport.notify(transmit_queue_index(pair));
         }
-        Ok(token)
+        // The header was already parsed by `check_tx_buf`.
+        let header = VirtioNetHdr::read_from_prefix(tx_buf).unwrap();
+        self.stats
+            .record_tx(&header, tx_buf.len() - self.hdr_len, &result);
+        result
     }
 
     /// Fetches the token of the next completed transmission request from the
     /// used ring and returns it, without removing it from the used ring. If
     /// there are no pending completed requests it returns [`None`].
     pub fn poll_transmit(&mut self) -> Option<u16> {
-        self.send_queue.peek_used()
+        self.send_queue(0).peek_used()
     }
 
     /// Completes a transmission operation which was started by [`transmit_begin`].
@@ -174,10 +1077,40 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     ///
     /// [`transmit_begin`]: Self::transmit_begin
     pub unsafe fn transmit_complete(&mut self, token: u16, tx_buf: &[u8]) -> Result<usize> {
-        let len = self.send_queue.pop_used(token, &[tx_buf], &mut [])?;
+        self.transmit_complete_on(0, token, tx_buf)
+    }
+
+    unsafe fn transmit_complete_on(
+        &mut self,
+        pair: u16,
+        token: u16,
+        tx_buf: &[u8],
+    ) -> Result<usize> {
+        let len = self.send_queue(pair).pop_used(token, &[tx_buf], &mut [])?;
         Ok(len as usize)
     }
 
+    /// Reclaims the buffer of a transmission which was dropped by
+    /// [`reset`](Self::reset), as listed in the [`DroppedTransmits`] it
+    /// returned, so that the buffer is no longer borrowed by the device.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`transmit_begin`](Self::transmit_begin) when it returned the token.
+    pub unsafe fn transmit_reclaim(&mut self, token: u16, tx_buf: &[u8]) -> Result {
+        self.transmit_reclaim_on(0, token, tx_buf)
+    }
+
+    pub(super) unsafe fn transmit_reclaim_on(
+        &mut self,
+        pair: u16,
+        token: u16,
+        tx_buf: &[u8],
+    ) -> Result {
+        self.send_queue(pair).pop_pending(token, &[tx_buf], &mut [])
+    }
+
     /// Submits a request to receive a buffer immediately without waiting for
     /// the reception to complete.
     ///
@@ -201,26 +1134,118 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// [`poll_receive`]: Self::poll_receive
     /// [`receive_complete`]: Self::receive_complete
     pub unsafe fn receive_begin(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
-        Self::check_rx_buf_len(rx_buf)?;
-        let token = self.recv_queue.add(&[], &mut [rx_buf])?;
-        if self.recv_queue.should_notify() {
-            self.transport.notify(QUEUE_RECEIVE);
+        self.receive_begin_on(0, rx_buf)
+    }
+
+    unsafe fn receive_begin_on(&mut self, pair: u16, rx_buf: &mut [u8]) -> Result<u16> {
+        // Safe because our caller promises the same things as we require.
+        let token = unsafe { self.add_rx_buffer_on(pair, rx_buf)? };
+        self.notify_rx_on(pair);
+        Ok(token)
+    }
+
+    /// Adds a receive buffer to the receive queue of the given pair like
+    /// [`receive_begin`](Self::receive_begin), but without notifying the
+    /// device, so that several buffers can be added with a single
+    /// notification from [`notify_rx_on`](Self::notify_rx_on).
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`receive_begin`](Self::receive_begin).
+    pub(super) unsafe fn add_rx_buffer_on(&mut self, pair: u16, rx_buf: &mut [u8]) -> Result<u16> {
+        self.check_rx_buf_len(rx_buf)?;
+        // Safe because our caller promises the same things as `VirtQueue::add` requires.
+        unsafe { self.recv_queue(pair).add(&[], &mut [rx_buf]) }
+    }
+
+    /// Submits a receive buffer in memory which is already shared with the
+    /// device, such as part of a [`Dma`] region, to the first receive queue
+    /// like [`receive_begin`](Self::receive_begin), but without sharing it
+    /// again.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must remain valid and accessible to the device at its
+    /// physical address, and must not be accessed, until it is returned by
+    /// [`receive_complete_shared`](Self::receive_complete_shared).
+    pub(super) unsafe fn receive_begin_shared(&mut self, rx_buf: SharedBuffer) -> Result<u16> {
+        if rx_buf.len() < self.min_rx_buffer_len() {
+            warn!("Receive buffer len {} is too small", rx_buf.len());
+            return Err(Error::InvalidParam);
         }
+        // Safe because our caller promises the same things as `VirtQueue::add_shared` requires.
+        let token = unsafe { self.recv_queue(0).add_shared(&[], rx_buf, &mut []) }?;
+        self.notify_rx_on(0);
         Ok(token)
     }
 
+    /// Completes a reception started by
+    /// [`receive_begin_shared`](Self::receive_begin_shared), like
+    /// [`receive_complete`](Self::receive_complete).
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// `receive_begin_shared` when it returned the token.
+    pub(super) unsafe fn receive_complete_shared(
+        &mut self,
+        token: u16,
+        rx_buf: SharedBuffer,
+    ) -> Result<(usize, usize)> {
+        // Safe because our caller promises the same things as `VirtQueue::pop_used_shared`
+        // requires.
+        let len = unsafe {
+            self.recv_queue(0)
+                .pop_used_shared(token, &[], rx_buf, &mut [])
+        }?;
+        self.check_rx_starved(0);
+        let packet_len = (len as usize)
+            .checked_sub(self.hdr_len)
+            .ok_or(Error::IoError)?;
+        // Safe because the device has finished with the buffer, so it belongs to us again.
+        let buf = unsafe { rx_buf.buffer().as_mut() };
+        if let Some(header) = VirtioNetHdr::read_from_prefix(buf) {
+            self.stats.record_rx(&header, packet_len);
+        }
+        if self.num_buffers(buf) == 1 {
+            complete_rx_checksum(&mut buf[..len as usize], self.hdr_len);
+        }
+        Ok((self.hdr_len, packet_len))
+    }
+
+    /// Notifies the device of new buffers in the receive queue of the given
+    /// pair, unless it has suppressed notifications.
+    pub(super) fn notify_rx_on(&mut self, pair: u16) {
+        if self.recv_queue(pair).should_notify() {
+            self.transport.notify(receive_queue_index(pair));
+        }
+    }
+
     /// Fetches the token of the next completed reception request from the
     /// used ring and returns it, without removing it from the used ring. If
     /// there are no pending completed requests it returns [`None`].
     pub fn poll_receive(&self) -> Option<u16> {
-        self.recv_queue.peek_used()
+        self.poll_receive_on(0)
+    }
+
+    pub(super) fn poll_receive_on(&self, pair: u16) -> Option<u16> {
+        self.recv_queues[usize::from(pair)].as_ref()?.peek_used()
+    }
+
+    /// Returns the number of completed receive buffers on the given pair
+    /// which haven't been taken yet.
+    pub(super) fn rx_returned_on(&self, pair: u16) -> usize {
+        self.recv_queues[usize::from(pair)]
+            .as_ref()
+            .map_or(0, VirtQueue::returned)
     }
 
     /// Completes a transmission operation which was started by [`receive_begin`].
     ///
     /// After completion, the `rx_buf` will contain a header followed by the
     /// received packet. It returns the length of the header and the length of
-    /// the packet.
+    /// the packet. If the device left a partial checksum in a packet which
+    /// fits in the buffer, it is completed.
     ///
     /// # Safety
     ///
@@ -233,37 +1258,230 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         token: u16,
         rx_buf: &mut [u8],
     ) -> Result<(usize, usize)> {
-        let len = self.recv_queue.pop_used(token, &[], &mut [rx_buf])? as usize;
-        let packet_len = len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)?;
-        Ok((NET_HDR_SIZE, packet_len))
+        self.receive_complete_on(0, token, rx_buf)
+    }
+
+    unsafe fn receive_complete_on(
+        &mut self,
+        pair: u16,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        let len = self.recv_queue(pair).pop_used(token, &[], &mut [rx_buf])? as usize;
+        self.check_rx_starved(pair);
+        let packet_len = len.checked_sub(self.hdr_len).ok_or(Error::IoError)?;
+        if let Some(header) = VirtioNetHdr::read_from_prefix(rx_buf) {
+            self.stats.record_rx(&header, packet_len);
+        }
+        if self.num_buffers(rx_buf) == 1 {
+            complete_rx_checksum(&mut rx_buf[..len], self.hdr_len);
+        }
+        Ok((self.hdr_len, packet_len))
+    }
+
+    /// Counts it in the statistics if the receive queue of the given pair has
+    /// no buffers left for the device.
+    fn check_rx_starved(&mut self, pair: u16) {
+        let recv_queue = self.recv_queue(pair);
+        if recv_queue.available_desc() == usize::from(recv_queue.size()) {
+            self.stats.rx_starved = self.stats.rx_starved.wrapping_add(1);
+        }
+    }
+
+    /// Returns the hash which the device reported for the packet in the given
+    /// completed receive buffer, if `VIRTIO_NET_F_HASH_REPORT` was negotiated
+    /// and the device calculated one.
+    pub fn rx_hash(&self, rx_buf: &[u8]) -> Option<RxHash> {
+        rx_hash(rx_buf, self.hdr_len)
+    }
+
+    /// Returns the number of receive buffers which the packet starting in the
+    /// given completed buffer was merged from.
+    ///
+    /// This is always 1 unless `VIRTIO_NET_F_MRG_RXBUF` was negotiated. If it
+    /// is more, the rest of the packet is in the following buffers returned by
+    /// [`poll_receive`], which must be completed with
+    /// [`receive_complete_merged`].
+    ///
+    /// [`poll_receive`]: Self::poll_receive
+    /// [`receive_complete_merged`]: Self::receive_complete_merged
+    pub fn num_buffers(&self, rx_buf: &[u8]) -> usize {
+        if !self.features.contains(Features::MRG_RXBUF) {
+            return 1;
+        }
+        VirtioNetHdrMrgRxbuf::read_from_prefix(rx_buf)
+            .map_or(1, |header| usize::from(header.num_buffers).max(1))
+    }
+
+    /// Completes a reception operation which was started by
+    /// [`receive_begin`], for a buffer which holds a later part of a packet
+    /// merged from several buffers, as reported by [`num_buffers`].
+    ///
+    /// Such buffers don't start with a header, so this returns the length of
+    /// the packet data in the buffer.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`receive_begin`] when it returned the token.
+    ///
+    /// [`receive_begin`]: Self::receive_begin
+    /// [`num_buffers`]: Self::num_buffers
+    pub unsafe fn receive_complete_merged(
+        &mut self,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<usize> {
+        self.receive_complete_merged_on(0, token, rx_buf)
+    }
+
+    unsafe fn receive_complete_merged_on(
+        &mut self,
+        pair: u16,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<usize> {
+        let len = self.recv_queue(pair).pop_used(token, &[], &mut [rx_buf])? as usize;
+        self.check_rx_starved(pair);
+        self.stats.rx_bytes = self.stats.rx_bytes.wrapping_add(len as u64);
+        Ok(len)
     }
 
     /// Sends a packet to the network, and blocks until the request completed.
     pub fn send(&mut self, tx_buf: &[u8]) -> Result {
-        let header = VirtioNetHdr::default();
-        if tx_buf.is_empty() {
+        self.send_with_header(&VirtioNetHdr::default(), tx_buf)
+    }
+
+    /// Sends a packet to the network with the given header, e.g. one which
+    /// requests checksum offload, and blocks until the request completed.
+    ///
+    /// If the header requests an offload which the device doesn't support, it
+    /// returns [`Error::Unsupported`].
+    pub fn send_with_header(&mut self, header: &VirtioNetHdr, tx_buf: &[u8]) -> Result {
+        self.send_with_header_on(0, header, tx_buf)
+    }
+
+    fn send_with_header_on(&mut self, pair: u16, header: &VirtioNetHdr, tx_buf: &[u8]) -> Result {
+        self.check_tx_header(header, tx_buf.len())?;
+        let tx_header = self.tx_header(header);
+        let tx_header = &tx_header.as_bytes()[..self.hdr_len];
+        let send_queue = self.send_queues[usize::from(pair)]
+            .as_mut()
+            .expect("queue pair not set up");
+        let result = if tx_buf.is_empty() {
             // Special case sending an empty packet, to avoid adding an empty buffer to the
             // virtqueue.
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes()],
-                &mut [],
-                &mut self.transport,
-            )?;
+            send_queue.add_notify_wait_pop(&[tx_header], &mut [], &mut self.transport)
         } else {
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes(), tx_buf],
-                &mut [],
-                &mut self.transport,
-            )?;
+            send_queue.add_notify_wait_pop(&[tx_header, tx_buf], &mut [], &mut self.transport)
+        };
+        self.stats.record_tx(header, tx_buf.len(), &result);
+        result.map(|_| ())
+    }
+
+    /// Submits a packet with the given header to be sent on the first queue
+    /// pair without copying it, and returns a token for the request.
+    ///
+    /// As the payload is a shared `'static` reference, it can't be changed or
+    /// freed while the device may still be reading it. The header is copied
+    /// into DMA memory owned by the driver, which is allocated the first time
+    /// this is called. Call [`VirtIONetRaw::poll_transmit_done`] to reap the
+    /// request once the device has finished with it.
+    ///
+    /// Requests made this way can't be mixed with
+    /// [`VirtIONetRaw::transmit_begin`] or blocking sends on the same queue
+    /// pair while any of them are outstanding, as completions are reaped in
+    /// order.
+    pub fn send_borrowed(&mut self, header: &VirtioNetHdr, payload: &'static [u8]) -> Result<u16> {
+        self.check_tx_header(header, payload.len())?;
+        let slot = self
+            .borrowed
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::QueueFull)?;
+        let tx_header = self.tx_header(header);
+        let tx_header = &tx_header.as_bytes()[..self.hdr_len];
+        let header_buf = self.borrowed_header(slot)?;
+        // Safe because the slot is free, so the device isn't accessing it.
+        unsafe {
+            header_buf
+                .as_ptr()
+                .cast::<u8>()
+                .copy_from_nonoverlapping(tx_header.as_ptr(), tx_header.len());
         }
-        Ok(())
+        // Safe because the header slot lives as long as the queue and isn't
+        // changed until the request is reaped, and the payload is immutable
+        // and `'static`.
+        let header_buf = unsafe { header_buf.as_ref() };
+        let send_queue = self.send_queue(0);
+        let result = if payload.is_empty() {
+            unsafe { send_queue.add(&[header_buf], &mut []) }
+        } else {
+            unsafe { send_queue.add(&[header_buf, payload], &mut []) }
+        };
+        if result.is_ok() && send_queue.should_notify() {
+            self.transport.notify(transmit_queue_index(0));
+        }
+        self.stats.record_tx(header, payload.len(), &result);
+        let token = result?;
+        self.borrowed[slot] = Some((token, payload));
+        Ok(token)
+    }
+
+    /// Reaps the next completed request made with
+    /// [`VirtIONetRaw::send_borrowed`], and returns its token, which means its
+    /// payload is no longer used by the device. If there are no completed
+    /// requests it returns `None`.
+    ///
+    /// If the next completed request on the first queue pair wasn't made with
+    /// `send_borrowed`, it returns [`Error::WrongToken`] and leaves it in the
+    /// used ring.
+    pub fn poll_transmit_done(&mut self) -> Result<Option<u16>> {
+        let Some(token) = self.send_queue(0).peek_used() else {
+            return Ok(None);
+        };
+        let slot = self
+            .borrowed
+            .iter()
+            .position(|borrowed| borrowed.is_some_and(|(t, _)| t == token))
+            .ok_or(Error::WrongToken)?;
+        let (_, payload) = self.borrowed[slot].take().unwrap();
+        // Safe because the slot was allocated and written by `send_borrowed`.
+        let header_buf = unsafe { self.borrowed_header(slot)?.as_ref() };
+        let send_queue = self.send_queue(0);
+        // Safe because these are the same buffers as were passed to `add` for
+        // this token.
+        if payload.is_empty() {
+            unsafe { send_queue.pop_used(token, &[header_buf], &mut []) }?;
+        } else {
+            unsafe { send_queue.pop_used(token, &[header_buf, payload], &mut []) }?;
+        }
+        Ok(Some(token))
+    }
+
+    /// Returns the given header slot for [`VirtIONetRaw::send_borrowed`],
+    /// allocating the DMA memory for them if necessary.
+    fn borrowed_header(&mut self, slot: usize) -> Result<NonNull<[u8]>> {
+        let headers = match &self.borrowed_headers {
+            Some(headers) => headers,
+            None => self.borrowed_headers.insert(Dma::new(
+                self.hal,
+                pages(QUEUE_SIZE * size_of::<VirtioNetHdrHash>()),
+                BufferDirection::DriverToDevice,
+            )?),
+        };
+        Ok(NonNull::slice_from_raw_parts(
+            headers.vaddr(slot * size_of::<VirtioNetHdrHash>()),
+            self.hdr_len,
+        ))
     }
 
     /// Blocks and waits for a packet to be received.
     ///
     /// After completion, the `rx_buf` will contain a header followed by the
     /// received packet. It returns the length of the header and the length of
-    /// the packet.
+    /// the packet. With mergeable receive buffers, the buffer must be large
+    /// enough for the whole packet.
     pub fn receive_wait(&mut self, rx_buf: &mut [u8]) -> Result<(usize, usize)> {
         let token = unsafe { self.receive_begin(rx_buf)? };
         while self.poll_receive().is_none() {
@@ -273,11 +1491,196 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     }
 }
 
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRaw<H, T, QUEUE_SIZE> {
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> Drop
+    for VirtIONetRaw<H, T, QUEUE_SIZE, MAX_PAIRS>
+{
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVE);
-        self.transport.queue_unset(QUEUE_TRANSMIT);
+        self.unset_pairs_from(0);
+        if self.ctrl_queue.is_some() {
+            self.transport.queue_unset(self.ctrl_queue_index);
+        }
+    }
+}
+
+/// The transmissions which were dropped by [`VirtIONetRaw::reset`], whose
+/// buffers are still borrowed until they are reclaimed with
+/// [`VirtIONetRaw::transmit_reclaim`] or [`NetQueuePair::transmit_reclaim`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DroppedTransmits<const QUEUE_SIZE: usize, const MAX_PAIRS: usize> {
+    tokens: [[bool; QUEUE_SIZE]; MAX_PAIRS],
+}
+
+impl<const QUEUE_SIZE: usize, const MAX_PAIRS: usize> DroppedTransmits<QUEUE_SIZE, MAX_PAIRS> {
+    /// Returns the tokens of the transmissions which were dropped on the given
+    /// queue pair.
+    pub fn tokens(&self, pair: u16) -> impl Iterator<Item = u16> + '_ {
+        self.tokens
+            .get(usize::from(pair))
+            .into_iter()
+            .flat_map(|tokens| (0..).zip(tokens))
+            .filter_map(|(token, &dropped)| dropped.then_some(token))
+    }
+
+    /// Whether no transmissions were dropped on any queue pair.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.iter().flatten().all(|&dropped| !dropped)
+    }
+}
+
+/// A handle to one of the queue pairs of a [`VirtIONetRaw`], returned by
+/// [`VirtIONetRaw::queue_pair`].
+///
+/// Its methods behave like the methods of the same names on [`VirtIONetRaw`],
+/// but use this pair's queues. Tokens are only valid for the pair which
+/// returned them.
+pub struct NetQueuePair<'a, H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> {
+    net: &'a mut VirtIONetRaw<H, T, QUEUE_SIZE, MAX_PAIRS>,
+    index: u16,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize>
+    NetQueuePair<'_, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    /// Returns the index of this queue pair.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the size of the receive queue of this pair.
+    pub fn rx_queue_size(&self) -> u16 {
+        self.net.rx_queue_size_on(self.index)
+    }
+
+    /// Whether the transmit queue of this pair has room for another packet.
+    pub fn can_send(&self) -> bool {
+        self.net.can_send_on(self.index)
+    }
+
+    /// Submits a request to transmit a buffer on this pair immediately without
+    /// waiting for the transmission to complete.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIONetRaw::transmit_begin`].
+    pub unsafe fn transmit_begin(&mut self, tx_buf: &[u8]) -> Result<u16> {
+        self.net.transmit_begin_on(self.index, tx_buf)
+    }
+
+    /// Fetches the token of the next completed transmission request on this
+    /// pair, without removing it from the used ring.
+    pub fn poll_transmit(&mut self) -> Option<u16> {
+        self.net.send_queue(self.index).peek_used()
+    }
+
+    /// Completes a transmission operation on this pair which was started by
+    /// [`NetQueuePair::transmit_begin`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIONetRaw::transmit_complete`].
+    pub unsafe fn transmit_complete(&mut self, token: u16, tx_buf: &[u8]) -> Result<usize> {
+        self.net.transmit_complete_on(self.index, token, tx_buf)
+    }
+
+    /// Reclaims the buffer of a transmission on this pair which was dropped by
+    /// [`VirtIONetRaw::reset`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIONetRaw::transmit_reclaim`].
+    pub unsafe fn transmit_reclaim(&mut self, token: u16, tx_buf: &[u8]) -> Result {
+        self.net.transmit_reclaim_on(self.index, token, tx_buf)
+    }
+
+    /// Submits a request to receive a buffer on this pair immediately without
+    /// waiting for the reception to complete.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIONetRaw::receive_begin`].
+    pub unsafe fn receive_begin(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
+        self.net.receive_begin_on(self.index, rx_buf)
+    }
+
+    /// Fetches the token of the next completed reception request on this pair,
+    /// without removing it from the used ring.
+    pub fn poll_receive(&self) -> Option<u16> {
+        self.net.poll_receive_on(self.index)
+    }
+
+    /// Completes a reception operation on this pair which was started by
+    /// [`NetQueuePair::receive_begin`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIONetRaw::receive_complete`].
+    pub unsafe fn receive_complete(
+        &mut self,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        self.net.receive_complete_on(self.index, token, rx_buf)
+    }
+
+    /// Completes a reception operation on this pair which filled one buffer of
+    /// a packet spanning several mergeable receive buffers.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIONetRaw::receive_complete_merged`].
+    pub unsafe fn receive_complete_merged(
+        &mut self,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<usize> {
+        self.net
+            .receive_complete_merged_on(self.index, token, rx_buf)
+    }
+
+    /// Sends a packet on this pair, and blocks until the request completed.
+    pub fn send(&mut self, tx_buf: &[u8]) -> Result {
+        self.send_with_header(&VirtioNetHdr::default(), tx_buf)
+    }
+
+    /// Sends a packet with the given header on this pair, and blocks until the
+    /// request completed.
+    pub fn send_with_header(&mut self, header: &VirtioNetHdr, tx_buf: &[u8]) -> Result {
+        self.net.send_with_header_on(self.index, header, tx_buf)
+    }
+}
+
+/// Reads the MAC address from the configuration space, and the MTU if
+/// `VIRTIO_NET_F_MTU` was negotiated.
+fn read_mac_and_mtu(
+    config: NonNull<Config>,
+    negotiated_features: Features,
+) -> (EthernetAddress, Option<u16>) {
+    // Safe because config points to a valid MMIO region for the config space.
+    unsafe {
+        let mac = volread!(config, mac);
+        let mtu = negotiated_features
+            .contains(Features::MTU)
+            .then(|| volread!(config, mtu));
+        debug!(
+            "Got MAC={:02x?}, status={:?}",
+            mac,
+            volread!(config, status)
+        );
+        (mac, mtu)
+    }
+}
+
+/// Reads the number of queue pairs which the device supports.
+///
+/// Multiple queue pairs can only be enabled with a control command, so this is
+/// 1 unless both `VIRTIO_NET_F_MQ` and `VIRTIO_NET_F_CTRL_VQ` were negotiated.
+fn read_device_pairs(config: NonNull<Config>, negotiated_features: Features) -> u16 {
+    if negotiated_features.contains(Features::MQ | Features::CTRL_VQ) {
+        // Safe because config points to a valid MMIO region for the config space.
+        unsafe { volread!(config, max_virtqueue_pairs) }.max(1)
+    } else {
+        1
     }
 }
@@ -0,0 +1,236 @@
+//! An [`embassy_net_driver::Driver`] implementation for a VirtIO network device.
+
+use super::net_buf::TxFrame;
+use super::{RxBuffer, VirtIONet, VirtioNetHdr};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use core::task::{Context, Poll};
+use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState, RxToken, TxToken};
+use lock_api::{Mutex, RawMutex};
+use log::warn;
+
+/// A [`VirtIONet`] guarded by the lock type `M`, to be used as a [`Driver`] by the embassy network
+/// stack.
+///
+/// The driver is implemented for shared references, so that the interrupt handler for the device
+/// can call [`VirtIONetEmbassy::handle_interrupt`] while the network stack owns the driver. `M`
+/// must therefore be safe to lock from the interrupt handler, e.g. a critical section mutex.
+///
+/// Received packets are passed to the stack in the buffer which the device wrote them to, and the
+/// buffer is recycled when the receive token is consumed or dropped. Packets are written into one
+/// of the transmit buffers which [`VirtIONet`] allocated up front, which is taken when the token is
+/// issued, and are sent without waiting for the device. The lock is only held to take the buffer
+/// and to submit it, not while the stack writes the packet. Transmit buffers which the device has
+/// finished with are reclaimed by [`VirtIONetEmbassy::handle_interrupt`].
+pub struct VirtIONetEmbassy<
+    M: RawMutex,
+    H: Hal,
+    T: Transport,
+    const QUEUE_SIZE: usize,
+    const MAX_PAIRS: usize = 1,
+> {
+    net: Mutex<M, VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>>,
+}
+
+impl<M: RawMutex, H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize>
+    VirtIONetEmbassy<M, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    /// Wraps the given network device.
+    ///
+    /// The device's interrupts are enabled, as the stack relies on them to be woken.
+    pub fn new(mut net: VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>) -> Self {
+        net.enable_interrupts();
+        Self {
+            net: Mutex::new(net),
+        }
+    }
+
+    /// Returns the lock guarding the underlying network device, e.g. to change its filters.
+    pub fn net(&self) -> &Mutex<M, VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>> {
+        &self.net
+    }
+
+    /// Handles an interrupt from the device, waking the network stack if it is waiting for it.
+    ///
+    /// See [`VirtIONet::handle_interrupt`].
+    pub fn handle_interrupt(&self) -> bool {
+        self.net.lock().handle_interrupt()
+    }
+}
+
+impl<'d, M, H, T, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> Driver
+    for &'d VirtIONetEmbassy<M, H, T, QUEUE_SIZE, MAX_PAIRS>
+where
+    M: RawMutex,
+    H: Hal,
+    T: Transport,
+{
+    type RxToken<'a>
+        = VirtIONetRxToken<'d, M, H, T, QUEUE_SIZE, MAX_PAIRS>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = VirtIONetTxToken<'d, M, H, T, QUEUE_SIZE, MAX_PAIRS>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        // The wakers are registered while the lock is held, so that an interrupt can't be missed
+        // between checking the queues and registering.
+        let mut net = self.net.lock();
+        let tx_frame = take_tx_frame(&mut net, cx)?;
+        let rx_buf = match net.poll_receive(cx) {
+            Poll::Ready(Ok(rx_buf)) => rx_buf,
+            Poll::Ready(Err(e)) => {
+                warn!("Failed to receive packet: {}", e);
+                net.register_rx_waker(cx.waker());
+                net.recycle_tx_frame(tx_frame);
+                return None;
+            }
+            Poll::Pending => {
+                net.recycle_tx_frame(tx_frame);
+                return None;
+            }
+        };
+        Some((
+            VirtIONetRxToken {
+                net: &self.net,
+                rx_buf: Some(rx_buf),
+            },
+            VirtIONetTxToken {
+                net: &self.net,
+                tx_frame: Some(tx_frame),
+            },
+        ))
+    }
+
+    fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        let tx_frame = take_tx_frame(&mut self.net.lock(), cx)?;
+        Some(VirtIONetTxToken {
+            net: &self.net,
+            tx_frame: Some(tx_frame),
+        })
+    }
+
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        let mut net = self.net.lock();
+        net.register_link_waker(cx.waker());
+        if net.link_up() {
+            LinkState::Up
+        } else {
+            LinkState::Down
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let net = self.net.lock();
+        let mut caps = Capabilities::default();
+        caps.max_transmission_unit = net.max_frame_len();
+        caps.max_burst_size = Some(net.max_burst_size());
+        caps
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        HardwareAddress::Ethernet(self.net.lock().mac_address())
+    }
+}
+
+/// Reclaims the transmit buffers which the device has finished with, and takes one for a transmit
+/// token if there is room to send, or else registers the task's waker to be woken once there is.
+fn take_tx_frame<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize>(
+    net: &mut VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>,
+    cx: &mut Context,
+) -> Option<TxFrame> {
+    if let Err(e) = net.reap_transmitted() {
+        warn!("Failed to reclaim transmit buffers: {}", e);
+    }
+    if !net.can_send() {
+        net.register_tx_waker(cx.waker());
+        return None;
+    }
+    net.take_tx_frame()
+}
+
+/// A token to receive a packet from a [`VirtIONetEmbassy`].
+pub struct VirtIONetRxToken<
+    'a,
+    M: RawMutex,
+    H: Hal,
+    T: Transport,
+    const QUEUE_SIZE: usize,
+    const MAX_PAIRS: usize,
+> {
+    net: &'a Mutex<M, VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>>,
+    /// The received packet, until it is recycled.
+    rx_buf: Option<RxBuffer>,
+}
+
+impl<M: RawMutex, H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> RxToken
+    for VirtIONetRxToken<'_, M, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(self.rx_buf.as_mut().unwrap().packet_mut())
+        // The buffer is recycled when `self` is dropped.
+    }
+}
+
+impl<M: RawMutex, H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> Drop
+    for VirtIONetRxToken<'_, M, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn drop(&mut self) {
+        if let Some(rx_buf) = self.rx_buf.take() {
+            if let Err(e) = self.net.lock().recycle_rx_buffer(rx_buf) {
+                warn!("Failed to recycle receive buffer: {}", e);
+            }
+        }
+    }
+}
+
+/// A token to send a packet with a [`VirtIONetEmbassy`].
+pub struct VirtIONetTxToken<
+    'a,
+    M: RawMutex,
+    H: Hal,
+    T: Transport,
+    const QUEUE_SIZE: usize,
+    const MAX_PAIRS: usize,
+> {
+    net: &'a Mutex<M, VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>>,
+    /// The transmit buffer to write the packet into, until it is sent.
+    tx_frame: Option<TxFrame>,
+}
+
+impl<M: RawMutex, H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> TxToken
+    for VirtIONetTxToken<'_, M, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn consume<R, F>(mut self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut tx_frame = self.tx_frame.take().unwrap();
+        let result = f(tx_frame.packet_mut(len));
+        // The stack doesn't expect sending to fail, so the packet is dropped as if it had been
+        // lost on the wire.
+        if let Err(e) = self
+            .net
+            .lock()
+            .send_tx_frame(tx_frame, &VirtioNetHdr::default())
+        {
+            warn!("Failed to send packet: {}", e);
+        }
+        result
+    }
+}
+
+impl<M: RawMutex, H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> Drop
+    for VirtIONetTxToken<'_, M, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn drop(&mut self) {
+        if let Some(tx_frame) = self.tx_frame.take() {
+            self.net.lock().recycle_tx_frame(tx_frame);
+        }
+    }
+}
@@ -3,20 +3,38 @@
 #[cfg(feature = "alloc")]
 mod dev;
 mod dev_raw;
+#[cfg(feature = "embassy")]
+pub mod embassy;
 #[cfg(feature = "alloc")]
 mod net_buf;
+mod rx_pool;
+#[cfg(feature = "smoltcp")]
+pub mod smoltcp;
 
-pub use self::dev_raw::VirtIONetRaw;
+pub use self::dev_raw::{DroppedTransmits, NetQueuePair, VirtIONetRaw};
+pub use self::rx_pool::{RxBufferPool, RxBufferRef};
 #[cfg(feature = "alloc")]
 pub use self::{dev::VirtIONet, net_buf::RxBuffer, net_buf::TxBuffer};
 
 use crate::volatile::ReadOnly;
+use crate::{Error, Result};
 use bitflags::bitflags;
+use core::fmt::{self, Display, Formatter};
+use core::mem::size_of;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const MAX_BUFFER_LEN: usize = 65535;
 const MIN_BUFFER_LEN: usize = 1526;
-const NET_HDR_SIZE: usize = core::mem::size_of::<VirtioNetHdr>();
+/// The length of an Ethernet header without a VLAN tag.
+const ETHERNET_HEADER_LEN: usize = 14;
+/// The length of an Ethernet header with a VLAN tag, which isn't counted in the MTU.
+const MAX_ETHERNET_HEADER_LEN: usize = ETHERNET_HEADER_LEN + 4;
+/// The MTU to assume if the device doesn't report one.
+const DEFAULT_MTU: u16 = 1500;
+/// The maximum length of a packet to be segmented by the device: an Ethernet header with a VLAN tag
+/// followed by the largest possible IP packet.
+const MAX_GSO_PACKET_LEN: usize = MAX_ETHERNET_HEADER_LEN + 65535;
+const NET_HDR_SIZE: usize = size_of::<VirtioNetHdr>();
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -73,6 +91,16 @@ bitflags! {
         const MQ = 1 << 22;
         /// Set MAC address through control channel.
         const CTL_MAC_ADDR = 1 << 23;
+        /// Device supports notification coalescing.
+        const NOTF_COAL = 1 << 53;
+        /// Device can receive USO packets.
+        const HOST_USO = 1 << 56;
+        /// Device can report the hash of received packets.
+        const HASH_REPORT = 1 << 57;
+        /// Device supports receive-side scaling.
+        const RSS = 1 << 60;
+        /// Device reports its link speed and duplex mode.
+        const SPEED_DUPLEX = 1 << 63;
 
         // device independent
         const RING_INDIRECT_DESC = 1 << 28;
@@ -98,11 +126,17 @@ bitflags! {
 }
 
 #[repr(C)]
+#[cfg_attr(test, derive(Default))]
 struct Config {
     mac: ReadOnly<EthernetAddress>,
     status: ReadOnly<Status>,
     max_virtqueue_pairs: ReadOnly<u16>,
     mtu: ReadOnly<u16>,
+    speed: ReadOnly<u32>,
+    duplex: ReadOnly<u8>,
+    rss_max_key_size: ReadOnly<u8>,
+    rss_max_indirection_table_length: ReadOnly<u16>,
+    supported_hash_types: ReadOnly<u32>,
 }
 
 type EthernetAddress = [u8; 6];
@@ -112,8 +146,12 @@ type EthernetAddress = [u8; 6];
 /// Packets are transmitted by placing them in the transmitq1. . .transmitqN,
 /// and buffers for incoming packets are placed in the receiveq1. . .receiveqN.
 /// In each case, the packet itself is preceded by a header.
+///
+/// Its fields are private: a header for a packet to transmit is made with `Default` and the
+/// offload setters, and a received one is read through the accessors of [`RxBuffer`]. The type
+/// itself is public so that the raw transmit methods of [`VirtIONetRaw`] can take one.
 #[repr(C)]
-#[derive(AsBytes, Debug, Default, FromBytes, FromZeroes)]
+#[derive(AsBytes, Clone, Debug, Default, FromBytes, FromZeroes)]
 pub struct VirtioNetHdr {
     flags: Flags,
     gso_type: GsoType,
@@ -121,10 +159,300 @@ pub struct VirtioNetHdr {
     gso_size: u16,
     csum_start: u16,
     csum_offset: u16,
-    // num_buffers: u16, // only available when the feature MRG_RXBUF is negotiated.
+    // num_buffers: u16, // only available when the feature MRG_RXBUF is negotiated, see
+    // `VirtioNetHdrMrgRxbuf`.
     // payload starts from here
 }
 
+/// The header used instead of [`VirtioNetHdr`] when `VIRTIO_NET_F_MRG_RXBUF` is negotiated, for
+/// both transmitted and received packets.
+#[repr(C)]
+#[derive(AsBytes, Clone, Debug, Default, FromBytes, FromZeroes)]
+pub struct VirtioNetHdrMrgRxbuf {
+    hdr: VirtioNetHdr,
+    /// The number of receive buffers which the packet was merged from, including this one. This
+    /// is always 0 for transmitted packets.
+    num_buffers: u16,
+}
+
+/// The header used instead of [`VirtioNetHdrMrgRxbuf`] when `VIRTIO_NET_F_HASH_REPORT` is
+/// negotiated, for both transmitted and received packets.
+#[repr(C)]
+#[derive(AsBytes, Clone, Debug, Default, FromBytes, FromZeroes)]
+pub struct VirtioNetHdrHash {
+    hdr: VirtioNetHdrMrgRxbuf,
+    /// The hash which the device calculated for a received packet.
+    hash_value: u32,
+    /// The type of hash which the device calculated for a received packet.
+    hash_report: HashReport,
+    padding: u16,
+}
+
+/// The hash which the device calculated for a received packet, if `VIRTIO_NET_F_HASH_REPORT` was
+/// negotiated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RxHash {
+    /// The value of the hash.
+    pub hash_value: u32,
+    /// The fields of the packet that the hash was calculated over.
+    pub hash_report: HashReport,
+}
+
+/// Completes the partial checksum which the device left in a received packet, if any, and marks it
+/// as valid in the header, so that callers which don't look at the header still get a packet with
+/// a correct checksum.
+///
+/// `rx_buf` starts with a header of `hdr_len` bytes and holds the whole packet after it. The sum is
+/// taken from the checksum start to the end and stored where the device left the sum of the
+/// pseudo-header. If the checksum location is outside the packet it is left alone, for the network
+/// stack to reject.
+fn complete_rx_checksum(rx_buf: &mut [u8], hdr_len: usize) {
+    let Some(mut header) = VirtioNetHdr::read_from_prefix(rx_buf) else {
+        return;
+    };
+    let RxChecksum::Partial { start, offset } = header.rx_checksum() else {
+        return;
+    };
+    let packet = &mut rx_buf[hdr_len..];
+    let start = usize::from(start);
+    let pos = start + usize::from(offset);
+    if pos + 2 > packet.len() {
+        return;
+    }
+    let mut sum: u32 = packet[start..]
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[pos..pos + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    header.flags.remove(Flags::NEEDS_CSUM);
+    header.flags.insert(Flags::DATA_VALID);
+    header.write_to_prefix(rx_buf).unwrap();
+}
+
+/// Returns the hash reported for a received packet in the given buffer, which starts with a header
+/// of `hdr_len` bytes, if there is one.
+fn rx_hash(rx_buf: &[u8], hdr_len: usize) -> Option<RxHash> {
+    if hdr_len != size_of::<VirtioNetHdrHash>() {
+        return None;
+    }
+    let header = VirtioNetHdrHash::read_from_prefix(rx_buf)?;
+    (header.hash_report != HashReport::NONE).then_some(RxHash {
+        hash_value: header.hash_value,
+        hash_report: header.hash_report,
+    })
+}
+
+/// The type of hash which the device calculated for a received packet, in an [`RxHash`].
+#[repr(transparent)]
+#[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct HashReport(u16);
+
+impl HashReport {
+    /// No hash was calculated.
+    pub const NONE: HashReport = HashReport(0);
+    /// The hash was calculated over the IPv4 addresses.
+    pub const IPV4: HashReport = HashReport(1);
+    /// The hash was calculated over the IPv4 addresses and TCP ports.
+    pub const TCPV4: HashReport = HashReport(2);
+    /// The hash was calculated over the IPv4 addresses and UDP ports.
+    pub const UDPV4: HashReport = HashReport(3);
+    /// The hash was calculated over the IPv6 addresses.
+    pub const IPV6: HashReport = HashReport(4);
+    /// The hash was calculated over the IPv6 addresses and TCP ports.
+    pub const TCPV6: HashReport = HashReport(5);
+    /// The hash was calculated over the IPv6 addresses and UDP ports.
+    pub const UDPV6: HashReport = HashReport(6);
+    /// The hash was calculated over the IPv6 addresses, including those from extension headers.
+    pub const IPV6_EX: HashReport = HashReport(7);
+    /// Like `IPV6_EX`, and also the TCP ports.
+    pub const TCPV6_EX: HashReport = HashReport(8);
+    /// Like `IPV6_EX`, and also the UDP ports.
+    pub const UDPV6_EX: HashReport = HashReport(9);
+}
+
+bitflags! {
+    /// The types of packets which the device calculates hashes of, for receive-side scaling or
+    /// hash reporting.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct HashTypes: u32 {
+        /// IPv4 packets, by their addresses.
+        const IPV4 = 1 << 0;
+        /// TCP over IPv4, by addresses and ports.
+        const TCPV4 = 1 << 1;
+        /// UDP over IPv4, by addresses and ports.
+        const UDPV4 = 1 << 2;
+        /// IPv6 packets, by their addresses.
+        const IPV6 = 1 << 3;
+        /// TCP over IPv6, by addresses and ports.
+        const TCPV6 = 1 << 4;
+        /// UDP over IPv6, by addresses and ports.
+        const UDPV6 = 1 << 5;
+        /// IPv6 packets, including addresses from extension headers.
+        const IPV6_EX = 1 << 6;
+        /// TCP over IPv6, including addresses from extension headers.
+        const TCPV6_EX = 1 << 7;
+        /// UDP over IPv6, including addresses from extension headers.
+        const UDPV6_EX = 1 << 8;
+    }
+}
+
+/// A receive-side scaling configuration, set with [`VirtIONetRaw::configure_rss`].
+#[derive(Clone, Copy, Debug)]
+pub struct RssConfig<'a> {
+    /// The types of packets to calculate hashes of.
+    pub hash_types: HashTypes,
+    /// The queue pair to receive each packet on, indexed by the low bits of its hash. Its length
+    /// must be a power of two, no more than the device's maximum.
+    pub indirection_table: &'a [u16],
+    /// The queue pair to receive packets on which aren't hashed.
+    pub unclassified_queue: u16,
+    /// The key for the hash function, no longer than the device's maximum key size.
+    pub key: &'a [u8],
+}
+
+impl VirtioNetHdr {
+    /// Asks the device to calculate the checksum of a transmitted packet, as it only has a partial
+    /// checksum.
+    ///
+    /// The device calculates the checksum from byte `start` of the packet to the end, and stores it
+    /// at `start + offset`. This needs `VIRTIO_NET_F_CSUM` to have been negotiated, or the packet
+    /// will be rejected.
+    pub fn set_partial_csum(&mut self, start: u16, offset: u16) {
+        self.flags.insert(Flags::NEEDS_CSUM);
+        self.csum_start = start;
+        self.csum_offset = offset;
+    }
+
+    /// Asks the device to split a transmitted packet into segments of at most `gso_size` bytes
+    /// of payload each, with the headers of length `hdr_len` repeated on each.
+    ///
+    /// The packet must also have a partial checksum set with [`VirtioNetHdr::set_partial_csum`],
+    /// and the device must support the given type of segmentation, or the packet will be rejected.
+    pub fn set_gso(&mut self, gso_type: GsoType, gso_size: u16, hdr_len: u16) {
+        self.gso_type = gso_type;
+        self.gso_size = gso_size;
+        self.hdr_len = hdr_len;
+    }
+
+    /// Returns the type of segmentation of the packet, which is [`GsoType::NONE`] unless it was
+    /// set with [`VirtioNetHdr::set_gso`] or a large packet was received with a guest offload.
+    pub fn gso_type(&self) -> GsoType {
+        self.gso_type
+    }
+
+    /// Returns the maximum segment size of a segmented packet, or 0 if it isn't segmented.
+    pub fn gso_size(&self) -> u16 {
+        self.gso_size
+    }
+
+    /// Returns the length of the headers which are repeated on each segment of a segmented
+    /// packet.
+    ///
+    /// For received packets this is only a hint from the device, which may be 0 or wrong.
+    pub fn gso_hdr_len(&self) -> u16 {
+        self.hdr_len
+    }
+
+    /// Returns the checksum state of a received packet.
+    pub fn rx_checksum(&self) -> RxChecksum {
+        if self.flags.contains(Flags::NEEDS_CSUM) {
+            RxChecksum::Partial {
+                start: self.csum_start,
+                offset: self.csum_offset,
+            }
+        } else if self.flags.contains(Flags::DATA_VALID) {
+            RxChecksum::Valid
+        } else {
+            RxChecksum::Unverified
+        }
+    }
+}
+
+/// Traffic statistics gathered by a [`VirtIONetRaw`], as returned by [`VirtIONetRaw::stats`].
+///
+/// Packets sent are counted when they are submitted to the device, and packets received when they
+/// are completed. The counters wrap around rather than overflowing.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NetStats {
+    /// The number of packets submitted for transmission.
+    pub tx_packets: u64,
+    /// The number of bytes submitted for transmission, not including the headers.
+    pub tx_bytes: u64,
+    /// The number of received packets.
+    pub rx_packets: u64,
+    /// The number of bytes received, not including the headers.
+    pub rx_bytes: u64,
+    /// The number of times the receive queue was left without any buffers for the device to fill,
+    /// during which the device must drop any packets it receives.
+    pub rx_starved: u64,
+    /// The number of packets which couldn't be submitted because the transmit queue was full.
+    pub tx_queue_full: u64,
+    /// The number of packets submitted for transmission which asked the device to calculate their
+    /// checksum.
+    pub tx_csum_offload: u64,
+    /// The number of received packets whose checksum the device had validated, or left partial.
+    pub rx_csum_offload: u64,
+    /// The number of interrupts acknowledged by [`VirtIONetRaw::ack_interrupt`].
+    pub interrupts: u64,
+}
+
+impl NetStats {
+    /// Records the submission of a packet with the given header and `len` bytes of data, and the
+    /// result of submitting it.
+    fn record_tx<T>(&mut self, header: &VirtioNetHdr, len: usize, result: &Result<T>) {
+        match result {
+            Ok(_) => {
+                self.tx_packets = self.tx_packets.wrapping_add(1);
+                self.tx_bytes = self.tx_bytes.wrapping_add(len as u64);
+                if header.flags.contains(Flags::NEEDS_CSUM) {
+                    self.tx_csum_offload = self.tx_csum_offload.wrapping_add(1);
+                }
+            }
+            Err(Error::QueueFull) => self.tx_queue_full = self.tx_queue_full.wrapping_add(1),
+            Err(_) => {}
+        }
+    }
+
+    /// Records the reception of a packet with the given header, of which `len` bytes were in the
+    /// first buffer.
+    fn record_rx(&mut self, header: &VirtioNetHdr, len: usize) {
+        self.rx_packets = self.rx_packets.wrapping_add(1);
+        self.rx_bytes = self.rx_bytes.wrapping_add(len as u64);
+        if header.rx_checksum() != RxChecksum::Unverified {
+            self.rx_csum_offload = self.rx_csum_offload.wrapping_add(1);
+        }
+    }
+}
+
+/// The checksum state of a received packet, from its [`VirtioNetHdr`].
+///
+/// This is only ever anything other than `Unverified` if `VIRTIO_NET_F_GUEST_CSUM` has been
+/// negotiated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RxChecksum {
+    /// The device hasn't validated the checksum, so the network stack must do so.
+    Unverified,
+    /// The device has validated the checksum, so the network stack can skip doing so.
+    Valid,
+    /// The packet only has a partial checksum, like one passed to
+    /// [`VirtioNetHdr::set_partial_csum`]. The checksum from byte `start` to the end of the packet
+    /// has not been calculated or stored at `start + offset`, but the rest of the packet has been
+    /// validated.
+    ///
+    /// The drivers complete such checksums before returning packets, and mark them `Valid`
+    /// instead, so this is only seen in the first buffer of a packet merged from several buffers
+    /// by [`VirtIONetRaw`].
+    Partial {
+        /// The offset in the packet from which the checksum should be calculated.
+        start: u16,
+        /// The offset from `start` at which the checksum should be stored.
+        offset: u16,
+    },
+}
+
 #[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
 #[repr(transparent)]
 struct Flags(u8);
@@ -137,21 +465,348 @@ bitflags! {
     }
 }
 
+/// The type of segmentation offload to apply to a packet, in a [`VirtioNetHdr`].
 #[repr(transparent)]
 #[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, FromZeroes, PartialEq)]
-struct GsoType(u8);
+pub struct GsoType(u8);
 
 impl GsoType {
-    const NONE: GsoType = GsoType(0);
-    const TCPV4: GsoType = GsoType(1);
-    const UDP: GsoType = GsoType(3);
-    const TCPV6: GsoType = GsoType(4);
-    const ECN: GsoType = GsoType(0x80);
+    /// The packet is not to be segmented.
+    pub const NONE: GsoType = GsoType(0);
+    /// TCP segmentation offload over IPv4. Needs `VIRTIO_NET_F_HOST_TSO4`.
+    pub const TCPV4: GsoType = GsoType(1);
+    /// UDP fragmentation offload. Needs `VIRTIO_NET_F_HOST_UFO`.
+    pub const UDP: GsoType = GsoType(3);
+    /// TCP segmentation offload over IPv6. Needs `VIRTIO_NET_F_HOST_TSO6`.
+    pub const TCPV6: GsoType = GsoType(4);
+    /// UDP segmentation offload. Needs `VIRTIO_NET_F_HOST_USO`.
+    pub const UDP_L4: GsoType = GsoType(5);
+    /// Can be combined with `TCPV4` or `TCPV6` if the packet has the ECN bit set. Needs
+    /// `VIRTIO_NET_F_HOST_ECN`.
+    pub const ECN: GsoType = GsoType(0x80);
+
+    /// Returns the type without the `ECN` bit.
+    fn without_ecn(self) -> Self {
+        Self(self.0 & !Self::ECN.0)
+    }
+}
+
+impl core::ops::BitOr for GsoType {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
 }
 
-const QUEUE_RECEIVE: u16 = 0;
-const QUEUE_TRANSMIT: u16 = 1;
+bitflags! {
+    /// The causes of an interrupt from a network device, as returned by
+    /// [`VirtIONetRaw::ack_interrupt_detailed`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct NetInterrupt: u8 {
+        /// A receive queue has a completed buffer.
+        const RX_USED = 1 << 0;
+        /// A transmit queue has a completed buffer.
+        const TX_USED = 1 << 1;
+        /// The device configuration, such as the link status, has changed.
+        const CONFIG_CHANGED = 1 << 2;
+    }
+}
+
+/// An event reported by a network device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NetEvent {
+    /// The link has gone up (`true`) or down (`false`).
+    LinkChanged(bool),
+    /// The device has asked the driver to announce its presence, e.g. after it was migrated, so
+    /// the network stack should send gratuitous ARP or unsolicited neighbour advertisement
+    /// packets and then call [`VirtIONetRaw::ack_announce`].
+    AnnounceRequested,
+}
+
+/// The speed and duplex mode of a network link, as reported by a device with
+/// `VIRTIO_NET_F_SPEED_DUPLEX`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LinkSpeed {
+    /// The speed in Mb/s, or `None` if the device doesn't know it.
+    pub speed_mbps: Option<u32>,
+    /// The duplex mode, or `None` if the device doesn't know it.
+    pub duplex: Option<Duplex>,
+}
+
+impl LinkSpeed {
+    /// The value of the speed field for an unknown speed.
+    const SPEED_UNKNOWN: u32 = 0xffff_ffff;
+    /// The highest valid value of the speed field.
+    const MAX_SPEED: u32 = 0x7fff_ffff;
+
+    /// Decodes the speed and duplex fields of the configuration space. Values which the VirtIO
+    /// specification doesn't allow are treated as unknown, like the explicit sentinels.
+    fn from_config(speed: u32, duplex: u8) -> Self {
+        Self {
+            speed_mbps: (speed <= Self::MAX_SPEED).then_some(speed),
+            duplex: match duplex {
+                0 => Some(Duplex::Half),
+                1 => Some(Duplex::Full),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The duplex mode of a network link.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Duplex {
+    /// Half duplex.
+    Half,
+    /// Full duplex.
+    Full,
+}
+
+/// An error reported by a network device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NetError {
+    /// The device rejected a control command (`VIRTIO_NET_ERR`).
+    CommandFailed,
+    /// The device returned an ack byte for a control command not defined by the VirtIO
+    /// specification.
+    BadAck(u8),
+    /// A packet to transmit was longer than the MTU reported by the device allows.
+    PacketTooLong {
+        /// The length of the packet, including its Ethernet header.
+        len: usize,
+        /// The maximum length allowed by the MTU, including an Ethernet header with a VLAN tag.
+        max: usize,
+    },
+    /// The device offered different features or a different number of queue pairs when it was
+    /// initialised again after a reset.
+    FeaturesChanged,
+}
+
+impl Display for NetError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::CommandFailed => write!(f, "Device rejected control command"),
+            Self::BadAck(ack) => write!(f, "Device returned unknown control ack {ack}"),
+            Self::PacketTooLong { len, max } => {
+                write!(f, "Packet of {len} bytes is longer than the maximum {max}")
+            }
+            Self::FeaturesChanged => write!(f, "Device features changed across a reset"),
+        }
+    }
+}
+
+/// The header of a command sent on the control queue, which is followed by command-specific data
+/// and then an ack byte written by the device.
+#[repr(C)]
+#[derive(AsBytes, Debug, Default, FromBytes, FromZeroes)]
+struct CtrlHdr {
+    class: CtrlClass,
+    command: u8,
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+struct CtrlClass(u8);
+
+impl CtrlClass {
+    const RX: CtrlClass = CtrlClass(0);
+    const MAC: CtrlClass = CtrlClass(1);
+    const VLAN: CtrlClass = CtrlClass(2);
+    const ANNOUNCE: CtrlClass = CtrlClass(3);
+    const MQ: CtrlClass = CtrlClass(4);
+    const NOTF_COAL: CtrlClass = CtrlClass(6);
+}
+
+/// Commands in the `VIRTIO_NET_CTRL_RX` class.
+const CTRL_RX_PROMISC: u8 = 0;
+const CTRL_RX_ALLMULTI: u8 = 1;
+const CTRL_RX_ALLUNI: u8 = 2;
+const CTRL_RX_NOMULTI: u8 = 3;
+const CTRL_RX_NOUNI: u8 = 4;
+const CTRL_RX_NOBCAST: u8 = 5;
+
+/// Commands in the `VIRTIO_NET_CTRL_MAC` class.
+const CTRL_MAC_TABLE_SET: u8 = 0;
+const CTRL_MAC_ADDR_SET: u8 = 1;
+
+/// Commands in the `VIRTIO_NET_CTRL_VLAN` class.
+const CTRL_VLAN_ADD: u8 = 0;
+const CTRL_VLAN_DEL: u8 = 1;
+
+/// The number of valid VLAN IDs.
+const MAX_VLAN_ID: u16 = 4096;
+
+/// Encodes a VLAN ID as the data for a `VIRTIO_NET_CTRL_VLAN` command, or returns
+/// [`Error::InvalidParam`] if it is out of range.
+fn vlan_command_data(vid: u16) -> Result<[u8; 2]> {
+    if vid >= MAX_VLAN_ID {
+        return Err(Error::InvalidParam);
+    }
+    Ok(vid.to_le_bytes())
+}
+
+/// Commands in the `VIRTIO_NET_CTRL_ANNOUNCE` class.
+const CTRL_ANNOUNCE_ACK: u8 = 0;
+
+/// Ack values written by the device after a control command.
+const CTRL_ACK_OK: u8 = 0;
+const CTRL_ACK_ERR: u8 = 1;
+
+/// Commands in the `VIRTIO_NET_CTRL_MQ` class.
+const CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const CTRL_MQ_RSS_CONFIG: u8 = 1;
+const CTRL_MQ_HASH_CONFIG: u8 = 2;
+
+/// Commands in the `VIRTIO_NET_CTRL_NOTF_COAL` class.
+const CTRL_NOTF_COAL_TX_SET: u8 = 0;
+const CTRL_NOTF_COAL_RX_SET: u8 = 1;
+
+/// Returns the data for a `VIRTIO_NET_CTRL_NOTF_COAL` set command, which is
+/// the maximum number of packets followed by the maximum delay, both in little
+/// endian.
+fn coalescing_command_data(usecs: u32, max_packets: u32) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[..4].copy_from_slice(&max_packets.to_le_bytes());
+    data[4..].copy_from_slice(&usecs.to_le_bytes());
+    data
+}
+
+/// Returns the index of the receive queue of the given queue pair.
+const fn receive_queue_index(pair: u16) -> u16 {
+    pair * 2
+}
+
+/// Returns the index of the transmit queue of the given queue pair.
+const fn transmit_queue_index(pair: u16) -> u16 {
+    pair * 2 + 1
+}
+
+/// The size of the control queue. Commands are sent one at a time, so this only needs to be big
+/// enough for the longest command's descriptor chain.
+const CTRL_QUEUE_SIZE: usize = 8;
+/// The maximum number of buffers of command-specific data in a control command.
+const MAX_CTRL_DATA: usize = 4;
 const SUPPORTED_FEATURES: Features = Features::MAC
+    .union(Features::MTU)
+    .union(Features::CSUM)
+    .union(Features::GUEST_CSUM)
+    .union(Features::HOST_TSO4)
+    .union(Features::HOST_TSO6)
+    .union(Features::HOST_ECN)
+    .union(Features::HOST_UFO)
+    .union(Features::HOST_USO)
+    .union(Features::SPEED_DUPLEX)
+    .union(Features::HASH_REPORT)
+    .union(Features::RSS)
+    .union(Features::NOTF_COAL)
+    .union(Features::MRG_RXBUF)
     .union(Features::STATUS)
+    .union(Features::CTRL_VQ)
+    .union(Features::MQ)
+    .union(Features::CTRL_RX)
+    .union(Features::CTRL_RX_EXTRA)
+    .union(Features::CTRL_VLAN)
+    .union(Features::GUEST_ANNOUNCE)
+    .union(Features::CTL_MAC_ADDR)
     .union(Features::RING_EVENT_IDX)
     .union(Features::RING_INDIRECT_DESC);
+
+/// Returns a fake transport for a network device with one queue pair and the given config space
+/// and features, and the state shared with it.
+#[cfg(test)]
+fn fake_transport(
+    config_space: core::ptr::NonNull<Config>,
+    device_features: Features,
+    queue_size: usize,
+) -> (
+    crate::transport::fake::FakeTransport<Config>,
+    alloc::sync::Arc<std::sync::Mutex<crate::transport::fake::State>>,
+) {
+    use crate::transport::{fake::State, DeviceType};
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+
+    let state = Arc::new(Mutex::new(State::new(3)));
+    let transport = crate::transport::fake::FakeTransport {
+        device_type: DeviceType::Network,
+        max_queue_size: queue_size.try_into().unwrap(),
+        device_features: device_features.bits(),
+        config_space,
+        state: state.clone(),
+    };
+    (transport, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vlan_command_data_little_endian() {
+        assert_eq!(vlan_command_data(0), Ok([0x00, 0x00]));
+        assert_eq!(vlan_command_data(0x123), Ok([0x23, 0x01]));
+        assert_eq!(vlan_command_data(4095), Ok([0xff, 0x0f]));
+        assert_eq!(vlan_command_data(4096), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn coalescing_command_data_packets_first() {
+        assert_eq!(
+            coalescing_command_data(0x0102_0304, 0x0a0b_0c0d),
+            [0x0d, 0x0c, 0x0b, 0x0a, 0x04, 0x03, 0x02, 0x01]
+        );
+    }
+
+    #[test]
+    fn link_speed_sentinels() {
+        assert_eq!(
+            LinkSpeed::from_config(10000, 1),
+            LinkSpeed {
+                speed_mbps: Some(10000),
+                duplex: Some(Duplex::Full),
+            }
+        );
+        assert_eq!(
+            LinkSpeed::from_config(LinkSpeed::SPEED_UNKNOWN, 0xff),
+            LinkSpeed {
+                speed_mbps: None,
+                duplex: None,
+            }
+        );
+        assert_eq!(
+            LinkSpeed::from_config(0x8000_0000, 0),
+            LinkSpeed {
+                speed_mbps: None,
+                duplex: Some(Duplex::Half),
+            }
+        );
+        assert_eq!(LinkSpeed::from_config(0, 2).duplex, None);
+    }
+
+    #[test]
+    fn rx_hash_only_with_hash_header() {
+        let header = VirtioNetHdrHash {
+            hash_value: 0x1234_5678,
+            hash_report: HashReport::TCPV4,
+            ..Default::default()
+        };
+        let hash_len = size_of::<VirtioNetHdrHash>();
+        assert_eq!(hash_len, 20);
+        assert_eq!(
+            rx_hash(header.as_bytes(), hash_len),
+            Some(RxHash {
+                hash_value: 0x1234_5678,
+                hash_report: HashReport::TCPV4,
+            })
+        );
+        assert_eq!(
+            rx_hash(header.as_bytes(), size_of::<VirtioNetHdrMrgRxbuf>()),
+            None
+        );
+        assert_eq!(
+            rx_hash(VirtioNetHdrHash::default().as_bytes(), hash_len),
+            None
+        );
+    }
+}
@@ -1,47 +1,112 @@
-use super::{VirtioNetHdr, NET_HDR_SIZE};
+use super::{
+    complete_rx_checksum, rx_hash, GsoType, RxChecksum, RxHash, VirtioNetHdr, NET_HDR_SIZE,
+};
 use alloc::{vec, vec::Vec};
 use core::{convert::TryInto, mem::size_of};
 use zerocopy::AsBytes;
 
 /// A buffer used for transmitting.
-pub struct TxBuffer(pub(crate) Vec<u8>);
+pub struct TxBuffer {
+    pub(crate) packet: Vec<u8>,
+    pub(crate) header: VirtioNetHdr,
+}
+
+/// A transmit buffer taken from the pool of a [`VirtIONet`](super::VirtIONet), for a network
+/// stack to fill in place.
+pub(crate) struct TxFrame {
+    /// The header followed by the packet.
+    pub(crate) buf: Vec<u8>,
+    /// The length of the header.
+    pub(crate) header_len: usize,
+}
 
 /// A buffer used for receiving.
 pub struct RxBuffer {
     pub(crate) buf: Vec<usize>, // for alignment
     pub(crate) packet_len: usize,
+    /// The length of the header before the packet.
+    pub(crate) hdr_len: usize,
     pub(crate) idx: u16,
+    /// The queue pair on which the buffer was queued.
+    pub(crate) pair: u16,
 }
 
 impl TxBuffer {
     /// Constructs the buffer from the given slice.
     pub fn from(buf: &[u8]) -> Self {
-        Self(Vec::from(buf))
+        Self {
+            packet: Vec::from(buf),
+            header: VirtioNetHdr::default(),
+        }
     }
 
     /// Returns the network packet length.
     pub fn packet_len(&self) -> usize {
-        self.0.len()
+        self.packet.len()
     }
 
     /// Returns the network packet as a slice.
     pub fn packet(&self) -> &[u8] {
-        self.0.as_slice()
+        self.packet.as_slice()
     }
 
     /// Returns the network packet as a mutable slice.
     pub fn packet_mut(&mut self) -> &mut [u8] {
-        self.0.as_mut_slice()
+        self.packet.as_mut_slice()
+    }
+
+    /// Asks the device to calculate the checksum from byte `start` of the packet to the end, and
+    /// store it at `start + offset`.
+    ///
+    /// See [`VirtioNetHdr::set_partial_csum`].
+    pub fn set_partial_csum(&mut self, start: u16, offset: u16) {
+        self.header.set_partial_csum(start, offset);
+    }
+
+    /// Asks the device to split the packet into segments with at most `mss` bytes of payload each,
+    /// repeating the first `hdr_len` bytes of the packet on each. This can only be used with a
+    /// partial checksum.
+    ///
+    /// See [`VirtioNetHdr::set_gso`].
+    pub fn set_gso(&mut self, gso_type: GsoType, mss: u16, hdr_len: u16) {
+        self.header.set_gso(gso_type, mss, hdr_len);
+    }
+
+    /// Returns the buffer with a partial checksum, like [`TxBuffer::set_partial_csum`].
+    pub fn with_partial_csum(mut self, start: u16, offset: u16) -> Self {
+        self.set_partial_csum(start, offset);
+        self
+    }
+
+    /// Returns the buffer with segmentation requested, like [`TxBuffer::set_gso`].
+    pub fn with_gso(mut self, gso_type: GsoType, mss: u16, hdr_len: u16) -> Self {
+        self.set_gso(gso_type, mss, hdr_len);
+        self
+    }
+
+    /// Returns the header which will be sent with the packet.
+    pub(crate) fn header(&self) -> &VirtioNetHdr {
+        &self.header
+    }
+}
+
+impl TxFrame {
+    /// Resizes the packet to `len` bytes, and returns it.
+    pub(crate) fn packet_mut(&mut self, len: usize) -> &mut [u8] {
+        self.buf.resize(self.header_len + len, 0);
+        &mut self.buf[self.header_len..]
     }
 }
 
 impl RxBuffer {
     /// Allocates a new buffer with length `buf_len`.
-    pub(crate) fn new(idx: usize, buf_len: usize) -> Self {
+    pub(crate) fn new(pair: u16, idx: usize, buf_len: usize) -> Self {
         Self {
             buf: vec![0; buf_len / size_of::<usize>()],
             packet_len: 0,
+            hdr_len: NET_HDR_SIZE,
             idx: idx.try_into().unwrap(),
+            pair,
         }
     }
 
@@ -50,6 +115,19 @@ impl RxBuffer {
         self.packet_len = packet_len
     }
 
+    /// Appends the given data to the end of the packet, growing the buffer if
+    /// necessary.
+    pub(crate) fn append_packet(&mut self, data: &[u8]) {
+        let start = self.hdr_len + self.packet_len;
+        let end = start + data.len();
+        let words = end.div_ceil(size_of::<usize>());
+        if self.buf.len() < words {
+            self.buf.resize(words, 0);
+        }
+        self.buf.as_bytes_mut()[start..end].copy_from_slice(data);
+        self.packet_len += data.len();
+    }
+
     /// Returns the network packet length (witout header).
     pub const fn packet_len(&self) -> usize {
         self.packet_len
@@ -71,13 +149,48 @@ impl RxBuffer {
         unsafe { &*(self.buf.as_ptr() as *const VirtioNetHdr) }
     }
 
+    /// Returns the checksum state of the received packet, from its header.
+    pub fn csum_state(&self) -> RxChecksum {
+        self.header().rx_checksum()
+    }
+
+    /// Returns the type of segmentation of the received packet, which is [`GsoType::NONE`] unless
+    /// a guest offload was negotiated and the device merged several segments into it.
+    pub fn gso_type(&self) -> GsoType {
+        self.header().gso_type()
+    }
+
+    /// Returns the maximum segment size of the received packet, if it was merged from several
+    /// segments.
+    pub fn gso_size(&self) -> u16 {
+        self.header().gso_size()
+    }
+
+    /// Returns the length of the VirtIO header before the packet in [`RxBuffer::as_bytes`].
+    pub fn hdr_len(&self) -> usize {
+        self.hdr_len
+    }
+
+    /// Returns the hash which the device reported for the received packet, if
+    /// `VIRTIO_NET_F_HASH_REPORT` was negotiated and the device calculated one.
+    pub fn hash(&self) -> Option<RxHash> {
+        rx_hash(self.as_bytes(), self.hdr_len)
+    }
+
+    /// Completes a partial checksum of the packet, if the device left one, once all the buffers
+    /// of a merged packet have been appended.
+    pub(crate) fn complete_checksum(&mut self) {
+        let len = self.hdr_len + self.packet_len;
+        complete_rx_checksum(&mut self.buf.as_bytes_mut()[..len], self.hdr_len);
+    }
+
     /// Returns the network packet as a slice.
     pub fn packet(&self) -> &[u8] {
-        &self.buf.as_bytes()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        &self.buf.as_bytes()[self.hdr_len..self.hdr_len + self.packet_len]
     }
 
     /// Returns the network packet as a mutable slice.
     pub fn packet_mut(&mut self) -> &mut [u8] {
-        &mut self.buf.as_bytes_mut()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        &mut self.buf.as_bytes_mut()[self.hdr_len..self.hdr_len + self.packet_len]
     }
 }
@@ -0,0 +1,402 @@
+//! A fixed pool of receive buffers in DMA memory, which receives packets without allocating.
+
+use super::{
+    rx_hash, RxChecksum, RxHash, VirtIONetRaw, VirtioNetHdr, DEFAULT_MTU, MAX_ETHERNET_HEADER_LEN,
+};
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::SharedBuffer;
+use crate::transport::Transport;
+use crate::{pages, Error, Result};
+use core::cell::{Cell, RefCell, RefMut};
+use core::mem::{size_of, ManuallyDrop};
+use core::ptr::NonNull;
+use log::warn;
+use zerocopy::FromBytes;
+
+/// The state of one buffer in an [`RxBufferPool`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SlotState {
+    /// The buffer isn't in the receive queue, because posting it failed or was deferred.
+    Idle,
+    /// The buffer is in the receive queue, waiting for the device to fill it.
+    Posted,
+    /// The buffer holds a received packet, and is borrowed by an [`RxBufferRef`].
+    InUse,
+}
+
+/// A pool of receive buffers for a [`VirtIONetRaw`], one for each slot of its receive queue,
+/// allocated once with [`Hal::dma_alloc`]. As that memory is already shared with the device, the
+/// buffers are posted by their physical addresses rather than being shared again each time.
+///
+/// All buffers are posted to the receive queue when the pool is created. [`RxBufferPool::receive`]
+/// returns an [`RxBufferRef`] which borrows the buffer holding a received packet, and posts it
+/// back to the receive queue when it is dropped or [recycled](RxBufferRef::recycle), so receiving
+/// packets never allocates.
+///
+/// Buffers are large enough for a whole frame of the device's MTU, so even with mergeable receive
+/// buffers every packet is in a single buffer.
+pub struct RxBufferPool<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    // This must be dropped before `dma`, so that the device stops using the buffers first.
+    net: RefCell<VirtIONetRaw<H, T, QUEUE_SIZE>>,
+    dma: Dma<H>,
+    buf_len: usize,
+    /// The number of buffers, which is the size of the receive queue.
+    num_slots: u16,
+    slots: [Cell<SlotState>; QUEUE_SIZE],
+    /// The buffer posted with each token.
+    token_slots: [Cell<Option<u16>>; QUEUE_SIZE],
+    /// Whether any buffers were recycled while the device was borrowed, and so still need to be
+    /// posted.
+    deferred: Cell<bool>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> RxBufferPool<H, T, QUEUE_SIZE> {
+    /// Allocates the pool for the given network device and posts all its buffers.
+    ///
+    /// Each buffer is `buf_len` bytes long, or larger if needed to hold a whole frame.
+    pub fn new(net: VirtIONetRaw<H, T, QUEUE_SIZE>, hal: H, buf_len: usize) -> Result<Self> {
+        let frame_len =
+            net.hdr_len() + MAX_ETHERNET_HEADER_LEN + usize::from(net.mtu().unwrap_or(DEFAULT_MTU));
+        let buf_len = buf_len
+            .max(net.min_rx_buffer_len())
+            .max(frame_len)
+            .next_multiple_of(size_of::<usize>());
+        let num_slots = net.rx_queue_size();
+        let dma = Dma::new(
+            hal,
+            pages(buf_len * usize::from(num_slots)),
+            BufferDirection::DeviceToDriver,
+        )?;
+        let pool = Self {
+            net: RefCell::new(net),
+            dma,
+            buf_len,
+            num_slots,
+            slots: [const { Cell::new(SlotState::Idle) }; QUEUE_SIZE],
+            token_slots: [const { Cell::new(None) }; QUEUE_SIZE],
+            deferred: Cell::new(false),
+        };
+        for slot in 0..num_slots {
+            pool.post(slot)?;
+        }
+        Ok(pool)
+    }
+
+    /// Returns the network device, e.g. to send packets.
+    ///
+    /// Buffers which are recycled while the device is borrowed are posted by the next call to
+    /// [`RxBufferPool::receive`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device is already borrowed.
+    pub fn net(&self) -> RefMut<'_, VirtIONetRaw<H, T, QUEUE_SIZE>> {
+        self.net.borrow_mut()
+    }
+
+    /// Returns the length of each buffer, including the header.
+    pub fn buf_len(&self) -> usize {
+        self.buf_len
+    }
+
+    /// Returns the number of buffers which are currently borrowed by an [`RxBufferRef`].
+    pub fn in_use(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.get() == SlotState::InUse)
+            .count()
+    }
+
+    /// Receives a packet, if one is ready, or else returns [`Error::NotReady`].
+    pub fn receive(&self) -> Result<RxBufferRef<'_, H, T, QUEUE_SIZE>> {
+        if self.deferred.take() {
+            for slot in 0..self.num_slots {
+                if self.slots[usize::from(slot)].get() == SlotState::Idle {
+                    self.post(slot)?;
+                }
+            }
+        }
+        let mut net = self.net.borrow_mut();
+        let token = net.poll_receive().ok_or(Error::NotReady)?;
+        let slot = self.token_slots[usize::from(token)]
+            .take()
+            .ok_or(Error::WrongToken)?;
+        // Safe because the buffer was posted with this token by `post`, and isn't otherwise
+        // accessed while it is posted.
+        let (hdr_len, packet_len) =
+            unsafe { net.receive_complete_shared(token, self.shared_buffer(slot))? };
+        self.slots[usize::from(slot)].set(SlotState::InUse);
+        // Safe because the buffer now belongs to us again.
+        let num_buffers = net.num_buffers(unsafe { self.buffer(slot).as_ref() });
+        drop(net);
+        let rx_buf = RxBufferRef {
+            pool: self,
+            slot,
+            hdr_len,
+            packet_len,
+        };
+        if num_buffers != 1 {
+            warn!("Packet spans {} receive buffers", num_buffers);
+            rx_buf.recycle()?;
+            return Err(Error::IoError);
+        }
+        Ok(rx_buf)
+    }
+
+    /// Posts any buffers whose [`RxBufferRef`] was leaked with [`core::mem::forget`], or whose
+    /// posting failed, back to the receive queue, and returns how many there were.
+    ///
+    /// As this takes `&mut self`, no `RxBufferRef`s can still be alive.
+    pub fn reclaim_leaked(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for slot in 0..self.num_slots {
+            if self.slots[usize::from(slot)].get() != SlotState::Posted {
+                self.post(slot)?;
+                count += 1;
+            }
+        }
+        if count > 0 {
+            warn!("Reclaimed {} leaked receive buffers", count);
+        }
+        Ok(count)
+    }
+
+    /// Returns the given buffer, which was returned by an [`RxBufferRef`], to the receive queue.
+    ///
+    /// Returns [`Error::WrongToken`] if the buffer wasn't in use, e.g. because it was already
+    /// recycled.
+    fn repost(&self, slot: u16) -> Result {
+        if self.slots[usize::from(slot)].get() != SlotState::InUse {
+            warn!("Receive buffer {} recycled twice", slot);
+            return Err(Error::WrongToken);
+        }
+        self.post(slot)
+    }
+
+    /// Adds the given buffer to the receive queue, or defers it until the next
+    /// [`RxBufferPool::receive`] if the device is borrowed.
+    fn post(&self, slot: u16) -> Result {
+        self.slots[usize::from(slot)].set(SlotState::Idle);
+        let Ok(mut net) = self.net.try_borrow_mut() else {
+            self.deferred.set(true);
+            return Ok(());
+        };
+        // Safe because the buffer is within the DMA region, which lives as long as the queue, and
+        // isn't accessed again until it is returned by `receive_complete_shared`.
+        let token = unsafe { net.receive_begin_shared(self.shared_buffer(slot))? };
+        self.token_slots[usize::from(token)].set(Some(slot));
+        self.slots[usize::from(slot)].set(SlotState::Posted);
+        Ok(())
+    }
+
+    /// Returns a pointer to the given buffer within the DMA region.
+    fn buffer(&self, slot: u16) -> NonNull<[u8]> {
+        NonNull::slice_from_raw_parts(
+            self.dma.vaddr(usize::from(slot) * self.buf_len),
+            self.buf_len,
+        )
+    }
+
+    /// Returns the given buffer, with its physical address within the DMA region.
+    fn shared_buffer(&self, slot: u16) -> SharedBuffer {
+        SharedBuffer::new(
+            self.buffer(slot),
+            self.dma.paddr() + usize::from(slot) * self.buf_len,
+            BufferDirection::DeviceToDriver,
+        )
+    }
+}
+
+/// A received packet, borrowed from an [`RxBufferPool`].
+///
+/// The buffer is posted back to the receive queue when this is dropped.
+pub struct RxBufferRef<'a, H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    pool: &'a RxBufferPool<H, T, QUEUE_SIZE>,
+    slot: u16,
+    hdr_len: usize,
+    packet_len: usize,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> RxBufferRef<'_, H, T, QUEUE_SIZE> {
+    /// Returns the header of the packet.
+    fn header(&self) -> VirtioNetHdr {
+        VirtioNetHdr::read_from_prefix(self.as_bytes()).unwrap()
+    }
+
+    /// Returns the checksum state of the packet, from its header.
+    pub fn csum_state(&self) -> RxChecksum {
+        self.header().rx_checksum()
+    }
+
+    /// Returns the hash which the device reported for the packet, if `VIRTIO_NET_F_HASH_REPORT`
+    /// was negotiated and the device calculated one.
+    pub fn hash(&self) -> Option<RxHash> {
+        rx_hash(self.as_bytes(), self.hdr_len)
+    }
+
+    /// Returns the packet as a slice.
+    pub fn packet(&self) -> &[u8] {
+        &self.as_bytes()[self.hdr_len..self.hdr_len + self.packet_len]
+    }
+
+    /// Returns the packet as a mutable slice.
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        let range = self.hdr_len..self.hdr_len + self.packet_len;
+        // Safe because the buffer is in use, so only this guard can access it.
+        unsafe { &mut self.pool.buffer(self.slot).as_mut()[range] }
+    }
+
+    /// Posts the buffer back to the receive queue.
+    ///
+    /// This is the same as dropping it, except that errors are returned rather than logged.
+    pub fn recycle(self) -> Result {
+        let this = ManuallyDrop::new(self);
+        this.pool.repost(this.slot)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        // Safe because the buffer is in use, so only this guard can access it.
+        unsafe { self.pool.buffer(self.slot).as_ref() }
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for RxBufferRef<'_, H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        if let Err(e) = self.pool.repost(self.slot) {
+            warn!("Failed to recycle receive buffer: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{fake_transport, Config, Features, NET_HDR_SIZE};
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use crate::transport::fake::{FakeTransport, State};
+    use alloc::sync::Arc;
+    use core::mem;
+    use core::sync::atomic::Ordering;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::Mutex;
+
+    const QUEUE_SIZE: usize = 4;
+
+    /// Counts the heap allocations made by each thread, so that tests can check that receiving
+    /// doesn't allocate.
+    struct CountingAllocator;
+
+    std::thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // Safe because all allocations are passed on to the system allocator.
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+            // Safe because our caller promises the same things as `System` requires.
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            // Safe because our caller promises the same things as `System` requires.
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Returns the number of heap allocations the current thread has made.
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    /// Creates a pool for a fake network device with the given config space, and returns it along with the device state and
+    /// the HAL.
+    fn fake_pool(
+        config_space: NonNull<Config>,
+    ) -> (
+        RxBufferPool<FakeHal, FakeTransport<Config>, QUEUE_SIZE>,
+        Arc<Mutex<State>>,
+        FakeHal,
+    ) {
+        let (transport, state) = fake_transport(config_space, Features::empty(), QUEUE_SIZE);
+        let hal = FakeHal::new();
+        let net = VirtIONetRaw::<FakeHal, _, QUEUE_SIZE>::new(transport, hal).unwrap();
+        (RxBufferPool::new(net, hal, 2048).unwrap(), state, hal)
+    }
+
+    /// Plays the device, receiving a frame with the given payload.
+    fn deliver(state: &Mutex<State>, payload: &[u8]) {
+        let mut frame = vec![0; NET_HDR_SIZE];
+        frame.extend_from_slice(payload);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(0, &frame);
+    }
+
+    #[test]
+    fn receive_without_sharing_again() {
+        let mut config_space = Config::default();
+        let (pool, state, hal) = fake_pool(NonNull::from(&mut config_space));
+        let counts = hal.counts();
+        let dma_alloc = counts.dma_alloc.load(Ordering::SeqCst);
+        let share = counts.share.load(Ordering::SeqCst);
+
+        for i in 0..QUEUE_SIZE * 3 {
+            deliver(&state, &[i as u8; 60]);
+            let before = allocations();
+            let rx_buf = pool.receive().unwrap();
+            assert_eq!(rx_buf.packet(), &[i as u8; 60]);
+            rx_buf.recycle().unwrap();
+            assert_eq!(allocations(), before);
+        }
+        assert_eq!(pool.in_use(), 0);
+
+        // Every buffer was posted without being shared or allocated again.
+        assert_eq!(counts.dma_alloc.load(Ordering::SeqCst), dma_alloc);
+        assert_eq!(counts.share.load(Ordering::SeqCst), share);
+        assert_eq!(counts.live_shares(), 0);
+    }
+
+    #[test]
+    fn recycle_twice() {
+        let mut config_space = Config::default();
+        let (pool, state, _hal) = fake_pool(NonNull::from(&mut config_space));
+        deliver(&state, &[1; 60]);
+        let rx_buf = pool.receive().unwrap();
+        let slot = rx_buf.slot;
+        rx_buf.recycle().unwrap();
+
+        // The buffer is back in the receive queue, so returning it again is refused.
+        assert_eq!(pool.repost(slot), Err(Error::WrongToken));
+        assert_eq!(pool.in_use(), 0);
+        deliver(&state, &[2; 60]);
+        assert_eq!(pool.receive().unwrap().packet(), &[2; 60]);
+    }
+
+    #[test]
+    fn reclaim_leaked_buffers() {
+        let mut config_space = Config::default();
+        let (mut pool, state, _hal) = fake_pool(NonNull::from(&mut config_space));
+        for i in 0..2 {
+            deliver(&state, &[i; 60]);
+            mem::forget(pool.receive().unwrap());
+        }
+        assert_eq!(pool.in_use(), 2);
+
+        assert_eq!(pool.reclaim_leaked(), Ok(2));
+        assert_eq!(pool.in_use(), 0);
+        assert_eq!(pool.reclaim_leaked(), Ok(0));
+
+        // All the buffers are posted again, so the device can fill every one.
+        for i in 0..QUEUE_SIZE as u8 {
+            deliver(&state, &[i; 60]);
+        }
+        for i in 0..QUEUE_SIZE as u8 {
+            assert_eq!(pool.receive().unwrap().packet(), &[i; 60]);
+        }
+    }
+}
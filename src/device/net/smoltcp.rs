@@ -0,0 +1,401 @@
+//! A [`smoltcp::phy::Device`] implementation for a VirtIO network device.
+//!
+//! This crate enables smoltcp's `medium-ethernet`, `proto-ipv4` and `socket-udp` features, which is
+//! the least smoltcp builds with. Applications can enable any other protocols and sockets which they
+//! use, e.g. `proto-ipv6` or `socket-tcp`.
+
+use super::net_buf::TxFrame;
+use super::{RxBuffer, RxChecksum, VirtIONet, VirtioNetHdr};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::Error;
+use ::smoltcp::phy::{Checksum, Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use ::smoltcp::time::Instant;
+use core::cell::RefCell;
+use log::warn;
+
+/// A [`VirtIONet`] wrapped to be used as a [`Device`] by the smoltcp network stack.
+///
+/// Received packets are passed to smoltcp in the buffer which the device wrote them to, and the
+/// buffer is recycled when the receive token is consumed or dropped. Packets are written into one
+/// of the transmit buffers which [`VirtIONet`] allocated up front, which is taken when the token is
+/// issued, and are sent without waiting for the device. Transmit buffers which the device has
+/// finished with are reclaimed each time smoltcp asks for a token.
+///
+/// TCP and UDP checksums of transmitted packets are left to the device if `VIRTIO_NET_F_CSUM` was
+/// negotiated, and those of received packets aren't checked by smoltcp if
+/// `VIRTIO_NET_F_GUEST_CSUM` was negotiated. Received packets which the device didn't validate are
+/// then checked before smoltcp sees them, and dropped if their checksum is wrong. IPv4 fragments can't be checked until they are reassembled, so
+/// they are passed on unchecked.
+pub struct VirtIONetSmoltcp<
+    H: Hal,
+    T: Transport,
+    const QUEUE_SIZE: usize,
+    const MAX_PAIRS: usize = 1,
+> {
+    net: RefCell<VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize>
+    VirtIONetSmoltcp<H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    /// Wraps the given network device.
+    pub fn new(net: VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>) -> Self {
+        Self {
+            net: RefCell::new(net),
+        }
+    }
+
+    /// Returns the wrapped network device, e.g. to acknowledge interrupts or change its filters.
+    pub fn net_mut(&mut self) -> &mut VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS> {
+        self.net.get_mut()
+    }
+
+    /// Unwraps the network device.
+    pub fn into_inner(self) -> VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS> {
+        self.net.into_inner()
+    }
+
+    fn tx_token(&self, tx_frame: TxFrame) -> VirtIONetTxToken<'_, H, T, QUEUE_SIZE, MAX_PAIRS> {
+        VirtIONetTxToken {
+            net: &self.net,
+            tx_frame: Some(tx_frame),
+        }
+    }
+
+    /// Reclaims the transmit buffers which the device has finished with, and takes one for a
+    /// transmit token if there is room to send.
+    fn take_tx_frame(&mut self) -> Option<TxFrame> {
+        let net = self.net.get_mut();
+        if let Err(e) = net.reap_transmitted() {
+            warn!("Failed to reclaim transmit buffers: {}", e);
+        }
+        if !net.can_send() {
+            return None;
+        }
+        net.take_tx_frame()
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> Device
+    for VirtIONetSmoltcp<H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    type RxToken<'a>
+        = VirtIONetRxToken<'a, H, T, QUEUE_SIZE, MAX_PAIRS>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = VirtIONetTxToken<'a, H, T, QUEUE_SIZE, MAX_PAIRS>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let tx_frame = self.take_tx_frame()?;
+        let net = self.net.get_mut();
+        let check_rx = net.rx_checksum_offload();
+        let rx_buf = loop {
+            let rx_buf = match net.receive() {
+                Ok(rx_buf) => rx_buf,
+                Err(e) => {
+                    if e != Error::NotReady {
+                        warn!("Failed to receive packet: {}", e);
+                    }
+                    net.recycle_tx_frame(tx_frame);
+                    return None;
+                }
+            };
+            if !check_rx
+                || rx_buf.csum_state() != RxChecksum::Unverified
+                || checksum_valid(rx_buf.packet())
+            {
+                break rx_buf;
+            }
+            warn!("Dropping received packet with a bad checksum");
+            if let Err(e) = net.recycle_rx_buffer(rx_buf) {
+                warn!("Failed to recycle receive buffer: {}", e);
+            }
+        };
+        let rx_token = VirtIONetRxToken {
+            net: &self.net,
+            rx_buf: Some(rx_buf),
+        };
+        Some((rx_token, self.tx_token(tx_frame)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        let tx_frame = self.take_tx_frame()?;
+        Some(self.tx_token(tx_frame))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        let net = self.net.borrow();
+        caps.max_transmission_unit = net.max_frame_len();
+        caps.max_burst_size = Some(net.max_burst_size());
+        let checksum = match (net.tx_checksum_offload(), net.rx_checksum_offload()) {
+            (false, false) => Checksum::Both,
+            (true, false) => Checksum::Rx,
+            (false, true) => Checksum::Tx,
+            (true, true) => Checksum::None,
+        };
+        caps.checksum.tcp = checksum;
+        caps.checksum.udp = checksum;
+        caps
+    }
+}
+
+/// A token to receive a packet from a [`VirtIONetSmoltcp`].
+pub struct VirtIONetRxToken<
+    'a,
+    H: Hal,
+    T: Transport,
+    const QUEUE_SIZE: usize,
+    const MAX_PAIRS: usize,
+> {
+    net: &'a RefCell<VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>>,
+    /// The received packet, until it is recycled.
+    rx_buf: Option<RxBuffer>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> RxToken
+    for VirtIONetRxToken<'_, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(self.rx_buf.as_ref().unwrap().packet())
+        // The buffer is recycled when `self` is dropped.
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> Drop
+    for VirtIONetRxToken<'_, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn drop(&mut self) {
+        if let Some(rx_buf) = self.rx_buf.take() {
+            if let Err(e) = self.net.borrow_mut().recycle_rx_buffer(rx_buf) {
+                warn!("Failed to recycle receive buffer: {}", e);
+            }
+        }
+    }
+}
+
+/// A token to send a packet with a [`VirtIONetSmoltcp`].
+pub struct VirtIONetTxToken<
+    'a,
+    H: Hal,
+    T: Transport,
+    const QUEUE_SIZE: usize,
+    const MAX_PAIRS: usize,
+> {
+    net: &'a RefCell<VirtIONet<H, T, QUEUE_SIZE, MAX_PAIRS>>,
+    /// The transmit buffer to write the packet into, until it is sent.
+    tx_frame: Option<TxFrame>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> TxToken
+    for VirtIONetTxToken<'_, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn consume<R, F>(mut self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut tx_frame = self.tx_frame.take().unwrap();
+        let packet = tx_frame.packet_mut(len);
+        let result = f(packet);
+        let mut net = self.net.borrow_mut();
+        let mut header = VirtioNetHdr::default();
+        if net.tx_checksum_offload() {
+            if let Some((start, offset)) = prepare_partial_checksum(packet) {
+                header.set_partial_csum(start, offset);
+            }
+        }
+        // smoltcp doesn't expect sending to fail, so the packet is dropped as if it had been lost
+        // on the wire.
+        if let Err(e) = net.send_tx_frame(tx_frame, &header) {
+            warn!("Failed to send packet: {}", e);
+        }
+        result
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize, const MAX_PAIRS: usize> Drop
+    for VirtIONetTxToken<'_, H, T, QUEUE_SIZE, MAX_PAIRS>
+{
+    fn drop(&mut self) {
+        if let Some(tx_frame) = self.tx_frame.take() {
+            self.net.borrow_mut().recycle_tx_frame(tx_frame);
+        }
+    }
+}
+
+/// The location of the TCP or UDP header in an Ethernet frame.
+struct L4Header {
+    /// The offset of the header in the frame.
+    start: usize,
+    /// The length of the header and its payload.
+    len: usize,
+    /// The offset of the checksum from `start`.
+    csum_offset: usize,
+    /// The unfolded ones' complement sum of the pseudo-header.
+    pseudo_sum: u32,
+    /// Whether the checksum may be 0 for none, as for UDP over IPv4.
+    optional: bool,
+}
+
+/// Finds the TCP or UDP header of an unfragmented IPv4 or IPv6 packet in the given Ethernet frame,
+/// possibly with a VLAN tag.
+///
+/// Returns `None` for other packets, or if the lengths in the frame don't fit.
+fn find_l4_header(frame: &[u8]) -> Option<L4Header> {
+    let (ethertype, ip_start) = match be16(frame, 12)? {
+        0x8100 => (be16(frame, 16)?, 18),
+        ethertype => (ethertype, 14),
+    };
+    let ip = frame.get(ip_start..)?;
+    let (start, len, protocol, addresses) = match ethertype {
+        // IPv4
+        0x0800 => {
+            let ihl = usize::from(*ip.first()? & 0x0f) * 4;
+            let total_len = usize::from(be16(ip, 2)?);
+            // A fragment can't be checksummed on its own.
+            if be16(ip, 6)? & 0x3fff != 0 || ihl < 20 || total_len < ihl {
+                return None;
+            }
+            (ihl, total_len - ihl, *ip.get(9)?, ip.get(12..20)?)
+        }
+        // IPv6, without extension headers.
+        0x86dd => (40, usize::from(be16(ip, 4)?), *ip.get(6)?, ip.get(8..40)?),
+        _ => return None,
+    };
+    let csum_offset = match protocol {
+        TCP => 16,
+        UDP => 6,
+        _ => return None,
+    };
+    if csum_offset + 2 > len || ip.len() < start + len {
+        return None;
+    }
+    Some(L4Header {
+        start: ip_start + start,
+        len,
+        csum_offset,
+        pseudo_sum: ones_complement_sum(addresses) + u32::from(protocol) + len as u32,
+        optional: protocol == UDP && ethertype == 0x0800,
+    })
+}
+
+/// The IP protocol number of TCP.
+const TCP: u8 = 6;
+/// The IP protocol number of UDP.
+const UDP: u8 = 17;
+
+/// Sets up the TCP or UDP checksum of the given frame to be completed by the device, by storing
+/// the checksum of the pseudo-header in it, and returns the checksum start and offset to pass to
+/// [`VirtioNetHdr::set_partial_csum`].
+///
+/// If the frame is padded after the packet, where the device would carry on summing, the checksum
+/// is calculated here instead and `None` is returned, as it is for other packets.
+fn prepare_partial_checksum(frame: &mut [u8]) -> Option<(u16, u16)> {
+    let l4 = find_l4_header(frame)?;
+    let csum_pos = l4.start + l4.csum_offset;
+    if l4.start + l4.len == frame.len() {
+        frame[csum_pos..csum_pos + 2].copy_from_slice(&fold(l4.pseudo_sum).to_be_bytes());
+        Some((l4.start as u16, l4.csum_offset as u16))
+    } else {
+        frame[csum_pos..csum_pos + 2].fill(0);
+        let sum = l4.pseudo_sum + ones_complement_sum(&frame[l4.start..l4.start + l4.len]);
+        let checksum = match !fold(sum) {
+            // 0 would mean there is no checksum.
+            0 if l4.optional => 0xffff,
+            checksum => checksum,
+        };
+        frame[csum_pos..csum_pos + 2].copy_from_slice(&checksum.to_be_bytes());
+        None
+    }
+}
+
+/// Returns whether the TCP or UDP checksum of the given received frame is valid, or true if it
+/// isn't an unfragmented TCP or UDP packet.
+fn checksum_valid(frame: &[u8]) -> bool {
+    let Some(l4) = find_l4_header(frame) else {
+        return true;
+    };
+    let segment = &frame[l4.start..l4.start + l4.len];
+    (l4.optional && segment[l4.csum_offset..l4.csum_offset + 2] == [0, 0])
+        || fold(l4.pseudo_sum + ones_complement_sum(segment)) == 0xffff
+}
+
+/// Reads the big-endian `u16` at the given offset, if it is in bounds.
+fn be16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+/// Returns the ones' complement sum of the given data as big-endian 16-bit words, padded with a
+/// zero byte if its length is odd, without folding the carries back in.
+fn ones_complement_sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+/// Folds the carries of a ones' complement sum back into 16 bits.
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an Ethernet frame holding an IPv4 UDP packet with the given payload and no
+    /// checksum.
+    fn udp_frame(payload: &[u8]) -> Vec<u8> {
+        let udp_len = 8 + payload.len() as u16;
+        let mut frame = vec![0xff; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, UDP, 0, 0]);
+        frame[16..18].copy_from_slice(&(20 + udp_len).to_be_bytes());
+        frame.extend_from_slice(&[10, 0, 2, 15, 10, 0, 2, 2]);
+        frame.extend_from_slice(&[0x30, 0x39, 0x00, 0x35]);
+        frame.extend_from_slice(&udp_len.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn partial_checksum_completed_by_device() {
+        let mut frame = udp_frame(b"hello, world");
+        let (start, offset) = prepare_partial_checksum(&mut frame).unwrap();
+        assert_eq!((start, offset), (34, 6));
+
+        // Complete the checksum as the device would.
+        let start = usize::from(start);
+        let pos = start + usize::from(offset);
+        let checksum = !fold(ones_complement_sum(&frame[start..]));
+        frame[pos..pos + 2].copy_from_slice(&checksum.to_be_bytes());
+        assert!(checksum_valid(&frame));
+
+        // The same checksum is calculated in software if the frame is padded.
+        let mut padded = udp_frame(b"hello, world");
+        padded.resize(64, 0);
+        assert_eq!(prepare_partial_checksum(&mut padded), None);
+        assert_eq!(padded[pos..pos + 2], checksum.to_be_bytes());
+
+        frame[start + 8] ^= 1;
+        assert!(!checksum_valid(&frame));
+    }
+
+    #[test]
+    fn udp_without_checksum_valid() {
+        assert!(checksum_valid(&udp_frame(b"hello, world")));
+    }
+}
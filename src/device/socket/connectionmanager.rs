@@ -22,12 +22,12 @@ const DEFAULT_PER_CONNECTION_BUFFER_CAPACITY: u32 = 1024;
 /// # Example
 ///
 /// ```
-/// # use virtio_drivers::{Error, Hal};
-/// # use virtio_drivers::transport::Transport;
-/// use virtio_drivers::device::socket::{VirtIOSocket, VsockAddr, VsockConnectionManager};
+/// # use virtio_drivers_sel4::{Error, Hal};
+/// # use virtio_drivers_sel4::transport::Transport;
+/// use virtio_drivers_sel4::device::socket::{VirtIOSocket, VsockAddr, VsockConnectionManager};
 ///
-/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
-/// let mut socket = VsockConnectionManager::new(VirtIOSocket::<HalImpl, _>::new(transport)?);
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T, hal: HalImpl) -> Result<(), Error> {
+/// let mut socket = VsockConnectionManager::new(VirtIOSocket::<HalImpl, _>::new(transport, hal)?);
 ///
 /// // Start a thread to call `socket.poll()` and handle events.
 ///
@@ -395,9 +395,7 @@ impl RingBuffer {
         // The number of bytes to copy out between `start` and the end of the buffer.
         let read_before_wraparound = min(bytes_read, self.buffer.len() - self.start);
         // The number of bytes to copy out from the beginning of the buffer after wrapping around.
-        let read_after_wraparound = bytes_read
-            .checked_sub(read_before_wraparound)
-            .unwrap_or_default();
+        let read_after_wraparound = bytes_read.saturating_sub(read_before_wraparound);
 
         out[0..read_before_wraparound]
             .copy_from_slice(&self.buffer[self.start..self.start + read_before_wraparound]);
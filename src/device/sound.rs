@@ -1,8 +1,5 @@
 //! Driver for VirtIO Sound devices.
 
-#[cfg(test)]
-mod fake;
-
 use super::common::Feature;
 use crate::{
     queue::{owning::OwningQueue, VirtQueue},
@@ -367,6 +364,7 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
     }
 
     /// Set selected stream parameters for the specified stream ID.
+    #[allow(clippy::too_many_arguments)]
     pub fn pcm_set_params(
         &mut self,
         stream_id: u32,
@@ -381,7 +379,10 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
             self.set_up()?;
             self.set_up = true;
         }
-        if period_bytes == 0 || period_bytes > buffer_bytes || buffer_bytes % period_bytes != 0 {
+        if period_bytes == 0
+            || period_bytes > buffer_bytes
+            || !buffer_bytes.is_multiple_of(period_bytes)
+        {
             return Err(Error::InvalidParam);
         }
         let request_hdr = VirtIOSndHdr::from(CommandCode::RPcmSetParams);
@@ -1,18 +1,23 @@
-
+#[cfg(test)]
+pub mod fake;
 
 use crate::{Error, Result, PAGE_SIZE};
+use core::hint::spin_loop;
 use core::ptr::NonNull;
+use log::warn;
 
 /// A physical address as used for virtio.
 pub type PhysAddr = usize;
 
 /// A region of contiguous physical memory used for DMA.
+///
+/// The memory is deallocated with [`Hal::dma_dealloc`] when this is dropped.
 #[derive(Debug)]
 pub struct Dma<H: Hal> {
     paddr: usize,
     vaddr: NonNull<u8>,
     pages: usize,
-    hal:  H, // Store the reference to Hal
+    hal: H, // Store the reference to Hal
 }
 
 // SAFETY: DMA memory can be accessed from any thread.
@@ -59,13 +64,26 @@ impl<H: Hal> Dma<H> {
     }
 }
 
+impl<H: Hal> Drop for Dma<H> {
+    fn drop(&mut self) {
+        // Safe because the memory was previously allocated by `dma_alloc` in `Dma::new`, not yet
+        // deallocated, and we are passing the values from then.
+        let err = unsafe { self.hal.dma_dealloc(self.paddr, self.vaddr, self.pages) };
+        if err != 0 {
+            warn!(
+                "Failed to deallocate DMA region at {:#x}: {}",
+                self.paddr, err
+            );
+        }
+    }
+}
+
 /// The interface which a particular hardware implementation must implement.
 ///
 /// # Safety
 ///
 /// Implementations of this trait must follow the "implementation safety" requirements documented
 /// for each method. Callers must follow the safety requirements documented for the unsafe methods.
-
 pub unsafe trait Hal: Copy {
     /// Allocates and zeroes the given number of contiguous physical pages of DMA memory for VirtIO
     /// use.
@@ -79,7 +97,8 @@ pub unsafe trait Hal: Copy {
     /// [_valid_](https://doc.rust-lang.org/std/ptr/index.html#safety) pointer, aligned to
     /// [`PAGE_SIZE`], and won't alias any other allocations or references in the program until it
     /// is deallocated by `dma_dealloc`. The pages must be zeroed.
-    unsafe fn dma_alloc(&self, pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>);
+    unsafe fn dma_alloc(&self, pages: usize, direction: BufferDirection)
+        -> (PhysAddr, NonNull<u8>);
 
     /// Deallocates the given contiguous physical DMA memory pages.
     ///
@@ -129,6 +148,19 @@ pub unsafe trait Hal: Copy {
     /// any other thread for the duration of this method call. The `paddr` must be the value
     /// previously returned by the corresponding `share` call.
     unsafe fn unshare(&self, paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection);
+
+    /// Waits for approximately the given number of microseconds.
+    ///
+    /// This is used by drivers which poll the device with a timeout, between polls. Implementations
+    /// may yield to other tasks or wait for an interrupt rather than spinning, but must not return
+    /// much earlier than requested. The default implementation just calls
+    /// [`core::hint::spin_loop`] once per microsecond, which is not calibrated to real time, so
+    /// implementations which care about accurate timeouts should override it.
+    fn delay_us(&self, us: u64) {
+        for _ in 0..us {
+            spin_loop();
+        }
+    }
 }
 
 /// The direction in which a buffer is passed.
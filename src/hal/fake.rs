@@ -0,0 +1,158 @@
+//! A fake HAL implementation for unit tests.
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use crate::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use zerocopy::FromZeroes;
+
+/// Counts of the calls made to a [`FakeHal`], and the buffers which are currently shared.
+#[derive(Debug, Default)]
+pub struct FakeHalCounts {
+    /// The number of calls to `dma_alloc`.
+    pub dma_alloc: AtomicUsize,
+    /// The number of calls to `dma_dealloc`.
+    pub dma_dealloc: AtomicUsize,
+    /// The number of calls to `share`.
+    pub share: AtomicUsize,
+    /// The number of calls to `unshare`.
+    pub unshare: AtomicUsize,
+    /// The physical address and length of each buffer which is currently shared.
+    shared: Mutex<Vec<(PhysAddr, usize)>>,
+}
+
+impl FakeHalCounts {
+    /// Returns the number of DMA regions which have been allocated but not deallocated.
+    pub fn live_dma(&self) -> usize {
+        self.dma_alloc.load(Ordering::SeqCst) - self.dma_dealloc.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of buffers which have been shared but not unshared.
+    pub fn live_shares(&self) -> usize {
+        self.share.load(Ordering::SeqCst) - self.unshare.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the given range of physical addresses lies within a buffer which is
+    /// currently shared.
+    pub fn is_shared(&self, paddr: PhysAddr, len: usize) -> bool {
+        self.shared
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|&(start, shared_len)| paddr >= start && paddr + len <= start + shared_len)
+    }
+}
+
+/// A fake implementation of [`Hal`] for unit tests, which maps physical addresses to the same
+/// virtual addresses and counts the calls made to it.
+///
+/// Shared buffers are copied to a separate allocation, so a driver which forgets to unshare a
+/// buffer doesn't see what the device wrote to it.
+#[derive(Clone, Copy, Debug)]
+pub struct FakeHal {
+    counts: &'static FakeHalCounts,
+}
+
+impl FakeHal {
+    /// Creates a fake HAL with its own counts.
+    pub fn new() -> Self {
+        Self {
+            counts: Box::leak(Box::default()),
+        }
+    }
+
+    /// Returns the counts of the calls made to this HAL and its copies.
+    pub fn counts(&self) -> &'static FakeHalCounts {
+        self.counts
+    }
+}
+
+unsafe impl Hal for FakeHal {
+    unsafe fn dma_alloc(
+        &self,
+        pages: usize,
+        _direction: BufferDirection,
+    ) -> (PhysAddr, NonNull<u8>) {
+        assert_ne!(pages, 0);
+        self.counts.dma_alloc.fetch_add(1, Ordering::SeqCst);
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+        // Safe because the size and alignment of the layout are non-zero.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if let Some(ptr) = NonNull::new(ptr) {
+            (ptr.as_ptr() as PhysAddr, ptr)
+        } else {
+            handle_alloc_error(layout);
+        }
+    }
+
+    unsafe fn dma_dealloc(&self, _paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        assert_ne!(pages, 0);
+        self.counts.dma_dealloc.fetch_add(1, Ordering::SeqCst);
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+        // Safe because the memory was allocated by `dma_alloc` above using the same allocator, and
+        // the layout is the same as was used then.
+        unsafe { dealloc(vaddr.as_ptr(), layout) };
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(&self, paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        NonNull::new(paddr as _).unwrap()
+    }
+
+    unsafe fn share(&self, buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        assert_ne!(buffer.len(), 0);
+        self.counts.share.fetch_add(1, Ordering::SeqCst);
+        let mut shared_buffer = u8::new_box_slice_zeroed(buffer.len());
+        if let BufferDirection::DriverToDevice | BufferDirection::Both = direction {
+            // Safe because the caller guarantees that the buffer is valid for its length.
+            unsafe {
+                buffer
+                    .as_ptr()
+                    .cast::<u8>()
+                    .copy_to(shared_buffer.as_mut_ptr(), buffer.len());
+            }
+        }
+        let paddr = Box::into_raw(shared_buffer) as *mut u8 as PhysAddr;
+        self.counts
+            .shared
+            .lock()
+            .unwrap()
+            .push((paddr, buffer.len()));
+        paddr
+    }
+
+    unsafe fn unshare(&self, paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        assert_ne!(paddr, 0);
+        self.counts.unshare.fetch_add(1, Ordering::SeqCst);
+        let mut shared = self.counts.shared.lock().unwrap();
+        let index = shared
+            .iter()
+            .position(|&region| region == (paddr, buffer.len()))
+            .expect("Unsharing a buffer which wasn't shared");
+        shared.swap_remove(index);
+        drop(shared);
+        // Safe because the shared buffer was allocated by `share` with the same length, and the
+        // caller guarantees that it hasn't been unshared already.
+        let shared_buffer = unsafe {
+            Box::from_raw(ptr::slice_from_raw_parts_mut(
+                paddr as *mut u8,
+                buffer.len(),
+            ))
+        };
+        if let BufferDirection::DeviceToDriver | BufferDirection::Both = direction {
+            // Safe because the caller guarantees that the buffer is valid for its length.
+            unsafe {
+                buffer
+                    .as_ptr()
+                    .cast::<u8>()
+                    .copy_from(shared_buffer.as_ptr(), buffer.len());
+            }
+        }
+    }
+}
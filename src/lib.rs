@@ -12,7 +12,7 @@
 //!
 //! ```
 //! use core::ptr::NonNull;
-//! use virtio_drivers::transport::mmio::{MmioTransport, VirtIOHeader};
+//! use virtio_drivers_sel4::transport::mmio::{MmioTransport, VirtIOHeader};
 //!
 //! # fn example(mmio_device_address: usize) {
 //! let header = NonNull::new(mmio_device_address as *mut VirtIOHeader).unwrap();
@@ -23,18 +23,18 @@
 //! You can then check what kind of VirtIO device it is and construct the appropriate driver:
 //!
 //! ```
-//! # use virtio_drivers::Hal;
+//! # use virtio_drivers_sel4::Hal;
 //! # #[cfg(feature = "alloc")]
-//! use virtio_drivers::{
+//! use virtio_drivers_sel4::{
 //!     device::console::VirtIOConsole,
 //!     transport::{mmio::MmioTransport, DeviceType, Transport},
 //! };
 
 //!
 //! # #[cfg(feature = "alloc")]
-//! # fn example<HalImpl: Hal>(transport: MmioTransport) {
+//! # fn example<HalImpl: Hal>(transport: MmioTransport, hal: HalImpl) {
 //! if transport.device_type() == DeviceType::Console {
-//!     let mut console = VirtIOConsole::<HalImpl, _>::new(transport).unwrap();
+//!     let mut console = VirtIOConsole::<HalImpl, _>::new(transport, hal).unwrap();
 //!     // Send a byte to the console.
 //!     console.send(b'H').unwrap();
 //! }
@@ -60,7 +60,7 @@ use core::{
     ptr::{self, NonNull},
 };
 
-pub use self::hal::{BufferDirection, Hal, PhysAddr};
+pub use self::hal::{BufferDirection, Dma, Hal, PhysAddr};
 
 /// The page size in bytes supported by the library (4 KiB).
 pub const PAGE_SIZE: usize = 0x1000;
@@ -91,8 +91,25 @@ pub enum Error {
     ConfigSpaceTooSmall,
     /// The device doesn't have any config space, but the driver expects some.
     ConfigSpaceMissing,
+    /// The device has set `DEVICE_NEEDS_RESET` in its status, and must be reset before it can be
+    /// used again.
+    DeviceNeedsReset,
+    /// The request would modify a read-only device.
+    ReadOnly,
+    /// The device didn't complete a request within the timeout.
+    Timeout,
+    /// Error from the block device.
+    BlkDeviceError(device::blk::BlkError),
+    /// Error from the network device.
+    NetDeviceError(device::net::NetError),
     /// Error from the socket device.
     SocketDeviceError(device::socket::SocketError),
+    /// Error from the console device.
+    #[cfg(feature = "alloc")]
+    ConsoleDeviceError(device::console::ConsoleError),
+    /// An error response from the GPU device to a control command.
+    #[cfg(feature = "alloc")]
+    GpuResponse(device::gpu::GpuError),
 }
 
 #[cfg(feature = "alloc")]
@@ -126,17 +143,79 @@ impl Display for Error {
                     "The device doesn't have any config space, but the driver expects some"
                 )
             }
+            Self::DeviceNeedsReset => write!(f, "Device needs to be reset"),
+            Self::ReadOnly => write!(f, "Device is read-only"),
+            Self::Timeout => write!(f, "Request timed out"),
+            Self::BlkDeviceError(e) => write!(f, "Error from the block device: {e:?}"),
+            Self::NetDeviceError(e) => write!(f, "Error from the network device: {e:?}"),
             Self::SocketDeviceError(e) => write!(f, "Error from the socket device: {e:?}"),
+            #[cfg(feature = "alloc")]
+            Self::ConsoleDeviceError(e) => write!(f, "Error from the console device: {e:?}"),
+            #[cfg(feature = "alloc")]
+            Self::GpuResponse(e) => write!(f, "Error response from the GPU device: {e}"),
         }
     }
 }
 
+impl core::error::Error for Error {}
+
+impl From<device::blk::BlkError> for Error {
+    fn from(e: device::blk::BlkError) -> Self {
+        Self::BlkDeviceError(e)
+    }
+}
+
+impl From<device::net::NetError> for Error {
+    fn from(e: device::net::NetError) -> Self {
+        Self::NetDeviceError(e)
+    }
+}
+
 impl From<device::socket::SocketError> for Error {
     fn from(e: device::socket::SocketError) -> Self {
         Self::SocketDeviceError(e)
     }
 }
 
+#[cfg(feature = "alloc")]
+impl From<device::console::ConsoleError> for Error {
+    fn from(e: device::console::ConsoleError) -> Self {
+        Self::ConsoleDeviceError(e)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl From<device::gpu::GpuError> for Error {
+    fn from(e: device::gpu::GpuError) -> Self {
+        Self::GpuResponse(e)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::Error for Error {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Self::QueueFull | Self::DmaError => embedded_io::ErrorKind::OutOfMemory,
+            Self::InvalidParam => embedded_io::ErrorKind::InvalidInput,
+            Self::Unsupported | Self::BlkDeviceError(device::blk::BlkError::Unsupported) => {
+                embedded_io::ErrorKind::Unsupported
+            }
+            Self::ReadOnly => embedded_io::ErrorKind::PermissionDenied,
+            Self::Timeout => embedded_io::ErrorKind::TimedOut,
+            #[cfg(feature = "alloc")]
+            Self::ConsoleDeviceError(
+                device::console::ConsoleError::InvalidPort(_)
+                | device::console::ConsoleError::QueueTooLarge(_),
+            ) => embedded_io::ErrorKind::InvalidInput,
+            #[cfg(feature = "alloc")]
+            Self::ConsoleDeviceError(device::console::ConsoleError::PortNotReady(_)) => {
+                embedded_io::ErrorKind::NotConnected
+            }
+            _ => embedded_io::ErrorKind::Other,
+        }
+    }
+}
+
 /// Align `size` up to a page.
 fn align_up(size: usize) -> usize {
     (size + PAGE_SIZE) & !(PAGE_SIZE - 1)
@@ -144,7 +223,7 @@ fn align_up(size: usize) -> usize {
 
 /// The number of pages required to store `size` bytes, rounded up to a whole number of pages.
 fn pages(size: usize) -> usize {
-    (size + PAGE_SIZE - 1) / PAGE_SIZE
+    size.div_ceil(PAGE_SIZE)
 }
 
 // TODO: Use NonNull::slice_from_raw_parts once it is stable.
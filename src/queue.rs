@@ -11,12 +11,13 @@ use alloc::boxed::Box;
 use bitflags::bitflags;
 #[cfg(test)]
 use core::cmp::min;
+use core::cmp::Reverse;
 use core::convert::TryInto;
 use core::hint::spin_loop;
 use core::mem::{size_of, take};
 #[cfg(test)]
 use core::ptr;
-use core::ptr::NonNull;
+use core::ptr::{addr_of, NonNull};
 use core::sync::atomic::{fence, AtomicU16, Ordering};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
@@ -47,6 +48,10 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
 
     /// The index of queue
     queue_idx: u16,
+    /// The number of descriptors and ring slots which the device was told about. This is `SIZE`
+    /// unless the queue was created with [`VirtQueue::new_clamped`], in which case it may be
+    /// smaller, and only the first `size` entries of each array are used.
+    size: u16,
     /// The number of descriptors currently in use.
     num_used: u16,
     /// The head desc index of the free list.
@@ -55,9 +60,28 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
     desc_shadow: [Descriptor; SIZE],
     /// Our trusted copy of `avail.idx`.
     avail_idx: u16,
+    /// The value of `avail_idx` at which each descriptor chain was made available, indexed by
+    /// token, so that `restart` can make chains available again in the order they were added.
+    avail_order: [u16; SIZE],
     last_used_idx: u16,
+    /// The lengths of used elements which have been taken from the used ring but whose buffers have
+    /// not yet been popped, indexed by token.
+    ///
+    /// This lets `pop_used` complete requests in a different order to that in which the device
+    /// returned them.
+    used_lens: [Option<u32>; SIZE],
+    /// The number of entries in `used_lens` which are `Some`.
+    num_used_lens: u16,
+    /// The value of `last_used_idx` at which each element in `used_lens` was taken from the used
+    /// ring, indexed by token, so that `peek_used` can return them in the order the device did.
+    used_order: [u16; SIZE],
+    /// The number of descriptor chains which have been added but not yet popped.
+    num_in_flight: u16,
     /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
     event_idx: bool,
+    /// How many more used elements after `last_used_idx` the device should return before sending a
+    /// used buffer notification, if `event_idx` is enabled.
+    completion_threshold: u16,
     #[cfg(feature = "alloc")]
     indirect: bool,
     #[cfg(feature = "alloc")]
@@ -85,13 +109,51 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         #[allow(clippy::let_unit_value)]
         let _ = Self::SIZE_OK;
 
-        if transport.queue_used(idx) {
-            return Err(Error::AlreadyUsed);
-        }
         if transport.max_queue_size(idx) < SIZE as u32 {
             return Err(Error::InvalidParam);
         }
-        let size = SIZE as u16;
+        Self::new_with_size(hal, transport, idx, indirect, event_idx, SIZE as u16)
+    }
+
+    /// Creates a new VirtQueue like [`VirtQueue::new`], but if the device's maximum size for the
+    /// queue is less than `SIZE` then the queue is made as large as the device allows instead of
+    /// failing.
+    ///
+    /// `SIZE` is then only an upper bound, and [`VirtQueue::size`] returns the size actually used,
+    /// which is the largest power of two no more than either. Returns [`Error::InvalidParam`] if
+    /// the device doesn't support the queue at all.
+    pub fn new_clamped<T: Transport>(
+        hal: H,
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_OK;
+
+        let max = transport.max_queue_size(idx).min(SIZE as u32);
+        if max == 0 {
+            return Err(Error::InvalidParam);
+        }
+        // Split virtqueues must have a power of two size.
+        let size = 1 << max.ilog2();
+        Self::new_with_size(hal, transport, idx, indirect, event_idx, size)
+    }
+
+    /// Creates a new VirtQueue with the given size, which must be a power of two no more than
+    /// `SIZE` and the device's maximum.
+    fn new_with_size<T: Transport>(
+        hal: H,
+        transport: &mut T,
+        idx: u16,
+        #[cfg_attr(not(feature = "alloc"), allow(unused_variables))] indirect: bool,
+        event_idx: bool,
+        size: u16,
+    ) -> Result<Self> {
+        if transport.queue_used(idx) {
+            return Err(Error::AlreadyUsed);
+        }
 
         let layout = if transport.requires_legacy_layout() {
             VirtQueueLayout::allocate_legacy(hal, size)?
@@ -107,8 +169,10 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             layout.device_area_paddr(),
         );
 
-        let desc =
-            nonnull_slice_from_raw_parts(layout.descriptors_vaddr().cast::<Descriptor>(), SIZE);
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<Descriptor>(),
+            size.into(),
+        );
         let avail = layout.avail_vaddr().cast();
         let used = layout.used_vaddr().cast();
 
@@ -131,12 +195,19 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             avail,
             used,
             queue_idx: idx,
+            size,
             num_used: 0,
             free_head: 0,
             desc_shadow,
             avail_idx: 0,
+            avail_order: [0; SIZE],
             last_used_idx: 0,
+            used_lens: [None; SIZE],
+            num_used_lens: 0,
+            used_order: [0; SIZE],
+            num_in_flight: 0,
             event_idx,
+            completion_threshold: 1,
             #[cfg(feature = "alloc")]
             indirect,
             #[cfg(feature = "alloc")]
@@ -160,34 +231,72 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
     ) -> Result<u16> {
-        if inputs.is_empty() && outputs.is_empty() {
+        // Safe because our caller promises the same things as we require.
+        unsafe { self.add_buffers(inputs, None, outputs) }
+    }
+
+    /// Adds buffers to the virtqueue like [`VirtQueue::add`], with the given already-shared buffer
+    /// between the inputs and the outputs, and returns a token.
+    ///
+    /// The shared buffer's physical address is used as it is, without calling [`Hal::share`] or
+    /// [`Hal::unshare`]. The buffers must not be empty.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used_shared` with the returned token succeeds. The shared buffer must remain valid and
+    /// accessible to the device at its physical address until then.
+    pub unsafe fn add_shared<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        shared: SharedBuffer,
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // Safe because our caller promises the same things as we require.
+        unsafe { self.add_buffers(inputs, Some(shared), outputs) }
+    }
+
+    /// Adds the given buffers to the virtqueue, with the optional shared buffer between the inputs
+    /// and the outputs.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtQueue::add_shared`].
+    unsafe fn add_buffers<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        shared: Option<SharedBuffer>,
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        let descriptors_needed = inputs.len() + usize::from(shared.is_some()) + outputs.len();
+        if descriptors_needed == 0 {
             return Err(Error::InvalidParam);
         }
-        let descriptors_needed = inputs.len() + outputs.len();
         // Only consider indirect descriptors if the alloc feature is enabled, as they require
         // allocation.
         #[cfg(feature = "alloc")]
-        if self.num_used as usize + 1 > SIZE
-            || descriptors_needed > SIZE
-            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE)
+        if self.num_used + 1 > self.size
+            || descriptors_needed > self.size.into()
+            || (!self.indirect
+                && usize::from(self.num_used) + descriptors_needed > self.size.into())
         {
             return Err(Error::QueueFull);
         }
         #[cfg(not(feature = "alloc"))]
-        if self.num_used as usize + descriptors_needed > SIZE {
+        if usize::from(self.num_used) + descriptors_needed > self.size.into() {
             return Err(Error::QueueFull);
         }
 
         #[cfg(feature = "alloc")]
         let head = if self.indirect && descriptors_needed > 1 {
-            self.add_indirect(inputs, outputs)
+            self.add_indirect(inputs, shared, outputs)
         } else {
-            self.add_direct(inputs, outputs)
+            self.add_direct(inputs, shared, outputs)
         };
         #[cfg(not(feature = "alloc"))]
-        let head = self.add_direct(inputs, outputs);
+        let head = self.add_direct(inputs, shared, outputs);
 
-        let avail_slot = self.avail_idx & (SIZE as u16 - 1);
+        let avail_slot = self.avail_idx & (self.size - 1);
         // Safe because self.avail is properly aligned, dereferenceable and initialised.
         unsafe {
             (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
@@ -198,7 +307,9 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         fence(Ordering::SeqCst);
 
         // increase head of avail ring
+        self.avail_order[usize::from(head)] = self.avail_idx;
         self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.num_in_flight += 1;
         // Safe because self.avail is properly aligned, dereferenceable and initialised.
         unsafe {
             (*self.avail.as_ptr())
@@ -212,13 +323,15 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     fn add_direct<'a, 'b>(
         &mut self,
         inputs: &'a [&'b [u8]],
+        shared: Option<SharedBuffer>,
         outputs: &'a mut [&'b mut [u8]],
     ) -> u16 {
         // allocate descriptors from free list
         let head = self.free_head;
         let mut last = self.free_head;
+        let mut count = 0;
 
-        for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+        for (buffer, direction, paddr) in InputOutputIter::new(inputs, shared, outputs) {
             assert_ne!(buffer.len(), 0);
 
             // Write to desc_shadow then copy.
@@ -226,9 +339,10 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             // Safe because our caller promises that the buffers live at least until `pop_used`
             // returns them.
             unsafe {
-                desc.set_buf::<H>(self.hal, buffer, direction, DescFlags::NEXT);
+                desc.set_buf::<H>(self.hal, buffer, direction, paddr, DescFlags::NEXT);
             }
             last = self.free_head;
+            count += 1;
             self.free_head = desc.next;
 
             self.write_desc(last);
@@ -240,7 +354,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             .remove(DescFlags::NEXT);
         self.write_desc(last);
 
-        self.num_used += (inputs.len() + outputs.len()) as u16;
+        self.num_used += count;
 
         head
     }
@@ -249,18 +363,23 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     fn add_indirect<'a, 'b>(
         &mut self,
         inputs: &'a [&'b [u8]],
+        shared: Option<SharedBuffer>,
         outputs: &'a mut [&'b mut [u8]],
     ) -> u16 {
         let head = self.free_head;
 
         // Allocate and fill in indirect descriptor list.
-        let mut indirect_list = Descriptor::new_box_slice_zeroed(inputs.len() + outputs.len());
-        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+        let mut indirect_list = Descriptor::new_box_slice_zeroed(
+            inputs.len() + usize::from(shared.is_some()) + outputs.len(),
+        );
+        for (i, (buffer, direction, paddr)) in
+            InputOutputIter::new(inputs, shared, outputs).enumerate()
+        {
             let desc = &mut indirect_list[i];
             // Safe because our caller promises that the buffers live at least until `pop_used`
             // returns them.
             unsafe {
-                desc.set_buf::<H>(self.hal, buffer, direction, DescFlags::NEXT);
+                desc.set_buf::<H>(self.hal, buffer, direction, paddr, DescFlags::NEXT);
             }
             desc.next = (i + 1) as u16;
         }
@@ -285,6 +404,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 self.hal,
                 Box::leak(indirect_list).as_bytes().into(),
                 BufferDirection::DriverToDevice,
+                None,
                 DescFlags::INDIRECT,
             );
         }
@@ -305,9 +425,33 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
         transport: &mut impl Transport,
+    ) -> Result<u32> {
+        // Safe because we wait forever, so never give up on the buffers.
+        unsafe {
+            self.add_notify_wait_pop_with(inputs, outputs, transport, || {
+                spin_loop();
+                true
+            })
+        }
+    }
+
+    /// Like [`VirtQueue::add_notify_wait_pop`], but calls `wait` each time the device hasn't yet
+    /// used the buffers, and gives up with [`Error::Timeout`] if it returns false.
+    ///
+    /// # Safety
+    ///
+    /// If this returns [`Error::Timeout`] then the buffers are still in the queue, and the device
+    /// may still access them. Before they are used again, the caller must make sure the device can
+    /// no longer access them, e.g. by resetting it.
+    pub unsafe fn add_notify_wait_pop_with<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+        mut wait: impl FnMut() -> bool,
     ) -> Result<u32> {
         // Safe because we don't return until the same token has been popped, so the buffers remain
-        // valid and are not otherwise accessed until then.
+        // valid and are not otherwise accessed until then, or our caller deals with the buffers.
         let token = unsafe { self.add(inputs, outputs) }?;
 
         // Notify the queue.
@@ -315,9 +459,12 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             transport.notify(self.queue_idx);
         }
 
-        // Wait until there is at least one element in the used ring.
-        while !self.can_pop() {
-            spin_loop();
+        // Wait until the device has used our buffers. Other elements it returns in the meantime are
+        // kept to be popped later.
+        while !self.take_used_until(token)? {
+            if !wait() {
+                return Err(Error::Timeout);
+            }
         }
 
         // Safe because these are the same buffers as we passed to `add` above and they are still
@@ -341,6 +488,16 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
+    /// Asks the device not to send a used buffer notification until it has returned at least `n`
+    /// more used elements, rather than after every one. 0 is treated as 1.
+    ///
+    /// This only has an effect if the `VIRTIO_F_EVENT_IDX` feature has been negotiated. If fewer
+    /// than `n` chains are in flight, there may be no notification until more are added.
+    pub fn set_completion_threshold(&mut self, n: u16) {
+        self.completion_threshold = n.max(1);
+        self.write_used_event();
+    }
+
     /// Returns whether the driver should notify the device after adding a new buffer to the
     /// virtqueue.
     ///
@@ -349,7 +506,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         if self.event_idx {
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
             // instance of UsedRing.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event.load(Ordering::Acquire) };
+            let avail_event = unsafe { (*self.avail_event()).load(Ordering::Acquire) };
             self.avail_idx >= avail_event.wrapping_add(1)
         } else {
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
@@ -358,6 +515,32 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
+    /// Returns a pointer to the `used_event` field of the available ring, which comes after the
+    /// first `size` slots of the ring rather than all `SIZE`.
+    fn used_event(&self) -> *const AtomicU16 {
+        // Safe because the available ring was allocated with `size` slots, so the field is within
+        // the allocation.
+        unsafe {
+            addr_of!((*self.avail.as_ptr()).ring)
+                .cast::<u16>()
+                .add(self.size.into())
+                .cast()
+        }
+    }
+
+    /// Returns a pointer to the `avail_event` field of the used ring, which comes after the first
+    /// `size` slots of the ring rather than all `SIZE`.
+    fn avail_event(&self) -> *const AtomicU16 {
+        // Safe because the used ring was allocated with `size` slots, so the field is within the
+        // allocation.
+        unsafe {
+            addr_of!((*self.used.as_ptr()).ring)
+                .cast::<UsedElem>()
+                .add(self.size.into())
+                .cast()
+        }
+    }
+
     /// Copies the descriptor at the given index from `desc_shadow` to `desc`, so it can be seen by
     /// the device.
     fn write_desc(&mut self, index: u16) {
@@ -371,6 +554,11 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
     /// Returns whether there is a used element that can be popped.
     pub fn can_pop(&self) -> bool {
+        self.num_used_lens != 0 || self.used_ring_nonempty()
+    }
+
+    /// Returns whether the device has added elements to the used ring which we haven't taken yet.
+    fn used_ring_nonempty(&self) -> bool {
         // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
         // instance of UsedRing.
         self.last_used_idx != unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) }
@@ -378,9 +566,21 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
     /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
     /// `None` if the used ring is empty.
+    ///
+    /// Elements which have already been taken from the used ring by an out-of-order `pop_used` are
+    /// returned before those still in the used ring, in the order in which the device returned
+    /// them.
     pub fn peek_used(&self) -> Option<u16> {
-        if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+        if self.num_used_lens != 0 {
+            // The oldest element is the one taken furthest before the current `last_used_idx`.
+            (0..self.size)
+                .filter(|&token| self.used_lens[usize::from(token)].is_some())
+                .max_by_key(|&token| {
+                    self.last_used_idx
+                        .wrapping_sub(self.used_order[usize::from(token)])
+                })
+        } else if self.used_ring_nonempty() {
+            let last_used_slot = self.last_used_idx & (self.size - 1);
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
             // readable instance of UsedRing.
             Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
@@ -389,18 +589,159 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
+    /// Returns the number of descriptor chains which have been added but not yet popped.
+    pub fn in_flight(&self) -> usize {
+        self.num_in_flight.into()
+    }
+
+    /// Returns the number of descriptor chains which the device has returned in the used ring but
+    /// which haven't yet been popped, reading the used ring's index once.
+    pub fn returned(&self) -> usize {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        let used_idx = unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) };
+        usize::from(self.num_used_lens) + usize::from(used_idx.wrapping_sub(self.last_used_idx))
+    }
+
+    /// Returns the number of descriptor chains which have been added but which the device hasn't
+    /// yet returned in the used ring.
+    pub fn pending(&self) -> usize {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        let used_idx = unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) };
+        let returned = self
+            .num_used_lens
+            .saturating_add(used_idx.wrapping_sub(self.last_used_idx));
+        usize::from(self.num_in_flight.saturating_sub(returned))
+    }
+
+    /// Takes any elements which the device has returned from the used ring, and returns which
+    /// tokens have been added but not yet returned by the device.
+    ///
+    /// The elements taken can still be popped as usual. This is intended to be called once the
+    /// device has been reset, to find out which requests must be resubmitted with
+    /// [`VirtQueue::restart`].
+    pub fn pending_tokens(&mut self) -> Result<[bool; SIZE]> {
+        while self.used_ring_nonempty() {
+            self.take_used()?;
+        }
+        // Descriptors which are in use have a non-zero length, and the heads of chains are those
+        // which no other descriptor in use links to.
+        let mut pending: [bool; SIZE] = core::array::from_fn(|i| self.desc_shadow[i].len != 0);
+        for desc in &self.desc_shadow {
+            if let (true, Some(next)) = (desc.len != 0, desc.next()) {
+                pending[usize::from(next)] = false;
+            }
+        }
+        for (pending, used_len) in pending.iter_mut().zip(&self.used_lens) {
+            *pending &= used_len.is_none();
+        }
+        Ok(pending)
+    }
+
+    /// Sets the queue up again with the transport after the device has been reset and its
+    /// features renegotiated, reusing the same memory, and makes the descriptor chains for the
+    /// given tokens available to the device again, in the order in which they were first added.
+    ///
+    /// Tokens are unchanged, so chains can still be popped as before. Elements which the device
+    /// returned before it was reset must already have been taken with
+    /// [`VirtQueue::pending_tokens`], and `tokens` must be a subset of the pending tokens it
+    /// returned, or [`Error::WrongToken`] is returned without making anything available. The
+    /// caller should notify the device if [`VirtQueue::should_notify`] returns true once the
+    /// device is ready.
+    pub fn restart<T: Transport>(&mut self, transport: &mut T, tokens: &[bool; SIZE]) -> Result {
+        if transport.queue_used(self.queue_idx) {
+            return Err(Error::AlreadyUsed);
+        }
+        let pending = self.pending_tokens()?;
+        if tokens
+            .iter()
+            .zip(&pending)
+            .any(|(&token, &pending)| token && !pending)
+        {
+            return Err(Error::WrongToken);
+        }
+
+        // Make the chains available again in the order in which they were originally added, as the
+        // device may rely on that, e.g. for write ordering.
+        let mut heads = [0; SIZE];
+        let mut num_heads = 0;
+        for head in (0..self.size).filter(|&head| tokens[usize::from(head)]) {
+            heads[num_heads] = head;
+            num_heads += 1;
+        }
+        let heads = &mut heads[..num_heads];
+        let old_avail_idx = self.avail_idx;
+        heads.sort_unstable_by_key(|&head| {
+            Reverse(old_avail_idx.wrapping_sub(self.avail_order[usize::from(head)]))
+        });
+
+        for index in 0..self.size {
+            self.write_desc(index);
+        }
+        self.avail_idx = 0;
+        // Keep the order of elements which were taken but not yet popped relative to the new
+        // `last_used_idx`.
+        for order in &mut self.used_order {
+            *order = order.wrapping_sub(self.last_used_idx);
+        }
+        self.last_used_idx = 0;
+        // Safe because self.avail and self.used are properly aligned, dereferenceable and
+        // initialised, and the device has been reset so won't access them until `queue_set`.
+        unsafe {
+            (*self.avail.as_ptr()).idx.store(0, Ordering::Release);
+            (*self.used_event()).store(self.completion_threshold - 1, Ordering::Release);
+            (*self.used.as_ptr()).idx.store(0, Ordering::Release);
+            (*self.avail_event()).store(0, Ordering::Release);
+        }
+
+        transport.queue_set(
+            self.queue_idx,
+            self.size.into(),
+            self.layout.descriptors_paddr(),
+            self.layout.driver_area_paddr(),
+            self.layout.device_area_paddr(),
+        );
+
+        for &mut head in heads {
+            let avail_slot = self.avail_idx & (self.size - 1);
+            // Safe because self.avail is properly aligned, dereferenceable and initialised.
+            unsafe {
+                (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
+            }
+            self.avail_order[usize::from(head)] = self.avail_idx;
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+        }
+        // Write barrier so that device sees changes to descriptor table and available ring before
+        // change to available index.
+        fence(Ordering::SeqCst);
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.avail.as_ptr())
+                .idx
+                .store(self.avail_idx, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the queue, which is `SIZE` unless it was created with
+    /// [`VirtQueue::new_clamped`] and the device doesn't support that many.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
     /// Returns the number of free descriptors.
     pub fn available_desc(&self) -> usize {
         #[cfg(feature = "alloc")]
         if self.indirect {
-            return if usize::from(self.num_used) == SIZE {
+            return if self.num_used == self.size {
                 0
             } else {
-                SIZE
+                self.size.into()
             };
         }
 
-        SIZE - usize::from(self.num_used)
+        usize::from(self.size - self.num_used)
     }
 
     /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
@@ -417,6 +758,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         &mut self,
         head: u16,
         inputs: &'a [&'a [u8]],
+        shared: Option<SharedBuffer>,
         outputs: &'a mut [&'a mut [u8]],
     ) {
         let original_free_head = self.free_head;
@@ -436,7 +778,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 head_desc.unset_buf();
                 self.num_used -= 1;
                 head_desc.next = original_free_head;
-                
+
                 unsafe {
                     self.hal.unshare(
                         paddr as usize,
@@ -446,16 +788,26 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 }
 
                 // Unshare the buffers in the indirect descriptor list, and free it.
-                assert_eq!(indirect_list.len(), inputs.len() + outputs.len());
-                for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+                assert_eq!(
+                    indirect_list.len(),
+                    inputs.len() + usize::from(shared.is_some()) + outputs.len()
+                );
+                for (i, (buffer, direction, shared_paddr)) in
+                    InputOutputIter::new(inputs, shared, outputs).enumerate()
+                {
                     assert_ne!(buffer.len(), 0);
+                    if shared_paddr.is_some() {
+                        // The buffer wasn't shared by us, so there is nothing to unshare.
+                        continue;
+                    }
 
                     // SAFETY: The caller ensures that the buffer is valid and matches the
                     // descriptor from which we got `paddr`.
                     unsafe {
                         // Unshare the buffer (and perhaps copy its contents back to the original
                         // buffer).
-                        self.hal.unshare(indirect_list[i].addr as usize, buffer, direction);
+                        self.hal
+                            .unshare(indirect_list[i].addr as usize, buffer, direction);
                     }
                 }
                 drop(indirect_list);
@@ -463,7 +815,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         } else {
             let mut next = Some(head);
 
-            for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+            for (buffer, direction, shared_paddr) in InputOutputIter::new(inputs, shared, outputs) {
                 assert_ne!(buffer.len(), 0);
 
                 let desc_index = next.expect("Descriptor chain was shorter than expected.");
@@ -479,6 +831,10 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
                 self.write_desc(desc_index);
 
+                if shared_paddr.is_some() {
+                    // The buffer wasn't shared by us, so there is nothing to unshare.
+                    continue;
+                }
                 // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
                 // from which we got `paddr`.
                 unsafe {
@@ -493,8 +849,13 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
-    /// If the given token is next on the device used queue, pops it and returns the total buffer
-    /// length which was used (written) by the device.
+    /// If the device has finished with the given token, pops it and returns the total buffer length
+    /// which was used (written) by the device.
+    ///
+    /// The token doesn't need to be next in the used ring: any elements before it are taken from the
+    /// used ring and kept until they are popped in turn. Returns [`Error::NotReady`] if the device
+    /// hasn't finished with the token yet, or [`Error::InvalidParam`] (without popping anything) if
+    /// the number, lengths or directions of the buffers don't match those added with the token.
     ///
     /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
     ///
@@ -508,44 +869,205 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
     ) -> Result<u32> {
-        if !self.can_pop() {
-            return Err(Error::NotReady);
+        // Safe because our caller promises the same things as we require.
+        unsafe { self.pop_used_buffers(token, inputs, None, outputs) }
+    }
+
+    /// Pops the given token like [`VirtQueue::pop_used`], for buffers which were added with
+    /// [`VirtQueue::add_shared`].
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs`, `shared` and `outputs` must match the set of buffers originally
+    /// added to the queue by `add_shared` when it returned the token being passed in here.
+    pub unsafe fn pop_used_shared<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        shared: SharedBuffer,
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // Safe because our caller promises the same things as we require.
+        unsafe { self.pop_used_buffers(token, inputs, Some(shared), outputs) }
+    }
+
+    /// Pops the given token, which the device has not returned, and unshares its buffers.
+    ///
+    /// This is intended to be called once the device has been reset, to reclaim the buffers of
+    /// requests which it will never complete. Returns [`Error::WrongToken`] if the token isn't
+    /// pending, or [`Error::InvalidParam`] (without popping anything) if the number, lengths or
+    /// directions of the buffers don't match those added with the token.
+    ///
+    /// # Safety
+    ///
+    /// The device must no longer access the buffers, e.g. because it has been reset, and the
+    /// buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_pending<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result {
+        if token >= self.size || !self.pending_tokens()?[usize::from(token)] {
+            return Err(Error::WrongToken);
         }
+        if !self.buffers_match(token, inputs, None, outputs) {
+            return Err(Error::InvalidParam);
+        }
+        self.num_in_flight -= 1;
 
-        // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-        let index;
-        let len;
-        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
+        // Safe because the caller ensures the buffers are valid and match the descriptor, and that
+        // the device is no longer accessing them.
         unsafe {
-            index = (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16;
-            len = (*self.used.as_ptr()).ring[last_used_slot as usize].len;
+            self.recycle_descriptors(token, inputs, None, outputs);
         }
+        Ok(())
+    }
 
-        if index != token {
-            // The device used a different descriptor chain to the one we were expecting.
+    /// Pops the given token, whose buffers are the inputs, optional shared buffer and outputs.
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtQueue::pop_used_shared`].
+    unsafe fn pop_used_buffers<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        shared: Option<SharedBuffer>,
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        if token >= self.size {
             return Err(Error::WrongToken);
         }
+        self.take_used_until(token)?;
+        let Some(len) = self.used_lens[usize::from(token)] else {
+            return Err(Error::NotReady);
+        };
+        if !self.buffers_match(token, inputs, shared, outputs) {
+            return Err(Error::InvalidParam);
+        }
+        self.used_lens[usize::from(token)] = None;
+        self.num_used_lens -= 1;
+        self.num_in_flight -= 1;
 
         // Safe because the caller ensures the buffers are valid and match the descriptor.
         unsafe {
-            self.recycle_descriptors(index, inputs, outputs);
+            self.recycle_descriptors(token, inputs, shared, outputs);
+        }
+
+        Ok(len)
+    }
+
+    /// Takes elements from the used ring until the given token is found, and returns whether it has
+    /// been.
+    fn take_used_until(&mut self, token: u16) -> Result<bool> {
+        while self.used_lens[usize::from(token)].is_none() && self.used_ring_nonempty() {
+            self.take_used()?;
+        }
+        Ok(self.used_lens[usize::from(token)].is_some())
+    }
+
+    /// Takes the next element from the used ring and records its length in `used_lens`, so that
+    /// the device can reuse the slot.
+    fn take_used(&mut self) -> Result<()> {
+        let last_used_slot = self.last_used_idx & (self.size - 1);
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        let (index, len) = unsafe {
+            let elem = &(*self.used.as_ptr()).ring[last_used_slot as usize];
+            (elem.id, elem.len)
+        };
+        let Some(used_len) = self.used_lens[..self.size.into()].get_mut(index as usize) else {
+            // The device used a descriptor chain which doesn't exist.
+            return Err(Error::WrongToken);
+        };
+        if used_len.is_some() {
+            // The device used the same descriptor chain twice.
+            return Err(Error::WrongToken);
         }
+        *used_len = Some(len);
+        self.num_used_lens += 1;
+        self.used_order[index as usize] = self.last_used_idx;
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
 
+        self.write_used_event();
+        Ok(())
+    }
+
+    /// Writes `used_event` so that the device sends a notification once it has returned
+    /// `completion_threshold` more used elements, if `event_idx` is enabled.
+    fn write_used_event(&mut self) {
         if self.event_idx {
+            let used_event = self
+                .last_used_idx
+                .wrapping_add(self.completion_threshold - 1);
+            // Safe because self.avail is properly aligned, dereferenceable and initialised.
             unsafe {
-                (*self.avail.as_ptr())
-                    .used_event
-                    .store(self.last_used_idx, Ordering::Release);
+                (*self.used_event()).store(used_event, Ordering::Release);
             }
         }
+    }
 
-        Ok(len)
+    /// Returns whether the given buffers have the same number, lengths and directions as the
+    /// descriptor chain starting at `head`.
+    fn buffers_match(
+        &self,
+        head: u16,
+        inputs: &[&[u8]],
+        shared: Option<SharedBuffer>,
+        outputs: &[&mut [u8]],
+    ) -> bool {
+        let buffers = inputs
+            .iter()
+            .map(|input| (input.len(), DescFlags::empty()))
+            .chain(shared.map(|shared| (shared.buffer.len(), shared.flags())))
+            .chain(
+                outputs
+                    .iter()
+                    .map(|output| (output.len(), DescFlags::WRITE)),
+            );
+        let head_desc = &self.desc_shadow[usize::from(head)];
+        if head_desc.flags.contains(DescFlags::INDIRECT) {
+            #[cfg(feature = "alloc")]
+            {
+                let Some(indirect_list) = self.indirect_lists[usize::from(head)] else {
+                    return false;
+                };
+                // SAFETY: We allocated the indirect list in `add_indirect`, and it isn't freed
+                // until the chain is recycled.
+                let indirect_list = unsafe { indirect_list.as_ref() };
+                let count = inputs.len() + usize::from(shared.is_some()) + outputs.len();
+                indirect_list.len() == count
+                    && indirect_list
+                        .iter()
+                        .zip(buffers)
+                        .all(|(desc, buffer)| desc_matches(desc, buffer))
+            }
+            #[cfg(not(feature = "alloc"))]
+            false
+        } else {
+            let mut next = Some(head);
+            for buffer in buffers {
+                let Some(index) = next else {
+                    return false;
+                };
+                let desc = &self.desc_shadow[usize::from(index)];
+                if !desc_matches(desc, buffer) {
+                    return false;
+                }
+                next = desc.next();
+            }
+            next.is_none()
+        }
     }
 }
 
+/// Returns whether the descriptor has the given length and `WRITE` flag.
+fn desc_matches(desc: &Descriptor, (len, write): (usize, DescFlags)) -> bool {
+    desc.len as usize == len && desc.flags & DescFlags::WRITE == write
+}
+
 // SAFETY: None of the virt queue resources are tied to a particular thread.
 unsafe impl<H: Hal, const SIZE: usize> Send for VirtQueue<H, SIZE> {}
 
@@ -575,9 +1097,6 @@ enum VirtQueueLayout<H: Hal> {
 }
 
 impl<H: Hal> VirtQueueLayout<H> {
-
-
-    
     /// Allocates a single DMA region containing all parts of the virtqueue, following the layout
     /// required by legacy interfaces.
     ///
@@ -601,7 +1120,8 @@ impl<H: Hal> VirtQueueLayout<H> {
     /// and allows the HAL to know which DMA regions are used in which direction.
     fn allocate_flexible(hal: H, queue_size: u16) -> Result<Self> {
         let (desc, avail, used) = queue_part_sizes(queue_size);
-        let driver_to_device_dma = Dma::new(hal, pages(desc + avail), BufferDirection::DriverToDevice)?;
+        let driver_to_device_dma =
+            Dma::new(hal, pages(desc + avail), BufferDirection::DriverToDevice)?;
         let device_to_driver_dma = Dma::new(hal, pages(used), BufferDirection::DeviceToDriver)?;
         Ok(Self::Modern {
             driver_to_device_dma,
@@ -713,7 +1233,8 @@ pub(crate) struct Descriptor {
 }
 
 impl Descriptor {
-    /// Sets the buffer address, length and flags, and shares it with the device.
+    /// Sets the buffer address, length and flags, and shares it with the device unless it has
+    /// already been shared at the given physical address.
     ///
     /// # Safety
     ///
@@ -723,12 +1244,14 @@ impl Descriptor {
         hal: H,
         buf: NonNull<[u8]>,
         direction: BufferDirection,
+        paddr: Option<PhysAddr>,
         extra_flags: DescFlags,
     ) {
-        // Safe because our caller promises that the buffer is valid.
-        unsafe {
-            self.addr = hal.share(buf, direction) as u64;
-        }
+        self.addr = match paddr {
+            Some(paddr) => paddr as u64,
+            // Safe because our caller promises that the buffer is valid.
+            None => unsafe { hal.share(buf, direction) as u64 },
+        };
         self.len = buf.len().try_into().unwrap();
         self.flags = extra_flags
             | match direction {
@@ -782,7 +1305,8 @@ struct AvailRing<const SIZE: usize> {
     /// A driver MUST NOT decrement the idx.
     idx: AtomicU16,
     ring: [u16; SIZE],
-    /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated.
+    /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated. This is only at this offset if the queue
+    /// has `SIZE` slots, so it is accessed with `VirtQueue::used_event`.
     used_event: AtomicU16,
 }
 
@@ -794,7 +1318,8 @@ struct UsedRing<const SIZE: usize> {
     flags: AtomicU16,
     idx: AtomicU16,
     ring: [UsedElem; SIZE],
-    /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated.
+    /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated. This is only at this offset if the queue
+    /// has `SIZE` slots, so it is accessed with `VirtQueue::avail_event`.
     avail_event: AtomicU16,
 }
 
@@ -805,26 +1330,85 @@ struct UsedElem {
     len: u32,
 }
 
+/// A buffer which has already been shared with the device, such as part of a [`Dma`] region, so
+/// that a [`VirtQueue`] can use its physical address directly rather than calling [`Hal::share`].
+#[derive(Clone, Copy, Debug)]
+pub struct SharedBuffer {
+    buffer: NonNull<[u8]>,
+    paddr: PhysAddr,
+    direction: BufferDirection,
+}
+
+impl SharedBuffer {
+    /// Creates a new shared buffer for the given memory, which the device can access at `paddr`.
+    ///
+    /// `direction` must be [`BufferDirection::DriverToDevice`] or
+    /// [`BufferDirection::DeviceToDriver`].
+    pub fn new(buffer: NonNull<[u8]>, paddr: PhysAddr, direction: BufferDirection) -> Self {
+        Self {
+            buffer,
+            paddr,
+            direction,
+        }
+    }
+
+    /// Returns the memory of the buffer.
+    pub fn buffer(&self) -> NonNull<[u8]> {
+        self.buffer
+    }
+
+    /// Returns the length of the buffer.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns the descriptor flags with which the buffer is added to a queue.
+    fn flags(&self) -> DescFlags {
+        if self.direction == BufferDirection::DeviceToDriver {
+            DescFlags::WRITE
+        } else {
+            DescFlags::empty()
+        }
+    }
+}
+
 struct InputOutputIter<'a, 'b> {
     inputs: &'a [&'b [u8]],
+    shared: Option<SharedBuffer>,
     outputs: &'a mut [&'b mut [u8]],
 }
 
 impl<'a, 'b> InputOutputIter<'a, 'b> {
-    fn new(inputs: &'a [&'b [u8]], outputs: &'a mut [&'b mut [u8]]) -> Self {
-        Self { inputs, outputs }
+    fn new(
+        inputs: &'a [&'b [u8]],
+        shared: Option<SharedBuffer>,
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Self {
+        Self {
+            inputs,
+            shared,
+            outputs,
+        }
     }
 }
 
 impl<'a, 'b> Iterator for InputOutputIter<'a, 'b> {
-    type Item = (NonNull<[u8]>, BufferDirection);
+    /// The buffer, its direction, and its physical address if it has already been shared.
+    type Item = (NonNull<[u8]>, BufferDirection, Option<PhysAddr>);
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(input) = take_first(&mut self.inputs) {
-            Some(((*input).into(), BufferDirection::DriverToDevice))
+            Some(((*input).into(), BufferDirection::DriverToDevice, None))
+        } else if let Some(shared) = self.shared.take() {
+            Some((shared.buffer, shared.direction, Some(shared.paddr)))
         } else {
             let output = take_first_mut(&mut self.outputs)?;
-            Some(((*output).into(), BufferDirection::DeviceToDriver))
+            Some(((*output).into(), BufferDirection::DeviceToDriver, None))
         }
     }
 }
@@ -979,3 +1563,263 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
         true
     }
 }
+
+/// Returns the address and length of each buffer in the descriptor chains which the driver has made
+/// available but the fake device hasn't yet used, including indirect descriptor lists and the
+/// buffers in them.
+#[cfg(test)]
+pub(crate) fn fake_available_buffers<const QUEUE_SIZE: usize>(
+    descriptors: *const [Descriptor; QUEUE_SIZE],
+    queue_driver_area: *const u8,
+    queue_device_area: *const u8,
+) -> Vec<(PhysAddr, usize)> {
+    use core::slice;
+
+    let available_ring = queue_driver_area as *const AvailRing<QUEUE_SIZE>;
+    let used_ring = queue_device_area as *const UsedRing<QUEUE_SIZE>;
+
+    let mut buffers = Vec::new();
+    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
+    // nothing else accesses them during this block.
+    unsafe {
+        let avail_idx = (*available_ring).idx.load(Ordering::Acquire);
+        let mut idx = (*used_ring).idx.load(Ordering::Acquire);
+        while idx != avail_idx {
+            let slot = idx & (QUEUE_SIZE as u16 - 1);
+            let mut descriptor =
+                &(*descriptors)[usize::from((*available_ring).ring[slot as usize])];
+            loop {
+                buffers.push((descriptor.addr as PhysAddr, descriptor.len as usize));
+                if descriptor.flags.contains(DescFlags::INDIRECT) {
+                    let indirect_descriptor_list: &[Descriptor] =
+                        zerocopy::Ref::new_slice(slice::from_raw_parts(
+                            descriptor.addr as *const u8,
+                            descriptor.len as usize,
+                        ))
+                        .unwrap()
+                        .into_slice();
+                    buffers.extend(
+                        indirect_descriptor_list
+                            .iter()
+                            .map(|desc| (desc.addr as PhysAddr, desc.len as usize)),
+                    );
+                }
+                let Some(next) = descriptor.next() else {
+                    break;
+                };
+                descriptor = &(*descriptors)[usize::from(next)];
+            }
+            idx = idx.wrapping_add(1);
+        }
+    }
+    buffers
+}
+
+/// Returns whether the fake device should send a used buffer notification after returning the
+/// latest element in the used ring, following the driver's `used_event` if `event_idx` has been
+/// negotiated or its `NO_INTERRUPT` flag otherwise.
+#[cfg(test)]
+pub(crate) fn fake_needs_interrupt<const QUEUE_SIZE: usize>(
+    queue_driver_area: *const u8,
+    queue_device_area: *const u8,
+    event_idx: bool,
+) -> bool {
+    let available_ring = queue_driver_area as *const AvailRing<QUEUE_SIZE>;
+    let used_ring = queue_device_area as *const UsedRing<QUEUE_SIZE>;
+
+    // Safe because the various pointers are properly aligned, dereferenceable and initialised.
+    unsafe {
+        if event_idx {
+            let new_idx = (*used_ring).idx.load(Ordering::Acquire);
+            let used_event = (*available_ring).used_event.load(Ordering::Acquire);
+            // The notification is needed if the used index has just moved past `used_event`.
+            new_idx.wrapping_sub(1) == used_event
+        } else {
+            (*available_ring).flags.load(Ordering::Acquire) & 0x0001 == 0
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use crate::transport::{
+        fake::{FakeTransport, State},
+        DeviceType,
+    };
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+
+    fn fake_transport() -> (FakeTransport<()>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State::new(1)));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::dangling(),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+
+    #[test]
+    fn peek_used_in_device_order() {
+        let (mut transport, state) = fake_transport();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(FakeHal::new(), &mut transport, 0, false, false).unwrap();
+        let (a, b, c, d) = ([1], [2], [3], [4]);
+
+        // Safe because the buffers outlive the queue's use of them, and are popped before they are
+        // dropped.
+        unsafe {
+            let token_a = queue.add(&[&a], &mut []).unwrap();
+            let token_b = queue.add(&[&b], &mut []).unwrap();
+            state.lock().unwrap().read_from_queue::<4>(0);
+            state.lock().unwrap().read_from_queue::<4>(0);
+            queue.pop_used(token_a, &[&a], &mut []).unwrap();
+
+            // C reuses A's descriptor, so the device returns B before C even though C has the
+            // lower token.
+            let token_c = queue.add(&[&c], &mut []).unwrap();
+            let token_d = queue.add(&[&d], &mut []).unwrap();
+            assert!(token_c < token_b);
+            state.lock().unwrap().read_from_queue::<4>(0);
+            state.lock().unwrap().read_from_queue::<4>(0);
+
+            // Popping D out of order stashes B and C.
+            assert_eq!(queue.pop_used(token_d, &[&d], &mut []), Ok(1));
+            assert_eq!(queue.peek_used(), Some(token_b));
+            assert_eq!(queue.pop_used(token_b, &[&b], &mut []), Ok(1));
+            assert_eq!(queue.peek_used(), Some(token_c));
+            assert_eq!(queue.pop_used(token_c, &[&c], &mut []), Ok(1));
+            assert_eq!(queue.peek_used(), None);
+        }
+    }
+
+    #[test]
+    fn pop_used_not_ready() {
+        let (mut transport, state) = fake_transport();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(FakeHal::new(), &mut transport, 0, false, false).unwrap();
+        let (a, b) = ([1], [2, 3]);
+
+        // Safe because the buffers outlive the queue's use of them, and are popped before they are
+        // dropped.
+        unsafe {
+            let token_a = queue.add(&[&a], &mut []).unwrap();
+            let token_b = queue.add(&[&b], &mut []).unwrap();
+            assert_eq!(
+                queue.pop_used(token_a, &[&a], &mut []),
+                Err(Error::NotReady)
+            );
+            state.lock().unwrap().read_from_queue::<4>(0);
+            state.lock().unwrap().read_from_queue::<4>(0);
+
+            assert_eq!(
+                queue.pop_used(token_b, &[&a], &mut []),
+                Err(Error::InvalidParam)
+            );
+            assert_eq!(queue.pop_used(token_b, &[&b], &mut []), Ok(2));
+            assert_eq!(queue.pop_used(token_a, &[&a], &mut []), Ok(1));
+            assert_eq!(queue.in_flight(), 0);
+        }
+    }
+
+    #[test]
+    fn pending_tokens() {
+        let (mut transport, state) = fake_transport();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(FakeHal::new(), &mut transport, 0, false, false).unwrap();
+        let (a, b, c) = ([1], [2], [3]);
+
+        // Safe because the buffers outlive the queue's use of them, and are popped before they are
+        // dropped.
+        unsafe {
+            let token_a = queue.add(&[&a], &mut []).unwrap();
+            let token_b = queue.add(&[&b], &mut []).unwrap();
+            let token_c = queue.add(&[&c], &mut []).unwrap();
+            state.lock().unwrap().read_from_queue::<4>(0);
+
+            let pending = queue.pending_tokens().unwrap();
+            let expected: [bool; 4] = core::array::from_fn(|token| {
+                token == usize::from(token_b) || token == usize::from(token_c)
+            });
+            assert_eq!(pending, expected);
+            assert_eq!(queue.pending(), 2);
+
+            // The element taken by `pending_tokens` can still be popped.
+            assert_eq!(queue.peek_used(), Some(token_a));
+            assert_eq!(queue.pop_used(token_a, &[&a], &mut []), Ok(1));
+            state.lock().unwrap().read_from_queue::<4>(0);
+            state.lock().unwrap().read_from_queue::<4>(0);
+            assert_eq!(queue.pop_used(token_b, &[&b], &mut []), Ok(1));
+            assert_eq!(queue.pop_used(token_c, &[&c], &mut []), Ok(1));
+            assert_eq!(queue.pending_tokens().unwrap(), [false; 4]);
+        }
+    }
+
+    #[test]
+    fn restart_in_submission_order() {
+        let (mut transport, state) = fake_transport();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(FakeHal::new(), &mut transport, 0, false, false).unwrap();
+        let (a, b, c) = ([1], [2], [3]);
+
+        // Safe because the buffers outlive the queue's use of them, and are popped before they are
+        // dropped.
+        unsafe {
+            let token_a = queue.add(&[&a], &mut []).unwrap();
+            let token_b = queue.add(&[&b], &mut []).unwrap();
+            state.lock().unwrap().read_from_queue::<4>(0);
+            queue.pop_used(token_a, &[&a], &mut []).unwrap();
+            // C reuses A's descriptor, so has a lower token than B even though it was added later.
+            let token_c = queue.add(&[&c], &mut []).unwrap();
+            assert!(token_c < token_b);
+
+            // Reset the device and make the pending requests available again.
+            transport.queue_unset(0);
+            let pending = queue.pending_tokens().unwrap();
+            queue.restart(&mut transport, &pending).unwrap();
+            assert_eq!(queue.in_flight(), 2);
+
+            assert_eq!(state.lock().unwrap().read_from_queue::<4>(0), b);
+            assert_eq!(state.lock().unwrap().read_from_queue::<4>(0), c);
+            assert_eq!(queue.peek_used(), Some(token_b));
+            assert_eq!(queue.pop_used(token_b, &[&b], &mut []), Ok(1));
+            assert_eq!(queue.pop_used(token_c, &[&c], &mut []), Ok(1));
+        }
+    }
+
+    #[test]
+    fn restart_keeps_taken_elements() {
+        let (mut transport, state) = fake_transport();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(FakeHal::new(), &mut transport, 0, false, false).unwrap();
+        let (a, b) = ([1], [2]);
+
+        // Safe because the buffers outlive the queue's use of them, and are popped before they are
+        // dropped.
+        unsafe {
+            let token_a = queue.add(&[&a], &mut []).unwrap();
+            let token_b = queue.add(&[&b], &mut []).unwrap();
+            state.lock().unwrap().read_from_queue::<4>(0);
+
+            // A was finished before the device was reset, so only B is made available again.
+            transport.queue_unset(0);
+            let pending = queue.pending_tokens().unwrap();
+            let mut wrong = pending;
+            wrong[usize::from(token_a)] = true;
+            assert_eq!(
+                queue.restart(&mut transport, &wrong),
+                Err(Error::WrongToken)
+            );
+            queue.restart(&mut transport, &pending).unwrap();
+
+            assert_eq!(queue.peek_used(), Some(token_a));
+            assert_eq!(queue.pop_used(token_a, &[&a], &mut []), Ok(1));
+            assert_eq!(state.lock().unwrap().read_from_queue::<4>(0), b);
+            assert_eq!(queue.pop_used(token_b, &[&b], &mut []), Ok(1));
+            assert!(!queue.can_pop());
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! A fake transport for unit tests.
+
+use super::{DeviceStatus, DeviceType, InterruptStatus, Transport};
+use crate::queue::{
+    fake_available_buffers, fake_needs_interrupt, fake_read_write_queue, Descriptor,
+};
+use crate::{PhysAddr, Result};
+use alloc::{sync::Arc, vec::Vec};
+use core::{any::TypeId, mem, ptr::NonNull};
+use std::{sync::Mutex, thread, time::Duration};
+
+/// The `VIRTIO_F_EVENT_IDX` feature bit.
+const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+
+/// A fake implementation of [`Transport`] for unit tests.
+///
+/// The device side is played by the test, usually on another thread, through the shared
+/// [`State`].
+#[derive(Debug)]
+pub struct FakeTransport<C: 'static> {
+    /// The type of device which the transport reports.
+    pub device_type: DeviceType,
+    /// The maximum size of every queue.
+    pub max_queue_size: u32,
+    /// The features which the device offers.
+    pub device_features: u64,
+    /// The config space of the device.
+    pub config_space: NonNull<C>,
+    /// The state shared with the fake device.
+    pub state: Arc<Mutex<State>>,
+}
+
+impl<C> Transport for FakeTransport<C> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.device_features
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.state.lock().unwrap().driver_features = driver_features;
+    }
+
+    fn max_queue_size(&mut self, _queue: u16) -> u32 {
+        self.max_queue_size
+    }
+
+    fn notify(&mut self, queue: u16) {
+        let mut state = self.state.lock().unwrap();
+        let queue = &mut state.queues[usize::from(queue)];
+        queue.notified = true;
+        queue.notify_count += 1;
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.state.lock().unwrap().status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        let mut state = self.state.lock().unwrap();
+        state.status = status;
+        if status.is_empty() {
+            // Resetting the device makes it forget the queues.
+            for queue in &mut state.queues {
+                queue.size = 0;
+                queue.descriptors = 0;
+                queue.driver_area = 0;
+                queue.device_area = 0;
+            }
+        }
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        self.state.lock().unwrap().guest_page_size = guest_page_size;
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let queue = &mut state.queues[usize::from(queue)];
+        queue.size = size;
+        queue.descriptors = descriptors;
+        queue.driver_area = driver_area;
+        queue.device_area = device_area;
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        let mut state = self.state.lock().unwrap();
+        let queue = &mut state.queues[usize::from(queue)];
+        queue.size = 0;
+        queue.descriptors = 0;
+        queue.driver_area = 0;
+        queue.device_area = 0;
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.state.lock().unwrap().queues[usize::from(queue)].descriptors != 0
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        mem::take(&mut self.state.lock().unwrap().interrupt_status)
+    }
+
+    fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
+        if TypeId::of::<T>() == TypeId::of::<C>() {
+            Ok(self.config_space.cast())
+        } else {
+            panic!("Unexpected config space type.");
+        }
+    }
+}
+
+/// The state of a [`FakeTransport`], shared with the test playing the device.
+#[derive(Debug, Default)]
+pub struct State {
+    /// The device status written by the driver.
+    pub status: DeviceStatus,
+    /// The features which the driver negotiated.
+    pub driver_features: u64,
+    /// The guest page size written by the driver.
+    pub guest_page_size: u32,
+    /// The causes of the interrupt pending to be acknowledged by the driver, if any.
+    pub interrupt_status: InterruptStatus,
+    /// The state of each queue.
+    pub queues: Vec<QueueStatus>,
+}
+
+impl State {
+    /// Creates the state for a device with the given number of queues.
+    pub fn new(queues: usize) -> Self {
+        let mut state = Self::default();
+        state.queues.resize_with(queues, Default::default);
+        state
+    }
+
+    /// Simulates the device writing to the given queue.
+    ///
+    /// The fake device always uses descriptors in order.
+    pub fn write_to_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16, data: &[u8]) {
+        self.read_write_queue::<QUEUE_SIZE>(queue_index, |input| {
+            assert_eq!(input, Vec::new());
+            data.to_vec()
+        });
+    }
+
+    /// Simulates the device reading from the given queue, and returns the data it read.
+    ///
+    /// The fake device always uses descriptors in order.
+    pub fn read_from_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16) -> Vec<u8> {
+        let mut ret = None;
+        self.read_write_queue::<QUEUE_SIZE>(queue_index, |input| {
+            ret = Some(input);
+            Vec::new()
+        });
+        ret.unwrap()
+    }
+
+    /// Simulates the device reading from and then writing to the given queue.
+    ///
+    /// The fake device always uses descriptors in order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue isn't set up or has no buffers available.
+    pub fn read_write_queue<const QUEUE_SIZE: usize>(
+        &mut self,
+        queue_index: u16,
+        handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+    ) {
+        assert!(self.try_read_write_queue::<QUEUE_SIZE>(queue_index, handler));
+    }
+
+    /// Simulates the device reading from and then writing to the given queue, if the driver has
+    /// made a buffer available, and returns whether it had.
+    ///
+    /// The fake device always uses descriptors in order, and raises a queue interrupt afterwards
+    /// unless the driver has suppressed it.
+    pub fn try_read_write_queue<const QUEUE_SIZE: usize>(
+        &mut self,
+        queue_index: u16,
+        handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+    ) -> bool {
+        let event_idx = self.driver_features & VIRTIO_F_EVENT_IDX != 0;
+        let queue = &mut self.queues[usize::from(queue_index)];
+        assert_ne!(queue.descriptors, 0);
+        if !fake_read_write_queue(
+            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
+            queue.driver_area as *const u8,
+            queue.device_area as *mut u8,
+            handler,
+        ) {
+            return false;
+        }
+        if fake_needs_interrupt::<QUEUE_SIZE>(
+            queue.driver_area as *const u8,
+            queue.device_area as *const u8,
+            event_idx,
+        ) {
+            queue.interrupt_count += 1;
+            self.interrupt_status |= InterruptStatus::QUEUE_INTERRUPT;
+        }
+        true
+    }
+
+    /// Returns the address and length of each buffer which the driver has made available on the
+    /// given queue and the fake device hasn't yet used.
+    pub fn available_buffers<const QUEUE_SIZE: usize>(
+        &self,
+        queue_index: u16,
+    ) -> Vec<(PhysAddr, usize)> {
+        let queue = &self.queues[usize::from(queue_index)];
+        assert_ne!(queue.descriptors, 0);
+        fake_available_buffers(
+            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
+            queue.driver_area as *const u8,
+            queue.device_area as *const u8,
+        )
+    }
+
+    /// Waits until the given queue is notified, and clears the notification.
+    pub fn wait_until_queue_notified(state: &Mutex<Self>, queue_index: u16) {
+        while !mem::take(&mut state.lock().unwrap().queues[usize::from(queue_index)].notified) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// The state of a queue of a [`FakeTransport`].
+#[derive(Debug, Default)]
+pub struct QueueStatus {
+    /// The size of the queue set by the driver, or 0 if it isn't set up.
+    pub size: u32,
+    /// The physical address of the descriptor table.
+    pub descriptors: PhysAddr,
+    /// The physical address of the driver area, i.e. the available ring.
+    pub driver_area: PhysAddr,
+    /// The physical address of the device area, i.e. the used ring.
+    pub device_area: PhysAddr,
+    /// Whether the queue has been notified since the test last cleared this.
+    pub notified: bool,
+    /// The number of times the queue has been notified.
+    pub notify_count: usize,
+    /// The number of used buffer notifications the fake device has sent for the queue.
+    pub interrupt_count: usize,
+}
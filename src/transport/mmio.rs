@@ -1,6 +1,6 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport};
 use crate::{
     align_up,
     queue::Descriptor,
@@ -215,7 +215,19 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: ReadOnly<u32>,
+
+    /// Shared memory region selection, new interface only.
+    shm_sel: WriteOnly<u32>,
+    /// The length of the selected shared memory region, or all ones if it doesn't exist.
+    shm_len_low: ReadOnly<u32>,
+    shm_len_high: ReadOnly<u32>,
+    /// The physical address of the selected shared memory region.
+    shm_base_low: ReadOnly<u32>,
+    shm_base_high: ReadOnly<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 15],
 
     config_generation: ReadOnly<u32>,
 }
@@ -265,6 +277,12 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            shm_sel: Default::default(),
+            shm_len_low: ReadOnly::new(u32::MAX),
+            shm_len_high: ReadOnly::new(u32::MAX),
+            shm_base_low: Default::default(),
+            shm_base_high: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
@@ -485,16 +503,14 @@ impl Transport for MmioTransport {
         }
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
             let interrupt = volread!(self.header, interrupt_status);
             if interrupt != 0 {
                 volwrite!(self.header, interrupt_ack, interrupt);
-                true
-            } else {
-                false
             }
+            InterruptStatus::from_bits_truncate(interrupt)
         }
     }
 
@@ -508,6 +524,29 @@ impl Transport for MmioTransport {
         }
         Ok(NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _).unwrap())
     }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        if self.version == MmioVersion::Legacy {
+            return None;
+        }
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        let (len, base) = unsafe {
+            volwrite!(self.header, shm_sel, id.into());
+            let len = u64::from(volread!(self.header, shm_len_low))
+                | u64::from(volread!(self.header, shm_len_high)) << 32;
+            let base = u64::from(volread!(self.header, shm_base_low))
+                | u64::from(volread!(self.header, shm_base_high)) << 32;
+            (len, base)
+        };
+        // A length of all ones means that there is no such region.
+        if len == u64::MAX {
+            return None;
+        }
+        Some(SharedMemoryRegion {
+            paddr: base as PhysAddr,
+            len,
+        })
+    }
 }
 
 impl Drop for MmioTransport {
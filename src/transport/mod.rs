@@ -1,5 +1,7 @@
 //! VirtIO transports.
 
+#[cfg(test)]
+pub mod fake;
 pub mod mmio;
 pub mod pci;
 
@@ -58,7 +60,14 @@ pub trait Transport {
     /// Acknowledges an interrupt.
     ///
     /// Returns true on success.
-    fn ack_interrupt(&mut self) -> bool;
+    fn ack_interrupt(&mut self) -> bool {
+        !self.ack_interrupt_status().is_empty()
+    }
+
+    /// Acknowledges an interrupt, and returns the causes of the interrupt.
+    ///
+    /// Returns an empty set if there was no pending interrupt.
+    fn ack_interrupt_status(&mut self) -> InterruptStatus;
 
     /// Begins initializing the device.
     ///
@@ -98,6 +107,24 @@ pub trait Transport {
 
     /// Gets the pointer to the config space.
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>>;
+
+    /// Returns the location of the device's shared memory region with the given ID, or `None` if
+    /// it doesn't have one.
+    ///
+    /// Ref: 2.10 Shared Memory Regions
+    fn shared_memory_region(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        None
+    }
+}
+
+/// A shared memory region of a device, which is memory of the device which it makes visible to
+/// the driver, e.g. in a PCI BAR.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The physical address of the start of the region.
+    pub paddr: PhysAddr,
+    /// The length of the region in bytes.
+    pub len: u64,
 }
 
 bitflags! {
@@ -130,6 +157,18 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The causes of an interrupt, as reported by the interrupt status register (MMIO) or ISR
+    /// status (PCI).
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct InterruptStatus: u32 {
+        /// The device has used a buffer in at least one of its virtqueues.
+        const QUEUE_INTERRUPT = 1 << 0;
+        /// The device configuration has changed.
+        const DEVICE_CONFIGURATION_INTERRUPT = 1 << 1;
+    }
+}
+
 /// Types of virtio devices.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -3,7 +3,7 @@
 pub mod bus;
 
 use self::bus::{DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR};
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport};
 use crate::{
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
@@ -40,6 +40,13 @@ const CAP_BAR_OFFSET_OFFSET: u8 = 8;
 const CAP_LENGTH_OFFSET: u8 = 12;
 /// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
 const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
+/// The offset of the `offset_hi` field within `virtio_pci_cap64`.
+const CAP_OFFSET_HI_OFFSET: u8 = 16;
+/// The offset of the `length_hi` field within `virtio_pci_cap64`.
+const CAP_LENGTH_HI_OFFSET: u8 = 20;
+
+/// The maximum number of shared memory regions which are recorded for a device.
+const MAX_SHARED_MEMORY_REGIONS: usize = 8;
 
 /// Common configuration.
 const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
@@ -49,6 +56,8 @@ const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
 const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// Shared memory region.
+const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
 
 fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
@@ -93,6 +102,8 @@ pub struct PciTransport {
     isr_status: NonNull<Volatile<u8>>,
     /// The VirtIO device-specific configuration within some BAR.
     config_space: Option<NonNull<[u32]>>,
+    /// The IDs and locations of the device's shared memory regions.
+    shared_memory_regions: [Option<(u8, SharedMemoryRegion)>; MAX_SHARED_MEMORY_REGIONS],
 }
 
 impl PciTransport {
@@ -119,6 +130,7 @@ impl PciTransport {
         let mut notify_off_multiplier = 0;
         let mut isr_cfg = None;
         let mut device_cfg = None;
+        let mut shm_caps = [None; MAX_SHARED_MEMORY_REGIONS];
         for capability in root.capabilities(device_function) {
             if capability.id != PCI_CAP_ID_VNDR {
                 continue;
@@ -154,10 +166,48 @@ impl PciTransport {
                 VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
                     device_cfg = Some(struct_info);
                 }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG if cap_len >= 24 => {
+                    let id = (root
+                        .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
+                        >> 8) as u8;
+                    let offset_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_OFFSET_HI_OFFSET,
+                    );
+                    let length_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_LENGTH_HI_OFFSET,
+                    );
+                    if let Some(slot) = shm_caps.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some((id, struct_info, offset_hi, length_hi));
+                    }
+                }
                 _ => {}
             }
         }
 
+        // The BARs can only be read once we are done with the capabilities.
+        let mut shared_memory_regions = [None; MAX_SHARED_MEMORY_REGIONS];
+        for (slot, (id, struct_info, offset_hi, length_hi)) in shared_memory_regions
+            .iter_mut()
+            .zip(shm_caps.into_iter().flatten())
+        {
+            let Some((bar_address, _)) = root
+                .bar_info(device_function, struct_info.bar)?
+                .memory_address_size()
+            else {
+                continue;
+            };
+            let offset = u64::from(offset_hi) << 32 | u64::from(struct_info.offset);
+            *slot = Some((
+                id,
+                SharedMemoryRegion {
+                    paddr: (bar_address + offset) as PhysAddr,
+                    len: u64::from(length_hi) << 32 | u64::from(struct_info.length),
+                },
+            ));
+        }
+
         let common_cfg = get_bar_region::<H, _>(
             root,
             hal,
@@ -199,6 +249,7 @@ impl PciTransport {
             notify_off_multiplier,
             isr_status,
             config_space,
+            shared_memory_regions,
         })
     }
 }
@@ -315,13 +366,12 @@ impl Transport for PciTransport {
         }
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
         let isr_status = unsafe { self.isr_status.as_ptr().vread() };
-        // TODO: Distinguish between queue interrupt and device configuration interrupt.
-        isr_status & 0x3 != 0
+        InterruptStatus::from_bits_truncate(isr_status.into())
     }
 
     fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
@@ -343,6 +393,14 @@ impl Transport for PciTransport {
             Err(Error::ConfigSpaceMissing)
         }
     }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        self.shared_memory_regions
+            .iter()
+            .flatten()
+            .find(|(region_id, _)| *region_id == id)
+            .map(|(_, region)| *region)
+    }
 }
 
 // SAFETY: MMIO can be done from any thread or CPU core.
@@ -382,7 +440,7 @@ struct CommonCfg {
 }
 
 /// Information about a VirtIO structure within some BAR, as provided by a `virtio_pci_cap`.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 struct VirtioCapabilityInfo {
     /// The bar in which the structure can be found.
     bar: u8,
@@ -413,10 +471,8 @@ fn get_bar_region<H: Hal, T>(
     let paddr = bar_address as PhysAddr + struct_info.offset as PhysAddr;
     // Safe because the paddr and size describe a valid MMIO region, at least according to the PCI
     // bus.
-    let vaddr = unsafe { 
-        hal.mmio_phys_to_virt(paddr, struct_info.length as usize) 
-    };
-    if vaddr.as_ptr() as usize % align_of::<T>() != 0 {
+    let vaddr = unsafe { hal.mmio_phys_to_virt(paddr, struct_info.length as usize) };
+    if !(vaddr.as_ptr() as usize).is_multiple_of(align_of::<T>()) {
         return Err(VirtioPciError::Misaligned {
             vaddr,
             alignment: align_of::<T>(),